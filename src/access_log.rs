@@ -0,0 +1,181 @@
+//! Writes the access log to a file, as one JSON object per request, rather than to stdout
+
+use crate::handler::RequestId;
+use chrono::offset::Local;
+use hyper::{Body, Request, Response};
+use serde::Serialize;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// A request as recorded in the access log
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    timestamp: String,
+    client_ip: Option<String>,
+    request_id: String,
+    method: String,
+    path: String,
+    version: String,
+    status: u16,
+    duration_ms: f64,
+    /// the artificial delay added with --response-delay, included in `duration_ms`
+    delay_ms: f64,
+}
+
+impl Entry {
+    /// Records a request as it arrives, before its response is known
+    pub fn new(
+        req: &Request<Body>,
+        client_ip: &Option<IpAddr>,
+        request_id: &RequestId,
+    ) -> Self {
+        Entry {
+            timestamp: Local::now().to_rfc3339(),
+            client_ip: client_ip.map(|ip| ip.to_string()),
+            request_id: request_id.0.clone(),
+            method: req.method().to_string(),
+            path: req.uri().path().to_owned(),
+            version: format!("{:?}", req.version()),
+            status: 0,
+            duration_ms: 0.0,
+            delay_ms: 0.0,
+        }
+    }
+
+    /// Completes this entry with the response sent and how long it took to send
+    pub fn finish(
+        self,
+        resp: &Response<Body>,
+        start: Instant,
+        delay: Duration,
+    ) -> Self {
+        Entry {
+            status: resp.status().as_u16(),
+            duration_ms: start.elapsed().as_secs_f64() * 1000.0,
+            delay_ms: delay.as_secs_f64() * 1000.0,
+            ..self
+        }
+    }
+}
+
+struct Sink {
+    file: File,
+    len: u64,
+}
+
+/// An access log file, rotated to `{path}.1` once writing to it would grow it past a maximum size
+#[derive(Clone)]
+pub struct AccessLog {
+    path: PathBuf,
+    max_size: Option<u64>,
+    sink: Arc<Mutex<Sink>>,
+}
+
+impl AccessLog {
+    /// Opens a log for appending, creating it when it doesn't exist
+    pub fn open(
+        path: impl AsRef<Path>,
+        max_size: Option<u64>,
+    ) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = file.metadata()?.len();
+        Ok(AccessLog {
+            path,
+            max_size,
+            sink: Arc::new(Mutex::new(Sink { file, len })),
+        })
+    }
+
+    /// Appends an entry as a line of JSON, rotating the log first when it would grow too large
+    pub fn write(
+        &self,
+        entry: &Entry,
+    ) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut sink = self.sink.lock().expect("access log poisoned");
+        let full = self
+            .max_size
+            .filter(|max| sink.len > 0 && sink.len + line.len() as u64 > *max)
+            .is_some();
+        if full {
+            // naive rotation: keep a single previous file, replacing any older one
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            fs::rename(&self.path, rotated)?;
+            sink.file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&self.path)?;
+            sink.len = 0;
+        }
+        sink.file.write_all(&line)?;
+        sink.len += line.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BoxError;
+
+    /// Returns an entry which always serializes to the same length
+    fn entry() -> Result<Entry, BoxError> {
+        let req = Request::get("/logged").body(Body::empty())?;
+        Ok(Entry {
+            timestamp: "2023-01-01T00:00:00+00:00".into(),
+            status: 200,
+            duration_ms: 1.5,
+            ..Entry::new(&req, &"127.0.0.1".parse().ok(), &RequestId::default())
+        })
+    }
+
+    #[test]
+    fn entries_are_finished_with_their_responses() -> Result<(), BoxError> {
+        let req = Request::get("/logged").body(Body::empty())?;
+        let entry = Entry::new(&req, &None, &RequestId::default()).finish(
+            &Response::new(Body::empty()),
+            Instant::now(),
+            Duration::from_millis(20),
+        );
+        assert_eq!(entry.status, 200);
+        assert_eq!(entry.delay_ms, 20.0);
+        Ok(())
+    }
+
+    #[test]
+    fn writes_are_appended_and_rotated() -> Result<(), BoxError> {
+        let dir = std::env::temp_dir().join(format!("fasttime-access-log-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("access.log");
+        let line_len = serde_json::to_vec(&entry()?)?.len() as u64 + 1;
+        // room for two lines, so the third rotates the log
+        let log = AccessLog::open(&path, Some(line_len * 2))?;
+        log.write(&entry()?)?;
+        log.write(&entry()?)?;
+        let lines = fs::read_to_string(&path)?;
+        assert_eq!(lines.lines().count(), 2);
+        let json: serde_json::Value =
+            serde_json::from_str(lines.lines().next().unwrap_or_default())?;
+        assert_eq!(json["path"], "/logged");
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["client_ip"], "127.0.0.1");
+
+        log.write(&entry()?)?;
+        let rotated = fs::read_to_string(dir.join("access.log.1"))?;
+        let current = fs::read_to_string(&path)?;
+        fs::remove_dir_all(&dir)?;
+        assert_eq!(rotated.lines().count(), 2);
+        assert_eq!(current.lines().count(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+//! Defines an admin server, separate from the application's listener, for inspecting and
+//! purging cached backend responses
+//!
+//! * `GET /cache` lists cached entries
+//! * `DELETE /cache` purges every entry
+//! * `DELETE /cache/surrogate/{key}` purges entries tagged with a surrogate key
+
+use crate::{cache::Cache, BoxError};
+use http::{header::CONTENT_TYPE, Method, Request, Response, StatusCode};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Server,
+};
+use serde_json::json;
+use std::{convert::Infallible, net::TcpListener};
+
+/// Serves admin requests on a listener until the process exits
+pub async fn serve(
+    listener: TcpListener,
+    cache: Cache,
+) -> Result<(), BoxError> {
+    Server::from_tcp(listener)?
+        .serve(make_service_fn(move |_| {
+            let cache = cache.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let resp = respond(&cache, &req);
+                    async move { Ok::<_, Infallible>(resp) }
+                }))
+            }
+        }))
+        .await?;
+    Ok(())
+}
+
+/// Responds to an admin request with JSON
+pub fn respond<B>(
+    cache: &Cache,
+    req: &Request<B>,
+) -> Response<Body> {
+    let path = req.uri().path().trim_end_matches('/');
+    let (status, body) = match (req.method(), path) {
+        (&Method::GET, "/cache") => (StatusCode::OK, json!(cache.list())),
+        (&Method::DELETE, "/cache") => (StatusCode::OK, json!({ "purged": cache.purge(None) })),
+        (&Method::DELETE, path) if path.starts_with("/cache/surrogate/") => {
+            let key = &path["/cache/surrogate/".len()..];
+            (StatusCode::OK, json!({ "purged": cache.purge(Some(key)) }))
+        }
+        (_, "/cache") => (
+            StatusCode::METHOD_NOT_ALLOWED,
+            json!({ "error": "method not allowed" }),
+        ),
+        _ => (StatusCode::NOT_FOUND, json!({ "error": "not found" })),
+    };
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .expect("invalid response")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use http::HeaderMap;
+    use std::time::Duration;
+
+    fn cache() -> Cache {
+        let cache = Cache::default();
+        for (key, surrogate_key) in &[("origin /a", "one"), ("origin /b", "two")] {
+            cache.insert(
+                key.to_string(),
+                &HeaderMap::new(),
+                Some(&crate::cache::CacheOverride {
+                    pass: false,
+                    ttl: Some(Duration::from_secs(60)),
+                }),
+                vec![surrogate_key.to_string()],
+                StatusCode::OK,
+                HeaderMap::new(),
+                Bytes::from_static(b"cached"),
+            );
+        }
+        cache
+    }
+
+    async fn json(resp: Response<Body>) -> Result<serde_json::Value, BoxError> {
+        Ok(serde_json::from_str(&crate::tests::body(resp).await?)?)
+    }
+
+    #[tokio::test]
+    async fn respond_lists_cached_entries() -> Result<(), BoxError> {
+        let resp = respond(&cache(), &Request::get("/cache").body(())?);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/json");
+        let entries = json(resp).await?;
+        assert_eq!(entries[0]["key"], "origin /a");
+        assert_eq!(entries[0]["surrogate_keys"], json!(["one"]));
+        assert_eq!(
+            entries[1]["ttl_remaining_secs"].as_u64().map(|t| t <= 60),
+            Some(true)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn respond_purges_entries() -> Result<(), BoxError> {
+        let cache = cache();
+        let resp = respond(&cache, &Request::delete("/cache/surrogate/one").body(())?);
+        assert_eq!(json(resp).await?, json!({ "purged": 1 }));
+        assert_eq!(cache.list().len(), 1);
+
+        let resp = respond(&cache, &Request::delete("/cache").body(())?);
+        assert_eq!(json(resp).await?, json!({ "purged": 1 }));
+        assert!(cache.list().is_empty());
+
+        let resp = respond(&cache, &Request::post("/cache").body(())?);
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        Ok(())
+    }
+}
@@ -1,16 +1,294 @@
 //! Defines interfaces for responding to backend requests
 
-use crate::BoxError;
-use hyper::{http::HeaderValue, Body, Request, Response};
+use crate::{
+    fastly_dictionary::Dictionaries,
+    fastly_http_req::SurrogateKey,
+    handler::{Handler, Settings},
+    BoxError,
+};
+use anyhow::anyhow;
+use bytes::Bytes;
+use hyper::{
+    body::to_bytes,
+    header::{CONNECTION, HOST},
+    http::{HeaderMap, HeaderName, HeaderValue},
+    Body, Method, Request, Response, StatusCode, Version,
+};
 use log::debug;
-use reqwest::{redirect::Policy, Client};
+use reqwest::{redirect::Policy, Client, Identity};
 use serde_derive::Deserialize;
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fs,
+    net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    rc::Rc,
+    str::FromStr,
+    sync::{Arc, Condvar, Mutex, PoisonError},
+    time::Duration,
+};
+use wasmtime::{Engine, Module, Store};
+
+/// Name of the backend which sends requests back through the same application
+pub const LOOPBACK: &str = "self";
+
+/// Maximum depth of nested loopback requests before responding with a 508
+const MAX_LOOPBACK_DEPTH: usize = 8;
 
 #[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct Backend {
     pub name: String,
     pub address: String,
+    /// the Host header requests for this backend are sent with
+    #[serde(default)]
+    pub host_header: HostHeader,
+}
+
+/// Which Host header requests for a backend are sent with
+///
+/// In configuration, this is `preserve`, `override`, or `value:{host}` to send a host as is
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub enum HostHeader {
+    /// the Host of the request the guest sent, falling back on the host in its uri
+    Preserve,
+    /// the backend's address
+    Override,
+    /// a fixed value
+    Value(String),
+}
+
+impl Default for HostHeader {
+    fn default() -> Self {
+        HostHeader::Preserve
+    }
+}
+
+impl FromStr for HostHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(HostHeader::Preserve),
+            "override" => Ok(HostHeader::Override),
+            _ => match s.strip_prefix("value:") {
+                Some(value) if !value.is_empty() => Ok(HostHeader::Value(value.into())),
+                _ => Err(format!(
+                    "invalid host header `{}`, expected preserve, override, or value:{{host}}",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for HostHeader {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl HostHeader {
+    /// Resolves the Host header to send a request to a backend at `address` with
+    fn resolve<'a>(
+        &'a self,
+        address: &'a str,
+        req: &'a Request<Body>,
+    ) -> &'a str {
+        match self {
+            HostHeader::Preserve => req
+                .headers()
+                .get(HOST)
+                .and_then(|host| host.to_str().ok())
+                .or_else(|| req.uri().authority().map(|authority| authority.as_str()))
+                .unwrap_or(address),
+            HostHeader::Override => address,
+            HostHeader::Value(value) => value,
+        }
+    }
+}
+
+/// A header added to outgoing backend requests, optionally scoped to a single backend
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct BackendHeader {
+    pub backend: Option<String>,
+    pub name: String,
+    pub value: String,
+}
+
+/// A client certificate and private key presented to a backend which requires mutual tls
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct BackendCert {
+    pub backend: String,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// What to do with requests for backends which are not configured
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendFallback {
+    /// respond with a 502
+    Error,
+    /// send the request to the host in its own uri
+    Passthrough,
+}
+
+impl Default for BackendFallback {
+    fn default() -> Self {
+        BackendFallback::Error
+    }
+}
+
+impl FromStr for BackendFallback {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(BackendFallback::Error),
+            "passthrough" => Ok(BackendFallback::Passthrough),
+            other => Err(format!(
+                "invalid backend fallback `{}`, expected error or passthrough",
+                other
+            )),
+        }
+    }
+}
+
+/// Addresses of backend hosts, resolved at startup, keyed by host name
+pub type Resolved = HashMap<String, SocketAddr>;
+
+fn client(resolved: &Resolved) -> reqwest::ClientBuilder {
+    resolved.iter().fold(
+        Client::builder().redirect(Policy::none()),
+        |builder, (host, addr)| builder.resolve(host, *addr),
+    )
+}
+
+/// Returns the host name of a backend address, like `example.com` for `https://example.com:8443/`
+fn address_host(address: &str) -> &str {
+    let address = address
+        .strip_prefix("https://")
+        .or_else(|| address.strip_prefix("http://"))
+        .unwrap_or(address);
+    let authority = address.split('/').next().unwrap_or_default();
+    match authority.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+        _ => authority.split(':').next().unwrap_or_default(),
+    }
+}
+
+/// Returns the port of a backend address, defaulting to that of its scheme, or 80 without one
+fn address_port(address: &str) -> u16 {
+    let default = if address.starts_with("https://") {
+        443
+    } else {
+        80
+    };
+    let address = address
+        .strip_prefix("https://")
+        .or_else(|| address.strip_prefix("http://"))
+        .unwrap_or(address);
+    let authority = address.split('/').next().unwrap_or_default();
+    let port = match authority.rfind(']') {
+        Some(end) => authority[end + 1..].strip_prefix(':'),
+        _ => authority.split(':').nth(1),
+    };
+    port.and_then(|port| port.parse().ok()).unwrap_or(default)
+}
+
+/// Connects to every backend, failing with a report of each which couldn't be reached within `timeout`
+pub fn check_reachable(
+    backends: &[Backend],
+    timeout: Duration,
+) -> Result<(), BoxError> {
+    let unreachable: Vec<String> = backends
+        .iter()
+        .filter_map(|Backend { name, address, .. }| {
+            let addr = (address_host(address), address_port(address))
+                .to_socket_addrs()
+                .map_err(|e| e.to_string())
+                .and_then(|mut addrs| addrs.next().ok_or_else(|| "no addresses".to_string()));
+            match addr.and_then(|addr| {
+                TcpStream::connect_timeout(&addr, timeout).map_err(|e| e.to_string())
+            }) {
+                Ok(_) => None,
+                Err(e) => Some(format!("{} ({}): {}", name, address, e)),
+            }
+        })
+        .collect();
+    if unreachable.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow!("unreachable backends\n  {}", unreachable.join("\n  ")).into())
+}
+
+/// Resolves the host names of backend addresses, failing on the first which doesn't resolve
+///
+/// Backends addressed by ip are skipped, as there is nothing to resolve
+pub fn resolve(backends: &[Backend]) -> Result<Resolved, BoxError> {
+    let mut resolved = Resolved::new();
+    for Backend { name, address, .. } in backends {
+        let host = address_host(address);
+        if host.parse::<IpAddr>().is_ok() || resolved.contains_key(host) {
+            continue;
+        }
+        let addr = (host, 0)
+            .to_socket_addrs()
+            .map_err(|e| anyhow!("unable to resolve backend {} address {}: {}", name, host, e))?
+            .next()
+            .ok_or_else(|| anyhow!("backend {} address {} resolved to nothing", name, host))?;
+        resolved.insert(host.to_owned(), addr);
+    }
+    Ok(resolved)
+}
+
+/// Describes where a backend's requests are sent, including its resolved ip when pinned
+pub fn describe(
+    backend: &Backend,
+    resolved: &Resolved,
+) -> String {
+    match resolved.get(address_host(&backend.address)) {
+        Some(addr) => format!("{} > {} ({})", backend.name, backend.address, addr.ip()),
+        _ => format!("{} > {}", backend.name, backend.address),
+    }
+}
+
+fn identity(
+    cert: &Path,
+    key: &Path,
+) -> Result<Identity, BoxError> {
+    let mut pem = fs::read(key)?;
+    pem.push(b'\n');
+    pem.extend(fs::read(cert)?);
+    Ok(Identity::from_pem(&pem)?)
+}
+
+/// Builds clients presenting each backend's client certificate, keyed by backend name
+///
+/// Backends sharing a certificate and key share a client. Clients send requests for
+/// resolved hosts to their resolved addresses
+pub fn identity_clients(
+    certs: &[BackendCert],
+    resolved: &Resolved,
+) -> Result<HashMap<String, Client>, BoxError> {
+    let mut clients: HashMap<(&Path, &Path), Client> = HashMap::new();
+    let mut backends = HashMap::new();
+    for BackendCert { backend, cert, key } in certs {
+        let client = match clients.get(&(cert.as_path(), key.as_path())) {
+            Some(client) => client.clone(),
+            _ => {
+                let client = client(resolved).identity(identity(cert, key)?).build()?;
+                clients.insert((cert.as_path(), key.as_path()), client.clone());
+                client
+            }
+        };
+        backends.insert(backend.clone(), client);
+    }
+    Ok(backends)
 }
 
 pub trait Backends: 'static {
@@ -35,15 +313,168 @@ where
 }
 
 pub struct Proxy {
-    backends: HashMap<String, String>,
+    backends: HashMap<String, Backend>,
+    headers: Vec<BackendHeader>,
     client: Client,
+    /// clients for backends which require a client certificate
+    identity_clients: HashMap<String, Client>,
+    fallback: BackendFallback,
+    unknown: UnknownBackend,
 }
 
 impl Proxy {
-    pub fn new(backends: Vec<Backend>) -> Self {
-        let client = Client::builder().redirect(Policy::none()).build().unwrap();
-        let backends = backends.into_iter().map(|b| (b.name, b.address)).collect();
-        Proxy { backends, client }
+    pub fn new(
+        backends: Vec<Backend>,
+        headers: Vec<BackendHeader>,
+    ) -> Self {
+        let client = client(&Resolved::new()).build().unwrap();
+        let backends = backends.into_iter().map(|b| (b.name.clone(), b)).collect();
+        Proxy {
+            backends,
+            headers,
+            client,
+            identity_clients: HashMap::new(),
+            fallback: BackendFallback::default(),
+            unknown: UnknownBackend::default(),
+        }
+    }
+
+    /// Sets the response to requests for backends which are not configured, when not passed through
+    pub fn with_unknown_backend(
+        self,
+        unknown: UnknownBackend,
+    ) -> Self {
+        Proxy { unknown, ..self }
+    }
+
+    /// Sets what to do with requests for backends which are not configured
+    pub fn with_fallback(
+        self,
+        fallback: BackendFallback,
+    ) -> Self {
+        Proxy { fallback, ..self }
+    }
+
+    /// Sends requests for resolved hosts to their resolved addresses, rather than resolving them per request
+    pub fn with_resolved(
+        self,
+        resolved: &Resolved,
+    ) -> Self {
+        if resolved.is_empty() {
+            return self;
+        }
+        Proxy {
+            client: client(resolved).build().unwrap(),
+            ..self
+        }
+    }
+
+    /// Uses clients presenting client certificates, built with `identity_clients`, for their backends
+    ///
+    /// Backends without one use a default client which presents no certificate
+    pub fn with_identity_clients(
+        self,
+        identity_clients: HashMap<String, Client>,
+    ) -> Self {
+        Proxy {
+            identity_clients,
+            ..self
+        }
+    }
+
+    /// Builds the upstream request for a backend, sent with a given Host header
+    ///
+    /// Hop-by-hop headers are not forwarded and configured backend headers
+    /// are appended after the guest's own headers
+    fn request(
+        &self,
+        backend: &str,
+        host: &str,
+        req: &Request<Body>,
+    ) -> Result<reqwest::Request, BoxError> {
+        let mut rreq = reqwest::Request::new(
+            req.method().clone(),
+            req.uri().to_string().parse::<reqwest::Url>()?,
+        );
+        *rreq.headers_mut() = req.headers().clone();
+        strip_hop_by_hop(rreq.headers_mut());
+        rreq.headers_mut().remove("host");
+        rreq.headers_mut()
+            .append("host", HeaderValue::from_str(&host)?);
+        for header in self.headers.iter().filter(|header| {
+            header
+                .backend
+                .as_ref()
+                .map_or(true, |scope| scope == backend)
+        }) {
+            rreq.headers_mut().append(
+                HeaderName::from_bytes(header.name.as_bytes())?,
+                HeaderValue::from_str(&header.value)?,
+            );
+        }
+        Ok(rreq)
+    }
+}
+
+/// Headers which describe a single connection rather than a request,
+/// and so are not forwarded by proxies
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Removes hop-by-hop headers, including those listed in the Connection header
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<String> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    for name in listed
+        .iter()
+        .map(String::as_str)
+        .chain(HOP_BY_HOP.iter().copied())
+    {
+        headers.remove(name);
+    }
+}
+
+impl Proxy {
+    /// Sends an upstream request with the client for its backend
+    fn execute(
+        &self,
+        backend: &str,
+        rreq: reqwest::Request,
+    ) -> Result<Response<Body>, BoxError> {
+        let client = self.identity_clients.get(backend).unwrap_or(&self.client);
+        let rresp = match futures_executor::block_on(client.execute(rreq)) {
+            Ok(r) => r,
+            Err(e) => {
+                log::error!("error calling backend {}", e);
+                return Err(e.into());
+            }
+        };
+        debug!("got response");
+        let headers = rresp.headers().clone();
+        let builder = Response::builder()
+            .status(rresp.status())
+            .version(rresp.version());
+
+        let mut resp = builder
+            .body(Body::from(futures_executor::block_on(rresp.bytes())?))
+            .expect("invalid response");
+        *resp.headers_mut() = headers;
+        Ok(resp)
     }
 }
 
@@ -53,61 +484,819 @@ impl Backends for Proxy {
         backend: &str,
         req: Request<Body>,
     ) -> Result<Response<Body>, BoxError> {
-        match self.backends.get(backend) {
-            Some(host) => {
-                debug!("proxying backend '{}' to '{}'", backend, host);
-
-                let mut rreq = reqwest::Request::new(
-                    req.method().clone(),
-                    req.uri()
-                        .to_string()
-                        .parse::<reqwest::Url>()
-                        .expect("invalid uri"),
+        if let Some(SurrogateKey(key)) = req.extensions().get() {
+            debug!("request tagged with surrogate keys '{}'", key);
+        }
+        match (self.backends.get(backend), self.fallback) {
+            (
+                Some(Backend {
+                    address,
+                    host_header,
+                    ..
+                }),
+                _,
+            ) => {
+                let host = host_header.resolve(address, &req);
+                debug!(
+                    "backend '{}' matched a configured backend, proxying to '{}' with host '{}'",
+                    backend, address, host
                 );
-                *rreq.headers_mut() = req.headers().clone();
-                rreq.headers_mut().remove("host");
-                rreq.headers_mut()
-                    .append("host", HeaderValue::from_str(&host)?);
-
-                let rresp = match futures_executor::block_on(self.client.execute(rreq)) {
-                    Ok(r) => r,
-                    Err(e) => {
-                        log::error!("error calling backend {}", e);
-                        return Err(e.into());
-                    }
-                };
-                debug!("got response");
-                let headers = rresp.headers().clone();
-                let builder = Response::builder()
-                    .status(rresp.status())
-                    .version(rresp.version());
-
-                let mut resp = builder
-                    .body(Body::from(futures_executor::block_on(rresp.bytes())?))
-                    .expect("invalid response");
-                *resp.headers_mut() = headers;
-                Ok(resp)
+                let rreq = self.request(backend, host, &req)?;
+                self.execute(backend, rreq)
             }
-            _ => GatewayError.send(backend, req),
+            (_, BackendFallback::Passthrough) => match req.uri().authority() {
+                Some(authority) => {
+                    debug!(
+                        "backend '{}' not configured, passing through to '{}'",
+                        backend, authority
+                    );
+                    let rreq = self.request(backend, authority.as_str(), &req)?;
+                    self.execute(backend, rreq)
+                }
+                _ => self.unknown.send(backend, req),
+            },
+            _ => self.unknown.send(backend, req),
         }
     }
 }
 
-struct GatewayError;
+/// Backends shared between a handler and the handlers of its loopback requests
+struct Shared(Rc<dyn Backends>);
 
-impl Backends for GatewayError {
+impl Backends for Shared {
+    fn send(
+        &self,
+        backend: &str,
+        req: Request<Body>,
+    ) -> Result<Response<Body>, BoxError> {
+        self.0.send(backend, req)
+    }
+}
+
+/// Sends requests for the `self` backend through the same application with a new handler,
+/// delegating requests for all other backends
+pub struct Loopback {
+    pub module: Module,
+    pub engine: Engine,
+    pub backends: Rc<dyn Backends>,
+    pub dictionaries: Dictionaries,
+    pub ip: Option<IpAddr>,
+    pub settings: Settings,
+    pub depth: usize,
+}
+
+impl Backends for Loopback {
+    fn send(
+        &self,
+        backend: &str,
+        req: Request<Body>,
+    ) -> Result<Response<Body>, BoxError> {
+        if backend != LOOPBACK {
+            return self.backends.send(backend, req);
+        }
+        if self.depth >= MAX_LOOPBACK_DEPTH {
+            log::error!(
+                "loopback requests exceeded max depth of {}",
+                MAX_LOOPBACK_DEPTH
+            );
+            return Ok(Response::builder()
+                .status(508)
+                .body(
+                    format!(
+                        "Loopback requests exceeded max depth of {}",
+                        MAX_LOOPBACK_DEPTH
+                    )
+                    .into(),
+                )
+                .expect("invalid response"));
+        }
+        debug!(
+            "backend '{}' routed back through the application at depth {}",
+            backend,
+            self.depth + 1
+        );
+        let handler = Handler::new(req).with_settings(self.settings.clone());
+        handler.inner.borrow_mut().depth = self.depth + 1;
+        handler.run(
+            &self.module,
+            Store::new(&self.engine),
+            Box::new(Shared(self.backends.clone())),
+            self.dictionaries.clone(),
+            self.ip,
+        )
+    }
+}
+
+/// Responds to requests for backends which are not configured, with a 502 by default
+#[derive(Clone, Debug)]
+pub struct UnknownBackend {
+    pub status: StatusCode,
+    /// response body, where `{name}` is replaced with the backend's name
+    pub body: String,
+    /// add a `Fastly-Backend-Unknown` header naming the backend, marking the response as synthetic
+    pub header: bool,
+}
+
+impl Default for UnknownBackend {
+    fn default() -> Self {
+        UnknownBackend {
+            status: StatusCode::BAD_GATEWAY,
+            body: "Unknown backend {name}".into(),
+            header: false,
+        }
+    }
+}
+
+impl Backends for UnknownBackend {
     fn send(
         &self,
         backend: &str,
         _: Request<Body>,
     ) -> Result<Response<Body>, BoxError> {
-        Ok(Response::builder()
-            .status(502)
-            .body(format!("Unknown backend {}", backend).into())
+        log::warn!(
+            "backend '{}' not configured, returning {}",
+            backend,
+            self.status.as_u16()
+        );
+        let mut builder = Response::builder().status(self.status);
+        if self.header {
+            builder = builder.header("Fastly-Backend-Unknown", header_safe(backend));
+        }
+        Ok(builder
+            .body(self.body.replace("{name}", backend).into())
             .expect("invalid response"))
     }
 }
 
+/// Returns a backend's name as a header value, percent encoding the control characters
+/// header values can't hold, so any name guests send to can be reported
+fn header_safe(name: &str) -> HeaderValue {
+    let encoded = name
+        .chars()
+        .map(|c| match c {
+            '%' => "%25".to_owned(),
+            c if c.is_ascii_control() && c != '\t' => format!("%{:02X}", c as u8),
+            c => c.to_string(),
+        })
+        .collect::<String>();
+    HeaderValue::from_str(&encoded).expect("control characters are encoded")
+}
+
+/// Header clients name an idempotency key with. Requests are only coalesced with requests
+/// which have the same key, or which also have none
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/// A response shared by coalesced requests
+#[derive(Clone)]
+struct SharedResponse {
+    status: StatusCode,
+    version: Version,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl SharedResponse {
+    fn to_response(&self) -> Response<Body> {
+        let mut resp = Response::new(Body::from(self.body.clone()));
+        *resp.status_mut() = self.status;
+        *resp.version_mut() = self.version;
+        *resp.headers_mut() = self.headers.clone();
+        resp
+    }
+}
+
+/// A request in flight, which coalesced requests wait on the response of
+#[derive(Default)]
+struct Flight {
+    response: Mutex<Option<Result<SharedResponse, String>>>,
+    done: Condvar,
+}
+
+/// Lands a flight when its leader is done with it, removing it from those in flight and
+/// answering its waiters, with an error when the leader panicked before it responded
+struct Landing<'a> {
+    in_flight: &'a InFlight,
+    key: &'a str,
+    flight: &'a Flight,
+    response: Option<Result<SharedResponse, String>>,
+}
+
+impl Drop for Landing<'_> {
+    fn drop(&mut self) {
+        self.in_flight
+            .0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(self.key);
+        *self
+            .flight
+            .response
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(
+            self.response
+                .take()
+                .unwrap_or_else(|| Err("the coalesced request panicked".into())),
+        );
+        self.flight.done.notify_all();
+    }
+}
+
+/// How long coalesced requests wait on the request they were coalesced with
+const FLIGHT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Requests in flight to backends, shared between handlers so concurrent identical requests
+/// can be coalesced
+#[derive(Clone, Default)]
+pub struct InFlight(Arc<Mutex<HashMap<String, Arc<Flight>>>>);
+
+/// Collapses concurrent identical requests into a single request to a backend, answering each
+/// of them with its response, as some edges do
+///
+/// Requests are identical when they are for the same backend with the same method, uri, and
+/// idempotency key. As their bodies aren't compared, only `GET` and `HEAD` requests, or requests
+/// carrying an idempotency key, are coalesced
+pub struct Coalesce {
+    backends: Box<dyn Backends>,
+    in_flight: InFlight,
+    /// how long requests wait on the request they were coalesced with before failing
+    timeout: Duration,
+}
+
+impl Coalesce {
+    pub fn new(
+        backends: Box<dyn Backends>,
+        in_flight: InFlight,
+    ) -> Self {
+        Coalesce {
+            backends,
+            in_flight,
+            timeout: FLIGHT_TIMEOUT,
+        }
+    }
+
+    /// Returns the key identical requests share, or `None` for requests which can't be coalesced
+    fn key(
+        backend: &str,
+        req: &Request<Body>,
+    ) -> Option<String> {
+        let idempotency_key = req
+            .headers()
+            .get(IDEMPOTENCY_KEY)
+            .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned());
+        if idempotency_key.is_none() && req.method() != Method::GET && req.method() != Method::HEAD
+        {
+            return None;
+        }
+        Some(format!(
+            "{} {} {} {}",
+            backend,
+            req.method(),
+            req.uri(),
+            idempotency_key.unwrap_or_default()
+        ))
+    }
+
+    /// Sends a request, buffering its response so it can be shared
+    fn send_shared(
+        &self,
+        backend: &str,
+        req: Request<Body>,
+    ) -> Result<SharedResponse, BoxError> {
+        let (parts, body) = self.backends.send(backend, req)?.into_parts();
+        Ok(SharedResponse {
+            status: parts.status,
+            version: parts.version,
+            headers: parts.headers,
+            body: futures_executor::block_on(to_bytes(body))?,
+        })
+    }
+}
+
+impl Backends for Coalesce {
+    fn send(
+        &self,
+        backend: &str,
+        req: Request<Body>,
+    ) -> Result<Response<Body>, BoxError> {
+        let key = match Coalesce::key(backend, &req) {
+            Some(key) => key,
+            _ => return self.backends.send(backend, req),
+        };
+        let (flight, leader) = {
+            let mut in_flight = self.in_flight.0.lock().expect("in flight lock poisoned");
+            match in_flight.get(&key) {
+                Some(flight) => (flight.clone(), false),
+                _ => {
+                    let flight = Arc::new(Flight::default());
+                    in_flight.insert(key.clone(), flight.clone());
+                    (flight, true)
+                }
+            }
+        };
+        if leader {
+            // lands the flight when dropped, should sending panic
+            let mut landing = Landing {
+                in_flight: &self.in_flight,
+                key: &key,
+                flight: &flight,
+                response: None,
+            };
+            let result = self.send_shared(backend, req);
+            landing.response = Some(match &result {
+                Ok(shared) => Ok(shared.clone()),
+                Err(e) => Err(e.to_string()),
+            });
+            drop(landing);
+            return result.map(|shared| shared.to_response());
+        }
+        debug!("coalescing request to backend '{}' with '{}'", backend, key);
+        let (response, _) = flight
+            .done
+            .wait_timeout_while(
+                flight.response.lock().expect("flight lock poisoned"),
+                self.timeout,
+                |response| response.is_none(),
+            )
+            .expect("flight lock poisoned");
+        match response.as_ref() {
+            Some(Ok(shared)) => Ok(shared.to_response()),
+            Some(Err(e)) => Err(anyhow!("coalesced request failed: {}", e).into()),
+            // flights are waited on until they respond or time out
+            None => Err(anyhow!("coalesced request timed out after {:?}", self.timeout).into()),
+        }
+    }
+}
+
 pub fn default() -> Box<dyn Backends + 'static> {
-    Box::new(GatewayError)
+    Box::new(UnknownBackend::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coalesce_sends_concurrent_identical_requests_once() -> Result<(), BoxError> {
+        let sent = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let in_flight = InFlight::default();
+        let send = |idempotency_key: &'static str| {
+            let sent = sent.clone();
+            let in_flight = in_flight.clone();
+            std::thread::spawn(move || {
+                // each handler has backends of its own, sharing only what's in flight
+                let slow = move |_: &str, _: Request<Body>| -> Result<Response<Body>, BoxError> {
+                    sent.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(200));
+                    Ok(Response::new(Body::from("shared")))
+                };
+                let resp = Coalesce::new(Box::new(slow), in_flight).send(
+                    "origin",
+                    Request::post("http://origin/orders")
+                        .header(IDEMPOTENCY_KEY, idempotency_key)
+                        .body(Body::empty())
+                        .expect("invalid request"),
+                );
+                resp.map(|resp| futures_executor::block_on(to_bytes(resp.into_body())))
+                    .map_err(|e| e.to_string())
+            })
+        };
+        let first = send("a");
+        let second = send("a");
+        assert_eq!(
+            first.join().expect("send panicked")?.ok(),
+            Some("shared".into())
+        );
+        assert_eq!(
+            second.join().expect("send panicked")?.ok(),
+            Some("shared".into())
+        );
+        assert_eq!(sent.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        // requests with different idempotency keys aren't identical
+        let first = send("a");
+        let second = send("b");
+        first.join().expect("send panicked")?;
+        second.join().expect("send panicked")?;
+        assert_eq!(sent.load(std::sync::atomic::Ordering::SeqCst), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn coalesce_answers_waiters_when_the_first_request_panics() -> Result<(), BoxError> {
+        let in_flight = InFlight::default();
+        let send = |panics: bool| {
+            let in_flight = in_flight.clone();
+            std::thread::spawn(move || {
+                let slow = move |_: &str, _: Request<Body>| -> Result<Response<Body>, BoxError> {
+                    std::thread::sleep(Duration::from_millis(200));
+                    if panics {
+                        panic!("backend panicked");
+                    }
+                    Ok(Response::new(Body::from("sent")))
+                };
+                Coalesce::new(Box::new(slow), in_flight)
+                    .send(
+                        "origin",
+                        Request::get("http://origin/")
+                            .body(Body::empty())
+                            .expect("invalid request"),
+                    )
+                    .map(|_| ())
+                    .map_err(|e| e.to_string())
+            })
+        };
+        let first = send(true);
+        std::thread::sleep(Duration::from_millis(50));
+        let second = send(false);
+        assert!(first.join().is_err());
+        let err = second
+            .join()
+            .expect("send panicked")
+            .expect_err("coalesced with a panicking request");
+        assert!(err.contains("panicked"), "{}", err);
+        // the flight landed, so identical requests are sent again
+        assert_eq!(send(false).join().expect("send panicked"), Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn coalesce_waits_on_the_first_request_for_a_bounded_time() -> Result<(), BoxError> {
+        let in_flight = InFlight::default();
+        let send = |timeout: Duration| {
+            let in_flight = in_flight.clone();
+            std::thread::spawn(move || {
+                let slow = move |_: &str, _: Request<Body>| -> Result<Response<Body>, BoxError> {
+                    std::thread::sleep(Duration::from_millis(500));
+                    Ok(Response::new(Body::from("sent")))
+                };
+                Coalesce {
+                    timeout,
+                    ..Coalesce::new(Box::new(slow), in_flight)
+                }
+                .send(
+                    "origin",
+                    Request::get("http://origin/")
+                        .body(Body::empty())
+                        .expect("invalid request"),
+                )
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+            })
+        };
+        let first = send(FLIGHT_TIMEOUT);
+        std::thread::sleep(Duration::from_millis(50));
+        let second = send(Duration::from_millis(50));
+        let err = second
+            .join()
+            .expect("send panicked")
+            .expect_err("waited on a slow request");
+        assert!(err.contains("timed out"), "{}", err);
+        assert_eq!(first.join().expect("send panicked"), Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn coalesce_sends_requests_which_may_differ_by_body_separately() -> Result<(), BoxError> {
+        let sent = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let in_flight = InFlight::default();
+        let send = |body: &'static str| {
+            let sent = sent.clone();
+            let in_flight = in_flight.clone();
+            std::thread::spawn(move || {
+                let echo = move |_: &str, req: Request<Body>| -> Result<Response<Body>, BoxError> {
+                    sent.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(200));
+                    Ok(Response::new(req.into_body()))
+                };
+                let resp = Coalesce::new(Box::new(echo), in_flight).send(
+                    "origin",
+                    Request::post("http://origin/orders")
+                        .body(Body::from(body))
+                        .expect("invalid request"),
+                );
+                resp.map(|resp| futures_executor::block_on(to_bytes(resp.into_body())))
+                    .map_err(|e| e.to_string())
+            })
+        };
+        let first = send("one");
+        let second = send("two");
+        assert_eq!(
+            first.join().expect("send panicked")?.ok(),
+            Some("one".into())
+        );
+        assert_eq!(
+            second.join().expect("send panicked")?.ok(),
+            Some("two".into())
+        );
+        assert_eq!(sent.load(std::sync::atomic::Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn proxy_appends_backend_headers() -> Result<(), BoxError> {
+        let proxy = Proxy::new(
+            vec![Backend {
+                name: "origin".into(),
+                address: "origin.example.com".into(),
+                host_header: HostHeader::default(),
+            }],
+            vec![
+                BackendHeader {
+                    backend: None,
+                    name: "fastly-debug".into(),
+                    value: "1".into(),
+                },
+                BackendHeader {
+                    backend: Some("origin".into()),
+                    name: "authorization".into(),
+                    value: "token".into(),
+                },
+                BackendHeader {
+                    backend: Some("other".into()),
+                    name: "x-other".into(),
+                    value: "other".into(),
+                },
+            ],
+        );
+        let req = Request::get("http://origin.example.com/")
+            .header("fastly-debug", "guest")
+            .body(Body::empty())?;
+        let rreq = proxy.request("origin", "origin.example.com", &req)?;
+        let debug: Vec<_> = rreq.headers().get_all("fastly-debug").iter().collect();
+        assert_eq!(debug, vec!["guest", "1"]);
+        assert_eq!(
+            rreq.headers().get("authorization"),
+            Some(&HeaderValue::from_static("token"))
+        );
+        assert!(rreq.headers().get("x-other").is_none());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn proxy_passes_unconfigured_backends_through() -> Result<(), BoxError> {
+        let origin = hyper::Server::try_bind(&([127, 0, 0, 1], 0).into())?.serve(
+            hyper::service::make_service_fn(|_| async {
+                Ok::<_, hyper::Error>(hyper::service::service_fn(|_| async {
+                    Ok::<_, hyper::Error>(Response::new(Body::from("origin")))
+                }))
+            }),
+        );
+        let addr = origin.local_addr();
+        tokio::spawn(origin);
+        let send = move |fallback| {
+            let req = Request::get(format!("http://{}/", addr)).body(Body::empty())?;
+            Proxy::new(Vec::new(), Vec::new())
+                .with_fallback(fallback)
+                .send("unconfigured", req)
+        };
+        let resp = tokio::task::spawn_blocking(move || {
+            send(BackendFallback::Passthrough).and_then(|passthrough| {
+                send(BackendFallback::Error).map(|error| (passthrough, error))
+            })
+        })
+        .await??;
+        let (passthrough, error) = resp;
+        assert_eq!(passthrough.status(), 200);
+        assert_eq!(
+            hyper::body::to_bytes(passthrough.into_body()).await?,
+            "origin"
+        );
+        assert_eq!(error.status(), 502);
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_backends_respond_as_configured() -> Result<(), BoxError> {
+        let resp = Proxy::new(Vec::new(), Vec::new())
+            .with_unknown_backend(UnknownBackend {
+                status: StatusCode::NOT_FOUND,
+                body: "no backend named {name}".into(),
+                header: true,
+            })
+            .send("missing", Request::get("/").body(Body::empty())?)?;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(resp.headers()["Fastly-Backend-Unknown"], "missing");
+        assert_eq!(
+            futures_executor::block_on(hyper::body::to_bytes(resp.into_body()))?,
+            "no backend named missing"
+        );
+
+        // names which can't be header values are encoded rather than failing the send
+        let resp = UnknownBackend {
+            header: true,
+            ..UnknownBackend::default()
+        }
+        .send("bad\nname%", Request::get("/").body(Body::empty())?)?;
+        assert_eq!(resp.headers()["Fastly-Backend-Unknown"], "bad%0Aname%25");
+
+        let resp = default().send("missing", Request::get("/").body(Body::empty())?)?;
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+        assert!(!resp.headers().contains_key("Fastly-Backend-Unknown"));
+        assert_eq!(
+            futures_executor::block_on(hyper::body::to_bytes(resp.into_body()))?,
+            "Unknown backend missing"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn host_header_policies_resolve() -> Result<(), BoxError> {
+        let address = "origin.internal";
+        let req = Request::get("http://uri.example.com/")
+            .header(HOST, "www.example.com")
+            .body(Body::empty())?;
+        assert_eq!(
+            HostHeader::Preserve.resolve(address, &req),
+            "www.example.com"
+        );
+        assert_eq!(HostHeader::Override.resolve(address, &req), address);
+        assert_eq!(
+            HostHeader::Value("vhost.example.com".into()).resolve(address, &req),
+            "vhost.example.com"
+        );
+
+        // without a Host header, the host in the request's uri is preserved
+        let req = Request::get("http://uri.example.com/").body(Body::empty())?;
+        assert_eq!(
+            HostHeader::Preserve.resolve(address, &req),
+            "uri.example.com"
+        );
+        let req = Request::get("/").body(Body::empty())?;
+        assert_eq!(HostHeader::Preserve.resolve(address, &req), address);
+        Ok(())
+    }
+
+    #[test]
+    fn host_header_policies_load_from_toml() -> Result<(), BoxError> {
+        #[derive(Deserialize)]
+        struct Backends {
+            backend: Vec<Backend>,
+        }
+        let Backends { backend } = toml::from_str(
+            r#"
+            [[backend]]
+            name = "default"
+            address = "a.example.com"
+
+            [[backend]]
+            name = "override"
+            address = "b.example.com"
+            host_header = "override"
+
+            [[backend]]
+            name = "value"
+            address = "c.example.com"
+            host_header = "value:vhost.example.com"
+            "#,
+        )?;
+        let policies: Vec<_> = backend.into_iter().map(|b| b.host_header).collect();
+        assert_eq!(
+            policies,
+            vec![
+                HostHeader::Preserve,
+                HostHeader::Override,
+                HostHeader::Value("vhost.example.com".into())
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn host_headers_parse() {
+        assert_eq!("preserve".parse(), Ok(HostHeader::Preserve));
+        assert_eq!("override".parse(), Ok(HostHeader::Override));
+        assert_eq!(
+            "value:vhost.example.com".parse(),
+            Ok(HostHeader::Value("vhost.example.com".into()))
+        );
+        // typos of policies aren't sent as hosts
+        assert!("overide".parse::<HostHeader>().is_err());
+        assert!("vhost.example.com".parse::<HostHeader>().is_err());
+        assert!("value:".parse::<HostHeader>().is_err());
+    }
+
+    #[test]
+    fn backend_fallback_parses() {
+        assert_eq!("error".parse(), Ok(BackendFallback::Error));
+        assert_eq!("passthrough".parse(), Ok(BackendFallback::Passthrough));
+        assert!("other".parse::<BackendFallback>().is_err());
+    }
+
+    #[test]
+    fn proxy_strips_hop_by_hop_headers() -> Result<(), BoxError> {
+        let proxy = Proxy::new(Vec::new(), Vec::new());
+        let req = Request::get("http://origin.example.com/")
+            .header("connection", "keep-alive, X-Session")
+            .header("keep-alive", "timeout=5")
+            .header("x-session", "abc")
+            .header("upgrade", "websocket")
+            .header("proxy-authorization", "secret")
+            .header("x-kept", "1")
+            .body(Body::empty())?;
+        let rreq = proxy.request("origin", "origin.example.com", &req)?;
+        for name in &[
+            "connection",
+            "keep-alive",
+            "x-session",
+            "upgrade",
+            "proxy-authorization",
+        ] {
+            assert!(
+                rreq.headers().get(*name).is_none(),
+                "{} was forwarded",
+                name
+            );
+        }
+        assert_eq!(rreq.headers()["x-kept"], "1");
+        Ok(())
+    }
+
+    #[test]
+    fn identity_clients_are_keyed_by_backend() -> Result<(), BoxError> {
+        let cert = |backend: &str| BackendCert {
+            backend: backend.into(),
+            cert: "tests/tls/sni/localhost.crt".into(),
+            key: "tests/tls/sni/localhost.key".into(),
+        };
+        let clients = identity_clients(&[cert("one"), cert("two")], &Resolved::new())?;
+        let mut backends: Vec<_> = clients.keys().collect();
+        backends.sort();
+        assert_eq!(backends, vec!["one", "two"]);
+        Ok(())
+    }
+
+    #[test]
+    fn identity_clients_fail_for_missing_certs() {
+        assert!(identity_clients(
+            &[BackendCert {
+                backend: "origin".into(),
+                cert: "tests/tls/missing.crt".into(),
+                key: "tests/tls/missing.key".into(),
+            }],
+            &Resolved::new()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn address_hosts_omit_schemes_and_ports() {
+        assert_eq!(address_host("example.com"), "example.com");
+        assert_eq!(address_host("localhost:3001"), "localhost");
+        assert_eq!(address_host("https://example.com:8443/path"), "example.com");
+        assert_eq!(address_host("[::1]:3001"), "::1");
+    }
+
+    #[test]
+    fn address_ports_default_by_scheme() {
+        assert_eq!(address_port("example.com"), 80);
+        assert_eq!(address_port("https://example.com/"), 443);
+        assert_eq!(address_port("localhost:3001"), 3001);
+        assert_eq!(address_port("[::1]:3001"), 3001);
+    }
+
+    #[test]
+    fn check_reachable_reports_unreachable_backends() -> Result<(), BoxError> {
+        let up = std::net::TcpListener::bind("127.0.0.1:0")?;
+        // a port nothing listens on, once its listener is dropped
+        let down = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let backend = |name: &str, address: String| Backend {
+            name: name.into(),
+            address,
+            host_header: HostHeader::default(),
+        };
+        let timeout = Duration::from_secs(1);
+        check_reachable(&[backend("up", up.local_addr()?.to_string())], timeout)?;
+        let err = check_reachable(
+            &[
+                backend("up", up.local_addr()?.to_string()),
+                backend("down", down.to_string()),
+            ],
+            timeout,
+        )
+        .expect_err("expected an unreachable backend");
+        assert!(err.to_string().contains("down (127.0.0.1:"));
+        assert!(!err.to_string().contains("up ("));
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_pins_backend_hosts() -> Result<(), BoxError> {
+        let backend = |name: &str, address: &str| Backend {
+            name: name.into(),
+            address: address.into(),
+            host_header: HostHeader::default(),
+        };
+        let backends = vec![
+            backend("local", "localhost:3001"),
+            backend("ip", "127.0.0.1:3002"),
+        ];
+        let resolved = resolve(&backends)?;
+        assert!(resolved["localhost"].ip().is_loopback());
+        assert!(!resolved.contains_key("127.0.0.1"));
+        assert!(describe(&backends[0], &resolved).starts_with("local > localhost:3001 ("));
+        assert_eq!(describe(&backends[1], &resolved), "ip > 127.0.0.1:3002");
+
+        assert!(resolve(&[backend("missing", "fasttime.invalid")]).is_err());
+        Ok(())
+    }
 }
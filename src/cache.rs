@@ -0,0 +1,555 @@
+//! Defines a cache of backend responses shared between requests, which honors the
+//! ttls applications set with `cache_override_v2_set` and those of `Cache-Control` headers.
+//! Responses with a `Vary` header are cached separately for each value of the request
+//! headers it names
+
+use crate::BoxError;
+use anyhow::anyhow;
+use bytes::Bytes;
+use http::{
+    header::{HeaderName, HeaderValue, AGE, CACHE_CONTROL, VARY},
+    HeaderMap, Method, Request, Response, StatusCode,
+};
+use hyper::Body;
+use serde::Serialize;
+use serde_derive::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// `tag` bit of `cache_override_v2_set` which bypasses the cache
+pub const PASS: u32 = 1 << 0;
+/// `tag` bit of `cache_override_v2_set` which overrides a response's ttl
+pub const TTL: u32 = 1 << 1;
+
+/// How an application overrode the caching of a request with `cache_override_v2_set`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheOverride {
+    pub pass: bool,
+    pub ttl: Option<Duration>,
+}
+
+impl CacheOverride {
+    pub fn new(
+        tag: u32,
+        ttl: u32,
+    ) -> Self {
+        CacheOverride {
+            pass: tag & PASS != 0,
+            ttl: if tag & TTL != 0 {
+                Some(Duration::from_secs(ttl.into()))
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// A cached backend response
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    /// surrogate keys the request was tagged with, for purging
+    pub surrogate_keys: Vec<String>,
+    pub stored: Instant,
+    pub ttl: Duration,
+    /// the values of the request headers named by the response's `Vary` header
+    pub vary: Vec<(HeaderName, Vec<HeaderValue>)>,
+}
+
+impl Entry {
+    fn age(&self) -> Duration {
+        self.stored.elapsed()
+    }
+
+    fn fresh(&self) -> bool {
+        self.age() < self.ttl
+    }
+
+    /// Returns whether a request has the same values for the headers this entry varies on
+    fn varies_with(
+        &self,
+        request_headers: &HeaderMap,
+    ) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, values)| request_values(request_headers, name) == *values)
+    }
+
+    /// Builds a response from this entry with an `Age` header
+    fn response(&self) -> Response<Body> {
+        let mut resp = Response::new(Body::from(self.body.clone()));
+        *resp.status_mut() = self.status;
+        *resp.headers_mut() = self.headers.clone();
+        resp.headers_mut()
+            .insert(AGE, HeaderValue::from(self.age().as_secs()));
+        resp
+    }
+}
+
+/// A summary of a cached entry, as listed by the admin server
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Listing {
+    pub key: String,
+    pub status: u16,
+    pub age_secs: u64,
+    pub ttl_remaining_secs: u64,
+    pub surrogate_keys: Vec<String>,
+}
+
+/// A response to pre-populate the cache with, loaded from a JSON file by --seed-cache
+///
+/// ```json
+/// {
+///   "backend": "origin",
+///   "url": "https://origin.example.com/greeting",
+///   "status": 200,
+///   "headers": { "content-type": "text/plain" },
+///   "body": "hello",
+///   "ttl_secs": 60,
+///   "surrogate_keys": ["greetings"]
+/// }
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Seed {
+    pub backend: String,
+    /// the url of the backend request, as the application sends it
+    pub url: String,
+    #[serde(default = "Seed::ok")]
+    pub status: u16,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub body: String,
+    pub ttl_secs: u64,
+    #[serde(default)]
+    pub surrogate_keys: Vec<String>,
+}
+
+impl Seed {
+    fn ok() -> u16 {
+        StatusCode::OK.as_u16()
+    }
+}
+
+/// Backend responses shared between requests, keyed by backend and uri, with an entry for
+/// each variant of a response
+#[derive(Clone, Default)]
+pub struct Cache(Arc<Mutex<HashMap<String, Vec<Entry>>>>);
+
+impl fmt::Debug for Cache {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.write_str("Cache")
+    }
+}
+
+impl Cache {
+    /// Returns the key a request for a backend is cached under, when it may be cached at all
+    pub fn key<B>(
+        backend: &str,
+        req: &Request<B>,
+    ) -> Option<String> {
+        if req.method() != Method::GET {
+            return None;
+        }
+        if req
+            .extensions()
+            .get::<CacheOverride>()
+            .map_or(false, |o| o.pass)
+        {
+            return None;
+        }
+        Some(format!("{} {}", backend, req.uri()))
+    }
+
+    /// Returns a fresh cached response for a request's headers, evicting stale entries
+    pub fn get(
+        &self,
+        key: &str,
+        request_headers: &HeaderMap,
+    ) -> Option<Response<Body>> {
+        let mut entries = self.0.lock().expect("poisoned cache");
+        let variants = entries.get_mut(key)?;
+        variants.retain(Entry::fresh);
+        let resp = variants
+            .iter()
+            .find(|entry| entry.varies_with(request_headers))
+            .map(Entry::response);
+        if variants.is_empty() {
+            entries.remove(key);
+        }
+        resp
+    }
+
+    /// Caches a response for the ttl an application overrode it with, or otherwise
+    /// the ttl of its `Cache-Control` header, as the variant for the request headers its `Vary`
+    /// header names. Responses without a ttl, or which vary on `*`, are not cached
+    #[allow(clippy::too_many_arguments)]
+    pub fn insert(
+        &self,
+        key: String,
+        request_headers: &HeaderMap,
+        cache_override: Option<&CacheOverride>,
+        surrogate_keys: Vec<String>,
+        status: StatusCode,
+        headers: HeaderMap,
+        body: Bytes,
+    ) {
+        let ttl = match cache_override.and_then(|o| o.ttl) {
+            Some(ttl) => Some(ttl),
+            _ if status.is_success() => max_age(&headers),
+            _ => None,
+        };
+        let vary: Vec<_> = match varied_headers(&headers) {
+            Some(names) => names
+                .into_iter()
+                .map(|name| {
+                    let values = request_values(request_headers, &name);
+                    (name, values)
+                })
+                .collect(),
+            _ => return,
+        };
+        match ttl {
+            Some(ttl) if ttl > Duration::default() => {
+                let entry = Entry {
+                    status,
+                    headers,
+                    body,
+                    surrogate_keys,
+                    stored: Instant::now(),
+                    ttl,
+                    vary,
+                };
+                let mut entries = self.0.lock().expect("poisoned cache");
+                let variants = entries.entry(key).or_default();
+                // a new response replaces the variant it was sent for
+                variants.retain(|variant| variant.vary != entry.vary);
+                variants.push(entry);
+            }
+            _ => (),
+        }
+    }
+
+    /// Caches the response of each `.json` file in a directory, in the format of a `Seed`,
+    /// returning how many were cached
+    pub fn seed(
+        &self,
+        dir: impl AsRef<Path>,
+    ) -> Result<usize, BoxError> {
+        let dir = dir.as_ref();
+        let mut paths = fs::read_dir(dir)
+            .map_err(|e| anyhow!("unable to read cache seeds {}: {}", dir.display(), e))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.retain(|path| path.extension().map_or(false, |ext| ext == "json"));
+        paths.sort();
+        for path in &paths {
+            let invalid =
+                |e: &dyn fmt::Display| anyhow!("invalid cache seed {}: {}", path.display(), e);
+            let seed: Seed =
+                serde_json::from_str(&fs::read_to_string(path)?).map_err(|e| invalid(&e))?;
+            let mut headers = HeaderMap::new();
+            for (name, value) in &seed.headers {
+                headers.append(
+                    HeaderName::from_bytes(name.as_bytes()).map_err(|e| invalid(&e))?,
+                    HeaderValue::from_str(value).map_err(|e| invalid(&e))?,
+                );
+            }
+            let req = Request::get(seed.url.as_str())
+                .body(())
+                .map_err(|e| invalid(&e))?;
+            let key = Cache::key(&seed.backend, &req).ok_or_else(|| invalid(&"uncacheable"))?;
+            self.insert(
+                key,
+                req.headers(),
+                Some(&CacheOverride {
+                    pass: false,
+                    ttl: Some(Duration::from_secs(seed.ttl_secs)),
+                }),
+                seed.surrogate_keys,
+                StatusCode::from_u16(seed.status).map_err(|e| invalid(&e))?,
+                headers,
+                Bytes::from(seed.body),
+            );
+        }
+        Ok(paths.len())
+    }
+
+    /// Lists fresh entries, ordered by key
+    pub fn list(&self) -> Vec<Listing> {
+        let mut entries = self.0.lock().expect("poisoned cache");
+        entries.retain(|_, variants| {
+            variants.retain(Entry::fresh);
+            !variants.is_empty()
+        });
+        let mut listings: Vec<_> = entries
+            .iter()
+            .flat_map(|(key, variants)| variants.iter().map(move |entry| (key, entry)))
+            .map(|(key, entry)| {
+                // entries may expire between the retain above and now
+                let age = entry.age();
+                Listing {
+                    key: key.clone(),
+                    status: entry.status.as_u16(),
+                    age_secs: age.as_secs(),
+                    ttl_remaining_secs: entry.ttl.checked_sub(age).unwrap_or_default().as_secs(),
+                    surrogate_keys: entry.surrogate_keys.clone(),
+                }
+            })
+            .collect();
+        listings.sort_by(|a, b| a.key.cmp(&b.key));
+        listings
+    }
+
+    /// Removes every entry, or only those tagged with a surrogate key, returning how many were removed
+    pub fn purge(
+        &self,
+        surrogate_key: Option<&str>,
+    ) -> usize {
+        let mut entries = self.0.lock().expect("poisoned cache");
+        let count =
+            |entries: &HashMap<String, Vec<Entry>>| entries.values().map(Vec::len).sum::<usize>();
+        let before = count(&entries);
+        match surrogate_key {
+            Some(surrogate_key) => entries.retain(|_, variants| {
+                variants.retain(|entry| {
+                    !entry
+                        .surrogate_keys
+                        .iter()
+                        .any(|key| key.as_str() == surrogate_key)
+                });
+                !variants.is_empty()
+            }),
+            _ => entries.clear(),
+        }
+        before - count(&entries)
+    }
+}
+
+/// Returns the names of the request headers a response varies on, or None when it varies
+/// on `*`, so can't be served from the cache
+fn varied_headers(headers: &HeaderMap) -> Option<Vec<HeaderName>> {
+    let mut names = Vec::new();
+    for name in headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        if name == "*" {
+            return None;
+        }
+        if let Ok(name) = HeaderName::from_bytes(name.as_bytes()) {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    Some(names)
+}
+
+/// Returns a request's values for a header, in order
+fn request_values(
+    request_headers: &HeaderMap,
+    name: &HeaderName,
+) -> Vec<HeaderValue> {
+    request_headers.get_all(name).iter().cloned().collect()
+}
+
+/// Returns the ttl of a `Cache-Control` header's s-maxage or max-age directive, preferring s-maxage.
+/// Responses marked no-store or private are never cached
+fn max_age(headers: &HeaderMap) -> Option<Duration> {
+    let directives: Vec<String> = headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.trim().to_ascii_lowercase())
+        .collect();
+    if directives
+        .iter()
+        .any(|directive| directive == "no-store" || directive == "private")
+    {
+        return None;
+    }
+    let seconds = |name: &str| {
+        directives.iter().find_map(|directive| {
+            directive
+                .strip_prefix(name)
+                .and_then(|value| value.strip_prefix('='))
+                .and_then(|value| value.trim_matches('"').parse().ok())
+        })
+    };
+    seconds("s-maxage")
+        .or_else(|| seconds("max-age"))
+        .map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache_control(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn max_age_prefers_s_maxage() {
+        assert_eq!(
+            max_age(&cache_control("public, max-age=60, s-maxage=120")),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            max_age(&cache_control("max-age=60")),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(max_age(&cache_control("private, max-age=60")), None);
+        assert_eq!(max_age(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn overrides_pass_and_set_ttls() {
+        let req = Request::get("http://origin.example.com/")
+            .extension(CacheOverride::new(PASS, 0))
+            .body(())
+            .expect("invalid request");
+        assert_eq!(Cache::key("origin", &req), None);
+        assert_eq!(
+            CacheOverride::new(TTL, 30).ttl,
+            Some(Duration::from_secs(30))
+        );
+    }
+
+    #[test]
+    fn entries_are_listed_and_purged() {
+        let cache = Cache::default();
+        for (key, surrogate_key) in &[("a", "one"), ("b", "two")] {
+            cache.insert(
+                key.to_string(),
+                &HeaderMap::new(),
+                None,
+                vec![surrogate_key.to_string()],
+                StatusCode::OK,
+                cache_control("max-age=60"),
+                Bytes::from_static(b"cached"),
+            );
+        }
+        // no ttl, so not cached
+        cache.insert(
+            "c".into(),
+            &HeaderMap::new(),
+            None,
+            Vec::new(),
+            StatusCode::OK,
+            HeaderMap::new(),
+            Bytes::new(),
+        );
+        let keys: Vec<_> = cache.list().into_iter().map(|l| l.key).collect();
+        assert_eq!(keys, vec!["a", "b"]);
+        assert!(cache.get("a", &HeaderMap::new()).is_some());
+
+        assert_eq!(cache.purge(Some("one")), 1);
+        assert!(cache.get("a", &HeaderMap::new()).is_none());
+        assert_eq!(cache.purge(None), 1);
+        assert!(cache.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn variants_are_cached_for_the_request_headers_responses_vary_on() -> Result<(), BoxError>
+    {
+        let accept_encoding = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("accept-encoding", HeaderValue::from_static(value));
+            headers
+        };
+        let cache = Cache::default();
+        for &(encoding, body) in &[("gzip", "compressed"), ("identity", "plain")] {
+            let mut headers = cache_control("max-age=60");
+            headers.insert(VARY, HeaderValue::from_static("Accept-Encoding"));
+            cache.insert(
+                "origin /".into(),
+                &accept_encoding(encoding),
+                None,
+                Vec::new(),
+                StatusCode::OK,
+                headers,
+                Bytes::from_static(body.as_bytes()),
+            );
+        }
+        assert_eq!(cache.list().len(), 2);
+        for &(encoding, body) in &[("gzip", "compressed"), ("identity", "plain")] {
+            let resp = cache
+                .get("origin /", &accept_encoding(encoding))
+                .expect("cached variant");
+            assert_eq!(hyper::body::to_bytes(resp.into_body()).await?, body);
+        }
+        assert!(cache.get("origin /", &accept_encoding("br")).is_none());
+        assert!(cache.get("origin /", &HeaderMap::new()).is_none());
+
+        // varying on every header is never cached
+        let mut headers = cache_control("max-age=60");
+        headers.insert(VARY, HeaderValue::from_static("*"));
+        cache.insert(
+            "origin /any".into(),
+            &HeaderMap::new(),
+            None,
+            Vec::new(),
+            StatusCode::OK,
+            headers,
+            Bytes::new(),
+        );
+        assert!(cache.get("origin /any", &HeaderMap::new()).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn seeds_load_from_a_directory() -> Result<(), BoxError> {
+        let dir = std::env::temp_dir().join(format!("fasttime-cache-seed-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        fs::write(
+            dir.join("greeting.json"),
+            r#"{
+  "backend": "origin",
+  "url": "https://origin.example.com/greeting",
+  "headers": { "content-type": "text/plain" },
+  "body": "hello",
+  "ttl_secs": 60,
+  "surrogate_keys": ["greetings"]
+}"#,
+        )?;
+        fs::write(dir.join("README.md"), "not a seed")?;
+        let cache = Cache::default();
+        let seeded = cache.seed(&dir);
+        fs::write(dir.join("broken.json"), "{}")?;
+        let broken = Cache::default().seed(&dir);
+        fs::remove_dir_all(&dir)?;
+        assert_eq!(seeded?, 1);
+        assert!(broken.is_err());
+
+        let resp = cache
+            .get(
+                "origin https://origin.example.com/greeting",
+                &HeaderMap::new(),
+            )
+            .expect("seeded response");
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], "text/plain");
+        assert_eq!(cache.list()[0].surrogate_keys, vec!["greetings"]);
+        Ok(())
+    }
+}
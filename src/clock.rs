@@ -0,0 +1,132 @@
+//! Defines the wall clock guests observe through WASI, which may be fixed or offset from
+//! the system's for reproducible time-dependent behavior
+//!
+//! Only WASI's realtime clock, read by `clock_time_get` and `clock_res_get` with a clock id of 0,
+//! is overridden. The monotonic clock always follows the system's, so that durations guests
+//! measure remain real
+
+use cap_std::time::SystemTime as CapSystemTime;
+use chrono::DateTime;
+use serde_derive::Deserialize;
+use std::{
+    convert::TryFrom,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+use wasi_common::clocks::WasiSystemClock;
+
+/// The wall clock a guest observes
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub enum Clock {
+    /// the system's clock
+    System,
+    /// a clock stopped at a point in time
+    Fixed(SystemTime),
+    /// the system's clock, ahead of or, when negative, behind by a duration
+    Offset { negative: bool, by: Duration },
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock::System
+    }
+}
+
+impl Clock {
+    /// Returns the time this clock reads now
+    pub fn now(&self) -> SystemTime {
+        match self {
+            Clock::System => SystemTime::now(),
+            Clock::Fixed(time) => *time,
+            Clock::Offset {
+                negative: false,
+                by,
+            } => SystemTime::now() + *by,
+            Clock::Offset { negative: true, by } => SystemTime::now() - *by,
+        }
+    }
+}
+
+impl FromStr for Clock {
+    type Err = String;
+
+    /// Parses `system`, `fixed:{rfc3339 time}`, or `offset:{duration}`, where durations
+    /// like `-1h` set the clock back
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "system" {
+            return Ok(Clock::System);
+        }
+        if let Some(time) = s.strip_prefix("fixed:") {
+            return DateTime::parse_from_rfc3339(time)
+                .map(|time| Clock::Fixed(time.into()))
+                .map_err(|e| format!("invalid fixed clock time `{}`: {}", time, e));
+        }
+        if let Some(offset) = s.strip_prefix("offset:") {
+            let (negative, by) = match offset.strip_prefix('-') {
+                Some(by) => (true, by),
+                _ => (false, offset.trim_start_matches('+')),
+            };
+            return humantime::parse_duration(by)
+                .map(|by| Clock::Offset { negative, by })
+                .map_err(|e| format!("invalid clock offset `{}`: {}", offset, e));
+        }
+        Err(format!(
+            "invalid clock `{}`, expected system, fixed:{{rfc3339 time}}, or offset:{{duration}}",
+            s
+        ))
+    }
+}
+
+impl TryFrom<String> for Clock {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl WasiSystemClock for Clock {
+    fn resolution(&self) -> Duration {
+        Duration::from_nanos(1)
+    }
+
+    fn now(
+        &self,
+        _precision: Duration,
+    ) -> CapSystemTime {
+        CapSystemTime::from_std(Clock::now(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clocks_parse() {
+        assert_eq!("system".parse(), Ok(Clock::System));
+        assert_eq!(
+            "fixed:2023-01-01T00:00:00Z".parse(),
+            Ok(Clock::Fixed(
+                SystemTime::UNIX_EPOCH + Duration::from_secs(1_672_531_200)
+            ))
+        );
+        assert_eq!(
+            "offset:-1h".parse(),
+            Ok(Clock::Offset {
+                negative: true,
+                by: Duration::from_secs(3600)
+            })
+        );
+        assert_eq!(
+            "offset:30s".parse(),
+            Ok(Clock::Offset {
+                negative: false,
+                by: Duration::from_secs(30)
+            })
+        );
+        assert!("fixed:yesterday".parse::<Clock>().is_err());
+        assert!("sundial".parse::<Clock>().is_err());
+    }
+}
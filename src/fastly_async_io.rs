@@ -0,0 +1,152 @@
+use crate::{
+    fastly_http_req::{read_handles, PendingRequestHandle},
+    handler::{HandleKind, Handler},
+    hostcall, memory,
+    memory::{trap, WriteMem},
+    BoxError,
+};
+use fastly_shared::FastlyStatus;
+use wasmtime::{Caller, Func, Linker, Store, Trap};
+
+/// `done_index_out` value for `select` when no handle became ready before its timeout
+const TIMED_OUT: u32 = u32::MAX;
+
+pub fn add_to_linker<'a>(
+    linker: &'a mut Linker,
+    handler: Handler,
+    store: &Store,
+) -> Result<&'a mut Linker, BoxError> {
+    Ok(linker
+        .define(
+            "fastly_async_io",
+            "is_ready",
+            is_ready(handler.clone(), &store),
+        )?
+        .define("fastly_async_io", "select", select(handler, &store))?)
+}
+
+/// Returns true when a handle refers to a pending request whose response was not yet taken
+///
+/// Pending requests are sent to completion by `send_async`, so any such request is ready
+fn ready(
+    handler: &Handler,
+    handle: PendingRequestHandle,
+) -> bool {
+    matches!(
+        handler.inner.borrow().pending.get(handle as usize),
+        Some(Some(_))
+    )
+}
+
+fn is_ready(
+    handler: Handler,
+    store: &Store,
+) -> Func {
+    Func::wrap(
+        store,
+        move |caller: Caller<'_>, handle: PendingRequestHandle, ready_out: i32| {
+            let _trace = hostcall!(
+                handler,
+                "fastly_async_io::is_ready handle={} ready_out={}",
+                handle,
+                ready_out
+            );
+            handler.check_handle(
+                "fastly_async_io::is_ready",
+                HandleKind::PendingRequest,
+                handle,
+            )?;
+            if !ready(&handler, handle) {
+                return Err(Trap::i32_exit(FastlyStatus::BADF.code));
+            }
+            memory!(caller).write_u32(ready_out, 1).map_err(trap)?;
+
+            Ok(FastlyStatus::OK.code)
+        },
+    )
+}
+
+/// Writes the index of the first ready handle to `done_index_out`. Since every pending
+/// request is complete, this never blocks
+fn select(
+    handler: Handler,
+    store: &Store,
+) -> Func {
+    Func::wrap(
+        store,
+        move |caller: Caller<'_>,
+              handles_addr: i32,
+              handles_len: i32,
+              timeout_ms: i32,
+              done_index_out: i32| {
+            let _trace = hostcall!(
+                handler,
+                "fastly_async_io::select handles_addr={} handles_len={} timeout_ms={} done_index_out={}",
+                handles_addr,
+                handles_len,
+                timeout_ms,
+                done_index_out
+            );
+            let mut memory = memory!(caller);
+            let handles = read_handles(&mut memory, handles_addr, handles_len)?;
+            for handle in &handles {
+                handler.check_handle(
+                    "fastly_async_io::select",
+                    HandleKind::PendingRequest,
+                    *handle,
+                )?;
+            }
+            match handles.iter().position(|handle| ready(&handler, *handle)) {
+                Some(index) => memory
+                    .write_u32(done_index_out, index as u32)
+                    .map_err(trap)?,
+                // with no timeout, select would wait forever on handles which can't become ready
+                None if timeout_ms == 0 => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
+                None => memory.write_u32(done_index_out, TIMED_OUT).map_err(trap)?,
+            }
+
+            Ok(FastlyStatus::OK.code)
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{guest, RecordingBackend};
+    use hyper::Request;
+    use std::collections::HashMap;
+
+    #[test]
+    fn select_and_is_ready_report_sent_requests() -> Result<(), BoxError> {
+        // responds with a status of 200 + is_ready's flag + select's done index
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "new" (func $req_new (param i32) (result i32)))
+  (import "fastly_http_req" "send_async" (func $send_async (param i32 i32 i32 i32 i32) (result i32)))
+  (import "fastly_async_io" "is_ready" (func $is_ready (param i32 i32) (result i32)))
+  (import "fastly_async_io" "select" (func $select (param i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "origin")
+  (func (export "_start")
+    (drop (call $req_new (i32.const 16)))
+    (drop (call $body_new (i32.const 20)))
+    (drop (call $send_async (i32.load (i32.const 16)) (i32.load (i32.const 20)) (i32.const 64) (i32.const 6) (i32.const 24)))
+    (drop (call $is_ready (i32.load (i32.const 24)) (i32.const 28)))
+    (drop (call $select (i32.const 24) (i32.const 1) (i32.const 0) (i32.const 32)))
+    (call $respond
+      (i32.add (i32.const 200) (i32.add (i32.load (i32.const 28)) (i32.load (i32.const 32))))
+      (i32.const 0)
+      (i32.const 0)))"#,
+        )?;
+        let backend = RecordingBackend::default();
+        let resp = Handler::new(Request::default()).run(
+            &module,
+            Store::new(&engine),
+            Box::new(backend.clone()),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        assert_eq!(resp.status().as_u16(), 201);
+        assert_eq!(backend.requests()[0].0, "origin");
+        Ok(())
+    }
+}
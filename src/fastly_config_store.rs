@@ -0,0 +1,62 @@
+use crate::{
+    fastly_dictionary::{self, Dictionaries},
+    handler::Handler,
+    BoxError,
+};
+use wasmtime::{Linker, Store};
+
+/// Config stores are the successor to edge dictionaries, sharing their data and semantics
+pub fn add_to_linker<'a>(
+    linker: &'a mut Linker,
+    handler: Handler,
+    store: &Store,
+    dictionaries: Dictionaries,
+) -> Result<&'a mut Linker, BoxError> {
+    linker
+        .define(
+            "fastly_config_store",
+            "open",
+            fastly_dictionary::open(handler.clone(), &store, dictionaries.clone()),
+        )?
+        .define(
+            "fastly_config_store",
+            "get",
+            fastly_dictionary::get(handler, &store, dictionaries),
+        )?;
+    Ok(linker)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{body, guest};
+    use hyper::Request;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn get_reads_dictionaries() -> Result<(), BoxError> {
+        let (engine, module) = guest(
+            r#"(import "fastly_config_store" "open" (func $open (param i32 i32 i32) (result i32)))
+  (import "fastly_config_store" "get" (func $get (param i32 i32 i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "dict")
+  (data (i32.const 128) "foo")
+  (func (export "_start")
+    (drop (call $open (i32.const 64) (i32.const 4) (i32.const 16)))
+    (drop (call $get (i32.load (i32.const 16)) (i32.const 128) (i32.const 3) (i32.const 1024) (i32.const 1024) (i32.const 20)))
+    (call $respond (i32.const 200) (i32.const 1024) (i32.load (i32.const 20))))"#,
+        )?;
+        let mut dictionaries = HashMap::new();
+        let mut dictionary = HashMap::new();
+        dictionary.insert("foo".to_string(), "bar".to_string());
+        dictionaries.insert("dict".to_string(), dictionary);
+        let resp = Handler::new(Request::default()).run(
+            &module,
+            Store::new(&engine),
+            crate::backend::default(),
+            dictionaries,
+            "127.0.0.1".parse().ok(),
+        )?;
+        assert_eq!("bar", body(resp).await?);
+        Ok(())
+    }
+}
@@ -1,43 +1,135 @@
 use crate::{
     handler::Handler,
-    memory,
-    memory::{ReadMem, WriteMem},
+    hostcall, memory,
+    memory::{trap, ReadMem, WriteMem},
     BoxError,
 };
+use anyhow::anyhow;
 use fastly_shared::FastlyStatus;
+use flate2::read::GzDecoder;
 use log::debug;
-use std::{collections::HashMap, str};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Read,
+    str,
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
 use wasmtime::{Caller, Func, Linker, Store, Trap};
 
-type DictionaryHandle = i32;
+pub type DictionaryHandle = i32;
+
+/// Status indicating the absence of a value, which fastly-shared does not yet define
+pub(crate) const NONE: FastlyStatus = FastlyStatus { code: 10 };
+
+/// Dictionaries shared across requests, so that changes made while handling one request
+/// are visible to the requests that follow
+///
+/// Dictionaries and their entries are kept sorted by name, so that enumerating them is
+/// stable from one run to the next
+///
+/// Access is guarded by a read-write lock. Any number of readers may hold it at once, while a
+/// writer waits for them to finish and holds it alone. Hostcalls only read for the length of a
+/// lookup, so writes land between them, but a guard held while handling a request on the same
+/// thread would deadlock its hostcalls, so guards should be dropped as soon as possible
+#[derive(Clone, Debug, Default)]
+pub struct Dictionaries(Arc<RwLock<BTreeMap<String, BTreeMap<String, String>>>>);
+
+impl Dictionaries {
+    pub fn read(&self) -> RwLockReadGuard<'_, BTreeMap<String, BTreeMap<String, String>>> {
+        self.0.read().expect("dictionaries lock poisoned")
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, BTreeMap<String, BTreeMap<String, String>>> {
+        self.0.write().expect("dictionaries lock poisoned")
+    }
+}
+
+impl From<BTreeMap<String, BTreeMap<String, String>>> for Dictionaries {
+    fn from(dictionaries: BTreeMap<String, BTreeMap<String, String>>) -> Self {
+        Dictionaries(Arc::new(RwLock::new(dictionaries)))
+    }
+}
+
+impl From<HashMap<String, HashMap<String, String>>> for Dictionaries {
+    fn from(dictionaries: HashMap<String, HashMap<String, String>>) -> Self {
+        dictionaries
+            .into_iter()
+            .map(|(name, entries)| (name, entries.into_iter().collect()))
+            .collect::<BTreeMap<_, _>>()
+            .into()
+    }
+}
+
+/// Decodes each value of the named dictionaries from base64 encoded gzip, so guests read them
+/// as plaintext
+pub fn gunzip_values(
+    dictionaries: &mut BTreeMap<String, BTreeMap<String, String>>,
+    names: &[String],
+) -> Result<(), BoxError> {
+    for name in names {
+        let entries = dictionaries
+            .get_mut(name)
+            .ok_or_else(|| anyhow!("no dictionary named {} to gunzip", name))?;
+        for (key, value) in entries.iter_mut() {
+            *value = gunzip(value).map_err(|e| {
+                anyhow!(
+                    "invalid gzip value for {} in dictionary {}: {}",
+                    key,
+                    name,
+                    e
+                )
+            })?;
+        }
+    }
+    Ok(())
+}
+
+fn gunzip(value: &str) -> Result<String, BoxError> {
+    let compressed = base64::decode(value.trim())?;
+    let mut plaintext = String::new();
+    GzDecoder::new(&compressed[..]).read_to_string(&mut plaintext)?;
+    Ok(plaintext)
+}
 
 pub fn add_to_linker<'a>(
     linker: &'a mut Linker,
     handler: Handler,
     store: &Store,
-    dictionaries: HashMap<String, HashMap<String, String>>,
+    dictionaries: Dictionaries,
 ) -> Result<&'a mut Linker, BoxError> {
     linker
         .define(
             "fastly_dictionary",
             "open",
-            open(handler.clone(), &store, dictionaries),
+            open(handler.clone(), &store, dictionaries.clone()),
         )?
-        .define("fastly_dictionary", "get", get(handler, &store))?;
+        .define(
+            "fastly_dictionary",
+            "get",
+            get(handler.clone(), &store, dictionaries.clone()),
+        )?
+        .define(
+            "fastly_dictionary",
+            "item_count",
+            item_count(handler, &store, dictionaries),
+        )?;
     Ok(linker)
 }
 
-fn open(
+pub(crate) fn open(
     handler: Handler,
     store: &Store,
-    dictionaries: HashMap<String, HashMap<String, String>>,
+    dictionaries: Dictionaries,
 ) -> Func {
     Func::wrap(
         &store,
         move |caller: Caller<'_>, addr: i32, len: i32, dict_out: DictionaryHandle| {
-            debug!(
+            let _trace = hostcall!(
+                handler,
                 "fastly_dictionary::open addr={} len={} dict_out={}",
-                addr, len, dict_out
+                addr,
+                len,
+                dict_out
             );
             let mut memory = memory!(caller);
             let (_, buf) = match memory.read_bytes(addr, len) {
@@ -45,15 +137,20 @@ fn open(
                 _ => return Err(Trap::new("failed to read dictionary name")),
             };
             let name = str::from_utf8(&buf).expect("utf8");
-            match dictionaries.get(name) {
-                Some(dict) => {
+            // handles refer to dictionaries by name, reading the shared entries on each lookup
+            match dictionaries.read().contains_key(name) {
+                true => {
                     debug!("fastly_dictionary::open opening dictionary {}", name);
                     let index = handler.inner.borrow().dictionaries.len();
-                    handler.inner.borrow_mut().dictionaries.push(dict.clone());
-                    memory.write_i32(dict_out, index as i32);
+                    handler
+                        .inner
+                        .borrow_mut()
+                        .dictionaries
+                        .push(name.to_owned());
+                    memory.write_i32(dict_out, index as i32).map_err(trap)?;
                     Ok(FastlyStatus::OK.code)
                 }
-                _ => {
+                false => {
                     debug!("fastly_dictionary::open no dictionary named {}", name);
                     Err(Trap::i32_exit(FastlyStatus::INVAL.code))
                 }
@@ -62,9 +159,10 @@ fn open(
     )
 }
 
-fn get(
+pub(crate) fn get(
     handler: Handler,
     store: &Store,
+    dictionaries: Dictionaries,
 ) -> Func {
     Func::wrap(
         &store,
@@ -75,13 +173,15 @@ fn get(
               value_addr: i32,
               _value_max_len: i32,
               nwritten: i32| {
-            debug!("fastly_dictionary::get");
-            match handler
+            let _trace = hostcall!(handler, "fastly_dictionary::get");
+            let name = handler
                 .inner
                 .borrow()
                 .dictionaries
                 .get(dict_handle as usize)
-            {
+                .cloned();
+            let dictionaries = dictionaries.read();
+            match name.and_then(|name| dictionaries.get(&name)) {
                 Some(dict) => {
                     let mut memory = memory!(caller);
                     let (_, buf) = match memory!(caller).read_bytes(key_addr, key_len) {
@@ -93,11 +193,15 @@ fn get(
                     match dict.get(key) {
                         Some(value) => match memory.write_bytes(value_addr, &value.as_bytes()) {
                             Ok(written) => {
-                                memory.write_i32(nwritten, written as i32);
+                                memory.write_i32(nwritten, written as i32).map_err(trap)?;
                             }
                             _ => return Err(Trap::new("failed to write dictionary value")),
                         },
-                        _ => memory.write_i32(nwritten, 0),
+                        _ => {
+                            // distinguish a missing key from an empty value
+                            memory.write_i32(nwritten, 0).map_err(trap)?;
+                            return Ok(NONE.code);
+                        }
                     }
                 }
                 _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
@@ -107,11 +211,191 @@ fn get(
     )
 }
 
+fn item_count(
+    handler: Handler,
+    store: &Store,
+    dictionaries: Dictionaries,
+) -> Func {
+    Func::wrap(
+        &store,
+        move |caller: Caller<'_>, dict_handle: DictionaryHandle, count_out: i32| {
+            let _trace = hostcall!(
+                handler,
+                "fastly_dictionary::item_count dict_handle={} count_out={}",
+                dict_handle,
+                count_out
+            );
+            let name = handler
+                .inner
+                .borrow()
+                .dictionaries
+                .get(dict_handle as usize)
+                .cloned();
+            match name.and_then(|name| dictionaries.read().get(&name).map(BTreeMap::len)) {
+                Some(len) => memory!(caller)
+                    .write_u32(count_out, len as u32)
+                    .map_err(trap)?,
+                _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
+            }
+            Ok(FastlyStatus::OK.code)
+        },
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::tests::{body, WASM};
-    use hyper::Request;
+    use crate::tests::{body, guest, WASM};
+    use hyper::{Body, Request, Response};
+
+    fn dictionaries() -> HashMap<String, HashMap<String, String>> {
+        let mut dictionaries = HashMap::new();
+        let mut dictionary = HashMap::new();
+        dictionary.insert("foo".to_string(), "bar".to_string());
+        dictionary.insert("empty".to_string(), "".to_string());
+        dictionaries.insert("dict".to_string(), dictionary);
+        dictionaries
+    }
+
+    /// Opens `dict` and gets a key, responding with a status of 200 + the get status
+    fn get_status(key: &str) -> Result<Response<Body>, BoxError> {
+        get_status_from(dictionaries().into(), key)
+    }
+
+    /// Like `get_status`, with the value as the response body, for a given set of dictionaries
+    fn get_status_from(
+        dictionaries: Dictionaries,
+        key: &str,
+    ) -> Result<Response<Body>, BoxError> {
+        let (engine, module) = guest(
+            r#"(import "fastly_dictionary" "open" (func $open (param i32 i32 i32) (result i32)))
+  (import "fastly_dictionary" "get" (func $get (param i32 i32 i32 i32 i32 i32) (result i32)))"#,
+            &format!(
+                r#"(data (i32.const 64) "dict")
+  (data (i32.const 128) "{}")
+  (func (export "_start")
+    (drop (call $open (i32.const 64) (i32.const 4) (i32.const 16)))
+    (call $respond
+      (i32.add (i32.const 200) (call $get (i32.load (i32.const 16)) (i32.const 128) (i32.const {}) (i32.const 1024) (i32.const 1024) (i32.const 20)))
+      (i32.const 1024)
+      (i32.load (i32.const 20))))"#,
+                key,
+                key.len()
+            ),
+        )?;
+        Handler::new(Request::default()).run(
+            &module,
+            Store::new(&engine),
+            crate::backend::default(),
+            dictionaries,
+            "127.0.0.1".parse().ok(),
+        )
+    }
+
+    #[tokio::test]
+    async fn gunzipped_values_are_read_as_plaintext() -> Result<(), BoxError> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, b"a large config value")?;
+        let mut dictionaries = BTreeMap::new();
+        let mut dictionary = BTreeMap::new();
+        dictionary.insert("config".to_string(), base64::encode(encoder.finish()?));
+        dictionaries.insert("dict".to_string(), dictionary);
+        gunzip_values(&mut dictionaries, &["dict".to_string()])?;
+        let resp = get_status_from(dictionaries.into(), "config")?;
+        assert_eq!(resp.status().as_u16() as i32, 200 + FastlyStatus::OK.code);
+        assert_eq!(body(resp).await?, "a large config value");
+        Ok(())
+    }
+
+    #[test]
+    fn gunzip_rejects_values_which_are_not_gzip() {
+        let mut dictionaries = BTreeMap::new();
+        let mut dictionary = BTreeMap::new();
+        dictionary.insert("plain".to_string(), "bar".to_string());
+        dictionaries.insert("dict".to_string(), dictionary);
+        assert!(gunzip_values(&mut dictionaries, &["dict".to_string()]).is_err());
+        assert!(gunzip_values(&mut dictionaries, &["missing".to_string()]).is_err());
+    }
+
+    #[test]
+    fn get_distinguishes_empty_values() -> Result<(), BoxError> {
+        let resp = get_status("empty")?;
+        assert_eq!(resp.status().as_u16() as i32, 200 + FastlyStatus::OK.code);
+        Ok(())
+    }
+
+    #[test]
+    fn get_distinguishes_missing_keys() -> Result<(), BoxError> {
+        let resp = get_status("missing")?;
+        assert_eq!(resp.status().as_u16() as i32, 200 + NONE.code);
+        Ok(())
+    }
+
+    #[test]
+    fn item_count_works() -> Result<(), BoxError> {
+        let (engine, module) = guest(
+            r#"(import "fastly_dictionary" "open" (func $open (param i32 i32 i32) (result i32)))
+  (import "fastly_dictionary" "item_count" (func $item_count (param i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "dict")
+  (func (export "_start")
+    (drop (call $open (i32.const 64) (i32.const 4) (i32.const 16)))
+    (drop (call $item_count (i32.load (i32.const 16)) (i32.const 20)))
+    (call $respond (i32.add (i32.const 200) (i32.load (i32.const 20))) (i32.const 0) (i32.const 0)))"#,
+        )?;
+        let resp = Handler::new(Request::default()).run(
+            &module,
+            Store::new(&engine),
+            crate::backend::default(),
+            dictionaries(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        assert_eq!(resp.status().as_u16(), 202);
+        Ok(())
+    }
+
+    #[test]
+    fn dictionaries_are_shared_across_requests() -> Result<(), BoxError> {
+        let (engine, module) = guest(
+            r#"(import "fastly_dictionary" "open" (func $open (param i32 i32 i32) (result i32)))
+  (import "fastly_dictionary" "item_count" (func $item_count (param i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "dict")
+  (func (export "_start")
+    (drop (call $open (i32.const 64) (i32.const 4) (i32.const 16)))
+    (drop (call $item_count (i32.load (i32.const 16)) (i32.const 20)))
+    (call $respond (i32.add (i32.const 200) (i32.load (i32.const 20))) (i32.const 0) (i32.const 0)))"#,
+        )?;
+        let shared = Dictionaries::from(dictionaries());
+        let run = || {
+            Handler::new(Request::default()).run(
+                &module,
+                Store::new(&engine),
+                crate::backend::default(),
+                shared.clone(),
+                "127.0.0.1".parse().ok(),
+            )
+        };
+        assert_eq!(run()?.status().as_u16(), 202);
+        shared
+            .write()
+            .entry("dict".into())
+            .or_default()
+            .insert("baz".into(), "boom".into());
+        assert_eq!(run()?.status().as_u16(), 203);
+        Ok(())
+    }
+
+    #[test]
+    fn dictionaries_are_sorted_by_name() {
+        let mut dictionaries = dictionaries();
+        dictionaries.insert("another".into(), HashMap::new());
+        let shared = Dictionaries::from(dictionaries);
+        let shared = shared.read();
+        assert_eq!(shared.keys().collect::<Vec<_>>(), vec!["another", "dict"]);
+        assert_eq!(
+            shared["dict"].keys().collect::<Vec<_>>(),
+            vec!["empty", "foo"]
+        );
+    }
 
     #[tokio::test]
     async fn hits_work() -> Result<(), BoxError> {
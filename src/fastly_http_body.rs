@@ -1,33 +1,284 @@
 use crate::{
-    handler::Handler,
-    memory,
-    memory::{ReadMem, WriteMem},
+    handler::{HandleKind, Handler},
+    hostcall, memory,
+    memory::{trap, ReadMem, WriteMem},
     BoxError,
 };
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use fastly_shared::FastlyStatus;
+use futures_executor::block_on;
+use futures_util::stream;
+use hyper::body::HttpBody;
 use log::debug;
+use std::{collections::VecDeque, io, mem, time::Instant};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
 use wasmtime::{Caller, Func, Linker, Store, Trap};
 
 pub type BodyHandle = i32;
 
+/// `body_end` value for `write` which appends bytes to the back of a body
+const BACK: i32 = 0;
+/// `body_end` value for `write` which prepends bytes to the front of a body
+const FRONT: i32 = 1;
+
+/// A body held by the host on behalf of a guest
+///
+/// Buffered bytes are held as a rope of chunks so that appending one body to another,
+/// or writing many small pieces, moves chunks rather than copying bytes. Bodies may
+/// also be backed by a stream, like a downstream request body, whose chunks are only
+/// pulled into the buffer as the guest reads them
+///
+/// A body sent downstream in streaming mode forwards the bytes later written to it
+/// to the client, rather than buffering them
+#[derive(Debug, Default)]
+pub struct HostBody {
+    /// chunks buffered but not yet read, in body order
+    chunks: VecDeque<Bytes>,
+    /// source of bytes not yet buffered
+    stream: Option<hyper::Body>,
+    /// destination of bytes written once this body is streamed downstream
+    sink: Option<UnboundedSender<Bytes>>,
+    /// number of bytes forwarded to `sink`
+    sent: usize,
+}
+
+impl HostBody {
+    /// Creates a new body which pulls bytes from a stream as they are read
+    pub fn streaming(stream: hyper::Body) -> Self {
+        HostBody {
+            stream: Some(stream),
+            ..HostBody::default()
+        }
+    }
+
+    /// Streams this body downstream, returning a body which yields the bytes buffered so far
+    /// followed by those written later. The returned body ends once this one is dropped
+    pub fn stream_downstream(&mut self) -> Result<hyper::Body, hyper::Error> {
+        self.buffer()?;
+        let (sink, rx) = unbounded_channel();
+        self.sink = Some(sink);
+        for chunk in mem::take(&mut self.chunks) {
+            self.push_back(chunk);
+        }
+        Ok(hyper::Body::wrap_stream(stream::unfold(
+            rx,
+            |mut rx| async move { rx.recv().await.map(|chunk| (Ok::<_, io::Error>(chunk), rx)) },
+        )))
+    }
+
+    /// Returns true when this body forwards bytes written to it downstream
+    pub fn is_streaming(&self) -> bool {
+        self.sink.is_some()
+    }
+
+    /// Returns the number of bytes forwarded downstream when streaming
+    pub fn sent(&self) -> usize {
+        self.sent
+    }
+
+    /// Reads up to `max` bytes, pulling the next chunk from the stream when nothing is buffered
+    ///
+    /// Reads never span chunks. An empty result indicates the end of the body
+    pub fn read(
+        &mut self,
+        max: usize,
+    ) -> Result<Bytes, hyper::Error> {
+        while self.chunks.is_empty() {
+            match self.stream.as_mut() {
+                Some(stream) => match block_on(stream.data()) {
+                    Some(chunk) => self.push_back(chunk?),
+                    None => self.stream = None,
+                },
+                None => break,
+            }
+        }
+        match self.chunks.front_mut() {
+            Some(chunk) if chunk.len() > max => Ok(chunk.split_to(max)),
+            _ => Ok(self.chunks.pop_front().unwrap_or_default()),
+        }
+    }
+
+    /// Reads and discards the rest of the stream, leaving buffered bytes in place
+    pub fn drain(&mut self) -> Result<(), hyper::Error> {
+        if let Some(mut stream) = self.stream.take() {
+            while let Some(chunk) = block_on(stream.data()) {
+                chunk?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Pulls any remaining chunks from the stream into the buffer
+    pub fn buffer(&mut self) -> Result<(), hyper::Error> {
+        if let Some(mut stream) = self.stream.take() {
+            while let Some(chunk) = block_on(stream.data()) {
+                self.push_back(chunk?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a chunk to the end of the body, or forwards it downstream when streaming
+    pub fn push_back(
+        &mut self,
+        chunk: Bytes,
+    ) {
+        if chunk.is_empty() {
+            return;
+        }
+        match &self.sink {
+            Some(sink) => {
+                self.sent += chunk.len();
+                // the client may have gone away, which the guest has no way to act on
+                let _ = sink.send(chunk);
+            }
+            _ => self.chunks.push_back(chunk),
+        }
+    }
+
+    /// Adds a chunk to the front of the body, ahead of any bytes not yet read
+    pub fn push_front(
+        &mut self,
+        chunk: Bytes,
+    ) {
+        if !chunk.is_empty() {
+            self.chunks.push_front(chunk);
+        }
+    }
+
+    /// Moves all of the chunks of `other` to the end of this body without copying them
+    pub fn append(
+        &mut self,
+        mut other: HostBody,
+    ) -> Result<(), hyper::Error> {
+        // the rest of this body's stream has to land ahead of other's bytes
+        self.buffer()?;
+        other.buffer()?;
+        if self.is_streaming() {
+            for chunk in other.chunks {
+                self.push_back(chunk);
+            }
+        } else {
+            self.chunks.append(&mut other.chunks);
+        }
+        Ok(())
+    }
+
+    /// Returns the number of buffered bytes
+    pub fn len(&self) -> usize {
+        self.chunks.iter().map(Bytes::len).sum()
+    }
+
+    /// Returns true when no bytes are buffered
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// Consumes this body, returning all of its remaining bytes
+    ///
+    /// This is the only place chunks are copied into a single contiguous buffer,
+    /// and only when there is more than one of them
+    pub fn into_bytes(mut self) -> Result<Bytes, hyper::Error> {
+        self.buffer()?;
+        if self.chunks.len() <= 1 {
+            return Ok(self.chunks.pop_front().unwrap_or_default());
+        }
+        let mut bytes = BytesMut::with_capacity(self.len());
+        for chunk in self.chunks {
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok(bytes.freeze())
+    }
+}
+
+impl From<BytesMut> for HostBody {
+    fn from(bytes: BytesMut) -> Self {
+        let mut body = HostBody::default();
+        body.push_back(bytes.freeze());
+        body
+    }
+}
+
 pub fn add_to_linker<'a>(
     linker: &'a mut Linker,
     handler: Handler,
     store: &Store,
 ) -> Result<&'a mut Linker, BoxError> {
     Ok(linker
-        .define("fastly_http_body", "close", close(&store))?
+        .define("fastly_http_body", "close", close(handler.clone(), &store))?
         .define("fastly_http_body", "new", new(handler.clone(), &store))?
         .define("fastly_http_body", "write", write(handler.clone(), &store))?
         .define("fastly_http_body", "read", read(handler.clone(), &store))?
         .define("fastly_http_body", "append", append(handler, &store))?)
 }
 
-fn close(store: &Store) -> Func {
-    Func::wrap(store, |_: BodyHandle| {
-        debug!("fastly_http_body::close");
-        // noop
+/// Buffers the body behind a handle, returning its length, including any bytes already streamed downstream
+fn buffered_len(
+    handler: &Handler,
+    handle: BodyHandle,
+) -> Result<usize, Trap> {
+    match handler.inner.borrow_mut().bodies.get_mut(handle as usize) {
+        Some(body) => {
+            body.buffer()
+                .map_err(|e| Trap::new(format!("failed to read body: {}", e)))?;
+            Ok(body.len() + body.sent())
+        }
+        _ => Err(Trap::i32_exit(FastlyStatus::BADF.code)),
+    }
+}
+
+/// Returns true, logging why, when a body would grow past `max_response_body` bytes
+fn exceeds_max_body(
+    handler: &Handler,
+    hostcall: &str,
+    len: usize,
+) -> bool {
+    match handler.inner.borrow().settings.max_response_body {
+        Some(max) if len > max => {
+            log::error!(
+                "{} would grow a body to {} bytes, exceeding the {} byte limit",
+                hostcall,
+                len,
+                max
+            );
+            true
+        }
+        _ => false,
+    }
+}
+
+/// The largest body a guest can measure, as it sizes them with its 32 bit `usize`
+const MAX_BODY_LEN: usize = u32::MAX as usize;
+
+/// Returns true, logging why, when a body would grow past what a guest can measure
+fn exceeds_abi(
+    hostcall: &str,
+    len: usize,
+) -> bool {
+    if len > MAX_BODY_LEN {
+        log::error!(
+            "{} would grow a body to {} bytes, more than the guest can represent",
+            hostcall,
+            len
+        );
+        return true;
+    }
+    false
+}
+
+/// Closes a body. Closing a body streamed downstream ends the downstream response,
+/// closing any other body is a noop
+fn close(
+    handler: Handler,
+    store: &Store,
+) -> Func {
+    Func::wrap(store, move |handle: BodyHandle| {
+        let _trace = hostcall!(handler, "fastly_http_body::close handle={}", handle);
+        if let Some(body) = handler.inner.borrow_mut().bodies.get_mut(handle as usize) {
+            if body.is_streaming() {
+                mem::take(body);
+            }
+        }
         FastlyStatus::OK.code
     })
 }
@@ -39,26 +290,44 @@ fn append(
     Func::wrap(
         store,
         move |dst_handle: BodyHandle, src_handle: BodyHandle| {
-            debug!(
+            let _trace = hostcall!(
+                handler,
                 "fastly_http_body::append dst_handle={} src_handle={}",
-                dst_handle, src_handle
+                dst_handle,
+                src_handle
             );
+            handler.check_handle("fastly_http_body::append", HandleKind::Body, dst_handle)?;
+            handler.check_handle("fastly_http_body::append", HandleKind::Body, src_handle)?;
+            // taking the source would empty the destination along with it
+            if dst_handle == src_handle {
+                return Ok(FastlyStatus::INVAL.code);
+            }
+            if handler.inner.borrow().settings.max_response_body.is_some() {
+                let len = buffered_len(&handler, dst_handle)? + buffered_len(&handler, src_handle)?;
+                if exceeds_max_body(&handler, "fastly_http_body::append", len) {
+                    return Ok(FastlyStatus::ERROR.code);
+                }
+            }
+            // appending consumes the source body, so its chunks can be moved rather than copied
             let src = match handler
                 .inner
                 .borrow_mut()
                 .bodies
                 .get_mut(src_handle as usize)
             {
-                Some(src) => src.clone(),
+                Some(src) => mem::take(src),
                 _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
             };
+            handler.consume(HandleKind::Body, src_handle);
             match handler
                 .inner
                 .borrow_mut()
                 .bodies
                 .get_mut(dst_handle as usize)
             {
-                Some(dst) => dst.extend_from_slice(src.as_ref()),
+                Some(dst) => dst
+                    .append(src)
+                    .map_err(|e| Trap::new(format!("failed to read body: {}", e)))?,
                 _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
             }
 
@@ -72,15 +341,19 @@ fn new(
     store: &Store,
 ) -> Func {
     Func::wrap(store, move |caller: Caller<'_>, handle_out: i32| {
-        debug!("fastly_http_body::new handle_out={}", handle_out);
+        let _trace = hostcall!(handler, "fastly_http_body::new handle_out={}", handle_out);
         let index = handler.inner.borrow().bodies.len();
-        handler.inner.borrow_mut().bodies.push(BytesMut::default());
-        memory!(caller).write_u32(handle_out, index as u32);
+        handler.inner.borrow_mut().bodies.push(HostBody::default());
+        memory!(caller)
+            .write_u32(handle_out, index as u32)
+            .map_err(trap)?;
 
         Ok(FastlyStatus::OK.code)
     })
 }
 
+/// Writes bytes to a body, where `body_end` selects the end of the body they are written to.
+/// `BACK` appends them, like writing the next chunk, while `FRONT` prepends them
 fn write(
     handler: Handler,
     store: &Store,
@@ -93,10 +366,22 @@ fn write(
               size: i32,
               body_end: i32,
               nwritten_out: i32| {
-            debug!(
+            let _trace = hostcall!(
+                handler,
                 "fastly_http_body::write handle={} addr={} size={} body_end={} nwritten_out={}",
-                handle, addr, size, body_end, nwritten_out
+                handle,
+                addr,
+                size,
+                body_end,
+                nwritten_out
             );
+            handler.check_handle("fastly_http_body::write", HandleKind::Body, handle)?;
+            if handler.inner.borrow().settings.max_response_body.is_some() {
+                let len = buffered_len(&handler, handle)? + memory::offset(size);
+                if exceeds_max_body(&handler, "fastly_http_body::write", len) {
+                    return Ok(FastlyStatus::ERROR.code);
+                }
+            }
             match handler.inner.borrow_mut().bodies.get_mut(handle as usize) {
                 Some(body) => {
                     let mut mem = memory!(caller);
@@ -104,9 +389,27 @@ fn write(
                         Ok((num, buf)) => (num, buf),
                         _ => return Err(Trap::new("Failed to read body memory")),
                     };
-                    body.extend_from_slice(&buf);
+                    // body_end selects which end of the body to write to, not whether
+                    // this is the final write
+                    match body_end {
+                        BACK => body
+                            .buffer()
+                            .map_err(|e| Trap::new(format!("failed to read body: {}", e)))?,
+                        // bytes already streamed downstream can't be written ahead of
+                        FRONT if body.is_streaming() => return Ok(FastlyStatus::INVAL.code),
+                        FRONT => (),
+                        _ => return Ok(FastlyStatus::INVAL.code),
+                    }
+                    if exceeds_abi("fastly_http_body::write", body.len() + body.sent() + read) {
+                        return Ok(FastlyStatus::ERROR.code);
+                    }
+                    if body_end == BACK {
+                        body.push_back(Bytes::from(buf));
+                    } else {
+                        body.push_front(Bytes::from(buf));
+                    }
 
-                    mem.write_u32(nwritten_out, read as u32);
+                    mem.write_u32(nwritten_out, read as u32).map_err(trap)?;
                 }
                 _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
             }
@@ -127,10 +430,16 @@ fn read(
               buf: i32,
               buf_len: i32,
               nread_out: i32| {
-            debug!(
+            let _trace = hostcall!(
+                handler,
                 "fastly_http_body::read body_handle={}, buf={} buf_len={} nread_out={}",
-                body_handle, buf, buf_len, nread_out
+                body_handle,
+                buf,
+                buf_len,
+                nread_out
             );
+            handler.check_handle("fastly_http_body::read", HandleKind::Body, body_handle)?;
+            let reading = Instant::now();
             match handler
                 .inner
                 .borrow_mut()
@@ -138,17 +447,22 @@ fn read(
                 .get_mut(body_handle as usize)
             {
                 Some(body) => {
+                    let chunk = body
+                        .read(memory::offset(buf_len))
+                        .map_err(|e| Trap::new(format!("failed to read body: {}", e)))?;
                     let mut memory = memory!(caller);
-                    match memory.write_bytes(buf, body.as_ref()) {
+                    match memory.write_bytes(buf, &chunk) {
                         Ok(written) => {
                             debug!("fastly_http_body::read write {} bytes", written);
-                            memory.write_i32(nread_out, written as i32);
+                            // written is at most buf_len, so fits the guest's 32 bit usize
+                            memory.write_u32(nread_out, written as u32).map_err(trap)?;
                         }
                         _ => return Err(Trap::new("failed to read body bytes")),
                     }
                 }
                 _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
             }
+            handler.record_ingest(body_handle, reading);
 
             Ok(FastlyStatus::OK.code)
         },
@@ -158,10 +472,169 @@ fn read(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::tests::{body, WASM};
+    use crate::tests::{body, guest, WASM};
+    use futures_util::stream;
     use hyper::{Body, Request, Response};
     use std::collections::HashMap;
 
+    #[tokio::test]
+    async fn read_streams_downstream_body_in_pieces() -> Result<(), BoxError> {
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "body_downstream_get" (func $body_downstream_get (param i32 i32) (result i32)))
+  (import "fastly_http_body" "read" (func $body_read (param i32 i32 i32 i32) (result i32)))"#,
+            r#"(func (export "_start") (local $len i32) (local $reads i32)
+    (drop (call $body_downstream_get (i32.const 16) (i32.const 20)))
+    (block $done
+      (loop $read
+        (drop (call $body_read (i32.load (i32.const 20)) (i32.add (i32.const 1024) (local.get $len)) (i32.const 4) (i32.const 24)))
+        (br_if $done (i32.eqz (i32.load (i32.const 24))))
+        (local.set $len (i32.add (local.get $len) (i32.load (i32.const 24))))
+        (local.set $reads (i32.add (local.get $reads) (i32.const 1)))
+        (br $read)))
+    ;; respond with the number of reads as the status
+    (call $respond (i32.add (i32.const 200) (local.get $reads)) (i32.const 1024) (local.get $len)))"#,
+        )?;
+        let chunks: Vec<Result<&'static str, std::io::Error>> =
+            vec![Ok("hello "), Ok("chunked "), Ok("world")];
+        let resp = Handler::new(
+            Request::post("/")
+                .header("Transfer-Encoding", "chunked")
+                .body(Body::wrap_stream(stream::iter(chunks)))?,
+        )
+        .run(
+            &module,
+            Store::new(&engine),
+            crate::backend::default(),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        // each 4 byte read is limited to the bytes of a single chunk
+        assert_eq!(resp.status().as_u16(), 206);
+        assert_eq!("hello chunked world", body(resp).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_honors_body_end() -> Result<(), BoxError> {
+        let (engine, module) = guest(
+            "",
+            r#"(data (i32.const 64) "world")
+  (data (i32.const 72) "hello ")
+  (func (export "_start")
+    (drop (call $resp_new (i32.const 16)))
+    (drop (call $body_new (i32.const 20)))
+    (drop (call $body_write (i32.load (i32.const 20)) (i32.const 64) (i32.const 5) (i32.const 0) (i32.const 24)))
+    (drop (call $body_write (i32.load (i32.const 20)) (i32.const 72) (i32.const 6) (i32.const 1) (i32.const 24)))
+    (drop (call $resp_send_downstream (i32.load (i32.const 16)) (i32.load (i32.const 20)) (i32.const 0))))"#,
+        )?;
+        let resp = Handler::new(Request::default()).run(
+            &module,
+            Store::new(&engine),
+            crate::backend::default(),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        assert_eq!("hello world", body(resp).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn out_of_range_nwritten_out_traps_rather_than_panics() -> Result<(), BoxError> {
+        let (engine, module) = guest(
+            "",
+            r#"(func (export "_start")
+    (drop (call $body_new (i32.const 16)))
+    ;; the last byte of the guest's address space, where no 4 byte count fits
+    (drop (call $body_write (i32.load (i32.const 16)) (i32.const 0) (i32.const 4) (i32.const 0) (i32.const -1))))"#,
+        )?;
+        let err = Handler::new(Request::default())
+            .run(
+                &module,
+                Store::new(&engine),
+                crate::backend::default(),
+                HashMap::default(),
+                "127.0.0.1".parse().ok(),
+            )
+            .expect_err("expected a trap");
+        assert!(err.to_string().contains("failed to write guest memory"));
+        Ok(())
+    }
+
+    #[test]
+    fn bodies_larger_than_the_guest_can_measure_are_rejected() {
+        assert!(!exceeds_abi("test", u32::MAX as usize));
+        assert!(exceeds_abi("test", u32::MAX as usize + 1));
+    }
+
+    #[tokio::test]
+    async fn write_and_append_stop_at_max_response_body() -> Result<(), BoxError> {
+        // writes 4 bytes at a time until a write fails, then tries appending a 4 byte body,
+        // responding with what was written and a status of 200 + the append's status
+        let (engine, module) = guest(
+            r#"(import "fastly_http_body" "append" (func $body_append (param i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "spam")
+  (func (export "_start")
+    (drop (call $resp_new (i32.const 16)))
+    (drop (call $body_new (i32.const 20)))
+    (block $done
+      (loop $write
+        (br_if $done (call $body_write (i32.load (i32.const 20)) (i32.const 64) (i32.const 4) (i32.const 0) (i32.const 24)))
+        (br $write)))
+    (drop (call $body_new (i32.const 28)))
+    (drop (call $body_write (i32.load (i32.const 28)) (i32.const 64) (i32.const 4) (i32.const 0) (i32.const 24)))
+    (drop (call $resp_status_set (i32.load (i32.const 16))
+      (i32.add (i32.const 200) (call $body_append (i32.load (i32.const 20)) (i32.load (i32.const 28))))))
+    (drop (call $resp_send_downstream (i32.load (i32.const 16)) (i32.load (i32.const 20)) (i32.const 0))))"#,
+        )?;
+        let resp = Handler::new(Request::default())
+            .with_settings(crate::handler::Settings {
+                max_response_body: Some(10),
+                ..Default::default()
+            })
+            .run(
+                &module,
+                Store::new(&engine),
+                crate::backend::default(),
+                HashMap::default(),
+                "127.0.0.1".parse().ok(),
+            )?;
+        assert_eq!(
+            resp.status().as_u16() as i32,
+            200 + FastlyStatus::ERROR.code
+        );
+        assert_eq!("spamspam", body(resp).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn append_rejects_appending_a_body_to_itself() -> Result<(), BoxError> {
+        // responds with the body and a status of 200 + the append's status
+        let (engine, module) = guest(
+            r#"(import "fastly_http_body" "append" (func $body_append (param i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "spam")
+  (func (export "_start")
+    (drop (call $resp_new (i32.const 16)))
+    (drop (call $body_new (i32.const 20)))
+    (drop (call $body_write (i32.load (i32.const 20)) (i32.const 64) (i32.const 4) (i32.const 0) (i32.const 24)))
+    (drop (call $resp_status_set (i32.load (i32.const 16))
+      (i32.add (i32.const 200) (call $body_append (i32.load (i32.const 20)) (i32.load (i32.const 20))))))
+    (drop (call $resp_send_downstream (i32.load (i32.const 16)) (i32.load (i32.const 20)) (i32.const 0))))"#,
+        )?;
+        let resp = Handler::new(Request::default()).run(
+            &module,
+            Store::new(&engine),
+            crate::backend::default(),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        assert_eq!(
+            resp.status().as_u16() as i32,
+            200 + FastlyStatus::INVAL.code
+        );
+        assert_eq!("spam", body(resp).await?);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn append_works() -> Result<(), BoxError> {
         match WASM.as_ref() {
@@ -185,4 +658,45 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn append_moves_chunks_in_order() -> Result<(), BoxError> {
+        let mut dst = HostBody::from(BytesMut::from("hello"));
+        let mut src = HostBody::streaming(Body::from(" world"));
+        src.push_front(Bytes::from_static(b","));
+        dst.append(src)?;
+        assert_eq!(dst.len(), 12);
+        assert_eq!(dst.read(3)?, "hel");
+        assert_eq!(dst.read(64)?, "lo");
+        assert_eq!(dst.into_bytes()?, ", world");
+        Ok(())
+    }
+
+    /// A rough benchmark of building a large body from many appended pieces
+    ///
+    /// Run with `cargo test --release bench_append_many_chunks -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_append_many_chunks() -> Result<(), BoxError> {
+        const CHUNKS: usize = 100_000;
+        let chunk = Bytes::from(vec![b'x'; 1024]);
+        let started = std::time::Instant::now();
+        let mut body = HostBody::default();
+        for _ in 0..CHUNKS {
+            let mut piece = HostBody::default();
+            piece.push_back(chunk.clone());
+            body.append(piece)?;
+        }
+        let appended = started.elapsed();
+        let bytes = body.into_bytes()?;
+        assert_eq!(bytes.len(), CHUNKS * chunk.len());
+        println!(
+            "appended {} chunks ({} bytes) in {:?}, flattened in {:?}",
+            CHUNKS,
+            bytes.len(),
+            appended,
+            started.elapsed() - appended
+        );
+        Ok(())
+    }
 }
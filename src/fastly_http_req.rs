@@ -1,25 +1,50 @@
+#[cfg(test)]
+use crate::handler::{Sent, SentRequest};
 use crate::{
     backend::Backends,
-    fastly_http_body::BodyHandle,
+    cache::{Cache, CacheOverride},
+    fastly_http_body::{BodyHandle, HostBody},
     fastly_http_resp::ResponseHandle,
     geo,
-    handler::Handler,
-    memory,
-    memory::{ReadMem, WriteMem},
+    handler::{HandleKind, Handler},
+    hostcall, memory,
+    memory::{trap, write_nul_terminated, ReadMem, WriteMem},
     BoxError,
 };
+use brotli_decompressor::Decompressor;
+use byteorder::{ByteOrder, LittleEndian};
 use bytes::BytesMut;
 use fastly_shared::{FastlyStatus, HttpVersion};
+use flate2::read::GzDecoder;
+use http::response::Parts as ResponseParts;
 use hyper::{
     body::to_bytes,
-    header::{HeaderName, HeaderValue},
+    header::{HeaderName, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH},
     Body, Method, Request, Uri,
 };
 use log::debug;
-use std::{convert::TryFrom, net::IpAddr, str};
-use wasmtime::{Caller, Func, Linker, Store, Trap};
+use std::{
+    convert::TryFrom,
+    io::{self, Read},
+    mem,
+    net::IpAddr,
+    rc::Rc,
+    str,
+    sync::Arc,
+    time::Instant,
+};
+use wasmtime::{Caller, Func, Linker, Memory, Store, Trap};
 
 pub type RequestHandle = i32;
+pub type PendingRequestHandle = i32;
+
+/// The exact uri bytes a guest provided to `uri_set`, returned as is by `uri_get`
+#[derive(Clone, Debug)]
+struct RawUri(Vec<u8>);
+
+/// The surrogate keys a guest tagged a request's cache entry with in `cache_override_v2_set`
+#[derive(Clone, Debug, PartialEq)]
+pub struct SurrogateKey(pub String);
 
 pub fn add_to_linker<'a>(
     linker: &'a mut Linker,
@@ -28,6 +53,61 @@ pub fn add_to_linker<'a>(
     backends: Box<dyn crate::Backends>,
     ip: Option<IpAddr>,
 ) -> Result<&'a mut Linker, BoxError> {
+    let backends: Rc<dyn crate::Backends> = backends.into();
+    handler.stub(
+        linker,
+        "fastly_http_req",
+        "downstream_tls_cipher_openssl_name",
+        |_cipher_out: i32, _cipher_max_len: i32, _nwritten: i32| {
+            debug!("fastly_http_req::downstream_tls_cipher_openssl_name (stub)");
+            FastlyStatus::UNSUPPORTED.code
+        },
+    )?;
+    handler.stub(
+        linker,
+        "fastly_http_req",
+        "downstream_tls_client_hello",
+        |_client_hello_out: i32, _client_hello_max_len: i32, _nwritten: i32| {
+            debug!("fastly_http_req::downstream_tls_client_hello (stub)");
+            FastlyStatus::UNSUPPORTED.code
+        },
+    )?;
+    handler.stub(
+        linker,
+        "fastly_http_req",
+        "downstream_tls_protocol",
+        |_protocol_out: i32, _protocol_max_len: i32, _nwritten: i32| {
+            debug!("fastly_http_req::downstream_tls_protocol (stub)");
+            FastlyStatus::UNSUPPORTED.code
+        },
+    )?;
+    handler.stub(
+        linker,
+        "fastly_http_req",
+        "header_append",
+        |_req_handle: RequestHandle, _name: i32, _name_len: i32, _value: i32, _value_len: i32| {
+            debug!("fastly_http_req::header_append (stub)");
+            FastlyStatus::UNSUPPORTED.code
+        },
+    )?;
+    handler.stub(
+        linker,
+        "fastly_http_req",
+        "header_insert",
+        |_req_handle: RequestHandle, _name: i32, _name_len: i32, _value: i32, _value_len: i32| {
+            debug!("fastly_http_req::header_insert (stub)");
+            FastlyStatus::UNSUPPORTED.code
+        },
+    )?;
+    handler.stub(
+        linker,
+        "fastly_http_req",
+        "header_remove",
+        |_req_handle: RequestHandle, _name: i32, _name_len: i32| {
+            debug!("fastly_http_req::header_remove (stub)");
+            FastlyStatus::UNSUPPORTED.code
+        },
+    )?;
     Ok(linker
         .define(
             "fastly_http_req",
@@ -49,67 +129,16 @@ pub fn add_to_linker<'a>(
             "downstream_client_ip_addr",
             downstream_client_ip_addr(handler.clone(), &store, ip),
         )?
-        .func(
-            "fastly_http_req",
-            "downstream_tls_cipher_openssl_name",
-            |_cipher_out: i32, _cipher_max_len: i32, _nwritten: i32| {
-                debug!("fastly_http_req::downstream_tls_cipher_openssl_name (stub)");
-                FastlyStatus::UNSUPPORTED.code
-            },
-        )?
-        .func(
-            "fastly_http_req",
-            "downstream_tls_client_hello",
-            |_client_hello_out: i32, _client_hello_max_len: i32, _nwritten: i32| {
-                debug!("fastly_http_req::downstream_tls_client_hello (stub)");
-                FastlyStatus::UNSUPPORTED.code
-            },
-        )?
-        .func(
-            "fastly_http_req",
-            "downstream_tls_protocol",
-            |_protocol_out: i32, _protocol_max_len: i32, _nwritten: i32| {
-                debug!("fastly_http_req::downstream_tls_protocol (stub)");
-                FastlyStatus::UNSUPPORTED.code
-            },
-        )?
-        .func(
-            "fastly_http_req",
-            "header_append",
-            |_req_handle: RequestHandle,
-             _name: i32,
-             _name_len: i32,
-             _value: i32,
-             _value_len: i32| {
-                debug!("fastly_http_req::header_append (stub)");
-                FastlyStatus::UNSUPPORTED.code
-            },
-        )?
-        .func(
+        .define(
             "fastly_http_req",
-            "header_insert",
-            |_req_handle: RequestHandle,
-             _name: i32,
-             _name_len: i32,
-             _value: i32,
-             _value_len: i32| {
-                debug!("fastly_http_req::header_insert (stub)");
-                FastlyStatus::UNSUPPORTED.code
-            },
+            "downstream_client_request_id",
+            downstream_client_request_id(handler.clone(), &store),
         )?
         .define(
             "fastly_http_req",
             "header_names_get",
             header_names_get(handler.clone(), &store),
         )?
-        .func(
-            "fastly_http_req",
-            "header_remove",
-            |_req_handle: RequestHandle, _name: i32, _name_len: i32| {
-                debug!("fastly_http_req::header_remove (stub)");
-                FastlyStatus::UNSUPPORTED.code
-            },
-        )?
         .define(
             "fastly_http_req",
             "header_values_get",
@@ -141,10 +170,30 @@ pub fn add_to_linker<'a>(
             "original_header_names_get",
             original_header_names_get(handler.clone(), &store),
         )?
+        .define(
+            "fastly_http_req",
+            "pending_req_poll",
+            pending_req_poll(handler.clone(), &store),
+        )?
+        .define(
+            "fastly_http_req",
+            "pending_req_select",
+            pending_req_select(handler.clone(), &store),
+        )?
+        .define(
+            "fastly_http_req",
+            "pending_req_wait",
+            pending_req_wait(handler.clone(), &store),
+        )?
         .define(
             "fastly_http_req",
             "send",
-            send(handler.clone(), &store, backends),
+            send(handler.clone(), &store, backends.clone()),
+        )?
+        .define(
+            "fastly_http_req",
+            "send_async",
+            send_async(handler.clone(), &store, backends),
         )?
         .define(
             "fastly_http_req",
@@ -176,11 +225,11 @@ fn original_header_names_get(
         store,
         move |caller: Caller<'_>,
               buf: i32,
-              _buf_len: i32,
+              buf_len: i32,
               cursor: i32,
               ending_cursor: i32,
               nwritten: i32| {
-            debug!("fastly_http_req::original_header_names_get");
+            let _trace = hostcall!(handler, "fastly_http_req::original_header_names_get");
 
             let mut names: Vec<_> = handler
                 .inner
@@ -195,7 +244,7 @@ fn original_header_names_get(
                         .collect::<Vec<_>>()
                 })
                 .or_else(|| {
-                    handler.inner.borrow().requests.first().map(|r| {
+                    handler.inner.borrow().requests.downstream().map(|r| {
                         r.headers
                             .keys()
                             .map(HeaderName::as_str)
@@ -210,22 +259,25 @@ fn original_header_names_get(
             let ucursor = cursor as usize;
             match names.get(ucursor) {
                 Some(hdr) => {
-                    let mut bytes = hdr.as_bytes().to_vec();
-                    bytes.push(0); // api requires a terminating \x00 byte
-                    let written = memory.write_bytes(buf, &bytes).unwrap();
-                    memory.write_i32(nwritten, written as i32);
-                    memory.write_i32(
-                        ending_cursor,
-                        if ucursor < names.len() - 1 {
-                            cursor + 1_i32
-                        } else {
-                            -1_i32
-                        },
-                    );
+                    if let Some(status) =
+                        write_nul_terminated(&mut memory, buf, buf_len, hdr.as_bytes(), nwritten)?
+                    {
+                        return Ok(status);
+                    }
+                    memory
+                        .write_i32(
+                            ending_cursor,
+                            if ucursor < names.len() - 1 {
+                                cursor + 1_i32
+                            } else {
+                                -1_i32
+                            },
+                        )
+                        .map_err(trap)?;
                 }
                 _ => {
-                    memory.write_i32(nwritten, 0);
-                    memory.write_i32(ending_cursor, -1);
+                    memory.write_i32(nwritten, 0).map_err(trap)?;
+                    memory.write_i32(ending_cursor, -1).map_err(trap)?;
                     return Ok(FastlyStatus::OK.code);
                 }
             }
@@ -240,7 +292,8 @@ fn original_header_count(
     store: &Store,
 ) -> Func {
     Func::wrap(store, move |caller: Caller<'_>, count_out: i32| {
-        debug!(
+        let _trace = hostcall!(
+            handler,
             "fastly_http_req::original_header_count count_out={}",
             count_out
         );
@@ -255,7 +308,7 @@ fn original_header_count(
                     .inner
                     .borrow()
                     .requests
-                    .first()
+                    .downstream()
                     .map(|r| r.headers.len())
             })
             .unwrap_or_default()
@@ -264,7 +317,7 @@ fn original_header_count(
             value => value as i32,
         };
         debug!("fastly_http_req::original_header_count count => {}", count);
-        memory!(caller).write_i32(count_out, count);
+        memory!(caller).write_i32(count_out, count).map_err(trap)?;
         Ok(FastlyStatus::OK.code)
     })
 }
@@ -276,12 +329,13 @@ fn body_downstream_get(
     Func::wrap(
         &store,
         move |caller: Caller<'_>, request_handle_out: RequestHandle, body_handle_out: i32| {
-            debug!(
+            let _trace = hostcall!(
+                handler,
                 "fastly_http_req::body_downstream_get request_handle_out={} body_handle_out={}",
-                request_handle_out, body_handle_out
+                request_handle_out,
+                body_handle_out
             );
-            let index = handler.inner.borrow().requests.len();
-            let (parts, body) = handler
+            let (mut parts, body) = handler
                 .inner
                 .borrow_mut()
                 .request
@@ -289,32 +343,118 @@ fn body_downstream_get(
                 .unwrap()
                 .into_parts();
             debug!("fastly_http_req::body_downstream_get {:?}", parts);
-            handler.inner.borrow_mut().requests.push(parts);
-            handler.inner.borrow_mut().bodies.push(BytesMut::from(
-                futures_executor::block_on(to_bytes(body)).unwrap().as_ref(),
-            ));
+            let encoding = parts
+                .headers
+                .get(CONTENT_ENCODING)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_ascii_lowercase)
+                .filter(|encoding| {
+                    handler.inner.borrow().settings.decompress_request
+                        && (encoding == "gzip" || encoding == "br")
+                });
+            // dumping and decompressing require the full body up front, otherwise the body is streamed as the guest reads it.
+            // either way, hyper sends any interim 100 Continue a client expects when the body is first polled
+            let body =
+                if handler.inner.borrow().settings.dump_bodies.is_some() || encoding.is_some() {
+                    let ingesting = Instant::now();
+                    let mut body = futures_executor::block_on(to_bytes(body))
+                        .map_err(|e| Trap::new(format!("failed to read request body: {}", e)))?;
+                    handler.inner.borrow_mut().timings.ingest += ingesting.elapsed();
+                    if let Some(encoding) = encoding {
+                        body = decompress(&encoding, &body)
+                            .map_err(|e| {
+                                Trap::new(format!(
+                                    "failed to decompress {} request body: {}",
+                                    encoding, e
+                                ))
+                            })?
+                            .into();
+                        parts.headers.remove(CONTENT_ENCODING);
+                        parts
+                            .headers
+                            .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+                    }
+                    handler.dump_body("request", &body);
+                    HostBody::from(BytesMut::from(body.as_ref()))
+                } else {
+                    HostBody::streaming(body)
+                };
+            let body_index = handler.inner.borrow().bodies.len();
+            let index = handler.inner.borrow_mut().requests.set_downstream(parts);
+            handler.inner.borrow_mut().bodies.push(body);
+            handler.inner.borrow_mut().downstream_body = Some(body_index as BodyHandle);
 
             let mut mem = memory!(caller);
-            mem.write_i32(request_handle_out, index as i32);
-            mem.write_i32(body_handle_out, index as i32);
+            mem.write_i32(request_handle_out, index as i32)
+                .map_err(trap)?;
+            mem.write_i32(body_handle_out, body_index as i32)
+                .map_err(trap)?;
+            Ok(FastlyStatus::OK.code)
+        },
+    )
+}
+
+/// Decompresses a `gzip` or `br` encoded body
+fn decompress(
+    encoding: &str,
+    body: &[u8],
+) -> io::Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    match encoding {
+        "gzip" => GzDecoder::new(body).read_to_end(&mut decompressed)?,
+        _ => Decompressor::new(body, 4096).read_to_end(&mut decompressed)?,
+    };
+    Ok(decompressed)
+}
+
+fn downstream_client_request_id(
+    handler: Handler,
+    store: &Store,
+) -> Func {
+    Func::wrap(
+        &store,
+        move |caller: Caller<'_>, addr: i32, maxlen: i32, nwritten_out: i32| {
+            let _trace = hostcall!(
+                handler,
+                "fastly_http_req::downstream_client_request_id addr={} maxlen={} nwritten_out={}",
+                addr,
+                maxlen,
+                nwritten_out
+            );
+            let id = handler.inner.borrow().request_id.0.clone();
+            let mut memory = memory!(caller);
+            if id.len() > maxlen as usize {
+                memory
+                    .write_i32(nwritten_out, id.len() as i32)
+                    .map_err(trap)?;
+                return Ok(FastlyStatus::BUFLEN.code);
+            }
+            match memory.write_bytes(addr, id.as_bytes()) {
+                Ok(written) => memory
+                    .write_i32(nwritten_out, written as i32)
+                    .map_err(trap)?,
+                _ => return Err(Trap::new("failed to write request id")),
+            }
             Ok(FastlyStatus::OK.code)
         },
     )
 }
 
 fn downstream_client_ip_addr(
-    _handler: Handler,
+    handler: Handler,
     store: &Store,
     ip: Option<IpAddr>,
 ) -> Func {
     Func::wrap(
         &store,
         move |caller: Caller<'_>, addr: i32, num_written: i32| {
-            let mut memory = memory!(caller);
-            debug!(
+            let _trace = hostcall!(
+                handler,
                 "fastly_http_req::downstream_client_ip_addr addr={} num_written={}",
-                addr, num_written
+                addr,
+                num_written
             );
+            let mut memory = memory!(caller);
             match ip {
                 Some(ip) => {
                     debug!(
@@ -326,11 +466,13 @@ fn downstream_client_ip_addr(
                         IpAddr::V6(ip) => ip.octets().to_vec(),
                     };
                     match memory.write_bytes(addr, &bytes) {
-                        Ok(written) => memory.write_i32(num_written, written as i32),
+                        Ok(written) => memory
+                            .write_i32(num_written, written as i32)
+                            .map_err(trap)?,
                         _ => return Err(Trap::new("failed to write ip address")),
                     }
                 }
-                _ => memory.write_i32(num_written, 0),
+                _ => memory.write_i32(num_written, 0).map_err(trap)?,
             }
 
             Ok(FastlyStatus::OK.code)
@@ -343,11 +485,12 @@ fn new(
     store: &Store,
 ) -> Func {
     Func::wrap(store, move |caller: Caller<'_>, request: RequestHandle| {
-        debug!("fastly_http_req::new request={}", request);
-        let index = handler.inner.borrow().requests.len();
+        let _trace = hostcall!(handler, "fastly_http_req::new request={}", request);
         let r: Request<Body> = Request::default();
-        handler.inner.borrow_mut().requests.push(r.into_parts().0);
-        memory!(caller).write_i32(request, index as i32);
+        let index = handler.inner.borrow_mut().requests.push(r.into_parts().0);
+        memory!(caller)
+            .write_i32(request, index as i32)
+            .map_err(trap)?;
         Ok(FastlyStatus::OK.code)
     })
 }
@@ -363,10 +506,15 @@ fn method_get(
               addr: i32,
               maxlen: i32,
               nwritten_out: i32| {
-            debug!(
+            let _trace = hostcall!(
+                handler,
                 "fastly_http_req::method_get handle={} addr={} maxlen={} nwritten_out={}",
-                handle, addr, maxlen, nwritten_out
+                handle,
+                addr,
+                maxlen,
+                nwritten_out
             );
+            handler.check_handle("fastly_http_req::method_get", HandleKind::Request, handle)?;
             let mut mem = memory!(caller);
             match handler.inner.borrow().requests.get(handle as usize) {
                 Some(req) => {
@@ -375,7 +523,7 @@ fn method_get(
                         Ok(num) => num,
                         _ => return Err(Trap::new("Failed to write request HTTP method bytes")),
                     };
-                    mem.write_u32(nwritten_out, written as u32);
+                    mem.write_u32(nwritten_out, written as u32).map_err(trap)?;
                 }
                 _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
             };
@@ -392,23 +540,51 @@ fn method_set(
     Func::wrap(
         store,
         move |caller: Caller<'_>, handle: RequestHandle, addr: i32, size: i32| {
+            let _trace = hostcall!(
+                handler,
+                "fastly_http_req::method_set handle={} addr={} size={}",
+                handle,
+                addr,
+                size
+            );
+            handler.check_handle("fastly_http_req::method_set", HandleKind::Request, handle)?;
             let (_, buf) = match memory!(caller).read_bytes(addr, size) {
                 Ok(result) => result,
                 _ => return Err(Trap::new("failed to read body memory")),
             };
-            match Method::from_bytes(&buf) {
-                Ok(method) => match handler.inner.borrow_mut().requests.get_mut(handle as usize) {
-                    Some(req) => req.method = method,
-                    _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
-                },
-                _ => return Err(Trap::i32_exit(FastlyStatus::HTTPPARSE.code)),
+            if buf.is_empty() {
+                debug!("fastly_http_req::method_set rejecting empty method");
+                return Ok(FastlyStatus::INVAL.code);
+            }
+            // validate up front so malformed methods never reach a backend
+            let method = match Method::from_bytes(&buf) {
+                Ok(method) if is_token(&buf) => method,
+                _ => {
+                    debug!(
+                        "fastly_http_req::method_set rejecting invalid method {:?}",
+                        String::from_utf8_lossy(&buf)
+                    );
+                    return Ok(FastlyStatus::HTTPPARSE.code);
+                }
             };
+            match handler.inner.borrow_mut().requests.get_mut(handle as usize) {
+                Some(req) => req.method = method,
+                _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
+            }
 
             Ok(FastlyStatus::OK.code)
         },
     )
 }
 
+/// Returns true when bytes are an HTTP token, as defined by RFC 7230
+fn is_token(bytes: &[u8]) -> bool {
+    !bytes.is_empty()
+        && bytes
+            .iter()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(b))
+}
+
 fn uri_get(
     handler: Handler,
     store: &Store,
@@ -420,20 +596,31 @@ fn uri_get(
               addr: i32,
               maxlen: i32,
               nwritten_out: i32| {
-            debug!(
+            let _trace = hostcall!(
+                handler,
                 "fastly_http_req::uri_get handle={} addr={} maxlen={} nwritten_out={}",
-                handle, addr, maxlen, nwritten_out
+                handle,
+                addr,
+                maxlen,
+                nwritten_out
             );
+            handler.check_handle("fastly_http_req::uri_get", HandleKind::Request, handle)?;
             let mut mem = memory!(caller);
             match handler.inner.borrow().requests.get(handle as usize) {
                 Some(request) => {
-                    let uri = request.uri.to_string();
-                    debug!("fastly_http_req::uri_get => {}", uri);
-                    let written = match mem.write_bytes(addr, uri.as_bytes()) {
+                    let uri = match request.extensions.get::<RawUri>() {
+                        Some(RawUri(raw)) => raw.clone(),
+                        _ => request.uri.to_string().into_bytes(),
+                    };
+                    debug!(
+                        "fastly_http_req::uri_get => {}",
+                        String::from_utf8_lossy(&uri)
+                    );
+                    let written = match mem.write_bytes(addr, &uri) {
                         Ok(num) => num,
                         _ => return Err(Trap::new("failed to write method bytes")),
                     };
-                    mem.write_u32(nwritten_out, written as u32);
+                    mem.write_u32(nwritten_out, written as u32).map_err(trap)?;
                 }
                 _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
             }
@@ -443,10 +630,211 @@ fn uri_get(
     )
 }
 
+/// Sends the request and body behind a pair of handles to a backend, consuming them
+///
+/// Failures the guest is expected to handle, like a geolocation lookup for a malformed address,
+/// are returned as the status to answer the guest with
+fn send_request(
+    handler: &Handler,
+    backends: &dyn crate::Backends,
+    req_handle: RequestHandle,
+    body_handle: BodyHandle,
+    backend: &str,
+) -> Result<Result<(ResponseParts, HostBody), FastlyStatus>, Trap> {
+    debug!("fastly_http_req::send resolving backend '{}'", backend);
+
+    // consumed handles are left in place so later handles remain valid
+    let parts = match handler
+        .inner
+        .borrow_mut()
+        .requests
+        .get_mut(req_handle as usize)
+    {
+        Some(parts) => mem::replace(parts, Request::new(()).into_parts().0),
+        _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
+    };
+    let body = match handler
+        .inner
+        .borrow_mut()
+        .bodies
+        .get_mut(body_handle as usize)
+    {
+        Some(body) => mem::take(body),
+        _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
+    };
+    handler.consume(HandleKind::Request, req_handle);
+    handler.consume(HandleKind::Body, body_handle);
+    let body = body
+        .into_bytes()
+        .map_err(|e| Trap::new(format!("failed to read request body: {}", e)))?;
+    handler.dump_backend_request(backend, &parts, &body);
+    #[cfg(test)]
+    let sent = if handler.inner.borrow().settings.record_sends {
+        Some(SentRequest {
+            backend: backend.to_owned(),
+            method: parts.method.clone(),
+            uri: parts.uri.clone(),
+            headers: parts.headers.clone(),
+        })
+    } else {
+        None
+    };
+    let req = Request::from_parts(parts, Body::from(body));
+    let sending = Instant::now();
+    let geolocation =
+        backend == geo::GEOLOCATION && !handler.inner.borrow().settings.disable_geolocation;
+    let (parts, body) = if geolocation {
+        debug!("backend 'geolocation' routed to the built in geolocation backend");
+        let (abi, lookup) = {
+            let settings = &handler.inner.borrow().settings;
+            (settings.geo_abi, settings.geo_lookup.clone())
+        };
+        let sent = geo::GeoBackend::new(lookup.unwrap_or_else(|| Arc::new(geo::Geo::default())))
+            .with_abi(abi)
+            .send(backend, req);
+        let (parts, body) = match sent {
+            Ok(resp) => resp.into_parts(),
+            Err(e) => {
+                debug!("fastly_http_req::send geolocation lookup failed: {}", e);
+                return Ok(Err(FastlyStatus::ERROR));
+            }
+        };
+        (parts, HostBody::streaming(body))
+    } else {
+        let cache = handler.inner.borrow().settings.cache.clone();
+        match cache.and_then(|cache| Cache::key(backend, &req).map(|key| (cache, key))) {
+            Some((cache, key)) => match send_cached(&cache, key, backends, backend, req)? {
+                Ok(resp) => resp,
+                Err(status) => return Ok(Err(status)),
+            },
+            _ => match backends.send(backend, req) {
+                Ok(resp) => {
+                    let (parts, body) = resp.into_parts();
+                    (parts, HostBody::streaming(body))
+                }
+                Err(e) => {
+                    debug!(
+                        "fastly_http_req::send failed to send to '{}': {}",
+                        backend, e
+                    );
+                    return Ok(Err(FastlyStatus::ERROR));
+                }
+            },
+        }
+    };
+    handler.inner.borrow_mut().timings.backend += sending.elapsed();
+    // carried with the response until the guest receives a handle to it
+    #[cfg(test)]
+    let parts = {
+        let mut parts = parts;
+        if let Some(sent) = sent {
+            parts.extensions.insert(sent);
+        }
+        parts
+    };
+    Ok(Ok((parts, body)))
+}
+
+/// Responds from the cache when it holds a fresh response for a request, otherwise
+/// sending the request and caching its response. Sends which fail are answered with `ERROR`
+fn send_cached(
+    cache: &Cache,
+    key: String,
+    backends: &dyn crate::Backends,
+    backend: &str,
+    req: Request<Body>,
+) -> Result<Result<(ResponseParts, HostBody), FastlyStatus>, Trap> {
+    if let Some(resp) = cache.get(&key, req.headers()) {
+        debug!("fastly_http_req::send cache hit for '{}'", key);
+        let (parts, body) = resp.into_parts();
+        return Ok(Ok((parts, HostBody::streaming(body))));
+    }
+    debug!("fastly_http_req::send cache miss for '{}'", key);
+    let cache_override = req.extensions().get::<CacheOverride>().copied();
+    let request_headers = req.headers().clone();
+    let surrogate_keys = req
+        .extensions()
+        .get::<SurrogateKey>()
+        .map(|SurrogateKey(keys)| keys.split_whitespace().map(String::from).collect())
+        .unwrap_or_default();
+    let (parts, body) = match backends.send(backend, req) {
+        Ok(resp) => resp.into_parts(),
+        Err(e) => {
+            debug!(
+                "fastly_http_req::send failed to send to '{}': {}",
+                backend, e
+            );
+            return Ok(Err(FastlyStatus::ERROR));
+        }
+    };
+    let body = futures_executor::block_on(to_bytes(body))
+        .map_err(|e| Trap::new(format!("failed to read backend response body: {}", e)))?;
+    cache.insert(
+        key,
+        &request_headers,
+        cache_override.as_ref(),
+        surrogate_keys,
+        parts.status,
+        parts.headers.clone(),
+        body.clone(),
+    );
+    Ok(Ok((parts, HostBody::streaming(Body::from(body)))))
+}
+
+/// Stores a backend response, writing its response and body handles to the guest's memory
+fn write_response(
+    handler: &Handler,
+    memory: &mut Memory,
+    (parts, body): (ResponseParts, HostBody),
+    resp_handle_out: ResponseHandle,
+    resp_body_handle_out: BodyHandle,
+) -> Result<(), Trap> {
+    #[cfg(test)]
+    let parts = {
+        let mut parts = parts;
+        if let Some(request) = parts.extensions.remove::<SentRequest>() {
+            let response_handle = handler.inner.borrow().responses.len() as i32;
+            handler.inner.borrow_mut().sends.push(Sent {
+                request,
+                status: parts.status,
+                response_handle,
+            });
+        }
+        parts
+    };
+    handler.inner.borrow_mut().responses.push(parts);
+    handler.inner.borrow_mut().bodies.push(body);
+
+    memory
+        .write_i32(
+            resp_handle_out,
+            (handler.inner.borrow().responses.len() - 1) as i32,
+        )
+        .map_err(trap)?;
+    memory
+        .write_i32(
+            resp_body_handle_out,
+            (handler.inner.borrow().bodies.len() - 1) as i32,
+        )
+        .map_err(trap)
+}
+
+/// Reads a backend name from the guest's memory
+fn read_backend(
+    memory: &mut Memory,
+    backend_addr: i32,
+    backend_len: i32,
+) -> Result<String, Trap> {
+    match memory.read_bytes(backend_addr, backend_len) {
+        Ok((_, buf)) => Ok(String::from_utf8_lossy(&buf).into_owned()),
+        _ => Err(Trap::new("error reading backend name")),
+    }
+}
+
 fn send(
     handler: Handler,
     store: &Store,
-    backends: Box<dyn crate::Backends>,
+    backends: Rc<dyn crate::Backends>,
 ) -> Func {
     Func::wrap(
         store,
@@ -457,50 +845,218 @@ fn send(
               backend_len: i32,
               resp_handle_out: ResponseHandle,
               resp_body_handle_out: BodyHandle| {
-            debug!("fastly_http_req::send req_handle={}, body_handle={} backend_addr={} backend_len={} resp_handle_out={} resp_body_handle_out={}", req_handle, body_handle, backend_addr, backend_len, resp_handle_out, resp_body_handle_out);
+            let _trace = hostcall!(handler, "fastly_http_req::send req_handle={}, body_handle={} backend_addr={} backend_len={} resp_handle_out={} resp_body_handle_out={}", req_handle, body_handle, backend_addr, backend_len, resp_handle_out, resp_body_handle_out);
+            handler.check_handle("fastly_http_req::send", HandleKind::Request, req_handle)?;
+            handler.check_handle("fastly_http_req::send", HandleKind::Body, body_handle)?;
             let mut memory = memory!(caller);
-            let (_, buf) = match memory.read_bytes(backend_addr, backend_len) {
-                Ok(result) => result,
-                _ => return Err(Trap::new("error reading backend name")),
+            let backend = read_backend(&mut memory, backend_addr, backend_len)?;
+            if !handler.count_backend_request() {
+                return Ok(FastlyStatus::ERROR.code);
+            }
+            let response = match send_request(
+                &handler,
+                backends.as_ref(),
+                req_handle,
+                body_handle,
+                &backend,
+            )? {
+                Ok(response) => response,
+                Err(status) => return Ok(status.code),
             };
-            let backend = str::from_utf8(&buf).unwrap();
-            debug!("backend={}", backend);
+            write_response(
+                &handler,
+                &mut memory,
+                response,
+                resp_handle_out,
+                resp_body_handle_out,
+            )?;
 
-            let parts = handler
-                .inner
-                .borrow_mut()
-                .requests
-                .remove(req_handle as usize);
-            let body = handler
-                .inner
-                .borrow_mut()
-                .bodies
-                .remove(body_handle as usize);
-            let req = Request::from_parts(parts, Body::from(body.to_vec()));
-            let (parts, body) = match backend {
-                "geolocation" => geo::GeoBackend(Box::new(geo::Geo::default()))
-                    .send(backend, req)
-                    .expect("failed to send request")
-                    .into_parts(),
-                other => backends
-                    .send(other, req)
-                    .expect("failed to send request")
-                    .into_parts(),
+            Ok(FastlyStatus::OK.code)
+        },
+    )
+}
+
+/// Sends a request without waiting for its response
+///
+/// fasttime runs guests on a blocking thread, so the request is sent to completion here
+/// and its response is held until the guest polls, waits, or selects on the pending request
+fn send_async(
+    handler: Handler,
+    store: &Store,
+    backends: Rc<dyn crate::Backends>,
+) -> Func {
+    Func::wrap(
+        store,
+        move |caller: Caller<'_>,
+              req_handle: RequestHandle,
+              body_handle: BodyHandle,
+              backend_addr: i32,
+              backend_len: i32,
+              pending_req_handle_out: PendingRequestHandle| {
+            let _trace = hostcall!(handler, "fastly_http_req::send_async req_handle={}, body_handle={} backend_addr={} backend_len={} pending_req_handle_out={}", req_handle, body_handle, backend_addr, backend_len, pending_req_handle_out);
+            handler.check_handle(
+                "fastly_http_req::send_async",
+                HandleKind::Request,
+                req_handle,
+            )?;
+            handler.check_handle("fastly_http_req::send_async", HandleKind::Body, body_handle)?;
+            let mut memory = memory!(caller);
+            let backend = read_backend(&mut memory, backend_addr, backend_len)?;
+            if !handler.count_backend_request() {
+                return Ok(FastlyStatus::ERROR.code);
+            }
+            let response = match send_request(
+                &handler,
+                backends.as_ref(),
+                req_handle,
+                body_handle,
+                &backend,
+            )? {
+                Ok(response) => response,
+                Err(status) => return Ok(status.code),
             };
+            let index = handler.inner.borrow().pending.len();
+            handler.inner.borrow_mut().pending.push(Some(response));
+            memory
+                .write_i32(pending_req_handle_out, index as i32)
+                .map_err(trap)?;
 
-            handler.inner.borrow_mut().responses.push(parts);
-            handler.inner.borrow_mut().bodies.push(BytesMut::from(
-                futures_executor::block_on(to_bytes(body)).unwrap().as_ref(),
-            ));
+            Ok(FastlyStatus::OK.code)
+        },
+    )
+}
+
+/// Takes the response of a pending request, which is always complete
+fn take_pending(
+    handler: &Handler,
+    hostcall: &str,
+    handle: PendingRequestHandle,
+) -> Result<(ResponseParts, HostBody), Trap> {
+    handler.check_handle(hostcall, HandleKind::PendingRequest, handle)?;
+    let response = handler
+        .inner
+        .borrow_mut()
+        .pending
+        .get_mut(handle as usize)
+        .and_then(Option::take);
+    match response {
+        Some(response) => {
+            handler.consume(HandleKind::PendingRequest, handle);
+            Ok(response)
+        }
+        _ => Err(Trap::i32_exit(FastlyStatus::BADF.code)),
+    }
+}
 
-            memory.write_i32(
+fn pending_req_poll(
+    handler: Handler,
+    store: &Store,
+) -> Func {
+    Func::wrap(
+        store,
+        move |caller: Caller<'_>,
+              handle: PendingRequestHandle,
+              is_done_out: i32,
+              resp_handle_out: ResponseHandle,
+              resp_body_handle_out: BodyHandle| {
+            let _trace = hostcall!(handler, "fastly_http_req::pending_req_poll handle={} is_done_out={} resp_handle_out={} resp_body_handle_out={}", handle, is_done_out, resp_handle_out, resp_body_handle_out);
+            let response = take_pending(&handler, "fastly_http_req::pending_req_poll", handle)?;
+            let mut memory = memory!(caller);
+            memory.write_i32(is_done_out, 1).map_err(trap)?;
+            write_response(
+                &handler,
+                &mut memory,
+                response,
                 resp_handle_out,
-                (handler.inner.borrow().responses.len() - 1) as i32,
-            );
-            memory.write_i32(
                 resp_body_handle_out,
-                (handler.inner.borrow().bodies.len() - 1) as i32,
-            );
+            )?;
+
+            Ok(FastlyStatus::OK.code)
+        },
+    )
+}
+
+fn pending_req_wait(
+    handler: Handler,
+    store: &Store,
+) -> Func {
+    Func::wrap(
+        store,
+        move |caller: Caller<'_>,
+              handle: PendingRequestHandle,
+              resp_handle_out: ResponseHandle,
+              resp_body_handle_out: BodyHandle| {
+            let _trace = hostcall!(handler, "fastly_http_req::pending_req_wait handle={} resp_handle_out={} resp_body_handle_out={}", handle, resp_handle_out, resp_body_handle_out);
+            let response = take_pending(&handler, "fastly_http_req::pending_req_wait", handle)?;
+            let mut memory = memory!(caller);
+            write_response(
+                &handler,
+                &mut memory,
+                response,
+                resp_handle_out,
+                resp_body_handle_out,
+            )?;
+
+            Ok(FastlyStatus::OK.code)
+        },
+    )
+}
+
+/// Reads an array of `len` handles from the guest's memory
+pub fn read_handles(
+    memory: &mut Memory,
+    addr: i32,
+    len: i32,
+) -> Result<Vec<i32>, Trap> {
+    match memory.read_bytes(addr, len.saturating_mul(4)) {
+        Ok((_, buf)) => Ok(buf.chunks_exact(4).map(LittleEndian::read_i32).collect()),
+        _ => Err(Trap::new("error reading handles")),
+    }
+}
+
+fn pending_req_select(
+    handler: Handler,
+    store: &Store,
+) -> Func {
+    Func::wrap(
+        store,
+        move |caller: Caller<'_>,
+              handles_addr: i32,
+              handles_len: i32,
+              done_index_out: i32,
+              resp_handle_out: ResponseHandle,
+              resp_body_handle_out: BodyHandle| {
+            let _trace = hostcall!(handler, "fastly_http_req::pending_req_select handles_addr={} handles_len={} done_index_out={} resp_handle_out={} resp_body_handle_out={}", handles_addr, handles_len, done_index_out, resp_handle_out, resp_body_handle_out);
+            let mut memory = memory!(caller);
+            let handles = read_handles(&mut memory, handles_addr, handles_len)?;
+            // every pending request is complete, so the first which hasn't been taken is selected.
+            // When all have been, the first is, to answer the guest as taking it would
+            let live = handles.iter().position(|handle| {
+                matches!(
+                    handler.inner.borrow().pending.get(*handle as usize),
+                    Some(Some(_))
+                )
+            });
+            let index = match (live, handles.is_empty()) {
+                (Some(index), _) => index,
+                (_, false) => 0,
+                _ => return Ok(FastlyStatus::INVAL.code),
+            };
+            let response = take_pending(
+                &handler,
+                "fastly_http_req::pending_req_select",
+                handles[index],
+            )?;
+            memory
+                .write_i32(done_index_out, index as i32)
+                .map_err(trap)?;
+            write_response(
+                &handler,
+                &mut memory,
+                response,
+                resp_handle_out,
+                resp_body_handle_out,
+            )?;
 
             Ok(FastlyStatus::OK.code)
         },
@@ -514,10 +1070,15 @@ fn uri_set(
     Func::wrap(
         store,
         move |caller: Caller<'_>, rhandle: RequestHandle, addr: i32, size: i32| {
-            debug!(
+            let _trace = hostcall!(
+                handler,
                 "fastly_http_req::uri_set rhandle={} addr={} size={}",
-                rhandle, addr, size
+                rhandle,
+                addr,
+                size
             );
+            handler.check_handle("fastly_http_req::uri_set", HandleKind::Request, rhandle)?;
+            let max_uri_length = handler.inner.borrow().settings.max_uri_length;
             match handler
                 .inner
                 .borrow_mut()
@@ -529,8 +1090,19 @@ fn uri_set(
                         Ok(result) => result,
                         _ => return Err(Trap::new("failed to read request uri")),
                     };
-                    req.uri = Uri::from_maybe_shared(buf)
+                    if let Some(max) = max_uri_length {
+                        if buf.len() > max {
+                            debug!(
+                                "fastly_http_req::uri_set uri of {} bytes exceeds max_uri_length {}",
+                                buf.len(),
+                                max
+                            );
+                            return Ok(FastlyStatus::INVAL.code);
+                        }
+                    }
+                    req.uri = Uri::from_maybe_shared(buf.clone())
                         .map_err(|_| Trap::i32_exit(FastlyStatus::HTTPPARSE.code))?;
+                    req.extensions.insert(RawUri(buf));
                 }
                 _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
             }
@@ -540,13 +1112,16 @@ fn uri_set(
 }
 
 fn cache_override_set(
-    _handler: Handler,
+    handler: Handler,
     store: &Store,
 ) -> Func {
     Func::wrap(store, move |tag: i32, ttl: i32, swr: i32| {
-        debug!(
+        let _trace = hostcall!(
+            handler,
             "fastly_http_req::cache_override_set tag={} ttl={} swr={}",
-            tag, ttl, swr
+            tag,
+            ttl,
+            swr
         );
         // noop
         FastlyStatus::OK.code
@@ -554,29 +1129,57 @@ fn cache_override_set(
 }
 
 fn cache_override_v2_set(
-    _handler: Handler,
+    handler: Handler,
     store: &Store,
 ) -> Func {
     Func::wrap(
         store,
-        move |_caller: Caller<'_>,
-              handle_out: RequestHandle,
+        move |caller: Caller<'_>,
+              handle: RequestHandle,
               tag: u32,
               ttl: u32,
               swr: u32,
               sk: i32, // see fastly-sys types
               sk_len: i32| {
-            debug!(
-                "fastly_http_req::cache_override_v2_set handle_out={} tag={} ttl={} swr={} sk={} sk_len={}",
-                handle_out,
+            let _trace = hostcall!(
+                handler,
+                "fastly_http_req::cache_override_v2_set handle={} tag={} ttl={} swr={} sk={} sk_len={}",
+                handle,
                 tag,
                 ttl,
                 swr,
                 sk,
                 sk_len
             );
-            // noop
-            FastlyStatus::OK.code
+            handler.check_handle(
+                "fastly_http_req::cache_override_v2_set",
+                HandleKind::Request,
+                handle,
+            )?;
+            match handler.inner.borrow_mut().requests.get_mut(handle as usize) {
+                Some(req) => {
+                    req.extensions.insert(CacheOverride::new(tag, ttl));
+                }
+                _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
+            }
+            // surrogate keys are kept with the request for inspection and purging
+            if sk_len > 0 {
+                let key = match memory!(caller).read_bytes(sk, sk_len) {
+                    Ok((_, bytes)) => String::from_utf8_lossy(&bytes).into_owned(),
+                    _ => return Err(Trap::new("failed to read surrogate key")),
+                };
+                debug!(
+                    "fastly_http_req::cache_override_v2_set surrogate_key={}",
+                    key
+                );
+                match handler.inner.borrow_mut().requests.get_mut(handle as usize) {
+                    Some(req) => {
+                        req.extensions.insert(SurrogateKey(key));
+                    }
+                    _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
+                }
+            }
+            Ok(FastlyStatus::OK.code)
         },
     )
 }
@@ -590,11 +1193,16 @@ fn header_names_get(
         move |caller: Caller<'_>,
               handle: RequestHandle,
               addr: i32,
-              _maxlen: i32,
+              maxlen: i32,
               cursor: i32,
               ending_cursor_out: i32,
               nwritten_out: i32| {
-            debug!("fastly_http_req::header_names_get");
+            let _trace = hostcall!(handler, "fastly_http_req::header_names_get");
+            handler.check_handle(
+                "fastly_http_req::header_names_get",
+                HandleKind::Request,
+                handle,
+            )?;
             match handler.inner.borrow().requests.get(handle as usize) {
                 Some(req) => {
                     let mut names: Vec<_> = req.headers.keys().map(HeaderName::as_str).collect();
@@ -603,22 +1211,29 @@ fn header_names_get(
                     let ucursor = cursor as usize;
                     match names.get(ucursor) {
                         Some(hdr) => {
-                            let mut bytes = hdr.as_bytes().to_vec();
-                            bytes.push(0); // api requires a terminating \x00 byte
-                            let written = memory.write_bytes(addr, &bytes).unwrap();
-                            memory.write_i32(nwritten_out, written as i32);
-                            memory.write_i32(
-                                ending_cursor_out,
-                                if ucursor < names.len() - 1 {
-                                    cursor + 1_i32
-                                } else {
-                                    -1_i32
-                                },
-                            );
+                            if let Some(status) = write_nul_terminated(
+                                &mut memory,
+                                addr,
+                                maxlen,
+                                hdr.as_bytes(),
+                                nwritten_out,
+                            )? {
+                                return Ok(status);
+                            }
+                            memory
+                                .write_i32(
+                                    ending_cursor_out,
+                                    if ucursor < names.len() - 1 {
+                                        cursor + 1_i32
+                                    } else {
+                                        -1_i32
+                                    },
+                                )
+                                .map_err(trap)?;
                         }
                         _ => {
-                            memory.write_i32(nwritten_out, 0);
-                            memory.write_i32(ending_cursor_out, -1);
+                            memory.write_i32(nwritten_out, 0).map_err(trap)?;
+                            memory.write_i32(ending_cursor_out, -1).map_err(trap)?;
                             return Ok(FastlyStatus::OK.code);
                         }
                     }
@@ -641,11 +1256,16 @@ fn header_values_get(
               name_addr: i32,
               name_size: i32,
               addr: i32,
-              _maxlen: i32,
+              maxlen: i32,
               cursor: i32,
               ending_cursor_out: i32,
               nwritten_out: i32| {
-            debug!("fastly_http_req::header_values_get");
+            let _trace = hostcall!(handler, "fastly_http_req::header_values_get");
+            handler.check_handle(
+                "fastly_http_req::header_values_get",
+                HandleKind::Request,
+                handle,
+            )?;
             match handler.inner.borrow_mut().requests.get_mut(handle as usize) {
                 Some(req) => {
                     let mut memory = memory!(caller);
@@ -666,22 +1286,25 @@ fn header_values_get(
                     let ucursor = cursor as usize;
                     match values.get(ucursor) {
                         Some(val) => {
-                            let mut bytes = val.to_vec();
-                            bytes.push(0); // api requires a terminating \x00 byte
-                            let written = memory.write_bytes(addr, &bytes).unwrap();
-                            memory.write_i32(nwritten_out, written as i32);
-                            memory.write_i32(
-                                ending_cursor_out,
-                                if ucursor < values.len() - 1 {
-                                    cursor + 1_i32
-                                } else {
-                                    -1_i32
-                                },
-                            );
+                            if let Some(status) =
+                                write_nul_terminated(&mut memory, addr, maxlen, val, nwritten_out)?
+                            {
+                                return Ok(status);
+                            }
+                            memory
+                                .write_i32(
+                                    ending_cursor_out,
+                                    if ucursor < values.len() - 1 {
+                                        cursor + 1_i32
+                                    } else {
+                                        -1_i32
+                                    },
+                                )
+                                .map_err(trap)?;
                         }
                         _ => {
-                            memory.write_i32(nwritten_out, 0);
-                            memory.write_i32(ending_cursor_out, -1);
+                            memory.write_i32(nwritten_out, 0).map_err(trap)?;
+                            memory.write_i32(ending_cursor_out, -1).map_err(trap)?;
                             return Ok(FastlyStatus::OK.code);
                         }
                     }
@@ -706,7 +1329,12 @@ fn header_values_set(
               name_size: i32,
               values_addr: i32,
               values_size: i32| {
-            debug!("fastly_http_req::header_values_set handle={}, name_addr={} name_size={} values_addr={} values_size={}", handle, name_addr, name_size, values_addr, values_size);
+            let _trace = hostcall!(handler, "fastly_http_req::header_values_set handle={}, name_addr={} name_size={} values_addr={} values_size={}", handle, name_addr, name_size, values_addr, values_size);
+            handler.check_handle(
+                "fastly_http_req::header_values_set",
+                HandleKind::Request,
+                handle,
+            )?;
             match handler.inner.borrow_mut().requests.get_mut(handle as usize) {
                 Some(req) => {
                     let mut memory = memory!(caller);
@@ -752,14 +1380,17 @@ fn version_get(
     Func::wrap(
         store,
         move |caller: Caller<'_>, handle: RequestHandle, version_out: i32| {
-            debug!(
+            let _trace = hostcall!(
+                handler,
                 "fastly_http_req::version_get handle={} version_out={}",
-                handle, version_out
+                handle,
+                version_out
             );
+            handler.check_handle("fastly_http_req::version_get", HandleKind::Request, handle)?;
             match handler.inner.borrow().requests.get(handle as usize) {
-                Some(req) => {
-                    memory!(caller).write_u32(version_out, HttpVersion::from(req.version).as_u32())
-                }
+                Some(req) => memory!(caller)
+                    .write_u32(version_out, HttpVersion::from(req.version).as_u32())
+                    .map_err(trap)?,
                 _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
             }
             Ok(FastlyStatus::OK.code)
@@ -772,16 +1403,21 @@ fn version_set(
     store: &Store,
 ) -> Func {
     Func::wrap(&store, move |handle: RequestHandle, version: i32| {
-        debug!(
+        let _trace = hostcall!(
+            handler,
             "fastly_http_req::version_set handle={} version={}",
-            handle, version
+            handle,
+            version
         );
+        handler.check_handle("fastly_http_req::version_set", HandleKind::Request, handle)?;
         match handler.inner.borrow_mut().requests.get_mut(handle as usize) {
-            Some(req) => {
-                req.version = HttpVersion::try_from(version as u32)
-                    .expect("invalid version")
-                    .into();
-            }
+            Some(req) => match HttpVersion::try_from(version as u32) {
+                Ok(version) => req.version = version.into(),
+                _ => {
+                    debug!("invalid http version {}", version);
+                    return Ok(FastlyStatus::INVAL.code);
+                }
+            },
             _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
         }
 
@@ -792,10 +1428,1024 @@ fn version_set(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::tests::{body, WASM};
-    use hyper::Response;
+    use crate::{
+        handler::Settings,
+        tests::{body, guest, RecordingBackend, WASM},
+    };
+    use hyper::{Response, StatusCode};
     use std::collections::HashMap;
 
+    fn uri_round_trip(uri: &str) -> Result<Response<Body>, BoxError> {
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "new" (func $req_new (param i32) (result i32)))
+  (import "fastly_http_req" "uri_set" (func $uri_set (param i32 i32 i32) (result i32)))
+  (import "fastly_http_req" "uri_get" (func $uri_get (param i32 i32 i32 i32) (result i32)))"#,
+            &format!(
+                r#"(data (i32.const 64) "{}")
+  (func (export "_start")
+    (drop (call $req_new (i32.const 16)))
+    (drop (call $uri_set (i32.load (i32.const 16)) (i32.const 64) (i32.const {})))
+    (drop (call $uri_get (i32.load (i32.const 16)) (i32.const 1024) (i32.const 1024) (i32.const 20)))
+    (call $respond (i32.const 200) (i32.const 1024) (i32.load (i32.const 20))))"#,
+                uri,
+                uri.len()
+            ),
+        )?;
+        Handler::new(Request::default()).run(
+            &module,
+            Store::new(&engine),
+            crate::backend::default(),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )
+    }
+
+    const LOOPBACK_IMPORTS: &str = r#"(import "fastly_http_req" "new" (func $req_new (param i32) (result i32)))
+  (import "fastly_http_req" "original_header_count" (func $original_header_count (param i32) (result i32)))
+  (import "fastly_http_req" "send" (func $send (param i32 i32 i32 i32 i32 i32) (result i32)))"#;
+
+    #[tokio::test]
+    async fn send_loops_back_to_self() -> Result<(), BoxError> {
+        // only the downstream request has headers, so the loopback request responds directly
+        let (engine, module) = guest(
+            LOOPBACK_IMPORTS,
+            r#"(data (i32.const 64) "self")
+  (func (export "_start")
+    (drop (call $original_header_count (i32.const 16)))
+    (if (i32.gt_s (i32.load (i32.const 16)) (i32.const 0))
+      (then
+        (drop (call $req_new (i32.const 20)))
+        (drop (call $body_new (i32.const 24)))
+        (drop (call $send (i32.load (i32.const 20)) (i32.load (i32.const 24)) (i32.const 64) (i32.const 4) (i32.const 28) (i32.const 32)))
+        (drop (call $resp_send_downstream (i32.load (i32.const 28)) (i32.load (i32.const 32)) (i32.const 0))))
+      (else
+        (call $respond (i32.const 201) (i32.const 64) (i32.const 4)))))"#,
+        )?;
+        let resp = Handler::new(Request::get("/").header("foo", "bar").body(Body::empty())?).run(
+            &module,
+            Store::new(&engine),
+            crate::backend::default(),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        assert_eq!(resp.status().as_u16(), 201);
+        assert_eq!("self", body(resp).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn send_limits_loopback_depth() -> Result<(), BoxError> {
+        let (engine, module) = guest(
+            LOOPBACK_IMPORTS,
+            r#"(data (i32.const 64) "self")
+  (func (export "_start")
+    (drop (call $req_new (i32.const 20)))
+    (drop (call $body_new (i32.const 24)))
+    (drop (call $send (i32.load (i32.const 20)) (i32.load (i32.const 24)) (i32.const 64) (i32.const 4) (i32.const 28) (i32.const 32)))
+    (drop (call $resp_send_downstream (i32.load (i32.const 28)) (i32.load (i32.const 32)) (i32.const 0))))"#,
+        )?;
+        let resp = Handler::new(Request::default()).run(
+            &module,
+            Store::new(&engine),
+            crate::backend::default(),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        assert_eq!(resp.status().as_u16(), 508);
+        Ok(())
+    }
+
+    #[test]
+    fn version_set_rejects_unknown_versions() -> Result<(), BoxError> {
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "new" (func $req_new (param i32) (result i32)))
+  (import "fastly_http_req" "version_set" (func $version_set (param i32 i32) (result i32)))"#,
+            r#"(func (export "_start")
+    (drop (call $req_new (i32.const 16)))
+    (call $respond
+      (i32.add (i32.const 200) (call $version_set (i32.load (i32.const 16)) (i32.const 99)))
+      (i32.const 0)
+      (i32.const 0)))"#,
+        )?;
+        let resp = Handler::new(Request::default()).run(
+            &module,
+            Store::new(&engine),
+            crate::backend::default(),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        assert_eq!(
+            resp.status().as_u16() as i32,
+            200 + FastlyStatus::INVAL.code
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn guest_request_handles_are_independent_of_the_downstream_request(
+    ) -> Result<(), BoxError> {
+        // creates requests before and after getting the downstream request, setting their methods
+        // to PUT, then responds with the downstream request's method and a status of
+        // 200 + 100 * the first handle + 10 * the second + the third
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "new" (func $req_new (param i32) (result i32)))
+  (import "fastly_http_req" "body_downstream_get" (func $body_downstream_get (param i32 i32) (result i32)))
+  (import "fastly_http_req" "method_set" (func $method_set (param i32 i32 i32) (result i32)))
+  (import "fastly_http_req" "method_get" (func $method_get (param i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "PUT")
+  (func (export "_start")
+    (drop (call $req_new (i32.const 16)))
+    (drop (call $body_downstream_get (i32.const 20) (i32.const 24)))
+    (drop (call $req_new (i32.const 28)))
+    (drop (call $req_new (i32.const 32)))
+    (drop (call $method_set (i32.load (i32.const 16)) (i32.const 64) (i32.const 3)))
+    (drop (call $method_set (i32.load (i32.const 28)) (i32.const 64) (i32.const 3)))
+    (drop (call $method_set (i32.load (i32.const 32)) (i32.const 64) (i32.const 3)))
+    (drop (call $method_get (i32.load (i32.const 20)) (i32.const 1024) (i32.const 16) (i32.const 36)))
+    (call $respond
+      (i32.add
+        (i32.const 200)
+        (i32.add
+          (i32.mul (i32.const 100) (i32.load (i32.const 16)))
+          (i32.add
+            (i32.mul (i32.const 10) (i32.load (i32.const 28)))
+            (i32.load (i32.const 32)))))
+      (i32.const 1024)
+      (i32.load (i32.const 36))))"#,
+        )?;
+        let resp = Handler::new(Request::default()).run(
+            &module,
+            Store::new(&engine),
+            crate::backend::default(),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        assert_eq!(resp.status().as_u16(), 323);
+        assert_eq!(body(resp).await?, "GET");
+        Ok(())
+    }
+
+    #[test]
+    fn method_set_rejects_empty_and_invalid_methods() -> Result<(), BoxError> {
+        // responds with a status of 200 + the empty method's status + 10 * the invalid method's status
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "new" (func $req_new (param i32) (result i32)))
+  (import "fastly_http_req" "method_set" (func $method_set (param i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "GE T")
+  (func (export "_start")
+    (drop (call $req_new (i32.const 16)))
+    (call $respond
+      (i32.add
+        (i32.const 200)
+        (i32.add
+          (call $method_set (i32.load (i32.const 16)) (i32.const 64) (i32.const 0))
+          (i32.mul (i32.const 10) (call $method_set (i32.load (i32.const 16)) (i32.const 64) (i32.const 4)))))
+      (i32.const 0)
+      (i32.const 0)))"#,
+        )?;
+        let resp = Handler::new(Request::default()).run(
+            &module,
+            Store::new(&engine),
+            crate::backend::default(),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        assert_eq!(
+            resp.status().as_u16() as i32,
+            200 + FastlyStatus::INVAL.code + 10 * FastlyStatus::HTTPPARSE.code
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn is_token_accepts_only_token_characters() {
+        assert!(is_token(b"GET"));
+        assert!(is_token(b"X-CUSTOM_METHOD"));
+        assert!(!is_token(b""));
+        assert!(!is_token(b"GE T"));
+        assert!(!is_token(b"GET\r\n"));
+        assert!(!is_token("GÉT".as_bytes()));
+    }
+
+    #[tokio::test]
+    async fn header_values_get_counts_terminator_toward_maxlen() -> Result<(), BoxError> {
+        // a 5 byte buffer can't hold "hello" and its terminator, so the guest retries
+        // with the size reported back, which the value then fills exactly
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "body_downstream_get" (func $body_downstream_get (param i32 i32) (result i32)))
+  (import "fastly_http_req" "header_values_get" (func $header_values_get (param i32 i32 i32 i32 i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "x-test")
+  (func (export "_start")
+    (drop (call $body_downstream_get (i32.const 16) (i32.const 20)))
+    (i32.store (i32.const 24)
+      (call $header_values_get (i32.load (i32.const 16)) (i32.const 64) (i32.const 6) (i32.const 1024) (i32.const 5) (i32.const 0) (i32.const 28) (i32.const 32)))
+    (drop (call $header_values_get (i32.load (i32.const 16)) (i32.const 64) (i32.const 6) (i32.const 1024) (i32.load (i32.const 32)) (i32.const 0) (i32.const 28) (i32.const 32)))
+    (call $respond (i32.add (i32.const 200) (i32.load (i32.const 24))) (i32.const 1024) (i32.load (i32.const 32))))"#,
+        )?;
+        let resp = Handler::new(
+            Request::get("/")
+                .header("x-test", "hello")
+                .body(Body::empty())?,
+        )
+        .run(
+            &module,
+            Store::new(&engine),
+            crate::backend::default(),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        assert_eq!(
+            resp.status().as_u16() as i32,
+            200 + FastlyStatus::BUFLEN.code
+        );
+        assert_eq!("hello\0", body(resp).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn original_header_names_get_reports_buflen_without_advancing() -> Result<(), BoxError> {
+        // a 4 byte buffer can't hold the header name, so the cursor must be left untouched
+        // for the guest to retry with the size reported back
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "original_header_names_get" (func $names_get (param i32 i32 i32 i32 i32) (result i32)))"#,
+            r#"(func (export "_start")
+    (i32.store (i32.const 28) (i32.const 99))
+    (i32.store (i32.const 24) (call $names_get (i32.const 1024) (i32.const 4) (i32.const 0) (i32.const 28) (i32.const 32)))
+    (if (i32.ne (i32.load (i32.const 28)) (i32.const 99))
+      (then
+        (call $respond (i32.const 500) (i32.const 0) (i32.const 0))
+        (return)))
+    (drop (call $names_get (i32.const 1024) (i32.load (i32.const 32)) (i32.const 0) (i32.const 28) (i32.const 32)))
+    (call $respond (i32.add (i32.const 200) (i32.load (i32.const 24))) (i32.const 1024) (i32.load (i32.const 32))))"#,
+        )?;
+        let resp = Handler::new(
+            Request::get("/")
+                .header("x-long-header-name", "value")
+                .body(Body::empty())?,
+        )
+        .run(
+            &module,
+            Store::new(&engine),
+            crate::backend::default(),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        assert_eq!(
+            resp.status().as_u16() as i32,
+            200 + FastlyStatus::BUFLEN.code
+        );
+        assert_eq!("x-long-header-name\0", body(resp).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn header_names_get_reports_buflen_without_advancing() -> Result<(), BoxError> {
+        // enumerates "a", which fits an 8 byte buffer, then "x-long-header-name", which doesn't,
+        // so the cursor must be left untouched for the guest to retry with the size reported back
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "body_downstream_get" (func $body_downstream_get (param i32 i32) (result i32)))
+  (import "fastly_http_req" "header_names_get" (func $names_get (param i32 i32 i32 i32 i32 i32) (result i32)))"#,
+            r#"(func (export "_start") (local $len i32)
+    (drop (call $body_downstream_get (i32.const 16) (i32.const 20)))
+    (drop (call $names_get (i32.load (i32.const 16)) (i32.const 1024) (i32.const 8) (i32.const 0) (i32.const 28) (i32.const 32)))
+    (local.set $len (i32.load (i32.const 32)))
+    (i32.store (i32.const 36) (i32.load (i32.const 28)))
+    (i32.store (i32.const 28) (i32.const 99))
+    (i32.store (i32.const 24)
+      (call $names_get (i32.load (i32.const 16)) (i32.add (i32.const 1024) (local.get $len)) (i32.const 8) (i32.load (i32.const 36)) (i32.const 28) (i32.const 32)))
+    (if (i32.ne (i32.load (i32.const 28)) (i32.const 99))
+      (then
+        (call $respond (i32.const 500) (i32.const 0) (i32.const 0))
+        (return)))
+    (drop (call $names_get (i32.load (i32.const 16)) (i32.add (i32.const 1024) (local.get $len)) (i32.load (i32.const 32)) (i32.load (i32.const 36)) (i32.const 28) (i32.const 32)))
+    (call $respond (i32.add (i32.const 200) (i32.load (i32.const 24))) (i32.const 1024) (i32.add (local.get $len) (i32.load (i32.const 32)))))"#,
+        )?;
+        let resp = Handler::new(
+            Request::get("/")
+                .header("a", "value")
+                .header("x-long-header-name", "value")
+                .body(Body::empty())?,
+        )
+        .run(
+            &module,
+            Store::new(&engine),
+            crate::backend::default(),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        assert_eq!(
+            resp.status().as_u16() as i32,
+            200 + FastlyStatus::BUFLEN.code
+        );
+        assert_eq!("a\0x-long-header-name\0", body(resp).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn body_downstream_get_decompresses_gzip() -> Result<(), BoxError> {
+        // echos the downstream request body, responding with a status of 200 + its content-encoding header count
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "body_downstream_get" (func $body_downstream_get (param i32 i32) (result i32)))
+  (import "fastly_http_req" "header_values_get" (func $header_values_get (param i32 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+  (import "fastly_http_body" "read" (func $body_read (param i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "content-encoding")
+  (func (export "_start") (local $len i32)
+    (drop (call $body_downstream_get (i32.const 16) (i32.const 20)))
+    (drop (call $header_values_get (i32.load (i32.const 16)) (i32.const 64) (i32.const 16) (i32.const 512) (i32.const 256) (i32.const 0) (i32.const 28) (i32.const 32)))
+    (block $done
+      (loop $read
+        (drop (call $body_read (i32.load (i32.const 20)) (i32.add (i32.const 1024) (local.get $len)) (i32.const 1024) (i32.const 24)))
+        (br_if $done (i32.eqz (i32.load (i32.const 24))))
+        (local.set $len (i32.add (local.get $len) (i32.load (i32.const 24))))
+        (br $read)))
+    (call $respond (i32.add (i32.const 200) (i32.ne (i32.load (i32.const 32)) (i32.const 0))) (i32.const 1024) (local.get $len)))"#,
+        )?;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        io::Write::write_all(&mut encoder, b"hello compressed world")?;
+        let compressed = encoder.finish()?;
+        let run = |decompress_request| {
+            Handler::new(
+                Request::post("/")
+                    .header(CONTENT_ENCODING, "gzip")
+                    .body(Body::from(compressed.clone()))
+                    .expect("invalid request"),
+            )
+            .with_settings(Settings {
+                decompress_request,
+                ..Default::default()
+            })
+            .run(
+                &module,
+                Store::new(&engine),
+                crate::backend::default(),
+                HashMap::default(),
+                "127.0.0.1".parse().ok(),
+            )
+        };
+
+        let resp = run(true)?;
+        assert_eq!(resp.status().as_u16(), 200);
+        assert_eq!("hello compressed world", body(resp).await?);
+
+        let resp = run(false)?;
+        assert_eq!(resp.status().as_u16(), 201);
+        assert_eq!(
+            hyper::body::to_bytes(resp.into_body()).await?,
+            compressed.as_slice()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn cache_override_v2_set_captures_surrogate_keys() -> Result<(), BoxError> {
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "new" (func $req_new (param i32) (result i32)))
+  (import "fastly_http_req" "cache_override_v2_set" (func $cache_override_v2_set (param i32 i32 i32 i32 i32 i32) (result i32)))
+  (import "fastly_http_req" "send" (func $send (param i32 i32 i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "origin")
+  (data (i32.const 80) "key-a key-b")
+  (func (export "_start")
+    (drop (call $req_new (i32.const 16)))
+    (drop (call $body_new (i32.const 20)))
+    (drop (call $cache_override_v2_set (i32.load (i32.const 16)) (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 80) (i32.const 11)))
+    (drop (call $send (i32.load (i32.const 16)) (i32.load (i32.const 20)) (i32.const 64) (i32.const 6) (i32.const 24) (i32.const 28)))
+    (drop (call $resp_send_downstream (i32.load (i32.const 24)) (i32.load (i32.const 28)) (i32.const 0))))"#,
+        )?;
+        let backend = RecordingBackend::default();
+        Handler::new(Request::default()).run(
+            &module,
+            Store::new(&engine),
+            Box::new(backend.clone()),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        let requests = backend.requests();
+        let (name, parts, _) = &requests[0];
+        assert_eq!(name, "origin");
+        assert_eq!(
+            parts.extensions.get::<SurrogateKey>(),
+            Some(&SurrogateKey("key-a key-b".into()))
+        );
+        Ok(())
+    }
+
+    /// Sends a request from 127.0.0.1 to the backend named `geolocation`, responding with its response
+    fn send_to_geolocation(
+        settings: Settings,
+        backend: RecordingBackend,
+    ) -> Result<Response<Body>, BoxError> {
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "new" (func $req_new (param i32) (result i32)))
+  (import "fastly_http_req" "header_values_set" (func $header_values_set (param i32 i32 i32 i32 i32) (result i32)))
+  (import "fastly_http_req" "send" (func $send (param i32 i32 i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "geolocation")
+  (data (i32.const 80) "Fastly-XQD-arg1")
+  (data (i32.const 96) "127.0.0.1\00")
+  (func (export "_start")
+    (drop (call $req_new (i32.const 16)))
+    (drop (call $header_values_set (i32.load (i32.const 16)) (i32.const 80) (i32.const 15) (i32.const 96) (i32.const 10)))
+    (drop (call $body_new (i32.const 20)))
+    (drop (call $send (i32.load (i32.const 16)) (i32.load (i32.const 20)) (i32.const 64) (i32.const 11) (i32.const 24) (i32.const 28)))
+    (drop (call $resp_send_downstream (i32.load (i32.const 24)) (i32.load (i32.const 28)) (i32.const 0))))"#,
+        )?;
+        Handler::new(Request::default())
+            .with_settings(settings)
+            .run(
+                &module,
+                Store::new(&engine),
+                Box::new(backend),
+                HashMap::default(),
+                "127.0.0.1".parse().ok(),
+            )
+    }
+
+    #[tokio::test]
+    async fn send_responses_are_inspected_before_their_bodies_are_read() -> Result<(), BoxError> {
+        // responds with the status, header names, x-origin value, and body of a backend response
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "new" (func $req_new (param i32) (result i32)))
+  (import "fastly_http_req" "send" (func $send (param i32 i32 i32 i32 i32 i32) (result i32)))
+  (import "fastly_http_resp" "status_get" (func $resp_status_get (param i32 i32) (result i32)))
+  (import "fastly_http_resp" "header_names_get" (func $resp_header_names_get (param i32 i32 i32 i32 i32 i32) (result i32)))
+  (import "fastly_http_resp" "header_values_get" (func $resp_header_values_get (param i32 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+  (import "fastly_http_body" "read" (func $body_read (param i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "origin")
+  (data (i32.const 80) "x-origin")
+  (func (export "_start") (local $len i32)
+    (drop (call $req_new (i32.const 16)))
+    (drop (call $body_new (i32.const 20)))
+    (drop (call $send (i32.load (i32.const 16)) (i32.load (i32.const 20)) (i32.const 64) (i32.const 6) (i32.const 24) (i32.const 28)))
+    (drop (call $resp_status_get (i32.load (i32.const 24)) (i32.const 32)))
+    (drop (call $resp_header_names_get (i32.load (i32.const 24)) (i32.const 1024) (i32.const 64) (i32.const 0) (i32.const 36) (i32.const 40)))
+    (local.set $len (i32.load (i32.const 40)))
+    (drop (call $resp_header_names_get (i32.load (i32.const 24)) (i32.add (i32.const 1024) (local.get $len)) (i32.const 64) (i32.load (i32.const 36)) (i32.const 36) (i32.const 40)))
+    (local.set $len (i32.add (local.get $len) (i32.load (i32.const 40))))
+    (drop (call $resp_header_values_get (i32.load (i32.const 24)) (i32.const 80) (i32.const 8) (i32.add (i32.const 1024) (local.get $len)) (i32.const 64) (i32.const 0) (i32.const 44) (i32.const 40)))
+    (local.set $len (i32.add (local.get $len) (i32.load (i32.const 40))))
+    (drop (call $body_read (i32.load (i32.const 28)) (i32.add (i32.const 1024) (local.get $len)) (i32.const 64) (i32.const 40)))
+    (local.set $len (i32.add (local.get $len) (i32.load (i32.const 40))))
+    (call $respond (i32.load (i32.const 32)) (i32.const 1024) (local.get $len)))"#,
+        )?;
+        let backend = RecordingBackend::new(StatusCode::IM_A_TEAPOT, "hello")
+            .with_header("x-origin", "edge")
+            .with_header("x-cache", "MISS");
+        let resp = Handler::new(Request::default()).run(
+            &module,
+            Store::new(&engine),
+            Box::new(backend),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        assert_eq!(resp.status(), StatusCode::IM_A_TEAPOT);
+        assert_eq!(body(resp).await?, "x-cache\0x-origin\0edge\0hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_answers_geolocation_lookups() -> Result<(), BoxError> {
+        let backend = RecordingBackend::default();
+        let resp = send_to_geolocation(Settings::default(), backend.clone())?;
+        assert_eq!(
+            body(resp).await?,
+            serde_json::to_string(&geo::Geo::default())?
+        );
+        assert!(backend.requests().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn send_fails_geolocation_lookups_of_malformed_addresses() -> Result<(), BoxError> {
+        // responds with a status of 200 + the status send returned
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "new" (func $req_new (param i32) (result i32)))
+  (import "fastly_http_req" "header_values_set" (func $header_values_set (param i32 i32 i32 i32 i32) (result i32)))
+  (import "fastly_http_req" "send" (func $send (param i32 i32 i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "geolocation")
+  (data (i32.const 80) "Fastly-XQD-arg1")
+  (data (i32.const 96) "not-an-ip\00")
+  (func (export "_start")
+    (drop (call $req_new (i32.const 16)))
+    (drop (call $header_values_set (i32.load (i32.const 16)) (i32.const 80) (i32.const 15) (i32.const 96) (i32.const 10)))
+    (drop (call $body_new (i32.const 20)))
+    (call $respond
+      (i32.add (i32.const 200) (call $send (i32.load (i32.const 16)) (i32.load (i32.const 20)) (i32.const 64) (i32.const 11) (i32.const 24) (i32.const 28)))
+      (i32.const 0) (i32.const 0)))"#,
+        )?;
+        let backend = RecordingBackend::default();
+        let resp = Handler::new(Request::default()).run(
+            &module,
+            Store::new(&engine),
+            Box::new(backend.clone()),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        assert_eq!(
+            resp.status().as_u16(),
+            200 + FastlyStatus::ERROR.code as u16
+        );
+        assert!(backend.requests().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn failed_sends_answer_with_an_error() -> Result<(), BoxError> {
+        // responds with 200 plus the status send answered with
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "new" (func $req_new (param i32) (result i32)))
+  (import "fastly_http_req" "send" (func $send (param i32 i32 i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "origin")
+  (func (export "_start")
+    (drop (call $req_new (i32.const 16)))
+    (drop (call $body_new (i32.const 20)))
+    (call $respond
+      (i32.add (i32.const 200) (call $send (i32.load (i32.const 16)) (i32.load (i32.const 20)) (i32.const 64) (i32.const 6) (i32.const 24) (i32.const 28)))
+      (i32.const 0) (i32.const 0)))"#,
+        )?;
+        for cache in [None, Some(Cache::default())].iter().cloned() {
+            let resp = Handler::new(Request::default())
+                .with_settings(Settings {
+                    cache,
+                    ..Settings::default()
+                })
+                .run(
+                    &module,
+                    Store::new(&engine),
+                    Box::new(
+                        |_: &str, _: Request<Body>| -> Result<Response<Body>, BoxError> {
+                            Err("connection refused".into())
+                        },
+                    ),
+                    HashMap::default(),
+                    "127.0.0.1".parse().ok(),
+                )?;
+            assert_eq!(
+                resp.status().as_u16(),
+                200 + FastlyStatus::ERROR.code as u16
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_answers_geolocation_lookups_in_the_selected_abi() -> Result<(), BoxError> {
+        let resp = send_to_geolocation(
+            Settings {
+                geo_abi: geo::GeoAbi::V2,
+                ..Settings::default()
+            },
+            RecordingBackend::default(),
+        )?;
+        // the fields of fastly's geo struct after 0.5, which reads the offset from gmt_offset
+        let expected = serde_json::json!({
+            "as_name": "AS22252",
+            "as_number": 22252,
+            "area_code": 10026,
+            "city": "New York",
+            "conn_speed": "satellite",
+            "conn_type": "satellite",
+            "continent": "NA",
+            "country_code": "US",
+            "country_code3": "USA",
+            "country_name": "United States",
+            "gmt_offset": -500,
+            "latitude": 40.6987,
+            "longitude": -73.9859,
+            "metro_code": 0,
+            "postal_code": "11201",
+            "proxy_description": "cloud",
+            "proxy_type": "public",
+            "utc_offset": -500
+        });
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body(resp).await?)?,
+            expected
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_answers_geolocation_lookups_with_a_provided_lookup() -> Result<(), BoxError> {
+        // answers loopback clients from one city and everyone else from another
+        let lookup = |ip: IpAddr| geo::Geo {
+            city: if ip.is_loopback() {
+                "Localhost"
+            } else {
+                "Elsewhere"
+            }
+            .into(),
+            ..geo::Geo::default()
+        };
+        let resp = send_to_geolocation(
+            Settings {
+                geo_lookup: Some(Arc::new(lookup)),
+                ..Settings::default()
+            },
+            RecordingBackend::default(),
+        )?;
+        let geo: serde_json::Value = serde_json::from_str(&body(resp).await?)?;
+        assert_eq!(geo["city"], "Localhost");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_proxies_geolocation_backend_when_disabled() -> Result<(), BoxError> {
+        let backend = RecordingBackend::new(StatusCode::OK, "origin");
+        let resp = send_to_geolocation(
+            Settings {
+                disable_geolocation: true,
+                ..Settings::default()
+            },
+            backend.clone(),
+        )?;
+        assert_eq!(body(resp).await?, "origin");
+        assert_eq!(backend.requests()[0].0, "geolocation");
+        Ok(())
+    }
+
+    #[test]
+    fn send_dumps_backend_requests() -> Result<(), BoxError> {
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "new" (func $req_new (param i32) (result i32)))
+  (import "fastly_http_req" "header_values_set" (func $header_values_set (param i32 i32 i32 i32 i32) (result i32)))
+  (import "fastly_http_req" "send" (func $send (param i32 i32 i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "origin")
+  (data (i32.const 80) "x-test")
+  (data (i32.const 96) "yes\00")
+  (data (i32.const 112) "hello")
+  (func (export "_start")
+    (drop (call $req_new (i32.const 16)))
+    (drop (call $header_values_set (i32.load (i32.const 16)) (i32.const 80) (i32.const 6) (i32.const 96) (i32.const 4)))
+    (drop (call $body_new (i32.const 20)))
+    (drop (call $body_write (i32.load (i32.const 20)) (i32.const 112) (i32.const 5) (i32.const 0) (i32.const 32)))
+    (drop (call $send (i32.load (i32.const 16)) (i32.load (i32.const 20)) (i32.const 64) (i32.const 6) (i32.const 24) (i32.const 28)))
+    (drop (call $resp_send_downstream (i32.load (i32.const 24)) (i32.load (i32.const 28)) (i32.const 0))))"#,
+        )?;
+        let dir = std::env::temp_dir().join(format!(
+            "fasttime-dump-backend-requests-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir)?;
+        Handler::new(Request::default())
+            .with_settings(Settings {
+                dump_backend_requests: Some(dir.clone()),
+                ..Settings::default()
+            })
+            .run(
+                &module,
+                Store::new(&engine),
+                Box::new(RecordingBackend::default()),
+                HashMap::default(),
+                "127.0.0.1".parse().ok(),
+            )?;
+        let dumps = std::fs::read_dir(&dir)?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(dumps.len(), 1);
+        assert!(dumps[0]
+            .file_name()
+            .to_string_lossy()
+            .ends_with("-origin.request"));
+        let dump = std::fs::read_to_string(dumps[0].path())?;
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(dump, "GET / HTTP/1.1\r\nx-test: yes\r\n\r\nhello");
+        Ok(())
+    }
+
+    #[test]
+    fn send_stops_at_max_backend_requests() -> Result<(), BoxError> {
+        // sends up to 1000 requests, responding with a status of 200 + the number which succeeded
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "new" (func $req_new (param i32) (result i32)))
+  (import "fastly_http_req" "send" (func $send (param i32 i32 i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "origin")
+  (func (export "_start") (local $i i32) (local $sent i32)
+    (block $done
+      (loop $send
+        (br_if $done (i32.eq (local.get $i) (i32.const 1000)))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (drop (call $req_new (i32.const 16)))
+        (drop (call $body_new (i32.const 20)))
+        (if (i32.eqz (call $send (i32.load (i32.const 16)) (i32.load (i32.const 20)) (i32.const 64) (i32.const 6) (i32.const 24) (i32.const 28)))
+          (then (local.set $sent (i32.add (local.get $sent) (i32.const 1)))))
+        (br $send)))
+    (call $respond (i32.add (i32.const 200) (local.get $sent)) (i32.const 0) (i32.const 0)))"#,
+        )?;
+        let backend = RecordingBackend::default();
+        let resp = Handler::new(Request::default())
+            .with_settings(Settings {
+                max_backend_requests: Some(3),
+                ..Settings::default()
+            })
+            .run(
+                &module,
+                Store::new(&engine),
+                Box::new(backend.clone()),
+                HashMap::default(),
+                "127.0.0.1".parse().ok(),
+            )?;
+        assert_eq!(resp.status().as_u16(), 203);
+        assert_eq!(backend.requests().len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn send_caches_responses_with_overridden_ttls() -> Result<(), BoxError> {
+        // sends the same request, with a 60 second ttl, twice
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "new" (func $req_new (param i32) (result i32)))
+  (import "fastly_http_req" "cache_override_v2_set" (func $cache_override_v2_set (param i32 i32 i32 i32 i32 i32) (result i32)))
+  (import "fastly_http_req" "send" (func $send (param i32 i32 i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "origin")
+  (data (i32.const 80) "key-a")
+  (func $send_cached
+    (drop (call $req_new (i32.const 16)))
+    (drop (call $body_new (i32.const 20)))
+    (drop (call $cache_override_v2_set (i32.load (i32.const 16)) (i32.const 2) (i32.const 60) (i32.const 0) (i32.const 80) (i32.const 5)))
+    (drop (call $send (i32.load (i32.const 16)) (i32.load (i32.const 20)) (i32.const 64) (i32.const 6) (i32.const 24) (i32.const 28))))
+  (func (export "_start")
+    (call $send_cached)
+    (call $send_cached)
+    (call $respond (i32.const 200) (i32.const 0) (i32.const 0)))"#,
+        )?;
+        let backend = RecordingBackend::default();
+        let cache = Cache::default();
+        Handler::new(Request::default())
+            .with_settings(Settings {
+                cache: Some(cache.clone()),
+                ..Settings::default()
+            })
+            .run(
+                &module,
+                Store::new(&engine),
+                Box::new(backend.clone()),
+                HashMap::default(),
+                "127.0.0.1".parse().ok(),
+            )?;
+        assert_eq!(backend.requests().len(), 1);
+        let entries = cache.list();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].surrogate_keys, vec!["key-a"]);
+        assert!(entries[0].ttl_remaining_secs <= 60);
+        Ok(())
+    }
+
+    #[test]
+    fn send_responds_with_seeded_cache_entries() -> Result<(), BoxError> {
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "new" (func $req_new (param i32) (result i32)))
+  (import "fastly_http_req" "send" (func $send (param i32 i32 i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "origin")
+  (func (export "_start")
+    (drop (call $req_new (i32.const 16)))
+    (drop (call $body_new (i32.const 20)))
+    (drop (call $send (i32.load (i32.const 16)) (i32.load (i32.const 20)) (i32.const 64) (i32.const 6) (i32.const 24) (i32.const 28)))
+    (drop (call $resp_send_downstream (i32.load (i32.const 24)) (i32.load (i32.const 28)) (i32.const 0))))"#,
+        )?;
+        let dir = std::env::temp_dir().join(format!("fasttime-seeded-send-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(
+            dir.join("root.json"),
+            r#"{ "backend": "origin", "url": "/", "status": 203, "body": "seeded", "ttl_secs": 60 }"#,
+        )?;
+        let cache = Cache::default();
+        let seeded = cache.seed(&dir);
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(seeded?, 1);
+
+        let backend = RecordingBackend::default();
+        let resp = Handler::new(Request::default())
+            .with_settings(Settings {
+                cache: Some(cache),
+                ..Settings::default()
+            })
+            .run(
+                &module,
+                Store::new(&engine),
+                Box::new(backend.clone()),
+                HashMap::default(),
+                "127.0.0.1".parse().ok(),
+            )?;
+        assert_eq!(resp.status().as_u16(), 203);
+        assert!(backend.requests().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_sends_the_bodies_behind_each_handle() -> Result<(), BoxError> {
+        // writes to two bodies, then sends each with a different request, in the opposite order
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "new" (func $req_new (param i32) (result i32)))
+  (import "fastly_http_req" "send" (func $send (param i32 i32 i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "origin")
+  (data (i32.const 80) "one")
+  (data (i32.const 96) "two")
+  (data (i32.const 112) "three")
+  (func (export "_start")
+    (drop (call $body_new (i32.const 20)))
+    (drop (call $body_new (i32.const 24)))
+    (drop (call $body_write (i32.load (i32.const 20)) (i32.const 80) (i32.const 3) (i32.const 0) (i32.const 28)))
+    (drop (call $body_write (i32.load (i32.const 24)) (i32.const 96) (i32.const 3) (i32.const 0) (i32.const 28)))
+    (drop (call $req_new (i32.const 16)))
+    (drop (call $req_new (i32.const 32)))
+    (drop (call $send (i32.load (i32.const 32)) (i32.load (i32.const 24)) (i32.const 64) (i32.const 6) (i32.const 36) (i32.const 40)))
+    (drop (call $send (i32.load (i32.const 16)) (i32.load (i32.const 20)) (i32.const 64) (i32.const 6) (i32.const 44) (i32.const 48)))
+    (call $respond (i32.const 200) (i32.const 112) (i32.const 5)))"#,
+        )?;
+        let backend = RecordingBackend::default();
+        let resp = Handler::new(Request::default()).run(
+            &module,
+            Store::new(&engine),
+            Box::new(backend.clone()),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        let bodies: Vec<_> = backend
+            .requests()
+            .iter()
+            .map(|(_, _, body)| body.clone())
+            .collect();
+        assert_eq!(bodies, vec!["two", "one"]);
+        // bodies created after others were sent don't alias them
+        assert_eq!(body(resp).await?, "three");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_async_responses_are_waited_on() -> Result<(), BoxError> {
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "new" (func $req_new (param i32) (result i32)))
+  (import "fastly_http_req" "send_async" (func $send_async (param i32 i32 i32 i32 i32) (result i32)))
+  (import "fastly_http_req" "pending_req_wait" (func $pending_req_wait (param i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "origin")
+  (func (export "_start")
+    (drop (call $req_new (i32.const 16)))
+    (drop (call $body_new (i32.const 20)))
+    (drop (call $send_async (i32.load (i32.const 16)) (i32.load (i32.const 20)) (i32.const 64) (i32.const 6) (i32.const 24)))
+    (drop (call $pending_req_wait (i32.load (i32.const 24)) (i32.const 28) (i32.const 32)))
+    (drop (call $resp_send_downstream (i32.load (i32.const 28)) (i32.load (i32.const 32)) (i32.const 0))))"#,
+        )?;
+        let backend = RecordingBackend::new(StatusCode::ACCEPTED, "async");
+        let resp = Handler::new(Request::default()).run(
+            &module,
+            Store::new(&engine),
+            Box::new(backend.clone()),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        assert_eq!(body(resp).await?, "async");
+        assert_eq!(backend.requests()[0].0, "origin");
+        Ok(())
+    }
+
+    #[test]
+    fn select_skips_pending_requests_already_taken() -> Result<(), BoxError> {
+        // sends two requests asynchronously, waits on the first, then selects from both,
+        // responding with 200 plus the index selected
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "new" (func $req_new (param i32) (result i32)))
+  (import "fastly_http_req" "send_async" (func $send_async (param i32 i32 i32 i32 i32) (result i32)))
+  (import "fastly_http_req" "pending_req_wait" (func $pending_req_wait (param i32 i32 i32) (result i32)))
+  (import "fastly_http_req" "pending_req_select" (func $pending_req_select (param i32 i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "origin")
+  (func (export "_start")
+    (drop (call $req_new (i32.const 16)))
+    (drop (call $body_new (i32.const 20)))
+    (drop (call $send_async (i32.load (i32.const 16)) (i32.load (i32.const 20)) (i32.const 64) (i32.const 6) (i32.const 128)))
+    (drop (call $req_new (i32.const 16)))
+    (drop (call $body_new (i32.const 20)))
+    (drop (call $send_async (i32.load (i32.const 16)) (i32.load (i32.const 20)) (i32.const 64) (i32.const 6) (i32.const 132)))
+    (drop (call $pending_req_wait (i32.load (i32.const 128)) (i32.const 24) (i32.const 28)))
+    (drop (call $pending_req_select (i32.const 128) (i32.const 2) (i32.const 32) (i32.const 36) (i32.const 40)))
+    (call $respond (i32.add (i32.const 200) (i32.load (i32.const 32))) (i32.const 0) (i32.const 0)))"#,
+        )?;
+        let resp = Handler::new(Request::default()).run(
+            &module,
+            Store::new(&engine),
+            Box::new(RecordingBackend::default()),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        assert_eq!(resp.status().as_u16(), 201);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sends_are_recorded_with_the_responses_they_produced() -> Result<(), BoxError> {
+        // sends one request, then another asynchronously, to different uris
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "new" (func $req_new (param i32) (result i32)))
+  (import "fastly_http_req" "uri_set" (func $uri_set (param i32 i32 i32) (result i32)))
+  (import "fastly_http_req" "send" (func $send (param i32 i32 i32 i32 i32 i32) (result i32)))
+  (import "fastly_http_req" "send_async" (func $send_async (param i32 i32 i32 i32 i32) (result i32)))
+  (import "fastly_http_req" "pending_req_wait" (func $pending_req_wait (param i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "origin")
+  (data (i32.const 80) "http://origin/one")
+  (data (i32.const 112) "http://origin/two")
+  (func (export "_start")
+    (drop (call $req_new (i32.const 16)))
+    (drop (call $uri_set (i32.load (i32.const 16)) (i32.const 80) (i32.const 17)))
+    (drop (call $body_new (i32.const 20)))
+    (drop (call $send (i32.load (i32.const 16)) (i32.load (i32.const 20)) (i32.const 64) (i32.const 6) (i32.const 24) (i32.const 28)))
+    (drop (call $req_new (i32.const 16)))
+    (drop (call $uri_set (i32.load (i32.const 16)) (i32.const 112) (i32.const 17)))
+    (drop (call $body_new (i32.const 20)))
+    (drop (call $send_async (i32.load (i32.const 16)) (i32.load (i32.const 20)) (i32.const 64) (i32.const 6) (i32.const 32)))
+    (drop (call $pending_req_wait (i32.load (i32.const 32)) (i32.const 36) (i32.const 40)))
+    (drop (call $resp_send_downstream (i32.load (i32.const 36)) (i32.load (i32.const 40)) (i32.const 0))))"#,
+        )?;
+        let handler = Handler::new(Request::default()).with_settings(Settings {
+            record_sends: true,
+            ..Settings::default()
+        });
+        handler.clone().run(
+            &module,
+            Store::new(&engine),
+            Box::new(RecordingBackend::new(StatusCode::CREATED, "created")),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        let sends = handler.sends();
+        assert_eq!(sends.len(), 2);
+        assert_eq!(sends[0].request.backend, "origin");
+        assert_eq!(sends[0].request.uri, "http://origin/one");
+        assert_eq!(sends[0].status, StatusCode::CREATED);
+        assert_eq!(sends[0].response_handle, 0);
+        assert_eq!(sends[1].request.uri, "http://origin/two");
+        assert_eq!(sends[1].response_handle, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn downstream_client_request_id_is_stable() -> Result<(), BoxError> {
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "downstream_client_request_id" (func $request_id (param i32 i32 i32) (result i32)))"#,
+            r#"(func (export "_start")
+    (drop (call $request_id (i32.const 64) (i32.const 32) (i32.const 16)))
+    (drop (call $request_id (i32.const 96) (i32.const 32) (i32.const 20)))
+    (call $respond (i32.const 200) (i32.const 64) (i32.add (i32.load (i32.const 16)) (i32.load (i32.const 20)))))"#,
+        )?;
+        let mut req = Request::default();
+        req.extensions_mut().insert(crate::handler::RequestId(
+            "0123456789abcdef0123456789abcdef".into(),
+        ));
+        let resp = Handler::new(req).run(
+            &module,
+            Store::new(&engine),
+            crate::backend::default(),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        assert_eq!(
+            body(resp).await?,
+            "0123456789abcdef0123456789abcdef".repeat(2)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn uri_get_returns_uri_set() -> Result<(), BoxError> {
+        let resp = uri_round_trip("https://example.com/path?x=1")?;
+        assert_eq!("https://example.com/path?x=1", body(resp).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn uri_set_rejects_uris_past_max_uri_length() -> Result<(), BoxError> {
+        // responds with the status uri_set returned
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "new" (func $req_new (param i32) (result i32)))
+  (import "fastly_http_req" "uri_set" (func $uri_set (param i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "https://example.com/too/long")
+  (func (export "_start")
+    (drop (call $req_new (i32.const 16)))
+    (i32.store (i32.const 32) (call $uri_set (i32.load (i32.const 16)) (i32.const 64) (i32.const 28)))
+    (call $respond (i32.const 200) (i32.const 32) (i32.const 1)))"#,
+        )?;
+        let resp = Handler::new(Request::default())
+            .with_settings(Settings {
+                max_uri_length: Some(16),
+                ..Settings::default()
+            })
+            .run(
+                &module,
+                Store::new(&engine),
+                crate::backend::default(),
+                HashMap::default(),
+                "127.0.0.1".parse().ok(),
+            )?;
+        assert_eq!(
+            body(resp).await?.as_bytes(),
+            &[FastlyStatus::INVAL.code as u8]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn uri_get_returns_uri_set_without_path() -> Result<(), BoxError> {
+        let resp = uri_round_trip("https://example.com")?;
+        assert_eq!("https://example.com", body(resp).await?);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn downstream_original_header_count_works() -> Result<(), BoxError> {
         match WASM.as_ref() {
@@ -1,17 +1,17 @@
 use crate::{
     fastly_http_body::BodyHandle,
-    handler::Handler,
-    memory,
-    memory::{ReadMem, WriteMem},
+    handler::{HandleKind, Handler, StreamedResponse},
+    hostcall, memory,
+    memory::{trap, write_nul_terminated, ReadMem, WriteMem},
     BoxError,
 };
 use fastly_shared::{FastlyStatus, HttpVersion};
 use hyper::{
-    header::{HeaderName, HeaderValue},
-    Body, Response, StatusCode,
+    header::{HeaderName, HeaderValue, CONTENT_LENGTH, TRANSFER_ENCODING},
+    Body, Response, StatusCode, Version,
 };
 use log::debug;
-use std::{convert::TryFrom, str};
+use std::{convert::TryFrom, mem, str};
 use wasmtime::{Caller, Func, Linker, Store, Trap};
 
 pub type ResponseHandle = i32;
@@ -61,7 +61,17 @@ pub fn add_to_linker<'a>(
         .define(
             "fastly_http_resp",
             "header_values_set",
-            header_values_set(handler, &store),
+            header_values_set(handler.clone(), &store),
+        )?
+        .define(
+            "fastly_http_resp",
+            "header_append",
+            header_append(handler.clone(), &store),
+        )?
+        .define(
+            "fastly_http_resp",
+            "header_insert",
+            header_insert(handler, &store),
         )?)
 }
 
@@ -72,24 +82,77 @@ fn send_downstream(
     Func::wrap(
         store,
         move |_caller: Caller<'_>, whandle: ResponseHandle, bhandle: BodyHandle, stream: i32| {
-            debug!(
+            let _trace = hostcall!(
+                handler,
                 "fastly_http_resp::send_downstream whandle={} bhandle={} stream={}",
-                whandle, bhandle, stream
+                whandle,
+                bhandle,
+                stream
             );
-            if stream != 0 {
-                debug!("resp_send_downstream: streaming unsupported");
-                return FastlyStatus::UNSUPPORTED.code;
+            handler.check_handle(
+                "fastly_http_resp::send_downstream",
+                HandleKind::Response,
+                whandle,
+            )?;
+            handler.check_handle(
+                "fastly_http_resp::send_downstream",
+                HandleKind::Body,
+                bhandle,
+            )?;
+            // a guest only gets one response, so later sends leave the first, and their handles, untouched
+            if handler.inner.borrow().response_sent {
+                log::error!("fastly_http_resp::send_downstream called after a response was sent");
+                return Ok(FastlyStatus::ERROR.code);
             }
-            let parts = handler
+            // consumed handles are left in place so later handles remain valid
+            let mut parts = match handler
                 .inner
                 .borrow_mut()
                 .responses
-                .remove(whandle as usize);
-            let body = handler.inner.borrow_mut().bodies.remove(bhandle as usize);
-            handler.inner.borrow_mut().response =
-                Response::from_parts(parts, Body::from(body.to_vec()));
+                .get_mut(whandle as usize)
+            {
+                Some(parts) => mem::replace(parts, Response::new(()).into_parts().0),
+                _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
+            };
+            handler.consume(HandleKind::Response, whandle);
+            let body = if stream != 0 {
+                // a streamed body's length isn't known up front, so it's framed with
+                // chunked transfer-encoding. The guest keeps writing to its body handle
+                let body = match handler.inner.borrow_mut().bodies.get_mut(bhandle as usize) {
+                    Some(body) => body
+                        .stream_downstream()
+                        .map_err(|e| Trap::new(format!("failed to read response body: {}", e)))?,
+                    _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
+                };
+                parts.headers.remove(CONTENT_LENGTH);
+                parts.headers.remove(TRANSFER_ENCODING);
+                body
+            } else {
+                let body = match handler.inner.borrow_mut().bodies.get_mut(bhandle as usize) {
+                    Some(body) => mem::take(body),
+                    _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
+                };
+                handler.consume(HandleKind::Body, bhandle);
+                let body = body
+                    .into_bytes()
+                    .map_err(|e| Trap::new(format!("failed to read response body: {}", e)))?;
+                handler.dump_body("response", &body);
+                Body::from(body)
+            };
+            let mut inner = handler.inner.borrow_mut();
+            inner.response_sent = true;
+            match (stream != 0, inner.streamed_response.take()) {
+                (true, Some(StreamedResponse(deliver))) => {
+                    drop(inner);
+                    deliver(Response::from_parts(parts, body));
+                }
+                (_, streamed_response) => {
+                    inner.streamed_response = streamed_response;
+                    inner.response = Response::from_parts(parts, body);
+                }
+            }
 
-            FastlyStatus::OK.code
+            Ok(FastlyStatus::OK.code)
         },
     )
 }
@@ -99,10 +162,17 @@ fn status_set(
     store: &Store,
 ) -> Func {
     Func::wrap(store, move |whandle: ResponseHandle, status: i32| {
-        debug!(
+        let _trace = hostcall!(
+            handler,
             "fastly_http_resp::status_set whandle={} status={}",
-            whandle, status
+            whandle,
+            status
         );
+        handler.check_handle(
+            "fastly_http_resp::status_set",
+            HandleKind::Response,
+            whandle,
+        )?;
 
         match handler
             .inner
@@ -128,15 +198,20 @@ fn new(
     store: &Store,
 ) -> Func {
     Func::wrap(store, move |caller: Caller<'_>, handle_out: i32| {
-        debug!("fastly_http_resp::new handle_out={}", handle_out);
+        let _trace = hostcall!(handler, "fastly_http_resp::new handle_out={}", handle_out);
         let index = handler.inner.borrow().responses.len();
-        let resp: Response<Body> = Response::default();
+        let resp: Response<Body> = Response::builder()
+            .version(Version::HTTP_11)
+            .body(Body::empty())
+            .expect("invalid response");
         handler
             .inner
             .borrow_mut()
             .responses
             .push(resp.into_parts().0);
-        memory!(caller).write_u32(handle_out, index as u32);
+        memory!(caller)
+            .write_u32(handle_out, index as u32)
+            .map_err(trap)?;
 
         Ok(FastlyStatus::OK.code)
     })
@@ -155,8 +230,13 @@ fn header_names_get(
               cursor: i32,
               ending_cursor_out: i32,
               nwritten_out: i32| {
-            debug!("fastly_http_resp::header_names_get handle={} addr={} maxlen={} cursor={} ending_cursor_out={} nwritten_out={}",
+            let _trace = hostcall!(handler, "fastly_http_resp::header_names_get handle={} addr={} maxlen={} cursor={} ending_cursor_out={} nwritten_out={}",
         handle, addr, maxlen, cursor, ending_cursor_out, nwritten_out);
+            handler.check_handle(
+                "fastly_http_resp::header_names_get",
+                HandleKind::Response,
+                handle,
+            )?;
             match handler.inner.borrow().responses.get(handle as usize) {
                 Some(resp) => {
                     let mut names: Vec<_> = resp.headers.keys().map(HeaderName::as_str).collect();
@@ -165,22 +245,29 @@ fn header_names_get(
                     let ucursor = cursor as usize;
                     match names.get(ucursor) {
                         Some(hdr) => {
-                            let mut bytes = hdr.as_bytes().to_vec();
-                            bytes.push(0); // api requires a terminating \x00 byte
-                            let written = memory.write_bytes(addr, &bytes).unwrap();
-                            memory.write_i32(nwritten_out, written as i32);
-                            memory.write_i32(
-                                ending_cursor_out,
-                                if ucursor < names.len() - 1 {
-                                    cursor + 1_i32
-                                } else {
-                                    -1_i32
-                                },
-                            );
+                            if let Some(status) = write_nul_terminated(
+                                &mut memory,
+                                addr,
+                                maxlen,
+                                hdr.as_bytes(),
+                                nwritten_out,
+                            )? {
+                                return Ok(status);
+                            }
+                            memory
+                                .write_i32(
+                                    ending_cursor_out,
+                                    if ucursor < names.len() - 1 {
+                                        cursor + 1_i32
+                                    } else {
+                                        -1_i32
+                                    },
+                                )
+                                .map_err(trap)?;
                         }
                         _ => {
-                            memory.write_i32(nwritten_out, 0);
-                            memory.write_i32(ending_cursor_out, -1);
+                            memory.write_i32(nwritten_out, 0).map_err(trap)?;
+                            memory.write_i32(ending_cursor_out, -1).map_err(trap)?;
                             return Ok(FastlyStatus::OK.code);
                         }
                     }
@@ -204,11 +291,16 @@ fn header_values_get(
               name_addr: i32,
               name_size: i32,
               addr: i32,
-              _maxlen: i32,
+              maxlen: i32,
               cursor: i32,
               ending_cursor_out: i32,
               nwritten_out: i32| {
-            debug!("fastly_http_resp::header_values_get");
+            let _trace = hostcall!(handler, "fastly_http_resp::header_values_get");
+            handler.check_handle(
+                "fastly_http_resp::header_values_get",
+                HandleKind::Response,
+                handle,
+            )?;
 
             let mut memory = memory!(caller);
             match handler
@@ -234,22 +326,25 @@ fn header_values_get(
                     let ucursor = cursor as usize;
                     match values.get(ucursor) {
                         Some(val) => {
-                            let mut bytes = val.to_vec();
-                            bytes.push(0); // api requires a terminating \x00 byte
-                            let written = memory.write_bytes(addr, &bytes).unwrap();
-                            memory.write_i32(nwritten_out, written as i32);
-                            memory.write_i32(
-                                ending_cursor_out,
-                                if ucursor < values.len() - 1 {
-                                    cursor + 1_i32
-                                } else {
-                                    -1_i32
-                                },
-                            );
+                            if let Some(status) =
+                                write_nul_terminated(&mut memory, addr, maxlen, val, nwritten_out)?
+                            {
+                                return Ok(status);
+                            }
+                            memory
+                                .write_i32(
+                                    ending_cursor_out,
+                                    if ucursor < values.len() - 1 {
+                                        cursor + 1_i32
+                                    } else {
+                                        -1_i32
+                                    },
+                                )
+                                .map_err(trap)?;
                         }
                         _ => {
-                            memory.write_i32(nwritten_out, 0);
-                            memory.write_i32(ending_cursor_out, -1);
+                            memory.write_i32(nwritten_out, 0).map_err(trap)?;
+                            memory.write_i32(ending_cursor_out, -1).map_err(trap)?;
                             return Ok(FastlyStatus::OK.code);
                         }
                     }
@@ -274,8 +369,13 @@ fn header_values_set(
               name_size: i32,
               values_addr: i32,
               values_size: i32| {
-            debug!("fastly_http_resp::header_values_set handle={} name_addr={} name_size={} value_addr={} value_size={}", 
+            let _trace = hostcall!(handler, "fastly_http_resp::header_values_set handle={} name_addr={} name_size={} value_addr={} value_size={}", 
             handle, name_addr, name_size, values_addr, values_size);
+            handler.check_handle(
+                "fastly_http_resp::header_values_set",
+                HandleKind::Response,
+                handle,
+            )?;
             let mut memory = memory!(caller);
             match handler
                 .inner
@@ -327,12 +427,21 @@ fn status_get(
     Func::wrap(
         store,
         move |caller: Caller<'_>, resp_handle: ResponseHandle, status: i32| {
-            debug!(
+            let _trace = hostcall!(
+                handler,
                 "fastly_http_resp::status_get resp_handle={} status={}",
-                resp_handle, status
+                resp_handle,
+                status
             );
+            handler.check_handle(
+                "fastly_http_resp::status_get",
+                HandleKind::Response,
+                resp_handle,
+            )?;
             match handler.inner.borrow().responses.get(resp_handle as usize) {
-                Some(resp) => memory!(caller).write_i32(status, resp.status.as_u16() as i32),
+                Some(resp) => memory!(caller)
+                    .write_i32(status, resp.status.as_u16() as i32)
+                    .map_err(trap)?,
                 _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
             }
             Ok(FastlyStatus::OK.code)
@@ -347,14 +456,21 @@ fn version_get(
     Func::wrap(
         store,
         move |caller: Caller<'_>, resp_handle: ResponseHandle, version_out: i32| {
-            debug!(
+            let _trace = hostcall!(
+                handler,
                 "fastly_http_resp::version_get resp_handle={} version={}",
-                resp_handle, version_out
+                resp_handle,
+                version_out
             );
+            handler.check_handle(
+                "fastly_http_resp::version_get",
+                HandleKind::Response,
+                resp_handle,
+            )?;
             match handler.inner.borrow().responses.get(resp_handle as usize) {
-                Some(resp) => {
-                    memory!(caller).write_u32(version_out, HttpVersion::from(resp.version).as_u32())
-                }
+                Some(resp) => memory!(caller)
+                    .write_u32(version_out, HttpVersion::from(resp.version).as_u32())
+                    .map_err(trap)?,
                 _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
             }
 
@@ -368,23 +484,318 @@ fn version_set(
     store: &Store,
 ) -> Func {
     Func::wrap(store, move |whandle: ResponseHandle, version: i32| {
-        debug!(
+        let _trace = hostcall!(
+            handler,
             "fastly_http_resp::version_set handle={} version={}",
-            whandle, version
+            whandle,
+            version
         );
+        handler.check_handle(
+            "fastly_http_resp::version_set",
+            HandleKind::Response,
+            whandle,
+        )?;
         match handler
             .inner
             .borrow_mut()
             .responses
             .get_mut(whandle as usize)
         {
-            Some(req) => {
-                req.version = HttpVersion::try_from(version as u32)
-                    .expect("invalid version")
-                    .into();
-            }
+            Some(resp) => match HttpVersion::try_from(version as u32) {
+                Ok(version) => resp.version = version.into(),
+                _ => {
+                    debug!("invalid http version {}", version);
+                    return Ok(FastlyStatus::INVAL.code);
+                }
+            },
             _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
         }
         Ok(FastlyStatus::OK.code)
     })
 }
+
+/// Reads a header name and value which, unlike those for `header_values_set`, are not \u{0} terminated
+fn read_header(
+    caller: &Caller<'_>,
+    name_addr: i32,
+    name_size: i32,
+    value_addr: i32,
+    value_size: i32,
+) -> Result<(HeaderName, HeaderValue), Trap> {
+    let mut memory = memory!(caller);
+    let name = match memory.read_bytes(name_addr, name_size) {
+        Ok((_, bytes)) => match HeaderName::from_bytes(&bytes) {
+            Ok(name) => name,
+            _ => {
+                return Err(Trap::new(format!(
+                    "Invalid header name {:?}",
+                    str::from_utf8(&bytes)
+                )))
+            }
+        },
+        _ => return Err(Trap::new("Failed to read header name")),
+    };
+    let value = match memory.read_bytes(value_addr, value_size) {
+        Ok((_, bytes)) => match HeaderValue::from_bytes(&bytes) {
+            Ok(value) => value,
+            _ => {
+                return Err(Trap::new(format!(
+                    "Invalid header value for header {} {:?}",
+                    name,
+                    str::from_utf8(&bytes)
+                )))
+            }
+        },
+        _ => return Err(Trap::new("Failed to read header value")),
+    };
+    Ok((name, value))
+}
+
+fn header_append(
+    handler: Handler,
+    store: &Store,
+) -> Func {
+    Func::wrap(
+        store,
+        move |caller: Caller<'_>,
+              handle: ResponseHandle,
+              name_addr: i32,
+              name_size: i32,
+              value_addr: i32,
+              value_size: i32| {
+            let _trace = hostcall!(
+                handler,
+                "fastly_http_resp::header_append handle={} name_addr={} name_size={} value_addr={} value_size={}",
+                handle,
+                name_addr,
+                name_size,
+                value_addr,
+                value_size
+            );
+            handler.check_handle(
+                "fastly_http_resp::header_append",
+                HandleKind::Response,
+                handle,
+            )?;
+            let (name, value) = read_header(&caller, name_addr, name_size, value_addr, value_size)?;
+            match handler
+                .inner
+                .borrow_mut()
+                .responses
+                .get_mut(handle as usize)
+            {
+                Some(resp) => {
+                    resp.headers.append(name, value);
+                }
+                _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
+            }
+
+            Ok(FastlyStatus::OK.code)
+        },
+    )
+}
+
+fn header_insert(
+    handler: Handler,
+    store: &Store,
+) -> Func {
+    Func::wrap(
+        store,
+        move |caller: Caller<'_>,
+              handle: ResponseHandle,
+              name_addr: i32,
+              name_size: i32,
+              value_addr: i32,
+              value_size: i32| {
+            let _trace = hostcall!(
+                handler,
+                "fastly_http_resp::header_insert handle={} name_addr={} name_size={} value_addr={} value_size={}",
+                handle,
+                name_addr,
+                name_size,
+                value_addr,
+                value_size
+            );
+            handler.check_handle(
+                "fastly_http_resp::header_insert",
+                HandleKind::Response,
+                handle,
+            )?;
+            let (name, value) = read_header(&caller, name_addr, name_size, value_addr, value_size)?;
+            match handler
+                .inner
+                .borrow_mut()
+                .responses
+                .get_mut(handle as usize)
+            {
+                Some(resp) => {
+                    // replaces any existing values
+                    resp.headers.insert(name, value);
+                }
+                _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
+            }
+
+            Ok(FastlyStatus::OK.code)
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::guest;
+    use byteorder::{ByteOrder, LittleEndian};
+    use hyper::Request;
+    use std::collections::HashMap;
+
+    /// Sets then gets a response version, responding with a status of
+    /// 200 + (10 * version_set status) + version_get version
+    fn version_round_trip(version: i32) -> Result<Response<Body>, BoxError> {
+        let (engine, module) = guest(
+            r#"(import "fastly_http_resp" "version_set" (func $version_set (param i32 i32) (result i32)))
+  (import "fastly_http_resp" "version_get" (func $version_get (param i32 i32) (result i32)))"#,
+            &format!(
+                r#"(func (export "_start")
+    (drop (call $resp_new (i32.const 16)))
+    (i32.store (i32.const 24) (call $version_set (i32.load (i32.const 16)) (i32.const {})))
+    (drop (call $version_get (i32.load (i32.const 16)) (i32.const 20)))
+    (call $respond
+      (i32.add (i32.const 200) (i32.add (i32.mul (i32.const 10) (i32.load (i32.const 24))) (i32.load (i32.const 20))))
+      (i32.const 0)
+      (i32.const 0)))"#,
+                version
+            ),
+        )?;
+        Handler::new(Request::default()).run(
+            &module,
+            Store::new(&engine),
+            crate::backend::default(),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )
+    }
+
+    #[tokio::test]
+    async fn send_downstream_rejects_a_second_response() -> Result<(), BoxError> {
+        // streams a 201 downstream, then writes the status of sending a 500 to its body
+        let (engine, module) = guest(
+            "",
+            r#"(func (export "_start")
+    (drop (call $resp_new (i32.const 16)))
+    (drop (call $resp_status_set (i32.load (i32.const 16)) (i32.const 201)))
+    (drop (call $body_new (i32.const 20)))
+    (drop (call $resp_send_downstream (i32.load (i32.const 16)) (i32.load (i32.const 20)) (i32.const 1)))
+    (drop (call $resp_new (i32.const 24)))
+    (drop (call $resp_status_set (i32.load (i32.const 24)) (i32.const 500)))
+    (drop (call $body_new (i32.const 28)))
+    (i32.store (i32.const 64) (call $resp_send_downstream (i32.load (i32.const 24)) (i32.load (i32.const 28)) (i32.const 0)))
+    (drop (call $body_write (i32.load (i32.const 20)) (i32.const 64) (i32.const 4) (i32.const 0) (i32.const 32))))"#,
+        )?;
+        let resp = Handler::new(Request::default()).run(
+            &module,
+            Store::new(&engine),
+            crate::backend::default(),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let status = hyper::body::to_bytes(resp.into_body()).await?;
+        assert_eq!(LittleEndian::read_i32(&status), FastlyStatus::ERROR.code);
+        Ok(())
+    }
+
+    #[test]
+    fn version_round_trips() -> Result<(), BoxError> {
+        for version in &[
+            HttpVersion::Http09,
+            HttpVersion::Http10,
+            HttpVersion::Http11,
+            HttpVersion::H2,
+            HttpVersion::H3,
+        ] {
+            let resp = version_round_trip(version.as_u32() as i32)?;
+            assert_eq!(resp.status().as_u16() as u32, 200 + version.as_u32());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn version_set_rejects_unknown_versions() -> Result<(), BoxError> {
+        let resp = version_round_trip(99)?;
+        assert_eq!(
+            resp.status().as_u16() as i32,
+            200 + 10 * FastlyStatus::INVAL.code + HttpVersion::Http11.as_u32() as i32
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn header_names_get_reports_buflen_without_advancing() -> Result<(), BoxError> {
+        // "x-long-header-name" and its terminator don't fit a 18 byte buffer, so the cursor must
+        // be left untouched for the guest to retry with the size reported back
+        let (engine, module) = guest(
+            r#"(import "fastly_http_resp" "header_insert" (func $header_insert (param i32 i32 i32 i32 i32) (result i32)))
+  (import "fastly_http_resp" "header_names_get" (func $names_get (param i32 i32 i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "x-long-header-name")
+  (data (i32.const 96) "1")
+  (func (export "_start")
+    (drop (call $resp_new (i32.const 16)))
+    (drop (call $header_insert (i32.load (i32.const 16)) (i32.const 64) (i32.const 18) (i32.const 96) (i32.const 1)))
+    (i32.store (i32.const 28) (i32.const 99))
+    (i32.store (i32.const 24) (call $names_get (i32.load (i32.const 16)) (i32.const 1024) (i32.const 18) (i32.const 0) (i32.const 28) (i32.const 32)))
+    (if (i32.ne (i32.load (i32.const 28)) (i32.const 99))
+      (then
+        (call $respond (i32.const 500) (i32.const 0) (i32.const 0))
+        (return)))
+    (drop (call $names_get (i32.load (i32.const 16)) (i32.const 1024) (i32.load (i32.const 32)) (i32.const 0) (i32.const 28) (i32.const 32)))
+    (call $respond (i32.add (i32.const 200) (i32.load (i32.const 24))) (i32.const 1024) (i32.load (i32.const 32))))"#,
+        )?;
+        let resp = Handler::new(Request::default()).run(
+            &module,
+            Store::new(&engine),
+            crate::backend::default(),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        assert_eq!(
+            resp.status().as_u16() as i32,
+            200 + FastlyStatus::BUFLEN.code
+        );
+        assert_eq!(
+            "x-long-header-name\0",
+            hyper::body::to_bytes(resp.into_body()).await?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn header_append_and_insert_work() -> Result<(), BoxError> {
+        let (engine, module) = guest(
+            r#"(import "fastly_http_resp" "header_append" (func $header_append (param i32 i32 i32 i32 i32) (result i32)))
+  (import "fastly_http_resp" "header_insert" (func $header_insert (param i32 i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "x-appended")
+  (data (i32.const 80) "x-inserted")
+  (data (i32.const 96) "12")
+  (func (export "_start")
+    (drop (call $resp_new (i32.const 16)))
+    (drop (call $header_insert (i32.load (i32.const 16)) (i32.const 64) (i32.const 10) (i32.const 96) (i32.const 1)))
+    (drop (call $header_append (i32.load (i32.const 16)) (i32.const 64) (i32.const 10) (i32.const 97) (i32.const 1)))
+    (drop (call $header_append (i32.load (i32.const 16)) (i32.const 80) (i32.const 10) (i32.const 96) (i32.const 1)))
+    (drop (call $header_insert (i32.load (i32.const 16)) (i32.const 80) (i32.const 10) (i32.const 97) (i32.const 1)))
+    (drop (call $body_new (i32.const 20)))
+    (drop (call $resp_send_downstream (i32.load (i32.const 16)) (i32.load (i32.const 20)) (i32.const 0))))"#,
+        )?;
+        let resp = Handler::new(Request::default()).run(
+            &module,
+            Store::new(&engine),
+            crate::backend::default(),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        let appended: Vec<_> = resp.headers().get_all("x-appended").iter().collect();
+        assert_eq!(appended, vec!["1", "2"]);
+        let inserted: Vec<_> = resp.headers().get_all("x-inserted").iter().collect();
+        assert_eq!(inserted, vec!["2"]);
+        Ok(())
+    }
+}
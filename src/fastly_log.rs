@@ -1,7 +1,7 @@
 use crate::{
     handler::{Endpoint, Handler},
-    memory,
-    memory::{ReadMem, WriteMem},
+    hostcall, memory,
+    memory::{trap, ReadMem, WriteMem},
     BoxError,
 };
 use fastly_shared::FastlyStatus;
@@ -32,9 +32,12 @@ fn endpoint_get(
     Func::wrap(
         store,
         move |caller: Caller<'_>, name: i32, name_len: i32, endpoint_handle_out: i32| {
-            debug!(
+            let _trace = hostcall!(
+                handler,
                 "fastly_log::endpoint_get name={} name_len={} endpoint_handle_out={}",
-                name, name_len, endpoint_handle_out
+                name,
+                name_len,
+                endpoint_handle_out
             );
             let mut memory = memory!(caller);
             let endpoint = match memory.read_bytes(name, name_len) {
@@ -45,13 +48,15 @@ fn endpoint_get(
                 _ => return Err(Trap::new("failed to read endpoint name")),
             };
             debug!("fastly_log::endpoint_get endpoint={}", endpoint);
+            let index = handler.inner.borrow().endpoints.len();
             handler
                 .inner
                 .borrow_mut()
                 .endpoints
                 .push(Endpoint(endpoint));
-            // todo: store handle
-            memory.write_i32(endpoint_handle_out, 0);
+            memory
+                .write_i32(endpoint_handle_out, index as i32)
+                .map_err(trap)?;
             Ok(FastlyStatus::OK.code)
         },
     )
@@ -68,16 +73,21 @@ fn write(
               msg: i32,
               msg_len: i32,
               nwritten_out: i32| {
-            debug!(
+            let _trace = hostcall!(
+                handler,
                 "fastly_log::write endpoint_handle={} msg={} msg_len={} nwritten_out={}",
-                endpoint_handle, msg, msg_len, nwritten_out
+                endpoint_handle,
+                msg,
+                msg_len,
+                nwritten_out
             );
-            match handler
+            let endpoint = handler
                 .inner
                 .borrow()
                 .endpoints
                 .get(endpoint_handle as usize)
-            {
+                .map(|endpoint| endpoint.0.clone());
+            match endpoint {
                 Some(endpoint) => {
                     let mut memory = memory!(caller);
                     let message = match memory.read_bytes(msg, msg_len) {
@@ -88,8 +98,31 @@ fn write(
                         _ => return Err(Trap::new("failed to read endpoint name")),
                     };
                     debug!("fastly_log::write message={}", message);
-                    endpoint.log(&message);
-                    memory.write_i32(nwritten_out, message.len() as i32);
+                    memory
+                        .write_i32(nwritten_out, message.len() as i32)
+                        .map_err(trap)?;
+                    #[cfg(test)]
+                    {
+                        let mut inner = handler.inner.borrow_mut();
+                        if inner.settings.capture_logs {
+                            inner.logs.entry(endpoint).or_default().push(message);
+                            return Ok(FastlyStatus::OK.code);
+                        }
+                    }
+                    let inner = handler.inner.borrow();
+                    let format = inner.settings.log_endpoint_format;
+                    if let Some(path) = inner.settings.log_files.get(&endpoint) {
+                        if let Err(e) = Endpoint(endpoint).append(path, &message, format) {
+                            debug!(
+                                "fastly_log::write failed to append to {}: {}",
+                                path.display(),
+                                e
+                            );
+                            return Ok(FastlyStatus::ERROR.code);
+                        }
+                    } else {
+                        Endpoint(endpoint).log(&message, format);
+                    }
                 }
                 _ => return Err(Trap::i32_exit(FastlyStatus::BADF.code)),
             }
@@ -98,3 +131,77 @@ fn write(
         },
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{handler::Settings, tests::guest};
+    use hyper::Request;
+    use std::collections::HashMap;
+
+    #[test]
+    fn logs_are_captured_by_endpoint() -> Result<(), BoxError> {
+        let (engine, module) = guest(
+            r#"(import "fastly_log" "endpoint_get" (func $endpoint_get (param i32 i32 i32) (result i32)))
+  (import "fastly_log" "write" (func $write (param i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "one")
+  (data (i32.const 72) "two")
+  (data (i32.const 128) "hello")
+  (func (export "_start")
+    (drop (call $endpoint_get (i32.const 64) (i32.const 3) (i32.const 16)))
+    (drop (call $endpoint_get (i32.const 72) (i32.const 3) (i32.const 20)))
+    (drop (call $write (i32.load (i32.const 16)) (i32.const 128) (i32.const 5) (i32.const 24)))
+    (drop (call $write (i32.load (i32.const 20)) (i32.const 128) (i32.const 4) (i32.const 24)))
+    (call $respond (i32.const 200) (i32.const 0) (i32.const 0)))"#,
+        )?;
+        let handler = Handler::new(Request::default()).with_settings(Settings {
+            capture_logs: true,
+            ..Settings::default()
+        });
+        handler.clone().run(
+            &module,
+            Store::new(&engine),
+            crate::backend::default(),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        let inner = handler.inner.borrow();
+        assert_eq!(inner.logs["one"], vec!["hello".to_string()]);
+        assert_eq!(inner.logs["two"], vec!["hell".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn logs_are_appended_to_their_endpoint_files() -> Result<(), BoxError> {
+        let (engine, module) = guest(
+            r#"(import "fastly_log" "endpoint_get" (func $endpoint_get (param i32 i32 i32) (result i32)))
+  (import "fastly_log" "write" (func $write (param i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "one")
+  (data (i32.const 128) "hello\n")
+  (func (export "_start")
+    (drop (call $endpoint_get (i32.const 64) (i32.const 3) (i32.const 16)))
+    (drop (call $write (i32.load (i32.const 16)) (i32.const 128) (i32.const 6) (i32.const 24)))
+    (drop (call $write (i32.load (i32.const 16)) (i32.const 128) (i32.const 6) (i32.const 24)))
+    (call $respond (i32.const 200) (i32.const 0) (i32.const 0)))"#,
+        )?;
+        let path =
+            std::env::temp_dir().join(format!("fasttime-log-endpoint-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let handler = Handler::new(Request::default()).with_settings(Settings {
+            log_files: vec![("one".to_string(), path.clone())]
+                .into_iter()
+                .collect(),
+            ..Settings::default()
+        });
+        handler.run(
+            &module,
+            Store::new(&engine),
+            crate::backend::default(),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        assert_eq!(std::fs::read_to_string(&path)?, "hello\nhello\n");
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
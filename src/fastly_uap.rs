@@ -1,6 +1,6 @@
 use crate::{
     memory,
-    memory::{ReadMem, WriteMem},
+    memory::{trap, ReadMem, WriteMem},
     BoxError,
 };
 use fastly_shared::FastlyStatus;
@@ -51,25 +51,33 @@ fn parse(store: &Store) -> Func {
                         } = UAP.parse_product(a);
                         if let Some(fam) = name {
                             match memory.write_bytes(family_pos, fam.as_bytes()) {
-                                Ok(bytes) => memory.write_i32(family_written, bytes as i32),
+                                Ok(bytes) => memory
+                                    .write_i32(family_written, bytes as i32)
+                                    .map_err(trap)?,
                                 _ => return Err(Trap::i32_exit(FastlyStatus::ERROR.code)),
                             }
                         }
                         if let Some(maj) = major {
                             match memory.write_bytes(major_pos, maj.as_bytes()) {
-                                Ok(bytes) => memory.write_i32(major_written, bytes as i32),
+                                Ok(bytes) => memory
+                                    .write_i32(major_written, bytes as i32)
+                                    .map_err(trap)?,
                                 _ => return Err(Trap::i32_exit(FastlyStatus::ERROR.code)),
                             }
                         }
                         if let Some(min) = minor {
                             match memory.write_bytes(minor_pos, min.as_bytes()) {
-                                Ok(bytes) => memory.write_i32(minor_written, bytes as i32),
+                                Ok(bytes) => memory
+                                    .write_i32(minor_written, bytes as i32)
+                                    .map_err(trap)?,
                                 _ => return Err(Trap::i32_exit(FastlyStatus::ERROR.code)),
                             }
                         }
                         if let Some(pat) = patch {
                             match memory.write_bytes(patch_pos, pat.as_bytes()) {
-                                Ok(bytes) => memory.write_i32(patch_written, bytes as i32),
+                                Ok(bytes) => memory
+                                    .write_i32(patch_written, bytes as i32)
+                                    .map_err(trap)?,
                                 _ => return Err(Trap::i32_exit(FastlyStatus::ERROR.code)),
                             }
                         }
@@ -3,7 +3,12 @@
 use crate::BoxError;
 use hyper::{Body, Request, Response};
 use serde::Serialize;
-use std::net::IpAddr;
+use serde_derive::Deserialize;
+use std::{collections::HashMap, fmt, net::IpAddr, str::FromStr, sync::Arc};
+
+/// Name of the backend the fastly crate sends geolocation lookups to. Requests for it are
+/// answered by fasttime, rather than proxied, unless `disable_geolocation` is set
+pub const GEOLOCATION: &str = "geolocation";
 
 // https://docs.rs/fastly/0.5.0/src/fastly/geo.rs.html#44
 /// A resolved geo lookup result
@@ -55,10 +60,59 @@ impl Default for Geo {
     }
 }
 
+/// The generation of the fastly SDK's geolocation schema lookups are serialized for
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GeoAbi {
+    /// fastly 0.5, which reads `utc_offset` as whole hours
+    V1,
+    /// later SDKs, which read `utc_offset` as `±HHMM` and also accept it as `gmt_offset`
+    V2,
+}
+
+impl Default for GeoAbi {
+    fn default() -> Self {
+        GeoAbi::V1
+    }
+}
+
+impl FromStr for GeoAbi {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" => Ok(GeoAbi::V1),
+            "v2" => Ok(GeoAbi::V2),
+            other => Err(format!("invalid geo abi `{}`, expected v1 or v2", other)),
+        }
+    }
+}
+
+impl Geo {
+    /// Serializes this lookup as JSON in the schema of an SDK generation
+    pub fn to_json(
+        &self,
+        abi: GeoAbi,
+    ) -> serde_json::Result<String> {
+        match abi {
+            GeoAbi::V1 => serde_json::to_string(self),
+            GeoAbi::V2 => {
+                let mut json = serde_json::to_value(self)?;
+                let offset = self.utc_offset * 100;
+                json["utc_offset"] = offset.into();
+                json["gmt_offset"] = offset.into();
+                serde_json::to_string(&json)
+            }
+        }
+    }
+}
+
 /// Defines a way to lookup a `Geo` by ip address
 ///
-/// An implementaion is provided for a closure as well as static values
-pub trait Lookup {
+/// An implementaion is provided for a closure, static values, and a table of addresses
+///
+/// Embedders answer `geolocation` lookups with their own with `Settings::geo_lookup`
+pub trait Lookup: Send + Sync {
     fn lookup(
         &self,
         ip: IpAddr,
@@ -67,7 +121,7 @@ pub trait Lookup {
 
 impl<F> Lookup for F
 where
-    F: Fn(IpAddr) -> Geo,
+    F: Fn(IpAddr) -> Geo + Send + Sync,
 {
     fn lookup(
         &self,
@@ -77,6 +131,15 @@ where
     }
 }
 
+impl fmt::Debug for dyn Lookup {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.write_str("Lookup")
+    }
+}
+
 impl Lookup for Geo {
     fn lookup(
         &self,
@@ -86,7 +149,46 @@ impl Lookup for Geo {
     }
 }
 
-pub struct GeoBackend(pub Box<dyn Lookup>);
+/// Looks up a `Geo` by exact address, falling back on the default `Geo`
+///
+/// Addresses are matched by family, so an IPv4-mapped IPv6 address like `::ffff:1.2.3.4`
+/// will not match an entry for `1.2.3.4`
+impl Lookup for HashMap<IpAddr, Geo> {
+    fn lookup(
+        &self,
+        ip: IpAddr,
+    ) -> Geo {
+        self.get(&ip).cloned().unwrap_or_default()
+    }
+}
+
+/// Parses an ip address literal, tolerating brackets and IPv6 zone ids like `fe80::1%eth0`
+fn parse_ip(s: &str) -> Option<IpAddr> {
+    let s = s.trim().trim_start_matches('[').trim_end_matches(']');
+    s.split('%').next().unwrap_or(s).parse().ok()
+}
+
+pub struct GeoBackend {
+    lookup: Arc<dyn Lookup>,
+    abi: GeoAbi,
+}
+
+impl GeoBackend {
+    pub fn new(lookup: Arc<dyn Lookup>) -> Self {
+        GeoBackend {
+            lookup,
+            abi: GeoAbi::default(),
+        }
+    }
+
+    /// Serializes lookups in the schema of this SDK generation
+    pub fn with_abi(
+        self,
+        abi: GeoAbi,
+    ) -> Self {
+        GeoBackend { abi, ..self }
+    }
+}
 
 impl crate::Backends for GeoBackend {
     fn send(
@@ -100,11 +202,11 @@ impl crate::Backends for GeoBackend {
             .headers()
             .get("Fastly-XQD-arg1")
             .and_then(|hdr| hdr.to_str().ok())
-            .and_then(|s| s.parse::<IpAddr>().ok())
+            .and_then(parse_ip)
         {
-            Some(ip) => Ok(Response::new(Body::from(serde_json::to_string(
-                &self.0.lookup(ip),
-            )?))),
+            Some(ip) => Ok(Response::new(Body::from(
+                self.lookup.lookup(ip).to_json(self.abi)?,
+            ))),
             _ => Err(anyhow::anyhow!("expected request containing Fastly-XQD-arg1 header").into()),
         }
     }
@@ -113,6 +215,8 @@ impl crate::Backends for GeoBackend {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{handler::Handler, tests::WASM};
+    use wasmtime::Store;
 
     #[test]
     fn closures_lookup() -> Result<(), BoxError> {
@@ -130,4 +234,142 @@ mod tests {
         assert_eq!(value.lookup("127.0.0.0".parse::<IpAddr>()?), value);
         Ok(())
     }
+
+    #[test]
+    fn table_lookup_distinguishes_ipv6() -> Result<(), BoxError> {
+        let v6 = Geo {
+            city: "Amsterdam".into(),
+            country_code: "NL".into(),
+            ..Geo::default()
+        };
+        let mut table = HashMap::new();
+        table.insert("2001:db8::1".parse::<IpAddr>()?, v6.clone());
+        assert_eq!(table.lookup("2001:db8::1".parse::<IpAddr>()?), v6);
+        assert_eq!(table.lookup("127.0.0.1".parse::<IpAddr>()?), Geo::default());
+        Ok(())
+    }
+
+    #[test]
+    fn table_lookup_does_not_match_v4_mapped() -> Result<(), BoxError> {
+        let v4 = Geo {
+            city: "Paris".into(),
+            ..Geo::default()
+        };
+        let mut table = HashMap::new();
+        table.insert("192.0.2.1".parse::<IpAddr>()?, v4.clone());
+        assert_eq!(table.lookup("192.0.2.1".parse::<IpAddr>()?), v4);
+        assert_eq!(
+            table.lookup("::ffff:192.0.2.1".parse::<IpAddr>()?),
+            Geo::default()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn backend_looks_up_ipv6() -> Result<(), BoxError> {
+        let v6 = Geo {
+            city: "Amsterdam".into(),
+            ..Geo::default()
+        };
+        let expected = v6.clone();
+        let backend = GeoBackend::new(Arc::new(move |ip: IpAddr| match ip {
+            IpAddr::V6(_) => expected.clone(),
+            IpAddr::V4(_) => Geo::default(),
+        }));
+        let resp = crate::Backends::send(
+            &backend,
+            "geolocation",
+            Request::get("/")
+                .header("Fastly-XQD-arg1", "2001:db8::1")
+                .body(Body::empty())?,
+        )?;
+        assert_eq!(crate::tests::body(resp).await?, serde_json::to_string(&v6)?);
+        Ok(())
+    }
+
+    #[test]
+    fn v2_serializes_utc_offsets_as_hours_and_minutes() -> Result<(), BoxError> {
+        let geo = Geo {
+            utc_offset: -5,
+            ..Geo::default()
+        };
+        let v1: serde_json::Value = serde_json::from_str(&geo.to_json(GeoAbi::V1)?)?;
+        assert_eq!(v1["utc_offset"], -5);
+        assert!(v1.get("gmt_offset").is_none());
+        let v2: serde_json::Value = serde_json::from_str(&geo.to_json(GeoAbi::V2)?)?;
+        assert_eq!(v2["utc_offset"], -500);
+        assert_eq!(v2["gmt_offset"], -500);
+        assert_eq!(v2["city"], "New York");
+        Ok(())
+    }
+
+    #[test]
+    fn geo_abis_parse() {
+        assert_eq!("v1".parse(), Ok(GeoAbi::V1));
+        assert_eq!("v2".parse(), Ok(GeoAbi::V2));
+        assert!("v3".parse::<GeoAbi>().is_err());
+    }
+
+    #[test]
+    fn parse_ip_strips_brackets_and_zone_ids() -> Result<(), BoxError> {
+        assert_eq!(parse_ip("2001:db8::1"), Some("2001:db8::1".parse()?));
+        assert_eq!(parse_ip("[2001:db8::1]"), Some("2001:db8::1".parse()?));
+        assert_eq!(parse_ip("fe80::1%eth0"), Some("fe80::1".parse()?));
+        assert_eq!(parse_ip("127.0.0.1"), Some("127.0.0.1".parse()?));
+        assert_eq!(parse_ip("nope"), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn geo_route_works_for_ipv6_clients() -> Result<(), BoxError> {
+        match WASM.as_ref() {
+            None => Ok(()),
+            Some((engine, module)) => {
+                let resp = Handler::new(Request::get("/geo").body(Default::default())?).run(
+                    &module,
+                    Store::new(&engine),
+                    crate::backend::default(),
+                    HashMap::default(),
+                    "2001:db8::1".parse().ok(),
+                )?;
+                assert!(resp.status().is_success());
+                assert!(crate::tests::body(resp)
+                    .await?
+                    .starts_with("ip 2001:db8::1 "));
+                Ok(())
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn lookups_round_trip_through_the_guests_geo_lookup() -> Result<(), BoxError> {
+        match WASM.as_ref() {
+            None => Ok(()),
+            Some((engine, module)) => {
+                // the test app is built with fastly 0.5, which reads the v1 schema
+                let geo = Geo {
+                    city: "Amsterdam".into(),
+                    ..Geo::default()
+                };
+                let resp = Handler::new(Request::get("/geo").body(Default::default())?)
+                    .with_settings(crate::handler::Settings {
+                        geo_lookup: Some(Arc::new(geo)),
+                        ..crate::handler::Settings::default()
+                    })
+                    .run(
+                        &module,
+                        Store::new(&engine),
+                        crate::backend::default(),
+                        HashMap::default(),
+                        "127.0.0.1".parse().ok(),
+                    )?;
+                assert!(resp.status().is_success());
+                // the guest debug prints the lookup it parsed, which is None when it misparses one
+                let body = crate::tests::body(resp).await?;
+                assert!(body.starts_with("ip 127.0.0.1 Some("), "{}", body);
+                assert!(body.contains(r#"city: "Amsterdam""#), "{}", body);
+                Ok(())
+            }
+        }
+    }
 }
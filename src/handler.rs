@@ -1,16 +1,99 @@
 //! Defines an HTTP request handling interface
 
-use crate::BoxError;
-use bytes::BytesMut;
+use crate::{
+    backend::Loopback,
+    cache::Cache,
+    clock::Clock,
+    fastly_dictionary::Dictionaries,
+    fastly_http_body::HostBody,
+    geo::{GeoAbi, Lookup},
+    wasi_config::WasiConfig,
+    BoxError,
+};
+use chrono::offset::Local;
+use colored::Colorize;
 use fastly_shared::FastlyStatus;
+use futures_executor::block_on;
 use http::{request::Parts as RequestParts, response::Parts as ResponseParts};
-use hyper::{Body, Request, Response};
+use hyper::{body::to_bytes, Body, Request, Response};
+#[cfg(test)]
+use hyper::{HeaderMap, Method, StatusCode, Uri};
 use log::debug;
-use std::{cell::RefCell, collections::HashMap, net::IpAddr, rc::Rc};
+use serde_derive::Deserialize;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    rc::Rc,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 use wasi_cap_std_sync::WasiCtxBuilder;
-use wasmtime::{Linker, Module, Store, Trap};
+use wasmtime::{Engine, IntoFunc, Linker, Module, Store, Trap};
 use wasmtime_wasi::Wasi;
 
+/// Logs a hostcall with its arguments at debug level, returning a guard which records
+/// how long the hostcall took when `trace_hostcalls` is enabled
+#[macro_export]
+macro_rules! hostcall {
+    ($handler:expr, $($arg:tt)+) => {{
+        ::log::debug!($($arg)+);
+        $handler.trace(|| format!($($arg)+))
+    }};
+}
+
+/// How messages written to log endpoints are printed
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// messages as written
+    Plain,
+    /// one `{timestamp, endpoint, message}` JSON object per write
+    Jsonl,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Plain
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(LogFormat::Plain),
+            "jsonl" => Ok(LogFormat::Jsonl),
+            other => Err(format!(
+                "invalid log endpoint format `{}`, expected plain or jsonl",
+                other
+            )),
+        }
+    }
+}
+
+/// Identifies a downstream request across the access log, the guest, and debugging headers
+///
+/// Ids come from a monotonic counter, starting at 1, formatted as 32 hex digits like Fastly's
+/// own request ids. They are unique within a fasttime process but not across restarts
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        RequestId(format!("{:032x}", NEXT.fetch_add(1, Ordering::Relaxed)))
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct Endpoint(pub String);
 
@@ -18,10 +101,199 @@ impl Endpoint {
     pub fn log(
         &self,
         msg: &str,
+        format: LogFormat,
     ) {
-        print!("{}", msg);
+        print!("{}", self.render(msg, format));
+    }
+
+    /// Appends a message to a file, rendered as it would be printed
+    pub fn append(
+        &self,
+        path: &Path,
+        msg: &str,
+        format: LogFormat,
+    ) -> io::Result<()> {
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(self.render(msg, format).as_bytes())
+    }
+
+    fn render(
+        &self,
+        msg: &str,
+        format: LogFormat,
+    ) -> String {
+        match format {
+            LogFormat::Plain => msg.to_owned(),
+            LogFormat::Jsonl => format!("{}\n", self.jsonl(msg)),
+        }
     }
+
+    /// Renders a message as a JSON Lines record. Messages spanning multiple lines
+    /// are kept in one record, with their newlines escaped, less a trailing newline
+    fn jsonl(
+        &self,
+        msg: &str,
+    ) -> String {
+        let msg = msg.strip_suffix('\n').unwrap_or(msg);
+        serde_json::json!({
+            "timestamp": Local::now().to_rfc3339(),
+            "endpoint": self.0,
+            "message": msg.strip_suffix('\r').unwrap_or(msg),
+        })
+        .to_string()
+    }
+}
+/// Defines additional hostcalls, like experimental or non-standard funcs, to register
+/// with each request's `Linker` after the Fastly funcs
+///
+/// An implementation is provided for closures
+pub trait Extension: Send + Sync {
+    fn add_to_linker(
+        &self,
+        linker: &mut Linker,
+        handler: Handler,
+        store: &Store,
+    ) -> Result<(), BoxError>;
+}
+
+impl<F> Extension for F
+where
+    F: Fn(&mut Linker, Handler, &Store) -> Result<(), BoxError> + Send + Sync,
+{
+    fn add_to_linker(
+        &self,
+        linker: &mut Linker,
+        handler: Handler,
+        store: &Store,
+    ) -> Result<(), BoxError> {
+        self(linker, handler, store)
+    }
+}
+
+impl fmt::Debug for dyn Extension {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.write_str("Extension")
+    }
+}
+
+/// Runtime settings applied to each request/response cycle
+#[derive(Clone, Debug, Default)]
+pub struct Settings {
+    /// directory to write downstream request and final response bodies to
+    pub dump_bodies: Option<PathBuf>,
+    /// additional hostcalls to register
+    pub extensions: Vec<Arc<dyn Extension>>,
+    /// trap when a guest uses a handle after it was consumed
+    pub strict_handles: bool,
+    /// decompress gzip and brotli encoded downstream request bodies
+    pub decompress_request: bool,
+    /// print every hostcall with its arguments and timing after each request
+    pub trace_hostcalls: bool,
+    /// collect messages written to log endpoints in `Inner::logs`, rather than printing them
+    #[cfg(test)]
+    pub capture_logs: bool,
+    /// proxy requests for the reserved `geolocation` backend like any other backend
+    pub disable_geolocation: bool,
+    /// the SDK generation geolocation lookups are answered for
+    pub geo_abi: GeoAbi,
+    /// answers geolocation lookups, in place of the default `Geo` for every address
+    pub geo_lookup: Option<Arc<dyn Lookup>>,
+    /// how messages written to log endpoints are printed
+    pub log_endpoint_format: LogFormat,
+    /// files to append messages written to a log endpoint to, by endpoint name, rather than printing them
+    pub log_files: HashMap<String, PathBuf>,
+    /// directory to write requests the guest sends to backends to
+    pub dump_backend_requests: Option<PathBuf>,
+    /// maximum number of requests a guest may send to backends while handling a request
+    pub max_backend_requests: Option<usize>,
+    /// maximum size, in bytes, of a body a guest builds with `write` or `append`
+    pub max_response_body: Option<usize>,
+    /// maximum length, in bytes, of a uri a guest sets with `uri_set`
+    pub max_uri_length: Option<usize>,
+    /// backend responses shared between requests
+    pub cache: Option<Cache>,
+    /// log a backtrace of the guest's call stack when it traps
+    pub guest_backtrace: bool,
+    /// the wall clock the guest observes
+    pub clock: Clock,
+    /// arguments and environment variables the guest observes
+    pub wasi: WasiConfig,
+    /// record each request sent to a backend with the response it produced, for `Handler::sends`
+    #[cfg(test)]
+    pub record_sends: bool,
+    /// print how long each stage of handling a request took after each request
+    pub timings: bool,
+}
+
+/// How long each stage of handling a request took
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Timings {
+    /// reading the downstream request body
+    pub ingest: Duration,
+    /// linking hostcalls and instantiating the module
+    pub instantiate: Duration,
+    /// running the guest, less the time spent reading the downstream body and waiting on backends
+    pub guest: Duration,
+    /// waiting on backend responses
+    pub backend: Duration,
+}
+
+impl fmt::Display for Timings {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        write!(
+            f,
+            "ingest={:.2?} instantiate={:.2?} guest={:.2?} backend={:.2?}",
+            self.ingest, self.instantiate, self.guest, self.backend
+        )
+    }
+}
+
+/// A request the guest sent to a backend, recorded when `record_sends` is enabled
+#[cfg(test)]
+#[derive(Clone, Debug)]
+pub struct SentRequest {
+    pub backend: String,
+    pub method: Method,
+    pub uri: Uri,
+    pub headers: HeaderMap,
 }
+
+/// A `SentRequest` paired with the response it produced, once the guest received a handle to it
+#[cfg(test)]
+#[derive(Clone, Debug)]
+pub struct Sent {
+    pub request: SentRequest,
+    pub status: StatusCode,
+    pub response_handle: i32,
+}
+
+/// A hostcall fasttime links guests with, as listed by --list-hostcalls
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hostcall {
+    pub module: String,
+    pub name: String,
+    /// whether it always answers guests with `UNSUPPORTED` rather than being implemented
+    pub stubbed: bool,
+}
+
+/// The kinds of handles a guest holds
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HandleKind {
+    Request,
+    Response,
+    Body,
+    PendingRequest,
+}
+
 /// Represents state within a given request/response cycle
 ///
 /// an inbound request is provided by our driving server
@@ -31,78 +303,542 @@ impl Endpoint {
 pub struct Inner {
     /// downstream request
     pub request: Option<Request<Body>>,
-    /// requests initiated within the handler
-    pub requests: Vec<RequestParts>,
+    /// the downstream request, once the guest asked for it, and requests initiated within the handler
+    pub requests: Requests,
     /// responses from the requests initiated within the handler
     pub responses: Vec<ResponseParts>,
+    /// responses to requests sent with `send_async`, taken when the guest waits on them
+    pub pending: Vec<Option<(ResponseParts, HostBody)>>,
     /// bodies created within the handler
-    pub bodies: Vec<BytesMut>,
+    pub bodies: Vec<HostBody>,
     /// final handler response
     pub response: Response<Body>,
-    /// list of loaded dictionaries
-    pub dictionaries: Vec<HashMap<String, String>>,
+    /// whether the guest sent a final response downstream
+    pub response_sent: bool,
+    /// names of opened dictionaries
+    pub dictionaries: Vec<String>,
     /// list of loaded log endpoints
     pub endpoints: Vec<Endpoint>,
+    /// messages written to each log endpoint, when `capture_logs` is enabled
+    #[cfg(test)]
+    pub logs: HashMap<String, Vec<String>>,
+    /// id of the downstream request
+    pub request_id: RequestId,
+    /// runtime settings
+    pub settings: Settings,
+    /// depth of nested loopback requests
+    pub depth: usize,
+    /// handles consumed by hostcalls like `send`
+    pub consumed: HashSet<(HandleKind, i32)>,
+    /// number of requests sent to backends
+    pub backend_requests: usize,
+    /// handle of the downstream request body, once the guest asked for it
+    pub downstream_body: Option<i32>,
+    /// requests sent to backends and their responses, when `record_sends` is enabled
+    #[cfg(test)]
+    pub sends: Vec<Sent>,
+    /// how long each stage of handling the request took
+    pub timings: Timings,
+    /// receives a response streamed downstream as soon as the guest sends it
+    pub streamed_response: Option<StreamedResponse>,
+    /// the module and name of each hostcall linked as a stub
+    pub stubs: Vec<(String, String)>,
+}
+
+/// Delivers a response streamed downstream before the guest finishes, so its body reaches the
+/// client as the guest writes it
+pub struct StreamedResponse(pub Box<dyn FnOnce(Response<Body>)>);
+
+impl fmt::Debug for StreamedResponse {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.write_str("StreamedResponse")
+    }
+}
+
+/// Requests referred to by guest handles
+///
+/// The downstream request has a dedicated handle, `DOWNSTREAM_REQUEST`, so the handles of
+/// requests a guest creates don't depend on whether, or when, it asked for the downstream request
+#[derive(Debug, Default)]
+pub struct Requests {
+    downstream: Option<RequestParts>,
+    created: Vec<RequestParts>,
+}
+
+/// Handle of the downstream request
+pub const DOWNSTREAM_REQUEST: usize = 0;
+
+impl Requests {
+    /// Stores the downstream request, returning its handle
+    pub fn set_downstream(
+        &mut self,
+        parts: RequestParts,
+    ) -> usize {
+        self.downstream = Some(parts);
+        DOWNSTREAM_REQUEST
+    }
+
+    /// Returns the downstream request, once the guest asked for it
+    pub fn downstream(&self) -> Option<&RequestParts> {
+        self.downstream.as_ref()
+    }
+
+    /// Stores a request the guest created, returning its handle
+    pub fn push(
+        &mut self,
+        parts: RequestParts,
+    ) -> usize {
+        self.created.push(parts);
+        self.created.len()
+    }
+
+    pub fn get(
+        &self,
+        handle: usize,
+    ) -> Option<&RequestParts> {
+        match handle {
+            DOWNSTREAM_REQUEST => self.downstream.as_ref(),
+            handle => self.created.get(handle - 1),
+        }
+    }
+
+    pub fn get_mut(
+        &mut self,
+        handle: usize,
+    ) -> Option<&mut RequestParts> {
+        match handle {
+            DOWNSTREAM_REQUEST => self.downstream.as_mut(),
+            handle => self.created.get_mut(handle - 1),
+        }
+    }
+}
+
+/// Formats a trap's message followed by the guest functions on the stack when it trapped,
+/// innermost first, named when the module includes a name section
+fn backtrace(trap: &Trap) -> String {
+    let frames = trap
+        .trace()
+        .iter()
+        .enumerate()
+        .map(|(i, frame)| {
+            let name = match frame.func_name() {
+                Some(name) => name.to_owned(),
+                _ => format!("<wasm function {}>", frame.func_index()),
+            };
+            match frame.module_offset() {
+                offset if offset > 0 => format!("  {}: {} @ {:#x}", i, name, offset),
+                _ => format!("  {}: {}", i, name),
+            }
+        })
+        .collect::<Vec<_>>();
+    // a trap's display ends with a terser backtrace of its own
+    let message = trap.to_string();
+    let message = message.lines().next().unwrap_or_default();
+    format!("{}\n{}", message, frames.join("\n"))
+}
+
+/// Records a hostcall's timing when dropped
+pub struct Trace {
+    handler: Handler,
+    call: String,
+    start: Instant,
+}
+
+impl Drop for Trace {
+    fn drop(&mut self) {
+        let call = std::mem::take(&mut self.call);
+        self.handler
+            .traces
+            .borrow_mut()
+            .push((call, self.start.elapsed()));
+    }
 }
 
 #[derive(Default, Clone)]
 pub struct Handler {
     pub inner: Rc<RefCell<Inner>>,
+    /// hostcalls and their timings, when `trace_hostcalls` is enabled
+    ///
+    /// Kept apart from `inner`, which a hostcall's tail expression may still be borrowing
+    /// when its `Trace` drops, and only borrowed briefly to record or print traces
+    pub traces: Rc<RefCell<Vec<(String, Duration)>>>,
 }
 
 impl Handler {
     fn into_response(self) -> Response<Body> {
-        self.inner.replace(Default::default()).response
+        let inner = self.inner.replace(Default::default());
+        // captured logs and sends outlive the request, for inspection by clones of this handler
+        #[cfg(test)]
+        {
+            self.inner.borrow_mut().logs = inner.logs;
+            self.inner.borrow_mut().sends = inner.sends;
+        }
+        self.inner.borrow_mut().timings = inner.timings;
+        inner.response
+    }
+
+    /// Requests the application sent to backends, in the order it received their responses,
+    /// when `record_sends` is enabled
+    #[cfg(test)]
+    pub fn sends(&self) -> Vec<Sent> {
+        self.inner.borrow().sends.clone()
+    }
+
+    /// How long each stage of handling the latest request took
+    #[cfg(test)]
+    pub fn timings(&self) -> Timings {
+        self.inner.borrow().timings
     }
 }
 
 impl Handler {
+    /// Creates a handler for a downstream request, identified by its `RequestId`
+    /// extension or a new id when it has none
     pub fn new(request: hyper::Request<Body>) -> Self {
+        let request_id = request
+            .extensions()
+            .get::<RequestId>()
+            .cloned()
+            .unwrap_or_else(RequestId::next);
         Handler {
             inner: Rc::new(RefCell::new(Inner {
                 request: Some(request),
+                request_id,
                 ..Inner::default()
             })),
+            traces: Rc::default(),
         }
     }
 
+    /// Applies runtime `Settings` to this handler
+    pub fn with_settings(
+        self,
+        settings: Settings,
+    ) -> Self {
+        self.inner.borrow_mut().settings = settings;
+        self
+    }
+
+    /// Hands a response the guest streams downstream to `deliver` as soon as it's sent. `run`
+    /// then returns a placeholder in its place
+    pub fn with_streamed_response(
+        self,
+        deliver: impl FnOnce(Response<Body>) + 'static,
+    ) -> Self {
+        self.inner.borrow_mut().streamed_response = Some(StreamedResponse(Box::new(deliver)));
+        self
+    }
+
+    /// Writes a copy of a body to the `dump_bodies` directory, when configured
+    pub fn dump_body(
+        &self,
+        kind: &str,
+        body: &[u8],
+    ) {
+        if let Some(dir) = &self.inner.borrow().settings.dump_bodies {
+            let path = dir.join(format!(
+                "{}-{}.body",
+                Local::now().format("%Y%m%dT%H%M%S%.9f"),
+                kind
+            ));
+            match fs::write(&path, body) {
+                Ok(_) => println!(
+                    "{}",
+                    format!(" ⤓ {} body ({} bytes) {}", kind, body.len(), path.display()).dimmed()
+                ),
+                Err(e) => log::error!("failed to dump {} body to {}: {}", kind, path.display(), e),
+            }
+        }
+    }
+
+    /// Writes a request the guest sent to a backend, with its method, uri, headers, and body, to the
+    /// `dump_backend_requests` directory, when configured
+    ///
+    /// This is the request as the guest asked for it to be sent, before a backend modifies it
+    pub fn dump_backend_request(
+        &self,
+        backend: &str,
+        parts: &RequestParts,
+        body: &[u8],
+    ) {
+        if let Some(dir) = &self.inner.borrow().settings.dump_backend_requests {
+            let path = dir.join(format!(
+                "{}-{}.request",
+                Local::now().format("%Y%m%dT%H%M%S%.9f"),
+                backend.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_")
+            ));
+            let mut request =
+                format!("{} {} {:?}\r\n", parts.method, parts.uri, parts.version).into_bytes();
+            for (name, value) in &parts.headers {
+                request.extend_from_slice(name.as_str().as_bytes());
+                request.extend_from_slice(b": ");
+                request.extend_from_slice(value.as_bytes());
+                request.extend_from_slice(b"\r\n");
+            }
+            request.extend_from_slice(b"\r\n");
+            request.extend_from_slice(body);
+            match fs::write(&path, request) {
+                Ok(_) => println!(
+                    "{}",
+                    format!(" ⤓ {} backend request {}", backend, path.display()).dimmed()
+                ),
+                Err(e) => log::error!(
+                    "failed to dump {} backend request to {}: {}",
+                    backend,
+                    path.display(),
+                    e
+                ),
+            }
+        }
+    }
+
+    /// Links a hostcall fasttime doesn't implement yet, recording it as a stub
+    pub fn stub<Params, Results>(
+        &self,
+        linker: &mut Linker,
+        module: &str,
+        name: &str,
+        func: impl IntoFunc<Params, Results>,
+    ) -> Result<(), BoxError> {
+        linker.func(module, name, func)?;
+        self.inner
+            .borrow_mut()
+            .stubs
+            .push((module.to_owned(), name.to_owned()));
+        Ok(())
+    }
+
+    /// Lists the Fastly hostcalls guests are linked with, ordered by module and name, by building
+    /// a linker for a placeholder request
+    pub fn hostcalls(engine: &Engine) -> Result<Vec<Hostcall>, BoxError> {
+        let mut handler = Handler::new(Request::default());
+        let linker = handler.linker(
+            Store::new(engine),
+            crate::backend::default(),
+            Dictionaries::default(),
+            None,
+        )?;
+        let stubs = handler.inner.borrow().stubs.clone();
+        let mut hostcalls: Vec<_> = linker
+            .iter()
+            .filter(|(module, _, export)| {
+                module.starts_with("fastly_") && export.ty().func().is_some()
+            })
+            .map(|(module, name, _)| Hostcall {
+                module: module.to_owned(),
+                name: name.to_owned(),
+                stubbed: stubs
+                    .iter()
+                    .any(|(stub_module, stub_name)| stub_module == module && stub_name == name),
+            })
+            .collect();
+        hostcalls.sort_by(|a, b| (&a.module, &a.name).cmp(&(&b.module, &b.name)));
+        Ok(hostcalls)
+    }
+
+    /// Starts timing a hostcall, when `trace_hostcalls` is enabled
+    pub fn trace(
+        &self,
+        call: impl FnOnce() -> String,
+    ) -> Option<Trace> {
+        if !self.inner.borrow().settings.trace_hostcalls {
+            return None;
+        }
+        Some(Trace {
+            handler: self.clone(),
+            call: call(),
+            start: Instant::now(),
+        })
+    }
+
+    /// Prints traced hostcalls in the order they were called
+    fn print_traces(&self) {
+        let traces = std::mem::take(&mut *self.traces.borrow_mut());
+        if traces.is_empty() {
+            return;
+        }
+        let lines = traces
+            .iter()
+            .map(|(call, elapsed)| format!(" ↳ {} {:?}", call, elapsed))
+            .collect::<Vec<_>>();
+        println!("{}", lines.join("\n").dimmed());
+    }
+
+    /// Records how long the guest ran, less the time its hostcalls spent on other stages
+    fn record_guest_time(
+        &self,
+        elapsed: Duration,
+    ) {
+        let mut inner = self.inner.borrow_mut();
+        let timings = &mut inner.timings;
+        timings.guest = elapsed
+            .checked_sub(timings.ingest + timings.backend)
+            .unwrap_or_default();
+    }
+
+    /// Prints how long each stage took, when `timings` is enabled
+    fn print_timings(&self) {
+        let inner = self.inner.borrow();
+        if inner.settings.timings {
+            println!("{}", format!(" ↳ timings {}", inner.timings).dimmed());
+        }
+    }
+
+    /// Adds the time since `started` to the time spent reading the downstream request body,
+    /// when `body_handle` refers to it
+    pub fn record_ingest(
+        &self,
+        body_handle: i32,
+        started: Instant,
+    ) {
+        let mut inner = self.inner.borrow_mut();
+        if inner.downstream_body == Some(body_handle) {
+            inner.timings.ingest += started.elapsed();
+        }
+    }
+
+    /// Records that a hostcall, like `send`, consumed a guest's handle
+    pub fn consume(
+        &self,
+        kind: HandleKind,
+        handle: i32,
+    ) {
+        self.inner.borrow_mut().consumed.insert((kind, handle));
+    }
+
+    /// Counts a request to a backend, returning false when it would exceed `max_backend_requests`
+    pub fn count_backend_request(&self) -> bool {
+        let mut inner = self.inner.borrow_mut();
+        match inner.settings.max_backend_requests {
+            Some(max) if inner.backend_requests >= max => {
+                log::error!("handler exceeded {} backend requests", max);
+                false
+            }
+            _ => {
+                inner.backend_requests += 1;
+                true
+            }
+        }
+    }
+
+    /// Traps when a hostcall uses a handle which was already consumed and
+    /// `strict_handles` is enabled
+    pub fn check_handle(
+        &self,
+        hostcall: &str,
+        kind: HandleKind,
+        handle: i32,
+    ) -> Result<(), Trap> {
+        let inner = self.inner.borrow();
+        if inner.settings.strict_handles && inner.consumed.contains(&(kind, handle)) {
+            return Err(Trap::new(format!(
+                "{} used {:?} handle {} after it was consumed",
+                hostcall, kind, handle
+            )));
+        }
+        Ok(())
+    }
+
     /// Runs a Request to completion for a given `Module` and `Store`
     pub fn run(
         mut self,
         module: &Module,
         store: Store,
         backends: Box<dyn crate::Backends>,
-        dicionaries: HashMap<String, HashMap<String, String>>,
+        dictionaries: impl Into<Dictionaries>,
         ip: Option<IpAddr>,
     ) -> Result<Response<Body>, BoxError> {
-        if let Some(func) = self
-            .linker(store, backends, dicionaries, ip)?
+        let dictionaries = dictionaries.into();
+        let backends = Box::new(Loopback {
+            module: module.clone(),
+            engine: store.engine().clone(),
+            backends: Rc::from(backends),
+            dictionaries: dictionaries.clone(),
+            ip,
+            settings: self.inner.borrow().settings.clone(),
+            depth: self.inner.borrow().depth,
+        });
+        let instantiating = Instant::now();
+        let start = self
+            .linker(store, backends, dictionaries, ip)?
             .instantiate(&module)?
-            .get_func("_start")
-        {
-            func.call(&[])?;
+            .get_func("_start");
+        self.inner.borrow_mut().timings.instantiate = instantiating.elapsed();
+        if let Some(func) = start {
+            let running = Instant::now();
+            let result = func.call(&[]);
+            self.record_guest_time(running.elapsed());
+            if let Err(trap) = &result {
+                // exits, like those of proc_exit or a bad handle, aren't crashes
+                if self.inner.borrow().settings.guest_backtrace && trap.i32_exit_status().is_none()
+                {
+                    log::error!("guest trapped: {}", backtrace(trap));
+                }
+            }
+            self.print_traces();
+            let draining = Instant::now();
+            self.drain_downstream_body();
+            self.inner.borrow_mut().timings.ingest += draining.elapsed();
+            self.print_timings();
+            result?;
         } else {
             return Err(Trap::new("wasm module does not define a `_start` func").into());
         }
+        if !self.inner.borrow().response_sent {
+            log::error!("guest did not produce a response");
+            return Ok(Response::builder()
+                .status(500)
+                .body("guest did not produce a response".into())
+                .expect("invalid response"));
+        }
         Ok(self.into_response())
     }
 
+    /// Reads and discards any of the downstream request body the guest left unread, so the
+    /// client finishes sending it and its connection can be reused
+    fn drain_downstream_body(&self) {
+        let mut inner = self.inner.borrow_mut();
+        let result = match inner.request.take() {
+            Some(req) => block_on(to_bytes(req.into_body())).map(drop),
+            _ => match inner.downstream_body {
+                Some(handle) => match inner.bodies.get_mut(handle as usize) {
+                    Some(body) => body.drain(),
+                    _ => Ok(()),
+                },
+                _ => Ok(()),
+            },
+        };
+        if let Err(e) = result {
+            debug!("failed to drain downstream request body: {}", e);
+        }
+    }
+
     /// Builds a new linker given a provided `Store`
     /// configured with WASI and Fastly sys func implementations
     fn linker(
         &mut self,
         store: Store,
         backends: Box<dyn crate::Backends>,
-        dictionaries: HashMap<String, HashMap<String, String>>,
+        dictionaries: Dictionaries,
         ip: Option<IpAddr>,
     ) -> Result<Linker, BoxError> {
-        let wasi = Wasi::new(
-            &store,
-            WasiCtxBuilder::new()
-                .inherit_stdout()
-                .inherit_stderr()
-                .build()?,
-        );
+        let wasi = self.inner.borrow().settings.wasi.clone();
+        let mut builder = WasiCtxBuilder::new().inherit_stdout().inherit_stderr();
+        for arg in &wasi.args {
+            builder = builder.arg(arg)?;
+        }
+        for (name, value) in &wasi.env {
+            builder = builder.env(name, value)?;
+        }
+        let mut ctx = builder.build()?;
+        let clock = self.inner.borrow().settings.clock;
+        if clock != Clock::System {
+            ctx.clocks.system = Box::new(clock);
+        }
+        let wasi = Wasi::new(&store, ctx);
         let mut linker = Linker::new(&store);
 
         // add wasi funcs
@@ -116,12 +852,24 @@ impl Handler {
         })?;
 
         crate::fastly_uap::add_to_linker(&mut linker, &store)?;
-        crate::fastly_dictionary::add_to_linker(&mut linker, self.clone(), &store, dictionaries)?;
+        crate::fastly_dictionary::add_to_linker(
+            &mut linker,
+            self.clone(),
+            &store,
+            dictionaries.clone(),
+        )?;
+        crate::fastly_config_store::add_to_linker(&mut linker, self.clone(), &store, dictionaries)?;
+        crate::fastly_async_io::add_to_linker(&mut linker, self.clone(), &store)?;
         crate::fastly_http_body::add_to_linker(&mut linker, self.clone(), &store)?;
         crate::fastly_log::add_to_linker(&mut linker, self.clone(), &store)?;
         crate::fastly_http_req::add_to_linker(&mut linker, self.clone(), &store, backends, ip)?;
         crate::fastly_http_resp::add_to_linker(&mut linker, self.clone(), &store)?;
 
+        let extensions = self.inner.borrow().settings.extensions.clone();
+        for extension in extensions {
+            extension.add_to_linker(&mut linker, self.clone(), &store)?;
+        }
+
         Ok(linker)
     }
 }
@@ -129,8 +877,139 @@ impl Handler {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::tests::{body, WASM};
+    use crate::tests::{body, guest, WASM};
+    use byteorder::{ByteOrder, LittleEndian};
     use hyper::Request;
+    use wasmtime::Func;
+
+    #[tokio::test]
+    async fn run_fixes_the_guest_clock() -> Result<(), BoxError> {
+        // responds with the realtime clock's nanoseconds since the epoch
+        let (engine, module) = guest(
+            r#"(import "wasi_snapshot_preview1" "clock_time_get" (func $clock_time_get (param i32 i64 i32) (result i32)))"#,
+            r#"(func (export "_start")
+    (drop (call $clock_time_get (i32.const 0) (i64.const 1) (i32.const 64)))
+    (call $respond (i32.const 200) (i32.const 64) (i32.const 8)))"#,
+        )?;
+        let resp = Handler::new(Request::default())
+            .with_settings(Settings {
+                clock: "fixed:2023-01-01T00:00:00Z".parse()?,
+                ..Settings::default()
+            })
+            .run(
+                &module,
+                Store::new(&engine),
+                crate::backend::default(),
+                HashMap::default(),
+                "127.0.0.1".parse().ok(),
+            )?;
+        let nanos = hyper::body::to_bytes(resp.into_body()).await?;
+        assert_eq!(LittleEndian::read_u64(&nanos), 1_672_531_200_000_000_000);
+        Ok(())
+    }
+
+    #[test]
+    fn run_records_time_spent_waiting_on_backends() -> Result<(), BoxError> {
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "new" (func $req_new (param i32) (result i32)))
+  (import "fastly_http_req" "send" (func $send (param i32 i32 i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "origin")
+  (func (export "_start")
+    (drop (call $req_new (i32.const 16)))
+    (drop (call $body_new (i32.const 20)))
+    (drop (call $send (i32.load (i32.const 16)) (i32.load (i32.const 20)) (i32.const 64) (i32.const 6) (i32.const 24) (i32.const 28)))
+    (drop (call $resp_send_downstream (i32.load (i32.const 24)) (i32.load (i32.const 28)) (i32.const 0))))"#,
+        )?;
+        let slow = |_: &str, _: Request<Body>| -> Result<Response<Body>, BoxError> {
+            std::thread::sleep(Duration::from_millis(50));
+            Ok(Response::new(Body::empty()))
+        };
+        let handler = Handler::new(Request::default()).with_settings(Settings {
+            timings: true,
+            ..Settings::default()
+        });
+        handler.clone().run(
+            &module,
+            Store::new(&engine),
+            Box::new(slow),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        let timings = handler.timings();
+        assert!(timings.backend >= Duration::from_millis(50));
+        assert!(timings.guest < timings.backend);
+        assert!(timings.instantiate > Duration::default());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn run_passes_wasi_args_and_env() -> Result<(), BoxError> {
+        // responds with the guest's null terminated arguments followed by its environment
+        let (engine, module) = guest(
+            r#"(import "wasi_snapshot_preview1" "args_sizes_get" (func $args_sizes_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "args_get" (func $args_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "environ_sizes_get" (func $environ_sizes_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "environ_get" (func $environ_get (param i32 i32) (result i32)))"#,
+            r#"(func (export "_start")
+    (drop (call $args_sizes_get (i32.const 16) (i32.const 20)))
+    (drop (call $environ_sizes_get (i32.const 24) (i32.const 28)))
+    (drop (call $args_get (i32.const 256) (i32.const 1024)))
+    (drop (call $environ_get (i32.const 512) (i32.add (i32.const 1024) (i32.load (i32.const 20)))))
+    (call $respond (i32.const 200) (i32.const 1024) (i32.add (i32.load (i32.const 20)) (i32.load (i32.const 28)))))"#,
+        )?;
+        let path = std::env::temp_dir().join(format!("fasttime-wasi-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            args = ["app.wasm", "--verbose"]
+
+            [env]
+            GREETING = "hello"
+            "#,
+        )?;
+        let wasi = WasiConfig::load(&path);
+        std::fs::remove_file(&path)?;
+        let resp = Handler::new(Request::default())
+            .with_settings(Settings {
+                wasi: wasi?,
+                ..Settings::default()
+            })
+            .run(
+                &module,
+                Store::new(&engine),
+                crate::backend::default(),
+                HashMap::default(),
+                "127.0.0.1".parse().ok(),
+            )?;
+        assert_eq!(
+            hyper::body::to_bytes(resp.into_body()).await?,
+            "app.wasm\0--verbose\0GREETING=hello\0"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn backtrace_names_guest_functions() -> Result<(), BoxError> {
+        let engine = wasmtime::Engine::default();
+        let module = Module::new(
+            &engine,
+            r#"(module
+  (func $panic unreachable)
+  (func $handle (call $panic))
+  (func (export "_start") (call $handle)))"#,
+        )?;
+        let store = Store::new(&engine);
+        let start = wasmtime::Instance::new(&store, &module, &[])?
+            .get_func("_start")
+            .expect("missing _start");
+        let trap = start.call(&[]).expect_err("expected a trap");
+        let backtrace = backtrace(&trap);
+        let lines: Vec<_> = backtrace.lines().collect();
+        assert!(lines[0].contains("unreachable"), "{}", backtrace);
+        assert!(lines[1].contains("panic"), "{}", backtrace);
+        assert!(lines[2].contains("handle"), "{}", backtrace);
+        Ok(())
+    }
 
     #[tokio::test]
     async fn it_works() -> Result<(), BoxError> {
@@ -149,4 +1028,174 @@ mod tests {
             }
         }
     }
+
+    /// A downstream request body which counts how many of its chunks were read
+    fn counted_body(read: Arc<AtomicU64>) -> Body {
+        let chunks: Vec<Result<&'static str, std::io::Error>> = vec![Ok("ignored "), Ok("body")];
+        Body::wrap_stream(futures_util::stream::StreamExt::inspect(
+            futures_util::stream::iter(chunks),
+            move |_| {
+                read.fetch_add(1, Ordering::SeqCst);
+            },
+        ))
+    }
+
+    #[test]
+    fn run_drains_unread_downstream_bodies() -> Result<(), BoxError> {
+        let ignores = guest(
+            "",
+            r#"(func (export "_start")
+    (call $respond (i32.const 200) (i32.const 0) (i32.const 0)))"#,
+        )?;
+        // asks for the body but never reads it
+        let unread = guest(
+            r#"(import "fastly_http_req" "body_downstream_get" (func $body_downstream_get (param i32 i32) (result i32)))"#,
+            r#"(func (export "_start")
+    (drop (call $body_downstream_get (i32.const 16) (i32.const 20)))
+    (call $respond (i32.const 200) (i32.const 0) (i32.const 0)))"#,
+        )?;
+        for (engine, module) in vec![ignores, unread] {
+            let read = Arc::new(AtomicU64::default());
+            let resp = Handler::new(Request::post("/").body(counted_body(read.clone()))?).run(
+                &module,
+                Store::new(&engine),
+                crate::backend::default(),
+                HashMap::default(),
+                "127.0.0.1".parse().ok(),
+            )?;
+            assert_eq!(resp.status().as_u16(), 200);
+            assert_eq!(read.load(Ordering::SeqCst), 2);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn extensions_add_hostcalls() -> Result<(), BoxError> {
+        let (engine, module) = guest(
+            r#"(import "fasttime_test" "answer" (func $answer (result i32)))"#,
+            r#"(func (export "_start")
+    (call $respond (i32.add (i32.const 200) (call $answer)) (i32.const 0) (i32.const 0)))"#,
+        )?;
+        let extension = |linker: &mut Linker, _: Handler, store: &Store| -> Result<(), BoxError> {
+            linker.define("fasttime_test", "answer", Func::wrap(store, || 42))?;
+            Ok(())
+        };
+        let resp = Handler::new(Request::default())
+            .with_settings(Settings {
+                extensions: vec![Arc::new(extension)],
+                ..Settings::default()
+            })
+            .run(
+                &module,
+                Store::new(&engine),
+                crate::backend::default(),
+                HashMap::default(),
+                "127.0.0.1".parse().ok(),
+            )?;
+        assert_eq!(resp.status().as_u16(), 242);
+        Ok(())
+    }
+
+    #[test]
+    fn hostcalls_are_listed_with_stubs_marked() -> Result<(), BoxError> {
+        let hostcalls = Handler::hostcalls(&Engine::default())?;
+        let find = |module: &str, name: &str| {
+            hostcalls
+                .iter()
+                .find(|hostcall| hostcall.module == module && hostcall.name == name)
+                .map(|hostcall| hostcall.stubbed)
+        };
+        assert_eq!(find("fastly_http_req", "header_append"), Some(true));
+        assert_eq!(find("fastly_http_req", "send"), Some(false));
+        assert_eq!(find("fastly_abi", "init"), Some(false));
+        // wasi is linked, but isn't a fastly hostcall
+        assert!(hostcalls
+            .iter()
+            .all(|hostcall| hostcall.module.starts_with("fastly_")));
+        Ok(())
+    }
+
+    #[test]
+    fn strict_handles_trap_on_consumed_handles() -> Result<(), BoxError> {
+        // sends a response downstream then tries to update its status
+        let (engine, module) = guest(
+            "",
+            r#"(func (export "_start")
+    (call $respond (i32.const 200) (i32.const 0) (i32.const 0))
+    (drop (call $resp_status_set (i32.load (i32.const 0)) (i32.const 500))))"#,
+        )?;
+        let run = |strict_handles| {
+            Handler::new(Request::default())
+                .with_settings(Settings {
+                    strict_handles,
+                    ..Settings::default()
+                })
+                .run(
+                    &module,
+                    Store::new(&engine),
+                    crate::backend::default(),
+                    HashMap::default(),
+                    "127.0.0.1".parse().ok(),
+                )
+        };
+        assert_eq!(run(false)?.status().as_u16(), 200);
+        let err = run(true).expect_err("expected a trap");
+        assert!(err
+            .to_string()
+            .contains("fastly_http_resp::status_set used Response handle 0 after it was consumed"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn missing_responses_are_500s() -> Result<(), BoxError> {
+        let (engine, module) = guest("", r#"(func (export "_start"))"#)?;
+        let resp = Handler::new(Request::default()).run(
+            &module,
+            Store::new(&engine),
+            crate::backend::default(),
+            HashMap::default(),
+            "127.0.0.1".parse().ok(),
+        )?;
+        assert_eq!(resp.status().as_u16(), 500);
+        assert_eq!("guest did not produce a response", body(resp).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn traces_are_recorded_when_enabled() {
+        let handler = Handler::new(Request::default());
+        assert!(handler.trace(|| "untraced".into()).is_none());
+        let handler = handler.with_settings(Settings {
+            trace_hostcalls: true,
+            ..Settings::default()
+        });
+        drop(handler.trace(|| "fastly_http_req::new".into()));
+        // like a hostcall whose tail expression borrows the handler as its trace drops
+        let trace = handler.trace(|| "fastly_http_req::header_names_get".into());
+        let inner = handler.inner.borrow_mut();
+        drop(trace);
+        drop(inner);
+        let traces = handler.traces.borrow();
+        assert_eq!(traces.len(), 2);
+        assert_eq!(traces[0].0, "fastly_http_req::new");
+        assert_eq!(traces[1].0, "fastly_http_req::header_names_get");
+    }
+
+    #[test]
+    fn jsonl_records_escape_multiline_messages() -> Result<(), BoxError> {
+        let record = Endpoint("endpoint".into()).jsonl("one\ntwo\n");
+        assert!(!record.contains('\n'));
+        let record: serde_json::Value = serde_json::from_str(&record)?;
+        assert_eq!(record["endpoint"], "endpoint");
+        assert_eq!(record["message"], "one\ntwo");
+        assert!(record["timestamp"].is_string());
+        Ok(())
+    }
+
+    #[test]
+    fn log_format_parses() {
+        assert_eq!("plain".parse(), Ok(LogFormat::Plain));
+        assert_eq!("jsonl".parse(), Ok(LogFormat::Jsonl));
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
 }
@@ -0,0 +1,215 @@
+//! Replays requests captured in a HAR (HTTP Archive) file against the application, comparing
+//! its responses with the recorded ones
+
+use crate::BoxError;
+use anyhow::anyhow;
+use http::{header::HOST, uri::Scheme, Uri};
+use hyper::{Body, Request, StatusCode};
+use serde_derive::Deserialize;
+use std::{fs, path::Path};
+
+/// A HAR file, of which only the parts needed to replay requests are read
+///
+/// See http://www.softwareishard.com/blog/har-12-spec/
+#[derive(Debug, Deserialize)]
+pub struct Har {
+    pub log: Log,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Log {
+    pub entries: Vec<Entry>,
+}
+
+/// A recorded request and the response it was answered with
+#[derive(Debug, Deserialize)]
+pub struct Entry {
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NameValue {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RecordedRequest {
+    pub method: String,
+    /// the absolute url requested
+    pub url: String,
+    #[serde(default)]
+    pub headers: Vec<NameValue>,
+    #[serde(rename = "postData")]
+    pub post_data: Option<PostData>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PostData {
+    #[serde(default)]
+    pub text: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+    #[serde(default)]
+    pub content: Content,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Content {
+    /// the response body, absent when it wasn't recorded
+    pub text: Option<String>,
+    /// `base64` for binary bodies
+    pub encoding: Option<String>,
+}
+
+impl Har {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, BoxError> {
+        let path = path.as_ref();
+        let json = fs::read_to_string(path)
+            .map_err(|e| anyhow!("unable to read har {}: {}", path.display(), e))?;
+        Ok(serde_json::from_str(&json)
+            .map_err(|e| anyhow!("invalid har {}: {}", path.display(), e))?)
+    }
+}
+
+impl Entry {
+    /// Rebuilds the recorded request as a server would receive it, returning it along with the
+    /// scheme it was sent with
+    ///
+    /// HTTP/2 pseudo headers like `:authority` are dropped, and a `Host` header is added from
+    /// the url when one wasn't recorded
+    pub fn request(&self) -> Result<(Request<Body>, Scheme), BoxError> {
+        let url = self.request.url.parse::<Uri>()?;
+        let mut builder = Request::builder().method(self.request.method.as_str()).uri(
+            url.path_and_query()
+                .map(|path| path.as_str())
+                .unwrap_or("/"),
+        );
+        for NameValue { name, value } in &self.request.headers {
+            if !name.starts_with(':') {
+                builder = builder.header(name.as_str(), value.as_str());
+            }
+        }
+        let recorded_host = self
+            .request
+            .headers
+            .iter()
+            .any(|header| header.name.eq_ignore_ascii_case(HOST.as_str()));
+        if let (false, Some(authority)) = (recorded_host, url.authority()) {
+            builder = builder.header(HOST, authority.as_str());
+        }
+        let body = self
+            .request
+            .post_data
+            .as_ref()
+            .map(|data| data.text.clone())
+            .unwrap_or_default();
+        Ok((
+            builder.body(Body::from(body))?,
+            url.scheme().cloned().unwrap_or(Scheme::HTTP),
+        ))
+    }
+
+    /// Describes each way a response differs from the recorded one. Bodies are only compared
+    /// when one was recorded
+    pub fn compare(
+        &self,
+        status: StatusCode,
+        body: &[u8],
+    ) -> Result<Vec<String>, BoxError> {
+        let mut differences = Vec::new();
+        if status.as_u16() != self.response.status {
+            differences.push(format!(
+                "status {} differs from the recorded {}",
+                status.as_u16(),
+                self.response.status
+            ));
+        }
+        let content = &self.response.content;
+        if let Some(text) = &content.text {
+            let recorded = match content.encoding.as_deref() {
+                Some("base64") => base64::decode(text)?,
+                _ => text.clone().into_bytes(),
+            };
+            if recorded != body {
+                differences.push(format!(
+                    "body of {} bytes differs from the recorded {} bytes",
+                    body.len(),
+                    recorded.len()
+                ));
+            }
+        }
+        Ok(differences)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn har() -> Result<Har, BoxError> {
+        Ok(serde_json::from_str(
+            r#"{
+  "log": {
+    "version": "1.2",
+    "creator": { "name": "test", "version": "1" },
+    "entries": [
+      {
+        "startedDateTime": "2021-01-01T00:00:00.000Z",
+        "request": {
+          "method": "POST",
+          "url": "https://fasttime.co/greet?name=you",
+          "httpVersion": "HTTP/2",
+          "headers": [
+            { "name": ":authority", "value": "fasttime.co" },
+            { "name": "x-test", "value": "yes" }
+          ],
+          "postData": { "mimeType": "text/plain", "text": "hello" }
+        },
+        "response": {
+          "status": 200,
+          "content": { "size": 2, "mimeType": "text/plain", "text": "aGk=", "encoding": "base64" }
+        }
+      }
+    ]
+  }
+}"#,
+        )?)
+    }
+
+    #[tokio::test]
+    async fn entries_replay_recorded_requests() -> Result<(), BoxError> {
+        let har = har()?;
+        let (req, scheme) = har.log.entries[0].request()?;
+        assert_eq!(scheme, Scheme::HTTPS);
+        assert_eq!(req.method(), "POST");
+        assert_eq!(req.uri(), "/greet?name=you");
+        assert_eq!(req.headers()["host"], "fasttime.co");
+        assert_eq!(req.headers()["x-test"], "yes");
+        assert!(!req.headers().contains_key(":authority"));
+        assert_eq!(
+            hyper::body::to_bytes(req.into_body()).await?,
+            "hello".as_bytes()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn entries_compare_responses_with_recorded_ones() -> Result<(), BoxError> {
+        let har = har()?;
+        let entry = &har.log.entries[0];
+        assert!(entry.compare(StatusCode::OK, b"hi")?.is_empty());
+        assert_eq!(
+            entry.compare(StatusCode::NOT_FOUND, b"bye")?,
+            vec![
+                "status 404 differs from the recorded 200".to_string(),
+                "body of 3 bytes differs from the recorded 2 bytes".to_string()
+            ]
+        );
+        Ok(())
+    }
+}
@@ -1,6 +1,14 @@
 //! Fastly allows you to run WASM request handlers within a WASI-based runtime hosted on its managed edge servers. fasttime implements those runtime interfaces using wasmtime serving up your application on a local HTTP server allowing you to run you Compute@Edge applications ✨ locally on your laptop ✨.
 
+mod access_log;
+mod admin;
 mod backend;
+mod cache;
+mod clock;
+#[doc(hidden)]
+mod fastly_async_io;
+#[doc(hidden)]
+mod fastly_config_store;
 #[doc(hidden)]
 mod fastly_dictionary;
 #[doc(hidden)]
@@ -15,59 +23,173 @@ mod fastly_log;
 mod fastly_uap;
 mod geo;
 mod handler;
+mod har;
 mod memory;
 mod opts;
+mod rewrite;
+mod timeouts;
+mod tls;
+mod wasi_config;
 
+use access_log::AccessLog;
 use anyhow::anyhow;
 
-use backend::{Backend, Backends};
-use chrono::offset::Local;
+use backend::{Backend, BackendCert, BackendFallback, BackendHeader, Backends, UnknownBackend};
+use bytes::Bytes;
+use cache::Cache;
+use chrono::{offset::Local, DateTime, FixedOffset};
 use colored::Colorize;
 use core::task::{Context, Poll};
+use fastly_dictionary::Dictionaries;
 use futures_util::{
-    future::{ready, TryFutureExt},
+    future::{pending, ready, TryFutureExt},
     stream::{Stream, StreamExt},
 };
-use handler::Handler;
+use handler::{Handler, Hostcall, RequestId, Settings};
+use har::Har;
 use http::{
-    header::HOST,
+    header::{
+        HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+        ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE,
+        ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, CONNECTION, CONTENT_LENGTH,
+        CONTENT_RANGE, CONTENT_TYPE, ETAG, HOST, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+        ORIGIN, RANGE,
+    },
     uri::{Authority, Scheme, Uri},
-    Request, Response,
+    HeaderMap, Method, Request, Response, StatusCode, Version,
 };
 use hyper::{
-    server::conn::AddrStream,
+    body::to_bytes,
     service::{make_service_fn, service_fn},
     Body, Server,
 };
 use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
 use opts::Opts;
-use rustls::internal::pemfile;
+use rewrite::Rules;
 use serde_derive::Deserialize;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 use std::{
-    collections::HashMap,
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
     error::Error,
-    fs::{self, File},
-    io::BufReader,
-    net::IpAddr,
+    fmt, fs, io,
+    net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
     pin::Pin,
-    process::exit,
-    sync::{mpsc::channel, Arc, RwLock},
+    process::{exit, Command, ExitStatus},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::channel,
+        Arc, RwLock,
+    },
     time::{Duration, Instant, SystemTime},
 };
+use timeouts::{ClientTimeouts, TimeoutStream};
 use tokio::{
     net::{TcpListener, TcpStream},
+    sync::{oneshot, Notify, OwnedSemaphorePermit, Semaphore},
     task::spawn_blocking,
+    time::sleep,
 };
 use tokio_rustls::{server::TlsStream, TlsAcceptor};
-use wasmtime::{Engine, Module, Store};
+use wasi_config::WasiConfig;
+use wasmtime::{Engine, ExternType, Module, Store};
 
 pub type BoxError = Box<dyn Error + Send + Sync + 'static>;
 
 #[derive(Clone, Debug, Deserialize, PartialEq)]
 struct Dictionary {
     name: String,
-    entries: HashMap<String, String>,
+    entries: BTreeMap<String, String>,
+}
+
+/// A log endpoint whose messages are appended to a file rather than printed
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+struct LogEndpoint {
+    name: String,
+    path: PathBuf,
+}
+
+/// An object store's entries, declared ahead of fasttime linking the object store hostcalls
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+struct ObjectStore {
+    name: String,
+    entries: BTreeMap<String, String>,
+}
+
+/// A secret store's secrets, declared ahead of fasttime linking the secret store hostcalls
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+struct SecretStore {
+    name: String,
+    secrets: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+struct Header {
+    name: String,
+    value: String,
+}
+
+impl Header {
+    /// Validates this header's name and value
+    fn parse(&self) -> Result<(HeaderName, HeaderValue), BoxError> {
+        Ok((
+            HeaderName::from_bytes(self.name.as_bytes())?,
+            HeaderValue::from_str(&self.value)?,
+        ))
+    }
+}
+
+/// A module to handle requests for a single hostname
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+struct HostModule {
+    host: String,
+    wasm: PathBuf,
+}
+
+/// A synthetic response to answer requests for a path with, without invoking the application
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+struct Stub {
+    path: String,
+    status: u16,
+    #[serde(default)]
+    body: String,
+}
+
+impl Stub {
+    fn respond(&self) -> Response<Body> {
+        Response::builder()
+            .status(self.status)
+            .body(Body::from(self.body.clone()))
+            .expect("invalid stub response")
+    }
+}
+
+/// The hostname a tls client requested with SNI, recorded on each request from its connection
+#[derive(Clone, Debug)]
+struct ServerName(String);
+
+/// Selects the module configured for a request's hostname, preferring the SNI hostname
+/// of its tls connection over its Host header
+fn route<'a>(
+    host_modules: &'a HashMap<String, Module>,
+    req: &Request<Body>,
+) -> Option<&'a Module> {
+    if host_modules.is_empty() {
+        return None;
+    }
+    let host = match req.extensions().get::<ServerName>() {
+        Some(ServerName(name)) => Some(name.to_ascii_lowercase()),
+        _ => req
+            .headers()
+            .get(HOST)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.parse::<Authority>().ok())
+            .map(|a| a.host().to_ascii_lowercase()),
+    };
+    host.and_then(|host| host_modules.get(&host))
 }
 
 // re-writing uri to add host and authority. fastly requests validate these are present before sending them upstream
@@ -98,15 +220,17 @@ fn rewrite_uri(
 fn log_prefix(
     req: &Request<Body>,
     client_ip: &Option<IpAddr>,
+    request_id: &RequestId,
 ) -> String {
     format!(
         "{} \"{} {} {}\"",
         format!(
-            "{} - - [{}]",
+            "{} - - [{}] {}",
             client_ip
                 .map(|ip| ip.to_string())
                 .unwrap_or_else(|| "-".into()),
-            Local::now().to_rfc3339()
+            Local::now().to_rfc3339(),
+            request_id.0
         )
         .dimmed(),
         req.method(),
@@ -131,12 +255,12 @@ fn log_suffix(
     )
 }
 
-struct HyperAcceptor<'a> {
-    acceptor: Pin<Box<dyn Stream<Item = Result<TlsStream<TcpStream>, anyhow::Error>> + 'a>>,
+struct HyperAcceptor<'a, C> {
+    acceptor: Pin<Box<dyn Stream<Item = Result<C, anyhow::Error>> + 'a>>,
 }
 
-impl hyper::server::accept::Accept for HyperAcceptor<'_> {
-    type Conn = TlsStream<TcpStream>;
+impl<C> hyper::server::accept::Accept for HyperAcceptor<'_, C> {
+    type Conn = C;
     type Error = anyhow::Error;
 
     fn poll_accept(
@@ -147,6 +271,39 @@ impl hyper::server::accept::Accept for HyperAcceptor<'_> {
     }
 }
 
+/// Accepts plain http connections, limiting how slowly their clients may send requests
+fn accept_plain(
+    listener: std::net::TcpListener,
+    timeouts: ClientTimeouts,
+) -> io::Result<(SocketAddr, HyperAcceptor<'static, TimeoutStream<TcpStream>>)> {
+    listener.set_nonblocking(true)?;
+    let tcp = TcpListener::from_std(listener)?;
+    let addr = tcp.local_addr()?;
+    let acceptor = async_stream::stream! {
+        loop {
+            match tcp.accept().await {
+                Ok((socket, _)) => {
+                    if let Err(e) = socket.set_nodelay(true) {
+                        log::debug!("failed to set TCP_NODELAY: {}", e);
+                    }
+                    yield Ok(TimeoutStream::new(socket, timeouts));
+                }
+                Err(e) => {
+                    // errors like running out of file descriptors pass, so wait for them to
+                    log::error!("Incoming tcp request failed: {}", e);
+                    sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    };
+    Ok((
+        addr,
+        HyperAcceptor {
+            acceptor: Box::pin(acceptor),
+        },
+    ))
+}
+
 fn load_module(
     engine: &Engine,
     file: impl AsRef<Path>,
@@ -161,6 +318,8 @@ fn load_module(
     );
     let s = SystemTime::now();
     let module = Module::from_file(&engine, file)?;
+    check_imports(&module)?;
+    check_start(&module)?;
     println!(
         " {} {}oaded module in {:?} ✨",
         "✔".bold().green(),
@@ -170,324 +329,3117 @@ fn load_module(
     Ok(module)
 }
 
+/// Verifies a module imports WASI and otherwise only imports Fastly hostcalls, so modules built
+/// for other targets fail with a clearer message than instantiation's missing import errors
+fn check_imports(module: &Module) -> anyhow::Result<()> {
+    let mut wasi = false;
+    for import in module.imports() {
+        match import.module() {
+            "wasi_snapshot_preview1" => wasi = true,
+            fastly if fastly.starts_with("fastly_") => (),
+            other => {
+                return Err(anyhow!(
+                    "wasm module imports `{}` from `{}`, which is neither WASI nor a Fastly hostcall. Did you build for wasm32-wasi with the Fastly SDK?",
+                    import.name().unwrap_or_default(),
+                    other
+                ))
+            }
+        }
+    }
+    if !wasi {
+        return Err(anyhow!(
+            "wasm module doesn't import WASI. Did you build for wasm32-wasi with the Fastly SDK?"
+        ));
+    }
+    Ok(())
+}
+
+/// Verifies a module exports the `_start` func each request is run with
+fn check_start(module: &Module) -> anyhow::Result<()> {
+    if module
+        .exports()
+        .any(|export| export.name() == "_start" && matches!(export.ty(), ExternType::Func(_)))
+    {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "wasm module does not export a `_start` func. Make sure it is a Compute@Edge application built for the wasm32-wasi target, for instance with `fastly compute build`"
+    ))
+}
+
 #[doc(hidden)]
 #[derive(Clone)]
 struct State {
-    module: Module,
+    /// the application, absent when echoing requests with --echo
+    module: Option<Module>,
+    /// modules selected by hostname in place of `module`
+    host_modules: HashMap<String, Module>,
     engine: Engine,
     backends: Option<Vec<Backend>>,
-    dictionaries: HashMap<String, HashMap<String, String>>,
-}
-
-fn tls_config(
-    cert: impl AsRef<Path>,
-    key: impl AsRef<Path>,
-) -> Result<rustls::ServerConfig, BoxError> {
-    let certs = pemfile::certs(&mut BufReader::new(File::open(cert)?));
-    let key = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(key)?));
-    let mut cfg = rustls::ServerConfig::new(rustls::NoClientAuth::new());
-    cfg.set_single_cert(
-        certs.map_err(|_| anyhow!("unable to load tls certificate"))?,
-        key.map_err(|_| anyhow!("unable to load tls private key"))?[0].clone(),
-    )
-    .map_err(|e| anyhow!(e.to_string()))?;
-    // Configure ALPN to accept HTTP/2, HTTP/1.1 in that order.
-    cfg.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
-    Ok(cfg)
+    backend_headers: Vec<BackendHeader>,
+    backend_fallback: BackendFallback,
+    unknown_backend: UnknownBackend,
+    identity_clients: HashMap<String, reqwest::Client>,
+    /// backend hosts resolved at startup with --backends-from-dns
+    resolved_backends: backend::Resolved,
+    dictionaries: Dictionaries,
+    settings: Settings,
+    once: Option<Arc<Once>>,
+    error_page: ErrorPage,
+    fastly_headers: bool,
+    delay: Delay,
+    limits: Limits,
+    normalize_methods: bool,
+    cors: bool,
+    conditional: bool,
+    ranges: bool,
+    health: Health,
+    extra_downstream_headers: Vec<(HeaderName, HeaderValue)>,
+    rewrite_rules: Rules,
+    response_headers: ResponseHeaders,
+    /// how long to wait on the application before answering with a 504
+    request_timeout: Option<Duration>,
+    /// where to write the access log, rather than stdout
+    access_log: Option<AccessLog>,
+    /// backend requests in flight, when concurrent identical requests are coalesced
+    in_flight: Option<backend::InFlight>,
+    /// the cap on instances of the application running at once
+    instances: Option<Instances>,
+    /// responses to answer paths with in place of the application, the first match winning
+    stubs: Vec<Stub>,
+    /// whether downstream request uris are made absolute, with the Host header as their authority,
+    /// before the application receives them
+    default_host_rewrite: bool,
+    /// answers every backend request in place of the configured backends
+    #[cfg(test)]
+    test_backends: Option<Arc<dyn Backends + Send + Sync>>,
 }
 
-async fn run(opts: Opts) -> Result<(), BoxError> {
-    let Opts {
-        wasm,
-        port,
-        backends,
-        dictionaries,
-        tls_cert,
-        tls_key,
-        watch,
-        config_file: _,
-    } = opts;
+/// Answers health checks on a configured path without invoking the application
+#[derive(Clone)]
+struct Health {
+    path: Option<String>,
+    started: Instant,
+    /// why the latest attempt to reload the module failed, if it did
+    module_error: Option<String>,
+}
 
-    let engine = Engine::default();
+impl Default for Health {
+    fn default() -> Self {
+        Health {
+            path: None,
+            started: Instant::now(),
+            module_error: None,
+        }
+    }
+}
 
-    let module = load_module(&engine, &wasm, true)?;
+impl Health {
+    /// Responds with a 200, or a 503 when the module failed to load, for requests to the health path
+    fn check(
+        &self,
+        req: &Request<Body>,
+    ) -> Option<Response<Body>> {
+        if self.path.as_deref() != Some(req.uri().path()) {
+            return None;
+        }
+        let mut body = serde_json::json!({
+            "module_loaded": self.module_error.is_none(),
+            "uptime_secs": self.started.elapsed().as_secs(),
+        });
+        if let Some(error) = &self.module_error {
+            body["error"] = error.as_str().into();
+        }
+        Some(
+            Response::builder()
+                .status(if self.module_error.is_none() {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                })
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .expect("invalid health response"),
+        )
+    }
+}
 
-    let addr = ([127, 0, 0, 1], port).into();
+/// Limits on downstream request uris and headers, checked before invoking the application
+#[derive(Clone, Copy, Default)]
+struct Limits {
+    max_headers: Option<usize>,
+    max_header_size: Option<usize>,
+    max_uri_length: Option<usize>,
+}
 
-    // dictionaries of the same name can come from both the CLI params and config file,
-    // so merge them here. The correct order is provided in opts.rs.
-    let dictionaries: HashMap<String, HashMap<String, String>> = dictionaries
-        .unwrap_or_default()
-        .into_iter()
-        .fold(HashMap::new(), |mut map, d| {
-            map.entry(d.name).or_default().extend(d.entries.into_iter());
-            map
+impl Limits {
+    /// Returns a 414 response when a request's uri exceeds these limits, or a 431 response
+    /// when its headers do
+    ///
+    /// A header's size is the length of its name plus the length of its value
+    fn check(
+        &self,
+        req: &Request<Body>,
+    ) -> Option<Response<Body>> {
+        if let Some(max) = self.max_uri_length {
+            if req.uri().to_string().len() > max {
+                return Some(
+                    Response::builder()
+                        .status(StatusCode::URI_TOO_LONG)
+                        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+                        .body(Body::from("414 URI Too Long\n"))
+                        .expect("invalid limit response"),
+                );
+            }
+        }
+        let too_many = self
+            .max_headers
+            .filter(|max| req.headers().len() > *max)
+            .is_some();
+        let too_large = self.max_header_size.filter(|max| {
+            req.headers()
+                .iter()
+                .any(|(name, value)| name.as_str().len() + value.len() > *max)
         });
+        if !too_many && too_large.is_none() {
+            return None;
+        }
+        Some(
+            Response::builder()
+                .status(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+                .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+                .body(Body::from("431 Request Header Fields Too Large\n"))
+                .expect("invalid limit response"),
+        )
+    }
+}
 
-    let state = Arc::new(RwLock::new(State {
-        module,
-        engine: engine.clone(),
-        backends: backends.clone(),
-        dictionaries,
-    }));
-    println!("DEBUG: {:?}", state.read().unwrap().dictionaries);
-    let moved_state = state.clone();
-
-    match (tls_cert, tls_key) {
-        (Some(cert), Some(key)) => {
-            let tls_acceptor = TlsAcceptor::from(Arc::new(tls_config(cert, key)?));
-            let tcp = TcpListener::bind(&addr).await?;
-            let acceptor = async_stream::stream! {
-                loop {
-                    let (socket, _) = tcp.accept().await.map_err(|e|  anyhow!(format!("Incoming tpc request failed: {}", e)))?;
-                    let stream = tls_acceptor.accept(socket).map_err(|e| anyhow!(format!("TLS Error: {:?}", e)));
-                    yield stream.await;
-                }
-            }.filter(|res|  ready(res.is_ok()));
-            let server = Box::new(
-                Server::builder(HyperAcceptor {
-                    acceptor: Box::pin(acceptor),
-                })
-                .serve(make_service_fn(move |conn: &TlsStream<TcpStream>| {
-                    let state = moved_state.clone();
-                    let client_ip = conn.get_ref().0.peer_addr().ok().map(|addr| addr.ip());
-                    async move {
-                        Ok::<_, anyhow::Error>(service_fn(move |req| {
-                            let State {
-                                module,
-                                engine,
-                                backends,
-                                dictionaries,
-                            } = state.read().unwrap().clone();
-                            async move {
-                                let start = Instant::now();
-                                let log = log_prefix(&req, &client_ip);
-                                Ok::<Response<Body>, anyhow::Error>(
-                                    spawn_blocking(move || {
-                                        Handler::new(
-                                            rewrite_uri(req, Scheme::HTTPS).expect("invalid uri"),
-                                        )
-                                        .run(
-                                            &module,
-                                            Store::new(&engine),
-                                            if let Some(backends) = backends {
-                                                Box::new(backend::Proxy::new(backends))
-                                            } else {
-                                                backend::default()
-                                            },
-                                            dictionaries,
-                                            client_ip,
-                                        )
-                                        .map_err(|e| {
-                                            log::debug!("Handler::run error: {}", e);
-                                            anyhow!(e.to_string())
-                                        })
-                                        .map(|res| {
-                                            println!("{} {}", log, log_suffix(&res, start));
-                                            res
-                                        })
-                                    })
-                                    .await??,
-                                )
-                            }
-                        }))
-                    }
-                })),
-            );
+/// Headers added to every final response, to simulate headers an edge adds on its own
+#[derive(Clone, Default)]
+struct ResponseHeaders {
+    headers: Vec<(HeaderName, HeaderValue)>,
+    /// replace headers of the same name the application set, rather than keeping them
+    force: bool,
+}
 
-            println!(" {} Listening on https://{}", "●".bold().green(), addr);
-            if let Some(backends) = backends {
-                println!("   {} Backends", "❯".dimmed());
-                for b in backends {
-                    println!("     {} > {}", b.name, b.address);
-                }
+impl ResponseHeaders {
+    fn apply(
+        &self,
+        resp: &mut Response<Body>,
+    ) {
+        let set: Vec<HeaderName> = self
+            .headers
+            .iter()
+            .filter(|(name, _)| resp.headers().contains_key(name))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in &set {
+            if self.force {
+                resp.headers_mut().remove(name);
             }
-
-            // assign to something to prevent watch resources from being dropped
-            let _watcher = if watch {
-                Some(monitor(&wasm, engine, state)?)
-            } else {
-                None
-            };
-            server.await?
         }
-        _ => {
-            let server = Box::new(Server::try_bind(&addr)?.serve(make_service_fn(
-                move |conn: &AddrStream| {
-                    let state = moved_state.clone();
-                    let client_ip = Some(conn.remote_addr().ip());
-                    async move {
-                        Ok::<_, anyhow::Error>(service_fn(move |req| {
-                            let start = Instant::now();
-                            let log = log_prefix(&req, &client_ip);
-                            let State {
-                                module,
-                                engine,
-                                backends,
-                                dictionaries,
-                            } = state.read().expect("unable to lock server state").clone();
-                            async move {
-                                Ok::<Response<Body>, anyhow::Error>(
-                                    spawn_blocking(move || {
-                                        Handler::new(
-                                            rewrite_uri(req, Scheme::HTTP).expect("invalid uri"),
-                                        )
-                                        .run(
-                                            &module,
-                                            Store::new(&engine),
-                                            if let Some(backends) = backends {
-                                                Box::new(backend::Proxy::new(backends))
-                                            } else {
-                                                backend::default()
-                                            },
-                                            dictionaries,
-                                            client_ip,
-                                        )
-                                        .map_err(|e| {
-                                            log::debug!("Handler::run error: {}", e);
-                                            anyhow!(e.to_string())
-                                        })
-                                        .map(|res| {
-                                            println!("{} {}", log, log_suffix(&res, start));
-                                            res
-                                        })
-                                    })
-                                    .await??,
-                                )
-                            }
-                        }))
-                    }
-                },
-            )));
-
-            println!(" {} Listening on http://{}", "●".bold().green(), addr);
-            if let Some(backends) = backends {
-                println!("   {} Backends", "❯".dimmed());
-                for b in backends {
-                    println!("     {} > {}", b.name, b.address);
-                }
+        for (name, value) in &self.headers {
+            if self.force || !set.contains(name) {
+                resp.headers_mut().append(name.clone(), value.clone());
             }
-
-            // assign to something to prevent watch resources from being dropped
-            let _watcher = if watch {
-                Some(monitor(&wasm, engine, state)?)
-            } else {
-                None
-            };
-
-            server.await?;
         }
-    };
+    }
+}
 
-    // server.await?;
+/// The error a request fails with when it isn't handled within --request-timeout
+#[derive(Debug)]
+struct RequestTimeout(Duration);
 
-    Ok(())
+impl fmt::Display for RequestTimeout {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        write!(f, "request timed out after {:.2?}", self.0)
+    }
 }
 
-fn monitor(
-    wasm: &PathBuf,
-    engine: Engine,
-    state: Arc<RwLock<State>>,
-) -> Result<(notify::RecommendedWatcher, tokio::task::JoinHandle<()>), BoxError> {
-    // For receiving events from notify's watcher
-    let (tx, rx) = channel();
-    // Create a watcher object, delivering debounced events. The Duration is how
-    // long the watcher waits after each raw event to combine things into one
-    // debounced event.
-    let mut watcher = watcher(tx, Duration::from_secs(1))?;
+impl Error for RequestTimeout {}
 
-    // Monitor the parent, because deleting the file removes the watch on some
-    // platforms, but not all. So monitor the directory it's in, and then filter
-    // for the specific file. Canonicalize because the watcher deals in absolute
-    // paths. (Or at least it does on Linux.)
-    let wasm = fs::canonicalize(wasm)?;
-    let wasmdir = &wasm.parent().expect("expected parent directory to exist");
-    println!(" Watching for changes...");
-    watcher.watch(wasmdir, RecursiveMode::Recursive)?;
+impl RequestTimeout {
+    fn respond(&self) -> Response<Body> {
+        Response::builder()
+            .status(StatusCode::GATEWAY_TIMEOUT)
+            .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(Body::from("504 Gateway Timeout\n"))
+            .expect("invalid timeout response")
+    }
+}
 
-    // Unfortunately notify's watcher doesn't work with async channels, so let's
-    // have a thread for the blocking read from that.
-    let handle = spawn_blocking(move || loop {
-        let event = rx.recv();
-        match &event {
-            Ok(DebouncedEvent::Chmod(path))
-            | Ok(DebouncedEvent::Create(path))
-            | Ok(DebouncedEvent::Rename(_, path))
-            | Ok(DebouncedEvent::Remove(path))
-            | Ok(DebouncedEvent::Write(path)) => {
-                if *path == wasm {
-                    log::trace!("notify: {:?}", event);
-                    if let Ok(module) = load_module(&engine, &wasm, false) {
-                        match state.write() {
-                            Ok(mut guard) => guard.module = module,
-                            _ => break,
-                        }
-                    }
-                }
-            }
-            Err(e) => {
-                log::trace!("watch error: {:?}", e);
-                break;
-            }
-            _ => (),
-        }
-    });
-    Ok((watcher, handle))
+/// The error a request fails with when no instance frees up within --max-instances-timeout
+#[derive(Debug)]
+struct InstancesBusy(Duration);
+
+impl fmt::Display for InstancesBusy {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        write!(f, "no instance was free after waiting {:.2?}", self.0)
+    }
 }
 
-#[tokio::main]
-async fn main() {
-    pretty_env_logger::init();
-    if let Err(e) = run(Opts::merge_from_args_and_toml()).await {
-        eprintln!(" {} error: {}", "✖".bold().red(), e);
-        exit(1);
+impl Error for InstancesBusy {}
+
+impl InstancesBusy {
+    fn respond(&self) -> Response<Body> {
+        Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(Body::from("503 Service Unavailable\n"))
+            .expect("invalid busy response")
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use hyper::body::to_bytes;
-    use std::str;
+/// Caps how many instances of the application run at once, bounding their memory. Requests
+/// beyond the cap wait for an instance to finish, up to a timeout
+#[derive(Clone)]
+struct Instances {
+    permits: Arc<Semaphore>,
+    timeout: Duration,
+}
 
-    lazy_static::lazy_static! {
-        pub (crate) static ref WASM: Option<(Engine, Module)> =
-            match Path::new("./tests/app/target/wasm32-wasi/release/app.wasm") {
-                path if !path.exists() => {
-                    pretty_env_logger::init();
-                    log::debug!("test wasm app is absent. will skip wasm tests");
-                    None
-                }
-                path => {
-                    pretty_env_logger::init();
-                    log::debug!("loading wasm for test");
-                    let engine = Engine::default();
-                    Module::from_file(&engine, path)
-                        .ok()
-                        .map(|module| (engine, module))
-                }
-            };
+impl Instances {
+    fn new(
+        max: usize,
+        timeout: Duration,
+    ) -> Self {
+        Instances {
+            permits: Arc::new(Semaphore::new(max)),
+            timeout,
+        }
     }
 
-    pub(crate) async fn body(resp: Response<Body>) -> Result<String, BoxError> {
-        Ok(str::from_utf8(&to_bytes(resp.into_body()).await?)?.to_owned())
+    /// Waits for a free instance, which is taken until the returned permit is dropped
+    async fn acquire(&self) -> Result<OwnedSemaphorePermit, InstancesBusy> {
+        if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+        log::warn!("all instances are busy, queueing request");
+        match tokio::time::timeout(self.timeout, self.permits.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(permit),
+            _ => Err(InstancesBusy(self.timeout)),
+        }
     }
+}
 
-    #[test]
-    fn test_rewrite_uri_http() -> Result<(), BoxError> {
-        let req = Request::builder()
-            .uri("/foo")
-            .header(HOST, "fasttime.co")
-            .body(Body::empty())?;
-        let rewritten = rewrite_uri(req, Scheme::HTTP)?;
-        assert_eq!(
+/// An artificial delay added before sending responses, to simulate a slow edge
+#[derive(Clone, Copy, Default)]
+struct Delay {
+    fixed: Duration,
+    jitter: Duration,
+}
+
+impl Delay {
+    /// Returns the fixed delay plus a random amount of jitter
+    fn sample(&self) -> Duration {
+        if self.jitter == Duration::default() {
+            return self.fixed;
+        }
+        self.fixed + self.jitter.mul_f64(rand::random::<f64>())
+    }
+}
+
+/// The response served when the application fails to handle a request
+#[derive(Clone)]
+struct ErrorPage {
+    content_type: &'static str,
+    body: Bytes,
+}
+
+impl Default for ErrorPage {
+    fn default() -> Self {
+        ErrorPage {
+            content_type: "text/plain; charset=utf-8",
+            body: Bytes::from_static(
+                b"500 Internal Server Error: the application failed to handle this request\n",
+            ),
+        }
+    }
+}
+
+impl ErrorPage {
+    /// Loads an error page from a file, inferring its content type from its extension
+    fn load(path: impl AsRef<Path>) -> Result<Self, BoxError> {
+        let path = path.as_ref();
+        let content_type = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("html") | Some("htm") => "text/html; charset=utf-8",
+            Some("json") => "application/json",
+            _ => "text/plain; charset=utf-8",
+        };
+        let body = fs::read(path)
+            .map_err(|e| anyhow!("unable to read error page {}: {}", path.display(), e))?;
+        Ok(ErrorPage {
+            content_type,
+            body: body.into(),
+        })
+    }
+
+    /// Builds a 500 response, with a short reason in a `Fastly-Error` header when `fastly_headers` is set
+    fn respond(
+        &self,
+        error: &anyhow::Error,
+        fastly_headers: bool,
+    ) -> Response<Body> {
+        let mut builder = Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .header(CONTENT_TYPE, self.content_type);
+        if fastly_headers {
+            let reason = error.to_string();
+            let reason = reason.lines().next().unwrap_or_default();
+            builder = builder.header(
+                "Fastly-Error",
+                HeaderValue::from_str(reason)
+                    .unwrap_or_else(|_| HeaderValue::from_static("handler error")),
+            );
+        }
+        builder
+            .body(Body::from(self.body.clone()))
+            .expect("invalid error response")
+    }
+}
+
+/// Signals the server to stop after its first request when running with `--once`
+#[derive(Default)]
+struct Once {
+    done: Notify,
+    failed: AtomicBool,
+}
+
+impl Once {
+    fn finish(
+        &self,
+        ok: bool,
+    ) {
+        self.failed.store(!ok, Ordering::SeqCst);
+        self.done.notify_one();
+    }
+}
+
+/// Upper cases standard methods sent in another case, like `get`, rejecting
+/// extension methods with a 501
+fn normalize_method(req: &mut Request<Body>) -> Option<Response<Body>> {
+    match Method::from_bytes(req.method().as_str().to_ascii_uppercase().as_bytes()) {
+        Ok(method)
+            if [
+                Method::GET,
+                Method::HEAD,
+                Method::POST,
+                Method::PUT,
+                Method::DELETE,
+                Method::CONNECT,
+                Method::OPTIONS,
+                Method::TRACE,
+                Method::PATCH,
+            ]
+            .contains(&method) =>
+        {
+            *req.method_mut() = method;
+            None
+        }
+        _ => Some(
+            Response::builder()
+                .status(StatusCode::NOT_IMPLEMENTED)
+                .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+                .body(Body::from("501 Not Implemented\n"))
+                .expect("invalid method response"),
+        ),
+    }
+}
+
+/// Responds to CORS preflight requests with permissive headers, allowing
+/// whatever origin, method, and headers were requested
+fn cors_preflight(req: &Request<Body>) -> Option<Response<Body>> {
+    if req.method() != Method::OPTIONS
+        || !req.headers().contains_key(ORIGIN)
+        || !req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+    {
+        return None;
+    }
+    let mut builder = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+        .header(ACCESS_CONTROL_MAX_AGE, "86400");
+    for (requested, allowed) in &[
+        (ACCESS_CONTROL_REQUEST_METHOD, ACCESS_CONTROL_ALLOW_METHODS),
+        (ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_ALLOW_HEADERS),
+    ] {
+        if let Some(value) = req.headers().get(requested) {
+            builder = builder.header(allowed, value);
+        }
+    }
+    Some(
+        builder
+            .body(Body::empty())
+            .expect("invalid preflight response"),
+    )
+}
+
+/// Allows any origin to read a response, unless the application already set CORS headers
+fn allow_cors(resp: &mut Response<Body>) {
+    for name in &[ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS] {
+        resp.headers_mut()
+            .entry(name)
+            .or_insert_with(|| HeaderValue::from_static("*"));
+    }
+}
+
+/// Returns whether a client asked to keep its connection alive, with a keep-alive option in any
+/// of its comma separated Connection headers
+fn keep_alive(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|option| option.trim().eq_ignore_ascii_case("keep-alive"))
+}
+
+/// Frames a response in the client's HTTP version when the application set a newer one,
+/// closing HTTP/1.0 connections the client did not ask to keep alive. hyper enforces
+/// the version on the wire too, but reconciling it here keeps the access log truthful
+fn downgrade(
+    mut resp: Response<Body>,
+    version: Version,
+    keep_alive: bool,
+) -> Response<Body> {
+    if version >= resp.version() {
+        return resp;
+    }
+    *resp.version_mut() = version;
+    if version == Version::HTTP_10 && !keep_alive {
+        resp.headers_mut()
+            .insert(CONNECTION, HeaderValue::from_static("close"));
+    }
+    resp
+}
+
+/// Validators a client sent with a conditional GET or HEAD request
+struct Conditions {
+    if_none_match: Option<HeaderValue>,
+    if_modified_since: Option<HeaderValue>,
+}
+
+impl Conditions {
+    fn from_request(req: &Request<Body>) -> Option<Self> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return None;
+        }
+        Some(Conditions {
+            if_none_match: req.headers().get(IF_NONE_MATCH).cloned(),
+            if_modified_since: req.headers().get(IF_MODIFIED_SINCE).cloned(),
+        })
+    }
+
+    /// Replaces a 200 response with a bodiless 304 when its ETag or Last-Modified
+    /// header matches the client's validators. If-Modified-Since is ignored when
+    /// If-None-Match is present
+    fn apply(
+        &self,
+        resp: Response<Body>,
+    ) -> Response<Body> {
+        if resp.status() != StatusCode::OK {
+            return resp;
+        }
+        let not_modified = match &self.if_none_match {
+            Some(if_none_match) => resp
+                .headers()
+                .get(ETAG)
+                .map(|etag| etag_matches(if_none_match, etag))
+                .unwrap_or_default(),
+            _ => match (&self.if_modified_since, resp.headers().get(LAST_MODIFIED)) {
+                (Some(since), Some(last_modified)) => {
+                    match (http_date(since), http_date(last_modified)) {
+                        (Some(since), Some(last_modified)) => last_modified <= since,
+                        _ => false,
+                    }
+                }
+                _ => false,
+            },
+        };
+        if !not_modified {
+            return resp;
+        }
+        let (mut parts, _) = resp.into_parts();
+        parts.status = StatusCode::NOT_MODIFIED;
+        parts.headers.remove(CONTENT_LENGTH);
+        Response::from_parts(parts, Body::empty())
+    }
+}
+
+/// Weakly compares an ETag against a list of ETags from an If-None-Match header
+fn etag_matches(
+    if_none_match: &HeaderValue,
+    etag: &HeaderValue,
+) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_owned();
+    match (if_none_match.to_str(), etag.to_str()) {
+        (Ok(if_none_match), Ok(etag)) => if_none_match
+            .split(',')
+            .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag)),
+        _ => false,
+    }
+}
+
+/// Parses an HTTP date, like Wed, 21 Oct 2015 07:28:00 GMT
+fn http_date(value: &HeaderValue) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_rfc2822(value.to_str().ok()?).ok()
+}
+
+/// A single byte range a client requested with a Range header
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ByteRange {
+    /// bytes=start- or bytes=start-end, where end is inclusive
+    From(u64, Option<u64>),
+    /// bytes=-n, the last n bytes
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// Parses the Range header of a GET request. Requests for multiple ranges get full responses
+    fn from_request(req: &Request<Body>) -> Option<Self> {
+        if req.method() != Method::GET {
+            return None;
+        }
+        let spec = req
+            .headers()
+            .get(RANGE)?
+            .to_str()
+            .ok()?
+            .trim()
+            .strip_prefix("bytes=")?;
+        if spec.contains(',') {
+            return None;
+        }
+        let (start, end) = spec.split_at(spec.find('-')?);
+        let end = end[1..].trim();
+        match start.trim() {
+            "" => end.parse().ok().map(ByteRange::Suffix),
+            start => {
+                let start = start.parse().ok()?;
+                let end = match end {
+                    "" => None,
+                    end => Some(end.parse().ok()?),
+                };
+                match end {
+                    Some(end) if end < start => None,
+                    end => Some(ByteRange::From(start, end)),
+                }
+            }
+        }
+    }
+
+    /// Resolves this range against the length of a body, returning its inclusive
+    /// first and last byte positions, or None when it is unsatisfiable
+    fn resolve(
+        self,
+        len: u64,
+    ) -> Option<(u64, u64)> {
+        match self {
+            ByteRange::From(start, _) if start >= len => None,
+            ByteRange::From(start, end) => Some((start, end.unwrap_or(len - 1).min(len - 1))),
+            ByteRange::Suffix(0) => None,
+            ByteRange::Suffix(_) if len == 0 => None,
+            ByteRange::Suffix(n) => Some((len.saturating_sub(n), len - 1)),
+        }
+    }
+
+    /// Replaces a 200 response with a 206 Partial Content response for this range, or a
+    /// 416 Range Not Satisfiable response when the range lies outside of its body
+    async fn apply(
+        self,
+        resp: Response<Body>,
+    ) -> Result<Response<Body>, hyper::Error> {
+        if resp.status() != StatusCode::OK || resp.headers().contains_key(CONTENT_RANGE) {
+            return Ok(resp);
+        }
+        let (mut parts, body) = resp.into_parts();
+        let body = to_bytes(body).await?;
+        let len = body.len() as u64;
+        let (body, content_range) = match self.resolve(len) {
+            Some((first, last)) => {
+                parts.status = StatusCode::PARTIAL_CONTENT;
+                (
+                    body.slice(first as usize..=last as usize),
+                    format!("bytes {}-{}/{}", first, last, len),
+                )
+            }
+            _ => {
+                parts.status = StatusCode::RANGE_NOT_SATISFIABLE;
+                (Bytes::new(), format!("bytes */{}", len))
+            }
+        };
+        parts.headers.insert(
+            CONTENT_RANGE,
+            HeaderValue::from_str(&content_range).expect("invalid content range"),
+        );
+        parts
+            .headers
+            .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+        Ok(Response::from_parts(parts, Body::from(body)))
+    }
+}
+
+/// Removes the body of a response to a HEAD request, as HTTP requires, keeping
+/// the Content-Length of the body the application produced
+async fn strip_body(resp: Response<Body>) -> Result<Response<Body>, hyper::Error> {
+    let (mut parts, body) = resp.into_parts();
+    let body = to_bytes(body).await?;
+    if !parts.headers.contains_key(CONTENT_LENGTH) {
+        parts
+            .headers
+            .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+    }
+    Ok(Response::from_parts(parts, Body::empty()))
+}
+
+/// Describes a request's method, path, headers, and body as JSON, for smoke testing
+/// the server without an application
+async fn echo(req: Request<Body>) -> Result<Response<Body>, anyhow::Error> {
+    let (parts, body) = req.into_parts();
+    let body = to_bytes(body).await?;
+    let headers = parts.headers.iter().fold(
+        BTreeMap::<_, Vec<_>>::new(),
+        |mut headers, (name, value)| {
+            headers
+                .entry(name.as_str())
+                .or_default()
+                .push(String::from_utf8_lossy(value.as_bytes()));
+            headers
+        },
+    );
+    let json = serde_json::json!({
+        "method": parts.method.as_str(),
+        "path": parts.uri.path_and_query().map_or("/", |path| path.as_str()),
+        "headers": headers,
+        "body": String::from_utf8_lossy(&body),
+    });
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(json.to_string()))?)
+}
+
+/// Binds a listener with a given accept backlog, defaulting to tokio's own default of 1024
+fn bind(
+    addr: SocketAddr,
+    backlog: Option<u32>,
+) -> io::Result<std::net::TcpListener> {
+    let domain = if addr.is_ipv4() {
+        Domain::ipv4()
+    } else {
+        Domain::ipv6()
+    };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    // match std and tokio, which allow rebinding addresses in TIME_WAIT on unix
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&SockAddr::from(addr))?;
+    let backlog = backlog.unwrap_or(1024);
+    let backlog = i32::try_from(backlog).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("backlog {} exceeds the maximum of {}", backlog, i32::MAX),
+        )
+    })?;
+    socket.listen(backlog)?;
+    Ok(socket.into_tcp_listener())
+}
+
+/// Resolves when the server should gracefully shut down
+async fn shutdown(once: Option<Arc<Once>>) {
+    match once {
+        Some(once) => once.done.notified().await,
+        _ => pending().await,
+    }
+}
+
+/// Runs each request recorded in a HAR file through `handle`, in order, printing how each
+/// response differs from the recorded one. Returns the number of responses which differed
+async fn replay_har(
+    state: State,
+    har: &Har,
+) -> Result<usize, BoxError> {
+    let mut mismatched = 0;
+    for entry in &har.log.entries {
+        let (req, scheme) = entry.request()?;
+        let target = format!("{} {}", req.method(), req.uri());
+        let resp = handle(state.clone(), req, scheme, None).await?;
+        let status = resp.status();
+        let body = to_bytes(resp.into_body()).await?;
+        let differences = entry.compare(status, &body)?;
+        if differences.is_empty() {
+            println!(" {} {} matched", "✔".bold().green(), target);
+        } else {
+            mismatched += 1;
+            println!(
+                " {} {} {}",
+                "✘".bold().red(),
+                target,
+                differences.join(", ")
+            );
+        }
+    }
+    println!(
+        " {} {} matched, {} mismatched",
+        "●".bold(),
+        har.log.entries.len() - mismatched,
+        mismatched
+    );
+    Ok(mismatched)
+}
+
+/// Runs a downstream request through a new `Handler` on a blocking thread
+async fn handle(
+    state: State,
+    req: Request<Body>,
+    scheme: Scheme,
+    client_ip: Option<IpAddr>,
+) -> Result<Response<Body>, anyhow::Error> {
+    // health checks aren't application requests, so they're neither logged nor counted by --once
+    if let Some(resp) = state.health.check(&req) {
+        return Ok(resp);
+    }
+    let start = Instant::now();
+    let request_id = RequestId::next();
+    let mut req = req;
+    req.extensions_mut().insert(request_id.clone());
+    let log = log_prefix(&req, &client_ip, &request_id);
+    #[cfg(test)]
+    let test_backends = state.test_backends.clone();
+    let State {
+        module,
+        host_modules,
+        engine,
+        backends,
+        backend_headers,
+        backend_fallback,
+        unknown_backend,
+        identity_clients,
+        resolved_backends,
+        dictionaries,
+        settings,
+        once,
+        error_page,
+        fastly_headers,
+        delay,
+        limits,
+        normalize_methods,
+        cors,
+        conditional,
+        ranges,
+        extra_downstream_headers,
+        rewrite_rules,
+        response_headers,
+        request_timeout,
+        access_log,
+        in_flight,
+        instances,
+        stubs,
+        default_host_rewrite,
+        ..
+    } = state;
+    let access = access_log.map(|access_log| {
+        let entry = access_log::Entry::new(&req, &client_ip, &request_id);
+        (access_log, entry)
+    });
+    for (name, value) in extra_downstream_headers {
+        req.headers_mut().append(name, value);
+    }
+    rewrite_rules.apply(&mut req);
+    let rejected = limits
+        .check(&req)
+        .or_else(|| {
+            if normalize_methods {
+                normalize_method(&mut req)
+            } else {
+                None
+            }
+        })
+        .or_else(|| if cors { cors_preflight(&req) } else { None })
+        .or_else(|| {
+            stubs
+                .iter()
+                .find(|stub| stub.path == req.uri().path())
+                .map(Stub::respond)
+        });
+    let head = req.method() == Method::HEAD;
+    let version = req.version();
+    let keep_alive = keep_alive(req.headers());
+    let conditions = if conditional {
+        Conditions::from_request(&req)
+    } else {
+        None
+    };
+    let range = if ranges {
+        ByteRange::from_request(&req)
+    } else {
+        None
+    };
+    let module = route(&host_modules, &req).cloned().or(module);
+    let handled = async move {
+        match (rejected, module) {
+            (Some(resp), _) => Ok(resp),
+            (_, None) => echo(req).await,
+            (_, Some(module)) => {
+                let permit = match instances {
+                    Some(instances) => Some(instances.acquire().await?),
+                    _ => None,
+                };
+                let (tx, rx) = oneshot::channel();
+                spawn_blocking(move || {
+                    // the instance is freed once the guest finishes, even when its client
+                    // was answered earlier
+                    let _permit = permit;
+                    // a streamed response is answered with as soon as the guest sends it, rather
+                    // than when it finishes writing its body
+                    let tx = Rc::new(RefCell::new(Some(tx)));
+                    let streamed = tx.clone();
+                    let req = if default_host_rewrite {
+                        rewrite_uri(req, scheme).expect("invalid uri")
+                    } else {
+                        req
+                    };
+                    let result = Handler::new(req)
+                        .with_settings(settings)
+                        .with_streamed_response(move |resp| {
+                            if let Some(tx) = streamed.borrow_mut().take() {
+                                let _ = tx.send(Ok(resp));
+                            }
+                        })
+                        .run(
+                            &module,
+                            Store::new(&engine),
+                            {
+                                let backends: Box<dyn Backends> = match (backends, backend_fallback)
+                                {
+                                    (None, BackendFallback::Error) => Box::new(unknown_backend),
+                                    (backends, _) => Box::new(
+                                        backend::Proxy::new(
+                                            backends.unwrap_or_default(),
+                                            backend_headers,
+                                        )
+                                        .with_resolved(&resolved_backends)
+                                        .with_identity_clients(identity_clients)
+                                        .with_fallback(backend_fallback)
+                                        .with_unknown_backend(unknown_backend),
+                                    ),
+                                };
+                                #[cfg(test)]
+                                let backends: Box<dyn Backends> = match test_backends {
+                                    Some(test_backends) => {
+                                        Box::new(move |backend: &str, req: Request<Body>| {
+                                            test_backends.send(backend, req)
+                                        })
+                                    }
+                                    _ => backends,
+                                };
+                                match in_flight {
+                                    Some(in_flight) => {
+                                        Box::new(backend::Coalesce::new(backends, in_flight))
+                                    }
+                                    _ => backends,
+                                }
+                            },
+                            dictionaries,
+                            client_ip,
+                        )
+                        .map_err(|e| anyhow!(e.to_string()));
+                    match (tx.borrow_mut().take(), result) {
+                        (Some(tx), result) => {
+                            let _ = tx.send(result);
+                        }
+                        (_, Err(e)) => {
+                            log::error!("Handler::run error after streaming a response: {}", e)
+                        }
+                        _ => (),
+                    }
+                });
+                rx.await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|result| result)
+            }
+        }
+    };
+    // a blocking handler can't be interrupted, so one which times out is left to finish on its
+    // own thread while the client is answered
+    let result = match request_timeout {
+        Some(timeout) => tokio::select! {
+            result = handled => result,
+            _ = sleep(timeout) => Err(RequestTimeout(timeout).into()),
+        },
+        _ => handled.await,
+    };
+    let result = result.map(|resp| match &conditions {
+        Some(conditions) => conditions.apply(resp),
+        _ => resp,
+    });
+    let result = match (result, range) {
+        (Ok(resp), Some(range)) => range.apply(resp).await.map_err(anyhow::Error::from),
+        (result, _) => result,
+    };
+    let result = match result {
+        Ok(resp) if head && resp.status() != StatusCode::NOT_MODIFIED => {
+            strip_body(resp).await.map_err(anyhow::Error::from)
+        }
+        other => other,
+    };
+    let result = result.map(|mut resp| {
+        if cors {
+            allow_cors(&mut resp);
+        }
+        resp
+    });
+    if let Some(once) = once {
+        once.finish(result.is_ok());
+    }
+    let resp = result.unwrap_or_else(|e| {
+        if let Some(timeout) = e.downcast_ref::<RequestTimeout>() {
+            log::error!("{}", timeout);
+            return timeout.respond();
+        }
+        if let Some(busy) = e.downcast_ref::<InstancesBusy>() {
+            log::error!("rejected request: {}", busy);
+            return busy.respond();
+        }
+        log::error!("Handler::run error: {}", e);
+        error_page.respond(&e, fastly_headers)
+    });
+    let mut resp = resp;
+    response_headers.apply(&mut resp);
+    let mut resp = downgrade(resp, version, keep_alive);
+    if fastly_headers {
+        resp.headers_mut().insert(
+            "Fastly-Request-Id",
+            HeaderValue::from_str(&request_id.0).expect("invalid request id"),
+        );
+    }
+    let delay = delay.sample();
+    if delay > Duration::default() {
+        sleep(delay).await;
+    }
+    match access {
+        Some((access_log, entry)) => {
+            if let Err(e) = access_log.write(&entry.finish(&resp, start, delay)) {
+                log::error!("failed to write access log: {}", e);
+            }
+        }
+        _ if delay > Duration::default() => println!(
+            "{} {} {}",
+            log,
+            log_suffix(&resp, start),
+            format!("(incl. {:.2?} delay)", delay).dimmed()
+        ),
+        _ => println!("{} {}", log, log_suffix(&resp, start)),
+    }
+    Ok(resp)
+}
+
+async fn run(opts: Opts) -> Result<(), BoxError> {
+    let Opts {
+        wasm,
+        port,
+        backends,
+        backend_headers,
+        backend_certs,
+        dictionaries,
+        tls_cert,
+        tls_key,
+        tls_dir,
+        watch,
+        dump_bodies,
+        dump_backend_requests,
+        once,
+        error_page,
+        fastly_headers,
+        response_delay,
+        response_delay_jitter,
+        max_headers,
+        max_header_size,
+        max_uri_length,
+        normalize_methods,
+        strict_handles,
+        cors,
+        decompress_request,
+        trace_hostcalls,
+        timings,
+        conditional,
+        ranges,
+        backlog,
+        health_path,
+        disable_geolocation,
+        geo_abi,
+        backend_fallback,
+        log_endpoint_format,
+        echo,
+        rewrite_rules,
+        max_backend_requests,
+        max_response_body,
+        cache,
+        admin_port,
+        seed_cache,
+        guest_backtrace,
+        clock,
+        backends_from_dns,
+        force_response_header,
+        require_backends,
+        require_backends_timeout,
+        request_timeout,
+        client_header_timeout,
+        client_body_timeout,
+        unknown_backend_status,
+        unknown_backend_body,
+        unknown_backend_header,
+        wasi_config,
+        access_log,
+        access_log_max_size,
+        replay,
+        reload_command,
+        reload_dir,
+        dictionary_gzip,
+        coalesce_backends,
+        max_instances,
+        max_instances_timeout,
+        list_hostcalls,
+        no_default_host_rewrite,
+        extra_downstream_headers,
+        host_modules,
+        response_headers,
+        routes,
+        log_endpoints,
+        object_stores,
+        secret_stores,
+        config_file: _,
+    } = opts;
+
+    let engine = Engine::default();
+    if list_hostcalls {
+        print_hostcalls(&Handler::hostcalls(&engine)?);
+        return Ok(());
+    }
+    let stubs = routes.unwrap_or_default();
+    if let Some(stub) = stubs
+        .iter()
+        .find(|stub| StatusCode::from_u16(stub.status).is_err())
+    {
+        return Err(anyhow!("invalid status {} for route {}", stub.status, stub.path).into());
+    }
+
+    let module = if echo {
+        println!(
+            " {} Running in echo mode; no wasm loaded",
+            "●".bold().yellow()
+        );
+        None
+    } else {
+        Some(load_module(&engine, &wasm, true)?)
+    };
+
+    let host_modules = if echo {
+        Vec::new()
+    } else {
+        host_modules.unwrap_or_default()
+    };
+    let mut loaded_host_modules = HashMap::new();
+    for HostModule { host, wasm } in &host_modules {
+        loaded_host_modules.insert(host.to_ascii_lowercase(), load_module(&engine, wasm, true)?);
+    }
+
+    let addr = ([127, 0, 0, 1], port).into();
+    let listener = bind(addr, backlog)?;
+
+    // dictionaries of the same name can come from both the CLI params and config file,
+    // so merge them here. The correct order is provided in opts.rs.
+    let dictionaries =
+        dictionaries
+            .unwrap_or_default()
+            .into_iter()
+            .fold(Dictionaries::default(), |merged, d| {
+                merged
+                    .write()
+                    .entry(d.name)
+                    .or_default()
+                    .extend(d.entries.into_iter());
+                merged
+            });
+    fastly_dictionary::gunzip_values(
+        &mut dictionaries.write(),
+        &dictionary_gzip.unwrap_or_default(),
+    )?;
+
+    // there are no object or secret store hostcalls to answer with these yet, so say so
+    // rather than silently ignoring them
+    for (kind, name, len) in object_stores
+        .unwrap_or_default()
+        .into_iter()
+        .map(|store| ("object", store.name, store.entries.len()))
+        .chain(
+            secret_stores
+                .unwrap_or_default()
+                .into_iter()
+                .map(|store| ("secret", store.name, store.secrets.len())),
+        )
+    {
+        println!(
+            " {} Ignoring {} store {} ({} entries): {} stores are not supported yet",
+            "●".bold().yellow(),
+            kind,
+            name,
+            len,
+            kind
+        );
+    }
+    // endpoints of the same name can come from both the CLI params and config file. The
+    // CLI params come last, as provided in opts.rs, so they win
+    let log_files = log_endpoints
+        .unwrap_or_default()
+        .into_iter()
+        .map(|LogEndpoint { name, path }| (name, path))
+        .collect();
+
+    for dir in dump_bodies.iter().chain(dump_backend_requests.iter()) {
+        fs::create_dir_all(dir)?;
+    }
+
+    let error_page = match error_page {
+        Some(path) => ErrorPage::load(path)?,
+        _ => ErrorPage::default(),
+    };
+
+    if require_backends {
+        backend::check_reachable(
+            backends.as_deref().unwrap_or_default(),
+            require_backends_timeout.unwrap_or_else(|| Duration::from_secs(2)),
+        )?;
+    }
+
+    let resolved_backends = if backends_from_dns {
+        backend::resolve(backends.as_deref().unwrap_or_default())?
+    } else {
+        backend::Resolved::new()
+    };
+
+    let once = if once {
+        Some(Arc::new(Once::default()))
+    } else {
+        None
+    };
+
+    let cache = if cache || admin_port.is_some() || seed_cache.is_some() {
+        Some(Cache::default())
+    } else {
+        None
+    };
+    if let (Some(dir), Some(cache)) = (&seed_cache, &cache) {
+        let seeded = cache.seed(dir)?;
+        println!(
+            " {} Seeded the cache with {} responses from {}",
+            "●".bold().yellow(),
+            seeded,
+            dir.display()
+        );
+    }
+
+    let state = Arc::new(RwLock::new(State {
+        module,
+        host_modules: loaded_host_modules,
+        engine: engine.clone(),
+        backends: backends.clone(),
+        backend_headers: backend_headers.unwrap_or_default(),
+        backend_fallback,
+        unknown_backend: UnknownBackend {
+            status: match unknown_backend_status {
+                Some(status) => StatusCode::from_u16(status)?,
+                _ => StatusCode::BAD_GATEWAY,
+            },
+            body: unknown_backend_body.unwrap_or_else(|| UnknownBackend::default().body),
+            header: unknown_backend_header,
+        },
+        identity_clients: backend::identity_clients(
+            &backend_certs.unwrap_or_default(),
+            &resolved_backends,
+        )?,
+        resolved_backends: resolved_backends.clone(),
+        dictionaries,
+        settings: Settings {
+            dump_bodies,
+            strict_handles,
+            decompress_request,
+            trace_hostcalls,
+            timings,
+            disable_geolocation,
+            geo_abi,
+            log_endpoint_format,
+            log_files,
+            dump_backend_requests,
+            max_backend_requests,
+            max_response_body,
+            max_uri_length: Some(max_uri_length),
+            cache: cache.clone(),
+            guest_backtrace,
+            clock,
+            wasi: match wasi_config {
+                Some(path) => WasiConfig::load(path)?,
+                _ => WasiConfig::default(),
+            },
+            ..Settings::default()
+        },
+        once: once.clone(),
+        error_page,
+        fastly_headers,
+        delay: Delay {
+            fixed: response_delay.unwrap_or_default(),
+            jitter: response_delay_jitter.unwrap_or_default(),
+        },
+        limits: Limits {
+            max_headers,
+            max_header_size,
+            max_uri_length: Some(max_uri_length),
+        },
+        normalize_methods,
+        cors,
+        conditional,
+        ranges,
+        health: Health {
+            path: health_path,
+            ..Health::default()
+        },
+        extra_downstream_headers: extra_downstream_headers
+            .unwrap_or_default()
+            .iter()
+            .map(Header::parse)
+            .collect::<Result<_, _>>()?,
+        rewrite_rules: match rewrite_rules {
+            Some(path) => Rules::load(path)?,
+            _ => Rules::default(),
+        },
+        response_headers: ResponseHeaders {
+            headers: response_headers
+                .unwrap_or_default()
+                .iter()
+                .map(Header::parse)
+                .collect::<Result<_, _>>()?,
+            force: force_response_header,
+        },
+        request_timeout,
+        access_log: match access_log {
+            Some(path) => Some(
+                AccessLog::open(&path, access_log_max_size)
+                    .map_err(|e| anyhow!("unable to open access log {}: {}", path.display(), e))?,
+            ),
+            _ => None,
+        },
+        in_flight: if coalesce_backends {
+            Some(backend::InFlight::default())
+        } else {
+            None
+        },
+        instances: max_instances.map(|max| {
+            Instances::new(
+                max.get(),
+                max_instances_timeout.unwrap_or_else(|| Duration::from_secs(5)),
+            )
+        }),
+        stubs,
+        default_host_rewrite: !no_default_host_rewrite,
+        #[cfg(test)]
+        test_backends: None,
+    }));
+    println!("DEBUG: {:?}", state.read().unwrap().dictionaries);
+
+    if let Some(path) = replay {
+        let har = Har::load(path)?;
+        let state = state.read().expect("unable to lock server state").clone();
+        let mismatched = replay_har(state, &har).await?;
+        if mismatched > 0 {
+            return Err(anyhow!(
+                "{} of {} replayed requests differed from their recorded responses",
+                mismatched,
+                har.log.entries.len()
+            )
+            .into());
+        }
+        return Ok(());
+    }
+
+    if let (Some(port), Some(cache)) = (admin_port, cache) {
+        let admin = bind(([127, 0, 0, 1], port).into(), None)?;
+        println!(
+            " {} Admin listening on http://{}",
+            "●".bold().green(),
+            admin.local_addr()?
+        );
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve(admin, cache).await {
+                log::error!("admin server failed: {}", e);
+            }
+        });
+    }
+    let reload = reload_command.map(|command| Reload {
+        command,
+        dir: reload_dir,
+    });
+    let moved_state = state.clone();
+    let client_timeouts = ClientTimeouts {
+        header: client_header_timeout,
+        body: client_body_timeout,
+    };
+
+    match tls::config(tls_cert.as_deref(), tls_key.as_deref(), tls_dir.as_deref())? {
+        Some((tls_config, hosts)) => {
+            let tls_acceptor = TlsAcceptor::from(Arc::new(tls_config));
+            listener.set_nonblocking(true)?;
+            let tcp = TcpListener::from_std(listener)?;
+            let addr = tcp.local_addr()?;
+            let acceptor = async_stream::stream! {
+                loop {
+                    let (socket, _) = tcp.accept().await.map_err(|e|  anyhow!(format!("Incoming tpc request failed: {}", e)))?;
+                    if let Err(e) = socket.set_nodelay(true) {
+                        log::debug!("failed to set TCP_NODELAY: {}", e);
+                    }
+                    let stream = tls_acceptor.accept(TimeoutStream::new(socket, client_timeouts)).map_err(|e| anyhow!(format!("TLS Error: {:?}", e)));
+                    yield stream.await;
+                }
+            }.filter(|res|  ready(res.is_ok()));
+            let server = Box::new(
+                Server::builder(HyperAcceptor {
+                    acceptor: Box::pin(acceptor),
+                })
+                .http1_keepalive(true)
+                .serve(make_service_fn(
+                    move |conn: &TlsStream<TimeoutStream<TcpStream>>| {
+                        let state = moved_state.clone();
+                        let client_ip = conn
+                            .get_ref()
+                            .0
+                            .get_ref()
+                            .peer_addr()
+                            .ok()
+                            .map(|addr| addr.ip());
+                        let phase = conn.get_ref().0.phase();
+                        let server_name = conn
+                            .get_ref()
+                            .1
+                            .get_sni_hostname()
+                            .map(|name| ServerName(name.into()));
+                        async move {
+                            Ok::<_, anyhow::Error>(service_fn(move |mut req: Request<Body>| {
+                                if let Some(server_name) = &server_name {
+                                    req.extensions_mut().insert(server_name.clone());
+                                }
+                                phase.serve(req, |req| {
+                                    handle(
+                                        state.read().expect("unable to lock server state").clone(),
+                                        req,
+                                        Scheme::HTTPS,
+                                        client_ip,
+                                    )
+                                })
+                            }))
+                        }
+                    },
+                ))
+                .with_graceful_shutdown(shutdown(once.clone())),
+            );
+
+            println!(" {} Listening on https://{}", "●".bold().green(), addr);
+            if !hosts.is_empty() {
+                println!("   {} Certificates", "❯".dimmed());
+                for host in hosts {
+                    println!("     {}", host);
+                }
+            }
+            print_host_modules(&host_modules);
+            if let Some(backends) = backends {
+                println!("   {} Backends", "❯".dimmed());
+                for b in backends {
+                    println!("     {}", backend::describe(&b, &resolved_backends));
+                }
+            }
+
+            // assign to something to prevent watch resources from being dropped
+            let _watcher = if (watch || reload.is_some()) && !echo {
+                Some(monitor(&wasm, engine, state, reload)?)
+            } else {
+                None
+            };
+            server.await?
+        }
+        None => {
+            let (addr, acceptor) = accept_plain(listener, client_timeouts)?;
+            let server = Server::builder(acceptor)
+                .http1_keepalive(true)
+                .serve(make_service_fn(move |conn: &TimeoutStream<TcpStream>| {
+                    let state = moved_state.clone();
+                    let client_ip = conn.get_ref().peer_addr().ok().map(|addr| addr.ip());
+                    let phase = conn.phase();
+                    async move {
+                        Ok::<_, anyhow::Error>(service_fn(move |req| {
+                            phase.serve(req, |req| {
+                                handle(
+                                    state.read().expect("unable to lock server state").clone(),
+                                    req,
+                                    Scheme::HTTP,
+                                    client_ip,
+                                )
+                            })
+                        }))
+                    }
+                }));
+            let server = Box::new(server.with_graceful_shutdown(shutdown(once.clone())));
+
+            println!(" {} Listening on http://{}", "●".bold().green(), addr);
+            print_host_modules(&host_modules);
+            if let Some(backends) = backends {
+                println!("   {} Backends", "❯".dimmed());
+                for b in backends {
+                    println!("     {}", backend::describe(&b, &resolved_backends));
+                }
+            }
+
+            // assign to something to prevent watch resources from being dropped
+            let _watcher = if (watch || reload.is_some()) && !echo {
+                Some(monitor(&wasm, engine, state, reload)?)
+            } else {
+                None
+            };
+
+            server.await?;
+        }
+    };
+
+    // server.await?;
+
+    if let Some(once) = once {
+        if once.failed.load(Ordering::SeqCst) {
+            return Err(anyhow!("handler failed to handle request").into());
+        }
+    }
+
+    Ok(())
+}
+
+fn print_hostcalls(hostcalls: &[Hostcall]) {
+    let width = hostcalls
+        .iter()
+        .map(|hostcall| hostcall.module.len() + hostcall.name.len() + 2)
+        .max()
+        .unwrap_or_default();
+    for Hostcall {
+        module,
+        name,
+        stubbed,
+    } in hostcalls
+    {
+        let call = format!("{}::{}", module, name);
+        if *stubbed {
+            println!(
+                " {} {:width$} stubbed",
+                "●".bold().yellow(),
+                call,
+                width = width
+            );
+        } else {
+            println!(
+                " {} {:width$} implemented",
+                "●".bold().green(),
+                call,
+                width = width
+            );
+        }
+    }
+}
+
+fn print_host_modules(host_modules: &[HostModule]) {
+    if !host_modules.is_empty() {
+        println!("   {} Modules", "❯".dimmed());
+        for HostModule { host, wasm } in host_modules {
+            println!("     {} > {}", host, wasm.display());
+        }
+    }
+}
+
+/// A command rebuilding the application when sources in a directory change
+struct Reload {
+    command: String,
+    dir: PathBuf,
+}
+
+/// Runs a `--reload-command` through the platform's shell, streaming its output to ours
+fn run_reload_command(command: &str) -> io::Result<ExitStatus> {
+    if cfg!(windows) {
+        Command::new("cmd").args(&["/C", command]).status()
+    } else {
+        Command::new("sh").args(&["-c", command]).status()
+    }
+}
+
+/// Loads the module at `wasm` into the server's state, recording why it failed to load, if it
+/// did. Returns false when the state is no longer available
+fn reload_module(
+    engine: &Engine,
+    wasm: &Path,
+    state: &RwLock<State>,
+) -> bool {
+    let loaded = load_module(engine, wasm, false);
+    match state.write() {
+        Ok(mut guard) => {
+            match loaded {
+                Ok(module) => {
+                    guard.module = Some(module);
+                    guard.health.module_error = None;
+                }
+                Err(e) => guard.health.module_error = Some(e.to_string()),
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Reloads the module when the .wasm file changes or, with a `Reload`, rebuilds then reloads
+/// it when its sources change
+fn monitor(
+    wasm: &PathBuf,
+    engine: Engine,
+    state: Arc<RwLock<State>>,
+    reload: Option<Reload>,
+) -> Result<(notify::RecommendedWatcher, tokio::task::JoinHandle<()>), BoxError> {
+    // For receiving events from notify's watcher
+    let (tx, rx) = channel();
+    // Create a watcher object, delivering debounced events. The Duration is how
+    // long the watcher waits after each raw event to combine things into one
+    // debounced event.
+    let mut watcher = watcher(tx, Duration::from_secs(1))?;
+
+    // Monitor the parent, because deleting the file removes the watch on some
+    // platforms, but not all. So monitor the directory it's in, and then filter
+    // for the specific file. Canonicalize because the watcher deals in absolute
+    // paths. (Or at least it does on Linux.)
+    let wasm = fs::canonicalize(wasm)?;
+    let wasmdir = &wasm.parent().expect("expected parent directory to exist");
+    println!(" Watching for changes...");
+    match &reload {
+        // rebuilds write the .wasm file, so its directory needn't be watched as well
+        Some(Reload { dir, .. }) => {
+            watcher.watch(fs::canonicalize(dir)?, RecursiveMode::Recursive)?
+        }
+        _ => watcher.watch(wasmdir, RecursiveMode::Recursive)?,
+    }
+
+    // Unfortunately notify's watcher doesn't work with async channels, so let's
+    // have a thread for the blocking read from that.
+    let handle = spawn_blocking(move || loop {
+        let event = rx.recv();
+        match &event {
+            Ok(DebouncedEvent::Chmod(path))
+            | Ok(DebouncedEvent::Create(path))
+            | Ok(DebouncedEvent::Rename(_, path))
+            | Ok(DebouncedEvent::Remove(path))
+            | Ok(DebouncedEvent::Write(path)) => match &reload {
+                Some(Reload { command, .. }) => {
+                    log::trace!("notify: {:?}", event);
+                    println!("{}  Rebuilding with `{}`...", " ◌".dimmed(), command);
+                    match run_reload_command(command) {
+                        Ok(status) if status.success() => {
+                            if !reload_module(&engine, &wasm, &state) {
+                                break;
+                            }
+                        }
+                        Ok(status) => println!(
+                            " {} Rebuild failed with {}, keeping the loaded module",
+                            "✖".bold().red(),
+                            status
+                        ),
+                        Err(e) => println!(
+                            " {} Rebuild failed to run: {}, keeping the loaded module",
+                            "✖".bold().red(),
+                            e
+                        ),
+                    }
+                }
+                _ => {
+                    if *path == wasm {
+                        log::trace!("notify: {:?}", event);
+                        if !reload_module(&engine, &wasm, &state) {
+                            break;
+                        }
+                    }
+                }
+            },
+            Err(e) => {
+                log::trace!("watch error: {:?}", e);
+                break;
+            }
+            _ => (),
+        }
+    });
+    Ok((watcher, handle))
+}
+
+#[tokio::main]
+async fn main() {
+    pretty_env_logger::init();
+    if let Err(e) = run(Opts::merge_from_args_and_toml()).await {
+        eprintln!(" {} error: {}", "✖".bold().red(), e);
+        exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use http::request::Parts;
+    use std::{cell::Ref, str};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        time::timeout,
+    };
+
+    lazy_static::lazy_static! {
+        pub (crate) static ref WASM: Option<(Engine, Module)> =
+            match Path::new("./tests/app/target/wasm32-wasi/release/app.wasm") {
+                path if !path.exists() => {
+                    pretty_env_logger::init();
+                    log::debug!("test wasm app is absent. will skip wasm tests");
+                    None
+                }
+                path => {
+                    pretty_env_logger::init();
+                    log::debug!("loading wasm for test");
+                    let engine = Engine::default();
+                    Module::from_file(&engine, path)
+                        .ok()
+                        .map(|module| (engine, module))
+                }
+            };
+    }
+
+    pub(crate) async fn body(resp: Response<Body>) -> Result<String, BoxError> {
+        Ok(str::from_utf8(&to_bytes(resp.into_body()).await?)?.to_owned())
+    }
+
+    /// Compiles a guest module from WAT for exercising hostcalls directly
+    ///
+    /// The module imports the provided hostcalls, exports memory, and defines a
+    /// `$respond (status, addr, len)` func which sends a downstream response with
+    /// a body containing `len` bytes of memory at `addr`. Memory below offset 64
+    /// is reserved for `$respond`. `fields` should define an exported `_start` func
+    pub(crate) fn guest(
+        imports: &str,
+        fields: &str,
+    ) -> Result<(Engine, Module), BoxError> {
+        let engine = Engine::default();
+        let module = Module::new(
+            &engine,
+            format!(
+                r#"(module
+  (import "fastly_http_resp" "new" (func $resp_new (param i32) (result i32)))
+  (import "fastly_http_resp" "status_set" (func $resp_status_set (param i32 i32) (result i32)))
+  (import "fastly_http_resp" "send_downstream" (func $resp_send_downstream (param i32 i32 i32) (result i32)))
+  (import "fastly_http_body" "new" (func $body_new (param i32) (result i32)))
+  (import "fastly_http_body" "write" (func $body_write (param i32 i32 i32 i32 i32) (result i32)))
+  {}
+  (memory (export "memory") 1)
+  (func $respond (param $status i32) (param $addr i32) (param $len i32)
+    (drop (call $resp_new (i32.const 0)))
+    (drop (call $body_new (i32.const 4)))
+    (drop (call $resp_status_set (i32.load (i32.const 0)) (local.get $status)))
+    (drop (call $body_write (i32.load (i32.const 4)) (local.get $addr) (local.get $len) (i32.const 0) (i32.const 8)))
+    (drop (call $resp_send_downstream (i32.load (i32.const 0)) (i32.load (i32.const 4)) (i32.const 0))))
+  {})"#,
+                imports, fields
+            ),
+        )?;
+        Ok((engine, module))
+    }
+
+    /// Records the requests sent to it, responding to each with the same canned response
+    ///
+    /// Clones share recorded requests, so a clone may be handed to a `Handler` while
+    /// the original is kept for inspecting what the guest sent
+    #[derive(Clone)]
+    pub(crate) struct RecordingBackend {
+        requests: Rc<RefCell<Vec<(String, Parts, Bytes)>>>,
+        status: StatusCode,
+        headers: HeaderMap,
+        body: Bytes,
+    }
+
+    impl Default for RecordingBackend {
+        fn default() -> Self {
+            RecordingBackend::new(StatusCode::OK, Bytes::new())
+        }
+    }
+
+    impl RecordingBackend {
+        /// Creates a backend which responds with a given status and body
+        pub fn new(
+            status: StatusCode,
+            body: impl Into<Bytes>,
+        ) -> Self {
+            RecordingBackend {
+                requests: Default::default(),
+                status,
+                headers: HeaderMap::new(),
+                body: body.into(),
+            }
+        }
+
+        /// Adds a header to the canned response
+        pub fn with_header(
+            mut self,
+            name: &'static str,
+            value: &'static str,
+        ) -> Self {
+            self.headers.append(name, HeaderValue::from_static(value));
+            self
+        }
+
+        /// Returns the backend name, request parts, and body of each request sent, in order
+        pub fn requests(&self) -> Ref<'_, Vec<(String, Parts, Bytes)>> {
+            self.requests.borrow()
+        }
+    }
+
+    impl Backends for RecordingBackend {
+        fn send(
+            &self,
+            backend: &str,
+            req: Request<Body>,
+        ) -> Result<Response<Body>, BoxError> {
+            let (parts, body) = req.into_parts();
+            let body = futures_executor::block_on(to_bytes(body))?;
+            self.requests
+                .borrow_mut()
+                .push((backend.to_owned(), parts, body));
+            let mut resp = Response::builder()
+                .status(self.status)
+                .body(self.body.clone().into())
+                .expect("invalid response");
+            *resp.headers_mut() = self.headers.clone();
+            Ok(resp)
+        }
+    }
+
+    #[test]
+    fn recording_backend_records_requests() -> Result<(), BoxError> {
+        let backend = RecordingBackend::new(StatusCode::CREATED, "created");
+        let resp = backend.clone().send(
+            "origin",
+            Request::post("http://origin.example.com/items")
+                .header("x-test", "1")
+                .body(Body::from("item"))?,
+        )?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let requests = backend.requests();
+        assert_eq!(requests.len(), 1);
+        let (name, parts, body) = &requests[0];
+        assert_eq!(name, "origin");
+        assert_eq!(parts.method, Method::POST);
+        assert_eq!(parts.headers["x-test"], "1");
+        assert_eq!(body, "item");
+        Ok(())
+    }
+
+    #[test]
+    fn reload_commands_report_their_exit_status() -> Result<(), BoxError> {
+        assert!(run_reload_command("exit 0")?.success());
+        assert_eq!(run_reload_command("exit 3")?.code(), Some(3));
+        Ok(())
+    }
+
+    #[test]
+    fn check_imports_rejects_modules_without_wasi() {
+        let engine = Engine::default();
+        // like a wasm32-unknown-unknown build, importing from its host via `env`
+        let module = Module::new(
+            &engine,
+            r#"(module (import "env" "now" (func)) (func (export "_start")))"#,
+        )
+        .expect("invalid module");
+        let err = check_imports(&module).expect_err("expected env import to be rejected");
+        assert!(err.to_string().contains("`now` from `env`"));
+        let module =
+            Module::new(&engine, r#"(module (func (export "_start")))"#).expect("invalid module");
+        let err = check_imports(&module).expect_err("expected missing wasi to be rejected");
+        assert!(err.to_string().contains("doesn't import WASI"));
+    }
+
+    #[test]
+    fn check_imports_accepts_wasi_and_fastly_imports() {
+        let engine = Engine::default();
+        let module = Module::new(
+            &engine,
+            r#"(module
+  (import "wasi_snapshot_preview1" "proc_exit" (func (param i32)))
+  (import "fastly_http_req" "body_downstream_get" (func (param i32 i32) (result i32)))
+  (func (export "_start")))"#,
+        )
+        .expect("invalid module");
+        assert!(check_imports(&module).is_ok());
+    }
+
+    #[test]
+    fn check_start_rejects_modules_without_start() {
+        let engine = Engine::default();
+        let module = Module::new(&engine, "(module)").expect("invalid module");
+        assert!(check_start(&module).is_err());
+    }
+
+    #[test]
+    fn check_start_accepts_modules_with_start() {
+        let engine = Engine::default();
+        let module =
+            Module::new(&engine, r#"(module (func (export "_start")))"#).expect("invalid module");
+        assert!(check_start(&module).is_ok());
+    }
+
+    #[test]
+    fn test_rewrite_uri_http() -> Result<(), BoxError> {
+        let req = Request::builder()
+            .uri("/foo")
+            .header(HOST, "fasttime.co")
+            .body(Body::empty())?;
+        let rewritten = rewrite_uri(req, Scheme::HTTP)?;
+        assert_eq!(
             rewritten.uri().authority(),
             Some(&"fasttime.co".parse::<Authority>()?)
         );
-        assert_eq!(rewritten.uri().scheme().map(Scheme::as_str), Some("http"));
+        assert_eq!(rewritten.uri().scheme().map(Scheme::as_str), Some("http"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn error_page_responds_with_500() -> Result<(), BoxError> {
+        let resp = ErrorPage::default().respond(&anyhow!("guest trapped\nbacktrace"), false);
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(resp.headers().get("Fastly-Error").is_none());
+        assert!(body(resp).await?.starts_with("500 Internal Server Error"));
+        Ok(())
+    }
+
+    #[test]
+    fn error_page_sets_fastly_error_header() {
+        let resp = ErrorPage::default().respond(&anyhow!("guest trapped\nbacktrace"), true);
+        assert_eq!(
+            resp.headers().get("Fastly-Error"),
+            Some(&HeaderValue::from_static("guest trapped"))
+        );
+    }
+
+    #[test]
+    fn delay_samples_within_jitter() {
+        let delay = Delay {
+            fixed: Duration::from_millis(200),
+            jitter: Duration::from_millis(50),
+        };
+        for _ in 0..100 {
+            let sampled = delay.sample();
+            assert!(sampled >= Duration::from_millis(200));
+            assert!(sampled <= Duration::from_millis(250));
+        }
+        assert_eq!(Delay::default().sample(), Duration::default());
+    }
+
+    #[test]
+    fn limits_allow_requests_within_limits() -> Result<(), BoxError> {
+        let limits = Limits {
+            max_headers: Some(2),
+            max_header_size: Some(8),
+            max_uri_length: Some(8),
+        };
+        let req = Request::get("/")
+            .header("foo", "bar")
+            .header("baz", "12345")
+            .body(Body::empty())?;
+        assert!(limits.check(&req).is_none());
+        assert!(Limits::default().check(&req).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn limits_reject_too_many_headers() -> Result<(), BoxError> {
+        let limits = Limits {
+            max_headers: Some(2),
+            ..Limits::default()
+        };
+        let req = Request::get("/")
+            .header("a", "1")
+            .header("b", "2")
+            .header("c", "3")
+            .body(Body::empty())?;
+        let resp = limits.check(&req).expect("expected rejection");
+        assert_eq!(resp.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+        Ok(())
+    }
+
+    #[test]
+    fn limits_reject_too_large_headers() -> Result<(), BoxError> {
+        let limits = Limits {
+            max_header_size: Some(8),
+            ..Limits::default()
+        };
+        let req = Request::get("/")
+            .header("foo", "123456")
+            .body(Body::empty())?;
+        let resp = limits.check(&req).expect("expected rejection");
+        assert_eq!(resp.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handle_rejects_overlong_uris() -> Result<(), BoxError> {
+        let (engine, module) = ok_guest()?;
+        let limited = State {
+            limits: Limits {
+                max_uri_length: Some(16),
+                ..Limits::default()
+            },
+            ..state(engine, module)
+        };
+        let resp = handle(
+            limited.clone(),
+            Request::get("/short").body(Body::empty())?,
+            Scheme::HTTP,
+            None,
+        )
+        .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = handle(
+            limited,
+            Request::get("/a/much/longer/path?with=query").body(Body::empty())?,
+            Scheme::HTTP,
+            None,
+        )
+        .await?;
+        assert_eq!(resp.status(), StatusCode::URI_TOO_LONG);
+        Ok(())
+    }
+
+    #[test]
+    fn normalize_method_upper_cases_standard_methods() -> Result<(), BoxError> {
+        let mut req = Request::builder()
+            .method(Method::from_bytes(b"get")?)
+            .body(Body::empty())?;
+        assert!(normalize_method(&mut req).is_none());
+        assert_eq!(req.method(), Method::GET);
+        Ok(())
+    }
+
+    #[test]
+    fn normalize_method_rejects_extension_methods() -> Result<(), BoxError> {
+        let mut req = Request::builder()
+            .method(Method::from_bytes(b"PURGE")?)
+            .body(Body::empty())?;
+        let resp = normalize_method(&mut req).expect("expected rejection");
+        assert_eq!(resp.status(), StatusCode::NOT_IMPLEMENTED);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn strip_body_keeps_content_length() -> Result<(), BoxError> {
+        let resp = strip_body(Response::new(Body::from("hello"))).await?;
+        assert_eq!(
+            resp.headers().get(CONTENT_LENGTH),
+            Some(&HeaderValue::from(5))
+        );
+        assert_eq!("", body(resp).await?);
+        Ok(())
+    }
+
+    /// Returns server state for a module with default settings
+    fn state(
+        engine: Engine,
+        module: Module,
+    ) -> State {
+        State {
+            module: Some(module),
+            host_modules: HashMap::new(),
+            engine,
+            backends: None,
+            backend_headers: Vec::new(),
+            backend_fallback: BackendFallback::default(),
+            unknown_backend: UnknownBackend::default(),
+            identity_clients: HashMap::new(),
+            resolved_backends: HashMap::new(),
+            dictionaries: Dictionaries::default(),
+            settings: Settings::default(),
+            once: None,
+            error_page: ErrorPage::default(),
+            fastly_headers: false,
+            delay: Delay::default(),
+            limits: Limits::default(),
+            normalize_methods: false,
+            cors: false,
+            conditional: false,
+            ranges: false,
+            health: Health::default(),
+            extra_downstream_headers: Vec::new(),
+            rewrite_rules: Rules::default(),
+            response_headers: ResponseHeaders::default(),
+            request_timeout: None,
+            access_log: None,
+            in_flight: None,
+            instances: None,
+            stubs: Vec::new(),
+            default_host_rewrite: true,
+            test_backends: None,
+        }
+    }
+
+    /// Serves plain http requests with `state` on any available port in a background task
+    fn serve(state: State) -> Result<SocketAddr, BoxError> {
+        let (addr, _) = TestServer::from(state).start()?;
+        Ok(addr)
+    }
+
+    /// Builds an in-process server for exercising a guest over a real http round trip
+    ///
+    /// fasttime has no library target, so this is only available to its own tests
+    struct TestServer {
+        state: State,
+        client_ip: Option<IpAddr>,
+        client_timeouts: ClientTimeouts,
+    }
+
+    /// Gracefully shuts down a `TestServer` when triggered. Dropping it leaves the server running
+    struct Shutdown(Arc<Notify>);
+
+    impl Shutdown {
+        fn shutdown(self) {
+            self.0.notify_one();
+        }
+    }
+
+    impl From<State> for TestServer {
+        fn from(state: State) -> Self {
+            TestServer {
+                state,
+                client_ip: None,
+                client_timeouts: ClientTimeouts::default(),
+            }
+        }
+    }
+
+    impl TestServer {
+        fn new(
+            engine: Engine,
+            module: Module,
+        ) -> Self {
+            state(engine, module).into()
+        }
+
+        /// Answers every backend request with `backends`, like a closure, in place of proxying
+        /// them. They're shared by the server's threads, so must be `Send` and `Sync`
+        fn backends(
+            mut self,
+            backends: Box<dyn Backends + Send + Sync>,
+        ) -> Self {
+            self.state.test_backends = Some(backends.into());
+            self
+        }
+
+        fn dictionaries(
+            mut self,
+            dictionaries: HashMap<String, HashMap<String, String>>,
+        ) -> Self {
+            self.state.dictionaries = dictionaries.into();
+            self
+        }
+
+        /// Reports requests as coming from `ip` rather than the address they were sent from
+        fn client_ip(
+            mut self,
+            ip: IpAddr,
+        ) -> Self {
+            self.client_ip = Some(ip);
+            self
+        }
+
+        /// Limits how slowly clients may send requests
+        fn client_timeouts(
+            mut self,
+            client_timeouts: ClientTimeouts,
+        ) -> Self {
+            self.client_timeouts = client_timeouts;
+            self
+        }
+
+        /// Serves plain http requests on any available port in a background task, returning
+        /// the bound address and a handle to shut the server down with
+        fn start(self) -> Result<(SocketAddr, Shutdown), BoxError> {
+            let TestServer {
+                state,
+                client_ip,
+                client_timeouts,
+            } = self;
+            let shutdown = Arc::new(Notify::new());
+            let (addr, acceptor) =
+                accept_plain(bind(([127, 0, 0, 1], 0).into(), None)?, client_timeouts)?;
+            let server = Server::builder(acceptor)
+                .http1_keepalive(true)
+                .serve(make_service_fn(move |conn: &TimeoutStream<TcpStream>| {
+                    let state = state.clone();
+                    let client_ip =
+                        client_ip.or_else(|| conn.get_ref().peer_addr().ok().map(|addr| addr.ip()));
+                    let phase = conn.phase();
+                    async move {
+                        Ok::<_, anyhow::Error>(service_fn(move |req| {
+                            phase.serve(req, |req| {
+                                handle(state.clone(), req, Scheme::HTTP, client_ip)
+                            })
+                        }))
+                    }
+                }));
+            let notify = shutdown.clone();
+            tokio::spawn(server.with_graceful_shutdown(async move { notify.notified().await }));
+            Ok((addr, Shutdown(shutdown)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_round_trips_requests() -> Result<(), BoxError> {
+        // responds with the value of the "greeting" key of the "config" dictionary
+        let (engine, module) = guest(
+            r#"(import "fastly_dictionary" "open" (func $dict_open (param i32 i32 i32) (result i32)))
+  (import "fastly_dictionary" "get" (func $dict_get (param i32 i32 i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "config")
+  (data (i32.const 80) "greeting")
+  (func (export "_start")
+    (drop (call $dict_open (i32.const 64) (i32.const 6) (i32.const 16)))
+    (drop (call $dict_get (i32.load (i32.const 16)) (i32.const 80) (i32.const 8) (i32.const 1024) (i32.const 64) (i32.const 20)))
+    (call $respond (i32.const 200) (i32.const 1024) (i32.load (i32.const 20))))"#,
+        )?;
+        let (addr, shutdown) = TestServer::new(engine, module)
+            .dictionaries(
+                vec![(
+                    "config".to_string(),
+                    vec![("greeting".to_string(), "hello".to_string())]
+                        .into_iter()
+                        .collect(),
+                )]
+                .into_iter()
+                .collect(),
+            )
+            .start()?;
+        let resp = reqwest::get(&format!("http://{}/", addr)).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.text().await?, "hello");
+        shutdown.shutdown();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_server_sends_to_its_backends_from_its_client_ip() -> Result<(), BoxError> {
+        // sends the client's ip address to the origin backend, responding with its response
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "new" (func $req_new (param i32) (result i32)))
+  (import "fastly_http_req" "downstream_client_ip_addr" (func $client_ip_addr (param i32 i32) (result i32)))
+  (import "fastly_http_req" "send" (func $send (param i32 i32 i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "origin")
+  (func (export "_start")
+    (drop (call $req_new (i32.const 16)))
+    (drop (call $body_new (i32.const 20)))
+    (drop (call $client_ip_addr (i32.const 1024) (i32.const 32)))
+    (drop (call $body_write (i32.load (i32.const 20)) (i32.const 1024) (i32.load (i32.const 32)) (i32.const 0) (i32.const 36)))
+    (drop (call $send (i32.load (i32.const 16)) (i32.load (i32.const 20)) (i32.const 64) (i32.const 6) (i32.const 24) (i32.const 28)))
+    (drop (call $resp_send_downstream (i32.load (i32.const 24)) (i32.load (i32.const 28)) (i32.const 0))))"#,
+        )?;
+        let echo = |backend: &str, req: Request<Body>| -> Result<Response<Body>, BoxError> {
+            Ok(Response::builder()
+                .header("x-backend", backend)
+                .body(req.into_body())?)
+        };
+        let (addr, shutdown) = TestServer::new(engine, module)
+            .backends(Box::new(echo))
+            .client_ip([10, 0, 0, 1].into())
+            .start()?;
+        let resp = reqwest::get(&format!("http://{}/", addr)).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["x-backend"], "origin");
+        assert_eq!(&resp.bytes().await?[..], &[10, 0, 0, 1]);
+        shutdown.shutdown();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handle_answers_health_checks_without_the_application() -> Result<(), BoxError> {
+        // a guest which fails every request
+        let (engine, module) = guest("", r#"(func (export "_start") unreachable)"#)?;
+        let health = Health {
+            path: Some("/_fasttime/health".into()),
+            ..Health::default()
+        };
+        let healthy = State {
+            health: health.clone(),
+            ..state(engine.clone(), module.clone())
+        };
+        let resp = handle(
+            healthy,
+            Request::get("/_fasttime/health").body(Body::empty())?,
+            Scheme::HTTP,
+            None,
+        )
+        .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let json: serde_json::Value = serde_json::from_str(&body(resp).await?)?;
+        assert_eq!(json["module_loaded"], true);
+
+        let unhealthy = State {
+            health: Health {
+                module_error: Some("failed to parse".into()),
+                ..health
+            },
+            ..state(engine, module)
+        };
+        let resp = handle(
+            unhealthy,
+            Request::get("/_fasttime/health").body(Body::empty())?,
+            Scheme::HTTP,
+            None,
+        )
+        .await?;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handle_adds_request_ids_with_fastly_headers() -> Result<(), BoxError> {
+        let (engine, module) = ok_guest()?;
+        let state = State {
+            fastly_headers: true,
+            ..state(engine, module)
+        };
+        let resp = handle(
+            state,
+            Request::get("/").body(Body::empty())?,
+            Scheme::HTTP,
+            None,
+        )
+        .await?;
+        assert_eq!(resp.headers()["Fastly-Request-Id"].len(), 32);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handle_echoes_requests_without_a_module() -> Result<(), BoxError> {
+        let (engine, module) = ok_guest()?;
+        let echo = State {
+            module: None,
+            ..state(engine, module)
+        };
+        let resp = handle(
+            echo,
+            Request::post("/echo?q=1")
+                .header("x-a", "1")
+                .header("x-a", "2")
+                .body(Body::from("hello"))?,
+            Scheme::HTTP,
+            None,
+        )
+        .await?;
+        let json: serde_json::Value = serde_json::from_str(&body(resp).await?)?;
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "method": "POST",
+                "path": "/echo?q=1",
+                "headers": { "x-a": ["1", "2"] },
+                "body": "hello",
+            })
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handle_rewrites_requests_before_the_application() -> Result<(), BoxError> {
+        let (engine, module) = ok_guest()?;
+        let rewritten = State {
+            module: None,
+            rewrite_rules: toml::from_str(
+                r#"
+                [[rule]]
+                path = "/old/*"
+                rewrite = "/new/*"
+                headers = { "x-cdn" = "fasttime" }
+                "#,
+            )?,
+            ..state(engine, module)
+        };
+        let resp = handle(
+            rewritten,
+            Request::get("/old/page?q=1").body(Body::empty())?,
+            Scheme::HTTP,
+            None,
+        )
+        .await?;
+        let json: serde_json::Value = serde_json::from_str(&body(resp).await?)?;
+        assert_eq!(json["path"], "/new/page?q=1");
+        assert_eq!(json["headers"]["x-cdn"], serde_json::json!(["fasttime"]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handle_adds_response_headers() -> Result<(), BoxError> {
+        // responds with a server header of its own
+        let (engine, module) = guest(
+            r#"(import "fastly_http_resp" "header_append" (func $resp_header_append (param i32 i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "serverguest")
+  (func (export "_start")
+    (drop (call $resp_new (i32.const 0)))
+    (drop (call $resp_header_append (i32.load (i32.const 0)) (i32.const 64) (i32.const 6) (i32.const 70) (i32.const 5)))
+    (drop (call $body_new (i32.const 4)))
+    (drop (call $resp_send_downstream (i32.load (i32.const 0)) (i32.load (i32.const 4)) (i32.const 0))))"#,
+        )?;
+        let headers = |force| ResponseHeaders {
+            headers: vec![
+                (
+                    HeaderName::from_static("server"),
+                    HeaderValue::from_static("fasttime"),
+                ),
+                (
+                    HeaderName::from_static("strict-transport-security"),
+                    HeaderValue::from_static("max-age=60"),
+                ),
+            ],
+            force,
+        };
+        let respond = |force| {
+            handle(
+                State {
+                    response_headers: headers(force),
+                    ..state(engine.clone(), module.clone())
+                },
+                Request::get("/")
+                    .body(Body::empty())
+                    .expect("invalid request"),
+                Scheme::HTTP,
+                None,
+            )
+        };
+
+        let resp = respond(false).await?;
+        assert_eq!(resp.headers()["strict-transport-security"], "max-age=60");
+        assert_eq!(
+            resp.headers().get_all("server").iter().collect::<Vec<_>>(),
+            vec!["guest"]
+        );
+
+        let resp = respond(true).await?;
+        assert_eq!(
+            resp.headers().get_all("server").iter().collect::<Vec<_>>(),
+            vec!["fasttime"]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handle_rejects_requests_when_instances_are_busy() -> Result<(), BoxError> {
+        let (engine, module) = ok_guest()?;
+        let instances = Instances::new(1, Duration::from_millis(100));
+        let limited = State {
+            instances: Some(instances.clone()),
+            ..state(engine.clone(), module.clone())
+        };
+        // a request still running on the only instance
+        let busy = instances.acquire().await?;
+        let start = Instant::now();
+        let resp = handle(
+            limited,
+            Request::get("/").body(Body::empty())?,
+            Scheme::HTTP,
+            None,
+        )
+        .await?;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        drop(busy);
+
+        // concurrent requests queue for the instances, which are all freed once they finish
+        let instances = Instances::new(2, Duration::from_secs(30));
+        let limited = State {
+            instances: Some(instances.clone()),
+            ..state(engine, module)
+        };
+        let resps = futures_util::future::join_all((0..8).map(|_| {
+            handle(
+                limited.clone(),
+                Request::get("/")
+                    .body(Body::empty())
+                    .expect("invalid request"),
+                Scheme::HTTP,
+                None,
+            )
+        }))
+        .await;
+        for resp in resps {
+            assert_eq!(resp?.status(), StatusCode::OK);
+        }
+        assert_eq!(instances.permits.available_permits(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handle_times_out_slow_requests() -> Result<(), BoxError> {
+        // a backend which takes far longer to respond than the request timeout allows
+        let backend = Server::from_tcp(bind(([127, 0, 0, 1], 0).into(), None)?)?.serve(
+            make_service_fn(|_| async {
+                Ok::<_, anyhow::Error>(service_fn(|_| async {
+                    sleep(Duration::from_secs(5)).await;
+                    Ok::<_, anyhow::Error>(Response::new(Body::from("slow")))
+                }))
+            }),
+        );
+        let backend_addr = backend.local_addr();
+        tokio::spawn(backend);
+        // forwards the downstream request to the origin backend
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "body_downstream_get" (func $body_downstream_get (param i32 i32) (result i32)))
+  (import "fastly_http_req" "send" (func $send (param i32 i32 i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "origin")
+  (func (export "_start")
+    (drop (call $body_downstream_get (i32.const 16) (i32.const 20)))
+    (drop (call $send (i32.load (i32.const 16)) (i32.load (i32.const 20)) (i32.const 64) (i32.const 6) (i32.const 24) (i32.const 28)))
+    (drop (call $resp_send_downstream (i32.load (i32.const 24)) (i32.load (i32.const 28)) (i32.const 0))))"#,
+        )?;
+        let start = Instant::now();
+        let resp = handle(
+            State {
+                backends: Some(vec![Backend {
+                    name: "origin".into(),
+                    address: backend_addr.to_string(),
+                    host_header: backend::HostHeader::default(),
+                }]),
+                request_timeout: Some(Duration::from_millis(200)),
+                ..state(engine, module)
+            },
+            Request::get(format!("http://{}/", backend_addr))
+                .body(Body::empty())
+                .expect("invalid request"),
+            Scheme::HTTP,
+            None,
+        )
+        .await?;
+        assert_eq!(resp.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handle_routes_requests_to_host_modules() -> Result<(), BoxError> {
+        let (engine, module) = ok_guest()?;
+        let routed = State {
+            module: None,
+            host_modules: vec![("example.com".to_string(), module.clone())]
+                .into_iter()
+                .collect(),
+            ..state(engine, module)
+        };
+        let mut sni = Request::get("/")
+            .header(HOST, "other.com")
+            .body(Body::empty())?;
+        sni.extensions_mut()
+            .insert(ServerName("Example.com".into()));
+        let host = Request::get("/")
+            .header(HOST, "example.com:3000")
+            .body(Body::empty())?;
+        for req in vec![sni, host] {
+            let resp = handle(routed.clone(), req, Scheme::HTTPS, None).await?;
+            assert_eq!("ok", body(resp).await?);
+        }
+
+        // neither matches, so the default, echoing, module handles the request
+        let mut other = Request::get("/")
+            .header(HOST, "example.com")
+            .body(Body::empty())?;
+        other
+            .extensions_mut()
+            .insert(ServerName("other.com".into()));
+        let resp = handle(routed, other, Scheme::HTTPS, None).await?;
+        assert!(body(resp).await?.contains("\"method\":\"GET\""));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handle_passes_uris_through_without_the_default_host_rewrite() -> Result<(), BoxError> {
+        // responds with the downstream request's uri
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "body_downstream_get" (func $body_downstream_get (param i32 i32) (result i32)))
+  (import "fastly_http_req" "uri_get" (func $uri_get (param i32 i32 i32 i32) (result i32)))"#,
+            r#"(func (export "_start")
+    (drop (call $body_downstream_get (i32.const 16) (i32.const 20)))
+    (drop (call $uri_get (i32.load (i32.const 16)) (i32.const 1024) (i32.const 1024) (i32.const 24)))
+    (call $respond (i32.const 200) (i32.const 1024) (i32.load (i32.const 24))))"#,
+        )?;
+        let req = || {
+            Request::get("/foo?bar=baz")
+                .header(HOST, "fasttime.co")
+                .body(Body::empty())
+        };
+        let resp = handle(
+            state(engine.clone(), module.clone()),
+            req()?,
+            Scheme::HTTP,
+            None,
+        )
+        .await?;
+        assert_eq!("http://fasttime.co/foo?bar=baz", body(resp).await?);
+
+        let raw = State {
+            default_host_rewrite: false,
+            ..state(engine, module)
+        };
+        let resp = handle(raw, req()?, Scheme::HTTP, None).await?;
+        assert_eq!("/foo?bar=baz", body(resp).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handle_answers_stubbed_paths_without_the_application() -> Result<(), BoxError> {
+        let (engine, module) = ok_guest()?;
+        let stubbed = State {
+            stubs: vec![
+                Stub {
+                    path: "/health".into(),
+                    status: 200,
+                    body: "OK".into(),
+                },
+                Stub {
+                    path: "/health".into(),
+                    status: 500,
+                    body: "shadowed".into(),
+                },
+                Stub {
+                    path: "/gone".into(),
+                    status: 410,
+                    body: String::new(),
+                },
+            ],
+            ..state(engine, module)
+        };
+        let resp = handle(
+            stubbed.clone(),
+            Request::get("/health?verbose").body(Body::empty())?,
+            Scheme::HTTP,
+            None,
+        )
+        .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!("OK", body(resp).await?);
+
+        let resp = handle(
+            stubbed.clone(),
+            Request::get("/gone").body(Body::empty())?,
+            Scheme::HTTP,
+            None,
+        )
+        .await?;
+        assert_eq!(resp.status(), StatusCode::GONE);
+        assert_eq!("", body(resp).await?);
+
+        let resp = handle(
+            stubbed,
+            Request::get("/other").body(Body::empty())?,
+            Scheme::HTTP,
+            None,
+        )
+        .await?;
+        assert_eq!("ok", body(resp).await?);
+        Ok(())
+    }
+
+    /// Requests `range` of a "hello world" response with --ranges
+    async fn ranged(range: &str) -> Result<Response<Body>, BoxError> {
+        let (engine, module) = guest(
+            "",
+            r#"(data (i32.const 64) "hello world")
+  (func (export "_start")
+    (call $respond (i32.const 200) (i32.const 64) (i32.const 11)))"#,
+        )?;
+        let ranged = State {
+            ranges: true,
+            ..state(engine, module)
+        };
+        Ok(handle(
+            ranged,
+            Request::get("/").header(RANGE, range).body(Body::empty())?,
+            Scheme::HTTP,
+            None,
+        )
+        .await?)
+    }
+
+    #[tokio::test]
+    async fn handle_serves_byte_ranges() -> Result<(), BoxError> {
+        for (range, content_range, partial) in &[
+            ("bytes=0-4", "bytes 0-4/11", "hello"),
+            ("bytes=6-", "bytes 6-10/11", "world"),
+            ("bytes=-3", "bytes 8-10/11", "rld"),
+            ("bytes=6-100", "bytes 6-10/11", "world"),
+        ] {
+            let resp = ranged(range).await?;
+            assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+            assert_eq!(resp.headers()[CONTENT_RANGE], *content_range);
+            assert_eq!(body(resp).await?, *partial);
+        }
+        // multiple ranges aren't supported, so get the full response
+        let resp = ranged("bytes=0-1,3-4").await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body(resp).await?, "hello world");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handle_rejects_unsatisfiable_byte_ranges() -> Result<(), BoxError> {
+        let resp = ranged("bytes=20-30").await?;
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(resp.headers()[CONTENT_RANGE], "bytes */11");
+        assert_eq!(body(resp).await?, "");
+        Ok(())
+    }
+
+    #[test]
+    fn bind_listens_with_backlog() -> Result<(), BoxError> {
+        let listener = bind(([127, 0, 0, 1], 0).into(), Some(16))?;
+        let addr = listener.local_addr()?;
+        std::net::TcpStream::connect(addr)?;
+        listener.accept()?;
+        // rather than wrapping to a negative backlog
+        assert!(bind(([127, 0, 0, 1], 0).into(), Some(u32::MAX)).is_err());
+        Ok(())
+    }
+
+    /// Reads from a stream until it has read `expected`, returning everything read
+    async fn read_until(
+        stream: &mut TcpStream,
+        expected: &str,
+    ) -> Result<String, BoxError> {
+        let mut buf = [0; 1024];
+        let mut read = Vec::new();
+        while !str::from_utf8(&read)?.contains(expected) {
+            let n = timeout(Duration::from_secs(5), stream.read(&mut buf)).await??;
+            assert!(n > 0, "connection closed before reading {}", expected);
+            read.extend_from_slice(&buf[..n]);
+        }
+        Ok(str::from_utf8(&read)?.to_owned())
+    }
+
+    fn ok_guest() -> Result<(Engine, Module), BoxError> {
+        guest(
+            "",
+            r#"(data (i32.const 64) "ok")
+  (func (export "_start")
+    (call $respond (i32.const 200) (i32.const 64) (i32.const 2)))"#,
+        )
+    }
+
+    #[tokio::test]
+    async fn replay_har_counts_mismatched_responses() -> Result<(), BoxError> {
+        let (engine, module) = ok_guest()?;
+        let har: Har = serde_json::from_str(
+            r#"{"log": {"entries": [
+  {"request": {"method": "GET", "url": "http://fasttime.co/a"},
+   "response": {"status": 200, "content": {"text": "ok"}}},
+  {"request": {"method": "GET", "url": "http://fasttime.co/b"},
+   "response": {"status": 200, "content": {"text": "changed"}}},
+  {"request": {"method": "GET", "url": "http://fasttime.co/c"},
+   "response": {"status": 200}}
+]}}"#,
+        )?;
+        assert_eq!(replay_har(state(engine, module), &har).await?, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handle_reuses_keep_alive_connections() -> Result<(), BoxError> {
+        let (engine, module) = ok_guest()?;
+        let addr = serve(state(engine, module))?;
+        let mut stream = TcpStream::connect(addr).await?;
+        for _ in 0..2 {
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await?;
+            let resp = read_until(&mut stream, "\r\n\r\nok").await?;
+            assert!(resp.starts_with("HTTP/1.1 200 OK"));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handle_honors_connection_close() -> Result<(), BoxError> {
+        let (engine, module) = ok_guest()?;
+        let addr = serve(state(engine, module))?;
+        let mut stream = TcpStream::connect(addr).await?;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await?;
+        let resp = read_until(&mut stream, "\r\n\r\nok").await?;
+        assert!(resp.starts_with("HTTP/1.1 200 OK"));
+        let mut buf = [0; 1024];
+        let n = timeout(Duration::from_secs(5), stream.read(&mut buf)).await??;
+        assert_eq!(n, 0, "expected connection to be closed");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_disconnects_slow_clients() -> Result<(), BoxError> {
+        let (engine, module) = ok_guest()?;
+        let (addr, _) = TestServer::new(engine, module)
+            .client_timeouts(ClientTimeouts {
+                header: Some(Duration::from_millis(200)),
+                body: None,
+            })
+            .start()?;
+        let mut stream = TcpStream::connect(addr).await?;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await?;
+        let resp = read_until(&mut stream, "\r\n\r\nok").await?;
+        assert!(resp.starts_with("HTTP/1.1 200 OK"));
+        // start another request, then stall before finishing its headers
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: ").await?;
+        let mut buf = [0; 1024];
+        let read = timeout(Duration::from_secs(5), stream.read(&mut buf)).await?;
+        assert!(
+            matches!(read, Ok(0) | Err(_)),
+            "expected connection to be closed"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_waits_on_applications_slower_than_the_body_timeout() -> Result<(), BoxError> {
+        // proxies the downstream request to the origin backend, reading its body to send it
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "body_downstream_get" (func $body_downstream_get (param i32 i32) (result i32)))
+  (import "fastly_http_req" "send" (func $send (param i32 i32 i32 i32 i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "origin")
+  (func (export "_start")
+    (drop (call $body_downstream_get (i32.const 16) (i32.const 20)))
+    (drop (call $send (i32.load (i32.const 16)) (i32.load (i32.const 20)) (i32.const 64) (i32.const 6) (i32.const 24) (i32.const 28)))
+    (drop (call $resp_send_downstream (i32.load (i32.const 24)) (i32.load (i32.const 28)) (i32.const 0))))"#,
+        )?;
+        let slow = |_: &str, req: Request<Body>| -> Result<Response<Body>, BoxError> {
+            std::thread::sleep(Duration::from_millis(500));
+            Ok(Response::new(req.into_body()))
+        };
+        let (addr, shutdown) = TestServer::new(engine, module)
+            .backends(Box::new(slow))
+            .client_timeouts(ClientTimeouts {
+                header: None,
+                body: Some(Duration::from_millis(100)),
+            })
+            .start()?;
+        let resp = reqwest::Client::new()
+            .post(&format!("http://{}/", addr))
+            .body("request body")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.text().await?, "request body");
+        shutdown.shutdown();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handle_downgrades_responses_to_http_10_clients() -> Result<(), BoxError> {
+        let (engine, module) = guest(
+            r#"(import "fastly_http_resp" "version_set" (func $resp_version_set (param i32 i32) (result i32)))"#,
+            r#"(data (i32.const 64) "ok")
+  (func (export "_start")
+    (drop (call $resp_new (i32.const 0)))
+    (drop (call $resp_version_set (i32.load (i32.const 0)) (i32.const 2)))
+    (drop (call $body_new (i32.const 4)))
+    (drop (call $body_write (i32.load (i32.const 4)) (i32.const 64) (i32.const 2) (i32.const 0) (i32.const 8)))
+    (drop (call $resp_send_downstream (i32.load (i32.const 0)) (i32.load (i32.const 4)) (i32.const 0))))"#,
+        )?;
+        let addr = serve(state(engine, module))?;
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(b"GET / HTTP/1.0\r\n\r\n").await?;
+        let mut resp = Vec::new();
+        timeout(Duration::from_secs(5), stream.read_to_end(&mut resp)).await??;
+        let resp = str::from_utf8(&resp)?.to_ascii_lowercase();
+        assert!(resp.starts_with("http/1.0 200 ok"));
+        assert!(!resp.contains("transfer-encoding"));
+        assert!(resp.ends_with("\r\n\r\nok"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handle_sends_streamed_responses_chunked() -> Result<(), BoxError> {
+        // streams a response downstream, writing to its body after sending it
+        let (engine, module) = guest(
+            r#"(import "fastly_http_body" "close" (func $body_close (param i32) (result i32)))"#,
+            r#"(data (i32.const 64) "hello streamed world")
+  (func (export "_start")
+    (drop (call $resp_new (i32.const 0)))
+    (drop (call $body_new (i32.const 4)))
+    (drop (call $body_write (i32.load (i32.const 4)) (i32.const 64) (i32.const 6) (i32.const 0) (i32.const 8)))
+    (drop (call $resp_send_downstream (i32.load (i32.const 0)) (i32.load (i32.const 4)) (i32.const 1)))
+    (drop (call $body_write (i32.load (i32.const 4)) (i32.const 70) (i32.const 9) (i32.const 0) (i32.const 8)))
+    (drop (call $body_write (i32.load (i32.const 4)) (i32.const 79) (i32.const 5) (i32.const 0) (i32.const 8)))
+    (drop (call $body_close (i32.load (i32.const 4)))))"#,
+        )?;
+        let addr = serve(state(engine, module))?;
+        let mut stream = TcpStream::connect(addr).await?;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut resp = Vec::new();
+        timeout(Duration::from_secs(5), stream.read_to_end(&mut resp)).await??;
+        let resp = str::from_utf8(&resp)?.to_ascii_lowercase();
+        assert!(resp.starts_with("http/1.1 200 ok"));
+        assert!(resp.contains("transfer-encoding: chunked\r\n"));
+        assert!(!resp.contains("content-length"));
+
+        let resp = reqwest::get(&format!("http://{}/", addr)).await?;
+        assert_eq!(resp.text().await?, "hello streamed world");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handle_streams_appended_chunks_as_they_are_written() -> Result<(), BoxError> {
+        // streams "first" downstream, then waits on the request body before appending "second"
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "body_downstream_get" (func $body_downstream_get (param i32 i32) (result i32)))
+  (import "fastly_http_body" "read" (func $body_read (param i32 i32 i32 i32) (result i32)))
+  (import "fastly_http_body" "append" (func $body_append (param i32 i32) (result i32)))
+  (import "fastly_http_body" "close" (func $body_close (param i32) (result i32)))"#,
+            r#"(data (i32.const 64) "firstsecond")
+  (func (export "_start")
+    (drop (call $resp_new (i32.const 0)))
+    (drop (call $body_new (i32.const 4)))
+    (drop (call $body_write (i32.load (i32.const 4)) (i32.const 64) (i32.const 5) (i32.const 0) (i32.const 8)))
+    (drop (call $resp_send_downstream (i32.load (i32.const 0)) (i32.load (i32.const 4)) (i32.const 1)))
+    (drop (call $body_downstream_get (i32.const 16) (i32.const 20)))
+    (drop (call $body_read (i32.load (i32.const 20)) (i32.const 1024) (i32.const 64) (i32.const 24)))
+    (drop (call $body_new (i32.const 28)))
+    (drop (call $body_write (i32.load (i32.const 28)) (i32.const 69) (i32.const 6) (i32.const 0) (i32.const 8)))
+    (drop (call $body_append (i32.load (i32.const 4)) (i32.load (i32.const 28))))
+    (drop (call $body_close (i32.load (i32.const 4)))))"#,
+        )?;
+        let addr = serve(state(engine, module))?;
+        let mut stream = TcpStream::connect(addr).await?;
+        stream
+            .write_all(
+                b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\nConnection: close\r\n\r\n",
+            )
+            .await?;
+        // the guest can't have appended "second" until it has read the body sent after "first"
+        let resp = timeout(Duration::from_secs(5), read_until(&mut stream, "first")).await??;
+        assert!(!resp.contains("second"));
+        stream.write_all(b"go").await?;
+        let mut rest = Vec::new();
+        timeout(Duration::from_secs(5), stream.read_to_end(&mut rest)).await??;
+        assert!(str::from_utf8(&rest)?.contains("second"));
+        Ok(())
+    }
+
+    #[test]
+    fn keep_alive_is_found_among_connection_options() -> Result<(), BoxError> {
+        let connection = |values: &[&'static str]| {
+            let mut headers = HeaderMap::new();
+            for value in values {
+                headers.append(CONNECTION, HeaderValue::from_static(value));
+            }
+            keep_alive(&headers)
+        };
+        assert!(connection(&["keep-alive"]));
+        assert!(connection(&["Upgrade, Keep-Alive"]));
+        assert!(connection(&["upgrade", " KEEP-ALIVE "]));
+        assert!(!connection(&["close"]));
+        assert!(!connection(&["keep-alive-ish"]));
+        assert!(!connection(&[]));
+        Ok(())
+    }
+
+    #[test]
+    fn downgrade_keeps_requested_http_10_connections_alive() -> Result<(), BoxError> {
+        let resp = downgrade(Response::new(Body::empty()), Version::HTTP_10, true);
+        assert_eq!(resp.version(), Version::HTTP_10);
+        assert!(!resp.headers().contains_key(CONNECTION));
+        let resp = downgrade(Response::new(Body::empty()), Version::HTTP_10, false);
+        assert_eq!(resp.headers()[CONNECTION], "close");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handle_sends_100_continue() -> Result<(), BoxError> {
+        // echos the downstream request body
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "body_downstream_get" (func $body_downstream_get (param i32 i32) (result i32)))
+  (import "fastly_http_body" "read" (func $body_read (param i32 i32 i32 i32) (result i32)))"#,
+            r#"(func (export "_start") (local $len i32)
+    (drop (call $body_downstream_get (i32.const 16) (i32.const 20)))
+    (block $done
+      (loop $read
+        (drop (call $body_read (i32.load (i32.const 20)) (i32.add (i32.const 1024) (local.get $len)) (i32.const 1024) (i32.const 24)))
+        (br_if $done (i32.eqz (i32.load (i32.const 24))))
+        (local.set $len (i32.add (local.get $len) (i32.load (i32.const 24))))
+        (br $read)))
+    (call $respond (i32.const 200) (i32.const 1024) (local.get $len)))"#,
+        )?;
+        let addr = serve(state(engine, module))?;
+
+        // a client which will not send its body until it receives the interim response
+        let mut stream = TcpStream::connect(addr).await?;
+        stream
+            .write_all(b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n")
+            .await?;
+        let mut buf = [0; 1024];
+        let read = timeout(Duration::from_secs(5), stream.read(&mut buf)).await??;
+        assert!(buf[..read].starts_with(b"HTTP/1.1 100 Continue"));
+
+        stream.write_all(b"hello").await?;
+        let resp = read_until(&mut stream, "\r\n\r\nhello").await?;
+        assert!(resp.contains("HTTP/1.1 200 OK"));
+        Ok(())
+    }
+
+    #[test]
+    fn cors_preflight_allows_requested_method_and_headers() -> Result<(), BoxError> {
+        let req = Request::builder()
+            .method(Method::OPTIONS)
+            .header(ORIGIN, "http://localhost:8080")
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+            .header(ACCESS_CONTROL_REQUEST_HEADERS, "x-custom")
+            .body(Body::empty())?;
+        let resp = cors_preflight(&req).expect("expected preflight response");
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(resp.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert_eq!(resp.headers()[ACCESS_CONTROL_ALLOW_METHODS], "PUT");
+        assert_eq!(resp.headers()[ACCESS_CONTROL_ALLOW_HEADERS], "x-custom");
+        Ok(())
+    }
+
+    #[test]
+    fn cors_preflight_ignores_other_requests() -> Result<(), BoxError> {
+        let options = Request::builder()
+            .method(Method::OPTIONS)
+            .body(Body::empty())?;
+        assert!(cors_preflight(&options).is_none());
+        let get = Request::get("/")
+            .header(ORIGIN, "http://localhost:8080")
+            .body(Body::empty())?;
+        assert!(cors_preflight(&get).is_none());
         Ok(())
     }
 
+    #[test]
+    fn allow_cors_keeps_application_headers() -> Result<(), BoxError> {
+        let mut resp = Response::builder()
+            .header(ACCESS_CONTROL_ALLOW_ORIGIN, "https://example.com")
+            .body(Body::empty())?;
+        allow_cors(&mut resp);
+        assert_eq!(
+            resp.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://example.com"
+        );
+        assert_eq!(resp.headers()[ACCESS_CONTROL_EXPOSE_HEADERS], "*");
+        Ok(())
+    }
+
+    fn conditions(
+        name: HeaderName,
+        value: &'static str,
+    ) -> Result<Conditions, BoxError> {
+        let req = Request::get("/").header(name, value).body(Body::empty())?;
+        Ok(Conditions::from_request(&req).expect("expected conditions"))
+    }
+
+    #[test]
+    fn conditions_match_etags() -> Result<(), BoxError> {
+        let resp = || {
+            Response::builder()
+                .header(ETAG, "W/\"v1\"")
+                .header(CONTENT_LENGTH, "2")
+                .body(Body::from("ok"))
+        };
+        let matched = conditions(IF_NONE_MATCH, "\"v0\", \"v1\"")?.apply(resp()?);
+        assert_eq!(matched.status(), StatusCode::NOT_MODIFIED);
+        assert!(!matched.headers().contains_key(CONTENT_LENGTH));
+        assert_eq!(matched.headers()[ETAG], "W/\"v1\"");
+        let unmatched = conditions(IF_NONE_MATCH, "\"v2\"")?.apply(resp()?);
+        assert_eq!(unmatched.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[test]
+    fn conditions_compare_modified_dates() -> Result<(), BoxError> {
+        let resp = || {
+            Response::builder()
+                .header(LAST_MODIFIED, "Wed, 21 Oct 2015 07:28:00 GMT")
+                .body(Body::from("ok"))
+        };
+        let unmodified =
+            conditions(IF_MODIFIED_SINCE, "Wed, 21 Oct 2015 07:28:00 GMT")?.apply(resp()?);
+        assert_eq!(unmodified.status(), StatusCode::NOT_MODIFIED);
+        let modified =
+            conditions(IF_MODIFIED_SINCE, "Tue, 20 Oct 2015 07:28:00 GMT")?.apply(resp()?);
+        assert_eq!(modified.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[test]
+    fn conditions_ignore_unsafe_methods() -> Result<(), BoxError> {
+        let req = Request::post("/")
+            .header(IF_NONE_MATCH, "*")
+            .body(Body::empty())?;
+        assert!(Conditions::from_request(&req).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handle_adds_extra_downstream_headers() -> Result<(), BoxError> {
+        // responds with a status of 200 + the original header count
+        let (engine, module) = guest(
+            r#"(import "fastly_http_req" "original_header_count" (func $original_header_count (param i32) (result i32)))"#,
+            r#"(func (export "_start")
+    (drop (call $original_header_count (i32.const 16)))
+    (call $respond (i32.add (i32.const 200) (i32.load (i32.const 16))) (i32.const 0) (i32.const 0)))"#,
+        )?;
+        let state = State {
+            extra_downstream_headers: vec![
+                Header {
+                    name: "x-extra".into(),
+                    value: "1".into(),
+                }
+                .parse()?,
+                Header {
+                    name: "x-extra".into(),
+                    value: "2".into(),
+                }
+                .parse()?,
+            ],
+            ..state(engine, module)
+        };
+        let resp = handle(
+            state,
+            Request::get("/").header("foo", "bar").body(Body::empty())?,
+            Scheme::HTTP,
+            None,
+        )
+        .await?;
+        assert_eq!(resp.status().as_u16(), 203);
+        Ok(())
+    }
+
+    #[test]
+    fn header_parse_rejects_invalid_names() {
+        assert!(Header {
+            name: "invalid name".into(),
+            value: "value".into(),
+        }
+        .parse()
+        .is_err());
+    }
+
     #[test]
     fn test_rewrite_uri_https() -> Result<(), BoxError> {
         let req = Request::builder()
@@ -1,8 +1,9 @@
 //! Defines interfaces for working with WASM application's memory
 
 use byteorder::{ByteOrder, LittleEndian};
+use fastly_shared::FastlyStatus;
 use std::io::{self, Read, Write};
-use wasmtime::Memory;
+use wasmtime::{Memory, Trap};
 
 /// macro for getting exported memory from `Caller` or early return  on `Trap` error
 #[macro_export]
@@ -19,19 +20,70 @@ macro_rules! memory {
     };
 }
 
+/// Converts a guest address or length to a host one
+///
+/// The guest's `usize` is 32 bits, which the ABI passes as an `i32`, so values of 2GiB and
+/// over arrive negative and are reinterpreted as unsigned rather than sign extended
+pub fn offset(value: i32) -> usize {
+    value as u32 as usize
+}
+
+/// Returns the error for an access beyond the end of a guest's memory
+fn out_of_bounds(index: i32) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!("address {} is out of bounds", offset(index)),
+    )
+}
+
+/// The size of a page of guest memory
+const PAGE_SIZE: usize = 65536;
+
+/// How far beyond the end of memory `reserve` will grow it to reach
+const RESERVE_SLACK: usize = PAGE_SIZE;
+
+/// Grows memory to cover `end` when it's within a page beyond the current size and the memory's
+/// declared maximum, as guests may pass pointers into pages their allocator hasn't grown into
+/// yet. Pointers further out are more likely garbage than a page the guest is about to grow
+/// into, and as most guests declare no maximum, growing to reach them could commit gigabytes.
+/// Returns whether memory now covers `end`
+fn reserve(
+    memory: &Memory,
+    end: usize,
+) -> bool {
+    let size = memory.data_size();
+    if end <= size {
+        return true;
+    }
+    if end - size > RESERVE_SLACK {
+        return false;
+    }
+    let pages = (end - size + PAGE_SIZE - 1) / PAGE_SIZE;
+    memory.grow(pages as u32).is_ok()
+}
+
+/// Returns the `Trap` for a failed write to a guest's memory, for hostcalls handed an out
+/// parameter pointing outside of it
+pub fn trap(err: io::Error) -> Trap {
+    Trap::new(format!("failed to write guest memory: {}", err))
+}
+
 /// Convience api for common write operations
+///
+/// Writes grow memory to cover a target just past its end, when its declared maximum allows. Beyond that,
+/// `write_bytes` writes what fits, while writing an integer fails
 pub trait WriteMem {
     fn write_i32(
         &mut self,
         index: i32,
         value: i32,
-    );
+    ) -> io::Result<()>;
 
     fn write_u32(
         &mut self,
         index: i32,
         value: u32,
-    );
+    ) -> io::Result<()>;
 
     fn write_bytes(
         &mut self,
@@ -45,22 +97,31 @@ impl WriteMem for Memory {
         &mut self,
         index: i32,
         value: i32,
-    ) {
-        unsafe {
+    ) -> io::Result<()> {
+        reserve(self, offset(index) + 4);
+        match unsafe { self.data_unchecked_mut() }.get_mut(offset(index)..) {
             // one little, two little, three litte Endian...
-            LittleEndian::write_i32(&mut self.data_unchecked_mut()[index as usize..], value);
-        };
+            Some(slice) if slice.len() >= 4 => {
+                LittleEndian::write_i32(slice, value);
+                Ok(())
+            }
+            _ => Err(out_of_bounds(index)),
+        }
     }
 
     fn write_u32(
         &mut self,
         index: i32,
         value: u32,
-    ) {
-        LittleEndian::write_u32(
-            unsafe { &mut self.data_unchecked_mut()[index as usize..] },
-            value as u32,
-        )
+    ) -> io::Result<()> {
+        reserve(self, offset(index) + 4);
+        match unsafe { self.data_unchecked_mut() }.get_mut(offset(index)..) {
+            Some(slice) if slice.len() >= 4 => {
+                LittleEndian::write_u32(slice, value);
+                Ok(())
+            }
+            _ => Err(out_of_bounds(index)),
+        }
     }
 
     fn write_bytes(
@@ -68,7 +129,48 @@ impl WriteMem for Memory {
         index: i32,
         bytes: &[u8],
     ) -> io::Result<usize> {
-        (unsafe { &mut self.data_unchecked_mut()[index as usize..] }).write(bytes)
+        reserve(self, offset(index) + bytes.len());
+        match unsafe { self.data_unchecked_mut() }.get_mut(offset(index)..) {
+            Some(mut slice) => slice.write(bytes),
+            _ => Err(out_of_bounds(index)),
+        }
+    }
+}
+
+/// Writes bytes followed by the NUL terminator the ABI requires, and how many bytes that was
+///
+/// The terminator counts toward `maxlen`. When they don't fit, only the size needed is written
+/// and `BUFLEN` is returned, so the guest can retry with a larger buffer as long as the caller
+/// leaves its cursor where it was. Returns the status to answer the guest with when the bytes
+/// weren't written
+pub fn write_nul_terminated(
+    memory: &mut Memory,
+    addr: i32,
+    maxlen: i32,
+    bytes: &[u8],
+    nwritten_out: i32,
+) -> Result<Option<i32>, Trap> {
+    let mut terminated = Vec::with_capacity(bytes.len() + 1);
+    terminated.extend_from_slice(bytes);
+    terminated.push(0);
+    if terminated.len() > offset(maxlen) {
+        memory
+            .write_i32(nwritten_out, terminated.len() as i32)
+            .map_err(trap)?;
+        return Ok(Some(FastlyStatus::BUFLEN.code));
+    }
+    match memory.write_bytes(addr, &terminated) {
+        Ok(written) if written == terminated.len() => {
+            memory
+                .write_i32(nwritten_out, written as i32)
+                .map_err(trap)?;
+            Ok(None)
+        }
+        _ => Err(Trap::new(format!(
+            "failed to write {} bytes to address {}",
+            terminated.len(),
+            offset(addr)
+        ))),
     }
 }
 
@@ -87,9 +189,74 @@ impl ReadMem for Memory {
         index: i32,
         amount: i32,
     ) -> io::Result<(usize, Vec<u8>)> {
-        let mut buf = Vec::with_capacity(amount as usize);
-        let mut slice = unsafe { &self.data_unchecked_mut()[index as usize..] };
-        let num = (&mut slice).take(amount as u64).read_to_end(&mut buf)?;
+        let mut slice = match unsafe { self.data_unchecked_mut() }.get(offset(index)..) {
+            Some(slice) => slice,
+            _ => return Err(out_of_bounds(index)),
+        };
+        let mut buf = Vec::with_capacity(offset(amount).min(slice.len()));
+        let num = (&mut slice)
+            .take(offset(amount) as u64)
+            .read_to_end(&mut buf)?;
         Ok((num, buf))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasmtime::{Limits, MemoryType, Store};
+
+    #[test]
+    fn offsets_are_unsigned() {
+        assert_eq!(offset(0), 0);
+        assert_eq!(offset(i32::MAX), 2_147_483_647);
+        assert_eq!(offset(i32::MIN), 2_147_483_648);
+        assert_eq!(offset(-1), 4_294_967_295);
+    }
+
+    #[test]
+    fn accesses_beyond_memory_fail_rather_than_panic() -> Result<(), crate::BoxError> {
+        // a memory which can't grow
+        let mut memory = Memory::new(&Store::default(), MemoryType::new(Limits::new(1, Some(1))));
+        // one page is 64KiB, so only the last 6 bytes of a read from 65530 are in bounds
+        let (read, bytes) = memory.read_bytes(65530, 100)?;
+        assert_eq!((read, bytes.len()), (6, 6));
+        assert!(memory.read_bytes(-1, 4).is_err());
+        assert_eq!(memory.read_bytes(0, -1)?.0, 65536);
+        assert_eq!(memory.write_bytes(65535, &[1, 2])?, 1);
+        assert!(memory.write_bytes(i32::MIN, &[1]).is_err());
+        // integers are written whole or not at all
+        assert!(memory.write_i32(65534, 1).is_err());
+        assert!(memory.write_u32(-1, 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn writes_beyond_memory_grow_it_up_to_its_maximum() -> Result<(), crate::BoxError> {
+        let mut memory = Memory::new(&Store::default(), MemoryType::new(Limits::new(1, Some(2))));
+        // straddles the boundary of the first page, so a second is committed
+        assert_eq!(memory.write_bytes(65535, &[1, 2])?, 2);
+        assert_eq!(memory.size(), 2);
+        assert_eq!(memory.read_bytes(65535, 2)?.1, vec![1, 2]);
+        // a third page would exceed the maximum, so only what fits is written
+        assert_eq!(memory.write_bytes(131071, &[3, 4])?, 1);
+        assert_eq!(memory.size(), 2);
+
+        let mut memory = Memory::new(&Store::default(), MemoryType::new(Limits::new(1, Some(2))));
+        memory.write_u32(65534, 7)?;
+        assert_eq!(memory.size(), 2);
+        assert_eq!(memory.read_bytes(65534, 4)?.1, vec![7, 0, 0, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn wild_pointers_do_not_grow_memory() {
+        // like most guests, declares no maximum
+        let mut memory = Memory::new(&Store::default(), MemoryType::new(Limits::new(1, None)));
+        assert!(memory.write_bytes(-1, &[1]).is_err());
+        assert!(memory.write_u32(i32::MAX, 1).is_err());
+        // more than a page beyond the end of memory
+        assert!(memory.write_bytes(65536 * 2 + 1, &[1]).is_err());
+        assert_eq!(memory.size(), 1);
+    }
+}
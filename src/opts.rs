@@ -1,12 +1,20 @@
+use serde::{de, Deserializer};
 use serde_derive::Deserialize;
-use std::{collections::HashMap, error::Error as StdError, path::PathBuf, str::FromStr};
+use std::{
+    collections::BTreeMap, error::Error as StdError, fmt::Debug, num::NonZeroUsize, path::PathBuf,
+    str::FromStr, time::Duration,
+};
 use structopt::{
     clap::{Error, ErrorKind},
     StructOpt,
 };
 use structopt_toml::StructOptToml;
 
-use crate::{Backend, Dictionary};
+use crate::{
+    backend::HostHeader, clock::Clock, geo::GeoAbi, handler::LogFormat, Backend, BackendCert,
+    BackendFallback, BackendHeader, Dictionary, Header, HostModule, LogEndpoint, ObjectStore,
+    SecretStore, Stub,
+};
 
 #[derive(Debug, Deserialize)]
 struct TOMLTables {
@@ -14,6 +22,12 @@ struct TOMLTables {
     backends: Option<Vec<Backend>>,
     #[serde(rename = "dictionary")]
     dictionaries: Option<Vec<Dictionary>>,
+    #[serde(rename = "log_endpoint")]
+    log_endpoints: Option<Vec<LogEndpoint>>,
+    #[serde(rename = "object_store")]
+    object_stores: Option<Vec<ObjectStore>>,
+    #[serde(rename = "secret_store")]
+    secret_stores: Option<Vec<SecretStore>>,
 }
 
 /// ⏱️  A local Fastly Compute@Edge runtime emulator
@@ -23,24 +37,233 @@ pub struct Opts {
     /// Path to a Fastly Compute@Edge .wasm file
     #[structopt(long, short, default_value = "bin/main.wasm")]
     pub(crate) wasm: PathBuf,
-    /// Port to listen on
+    /// Port to listen on. Use 0 to listen on any available port
     #[structopt(long, short, default_value = "3000")]
     pub(crate) port: u16,
+    /// Path to a default tls certificate
     #[structopt(long)]
     pub(crate) tls_cert: Option<PathBuf>,
+    /// Path to the private key of the default tls certificate
     #[structopt(long)]
     pub(crate) tls_key: Option<PathBuf>,
+    /// Directory of {host}.crt and {host}.key pairs, selected by the client's SNI hostname
+    #[structopt(long)]
+    pub(crate) tls_dir: Option<PathBuf>,
     /// Watch for changes to .wasm file, reloading application when relevant
     #[structopt(long)]
     pub(crate) watch: bool,
+    /// Directory to write downstream request and final response bodies to, for debugging
+    #[structopt(long)]
+    pub(crate) dump_bodies: Option<PathBuf>,
+    /// Directory to write each request the application sends to a backend to, with its method, uri, headers,
+    /// and body, for debugging
+    #[structopt(long)]
+    pub(crate) dump_backend_requests: Option<PathBuf>,
+    /// Handle a single request then exit, with a non-zero status if the handler failed
+    #[structopt(long)]
+    pub(crate) once: bool,
+    /// Path to an HTML or JSON file to respond with, as a 500, when the application fails to handle a request
+    #[structopt(long)]
+    pub(crate) error_page: Option<PathBuf>,
+    /// Add Fastly debugging headers, like Fastly-Error, to responses
+    #[structopt(long)]
+    pub(crate) fastly_headers: bool,
+    /// Delay to add before sending each response, like 200ms, to simulate a slow edge
+    #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub(crate) response_delay: Option<Duration>,
+    /// Maximum random delay to add on top of --response-delay, like 50ms
+    #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub(crate) response_delay_jitter: Option<Duration>,
+    /// Maximum number of headers a downstream request may have. Requests with more are rejected with a 431
+    #[structopt(long)]
+    pub(crate) max_headers: Option<usize>,
+    /// Maximum size, in bytes, of a downstream request header's name and value. Requests with larger headers are rejected with a 431
+    #[structopt(long)]
+    pub(crate) max_header_size: Option<usize>,
+    /// Maximum length, in bytes, of a downstream request's uri, rejected with a 414 when longer, and of
+    /// uris the application sets on requests
+    #[structopt(long, default_value = "8192")]
+    pub(crate) max_uri_length: usize,
+    /// Upper case standard request methods sent in another case and reject extension methods with a 501
+    #[structopt(long)]
+    pub(crate) normalize_methods: bool,
+    /// Fail requests whose application uses a request, response, or body handle after it was consumed,
+    /// for example by sending it. This is a diagnostic aid for finding bugs in applications
+    #[structopt(long)]
+    pub(crate) strict_handles: bool,
+    /// Respond to CORS preflight requests and allow any origin to read responses. For local development only
+    #[structopt(long)]
+    pub(crate) cors: bool,
+    /// Decompress gzip and br encoded downstream request bodies before the application reads them,
+    /// removing their Content-Encoding header
+    #[structopt(long)]
+    pub(crate) decompress_request: bool,
+    /// Print every hostcall the application makes, with its arguments and how long it took, after each request
+    #[structopt(long)]
+    pub(crate) trace_hostcalls: bool,
+    /// Print how long reading the request body, instantiating the application, running it, and waiting on
+    /// backends took after each request
+    #[structopt(long)]
+    pub(crate) timings: bool,
+    /// Respond with a 304 Not Modified when a client's If-None-Match or If-Modified-Since header
+    /// matches the ETag or Last-Modified header of the application's response
+    #[structopt(long)]
+    pub(crate) conditional: bool,
+    /// Respond to GET requests with a single byte Range with a 206 Partial Content response for that
+    /// range of the application's response body, or a 416 Range Not Satisfiable when it lies outside of it
+    #[structopt(long)]
+    pub(crate) ranges: bool,
+    /// Maximum number of pending connections the listener queues before refusing new ones. Defaults to 1024
+    #[structopt(long)]
+    pub(crate) backlog: Option<u32>,
+    /// Path, like /_fasttime/health, to answer health checks on without invoking the application.
+    /// Responds with a 503 when the module failed to reload
+    #[structopt(long)]
+    pub(crate) health_path: Option<String>,
+    /// Proxy requests for a backend named geolocation, rather than answering them with geolocation lookups
+    #[structopt(long)]
+    pub(crate) disable_geolocation: bool,
+    /// The fastly SDK generation to answer geolocation lookups for: v1 for fastly 0.5, v2 for later versions
+    #[structopt(long, default_value = "v1", possible_values = &["v1", "v2"])]
+    pub(crate) geo_abi: GeoAbi,
+    /// What to do with requests for backends which are not configured: error responds with a 502,
+    /// passthrough sends them to the host in the request's uri
+    #[structopt(long, default_value = "error", possible_values = &["error", "passthrough"])]
+    pub(crate) backend_fallback: BackendFallback,
+    /// How to print messages the application writes to log endpoints: plain prints them as written,
+    /// jsonl prints one {timestamp, endpoint, message} JSON object per write
+    #[structopt(long, default_value = "plain", possible_values = &["plain", "jsonl"])]
+    pub(crate) log_endpoint_format: LogFormat,
+    /// Skip loading a .wasm file and respond to each request with its method, path, headers, and body as JSON,
+    /// for smoke testing fasttime's server independent of an application
+    #[structopt(long)]
+    pub(crate) echo: bool,
+    /// TOML file of rules rewriting the path of, or adding headers to, downstream requests
+    /// before the application receives them
+    #[structopt(long)]
+    pub(crate) rewrite_rules: Option<PathBuf>,
+    /// Maximum number of requests the application may send to backends while handling a single request.
+    /// Sends beyond it fail, protecting shared origins from runaway applications
+    #[structopt(long)]
+    pub(crate) max_backend_requests: Option<usize>,
+    /// Maximum size, in bytes, of a body the application writes. Writes and appends beyond it fail,
+    /// protecting fasttime from running out of memory
+    #[structopt(long)]
+    pub(crate) max_response_body: Option<usize>,
+    /// Cache GET backend responses for the ttl the application sets with cache overrides,
+    /// or otherwise the ttl of their Cache-Control header
+    #[structopt(long)]
+    pub(crate) cache: bool,
+    /// Port of a separate listener for listing and purging cached responses as JSON. Implies --cache
+    #[structopt(long)]
+    pub(crate) admin_port: Option<u16>,
+    /// Directory of JSON files with responses to pre-populate the cache with at startup,
+    /// keyed by backend and url, so the first requests hit the cache. Implies --cache
+    #[structopt(long)]
+    pub(crate) seed_cache: Option<PathBuf>,
+    /// Log a backtrace of the application's functions when it traps, like when it panics.
+    /// Functions are named when the .wasm file includes a name section
+    #[structopt(long)]
+    pub(crate) guest_backtrace: bool,
+    /// The wall clock the application observes: system, fixed:{rfc3339 time} like fixed:2023-01-01T00:00:00Z,
+    /// or offset:{duration} like offset:-1h
+    #[structopt(long, default_value = "system")]
+    pub(crate) clock: Clock,
+    /// Resolve the host names of backend addresses at startup, failing to start when one doesn't resolve,
+    /// and send their requests to the resolved addresses rather than resolving them per request
+    #[structopt(long)]
+    pub(crate) backends_from_dns: bool,
+    /// Replace headers the application set with --response-header headers of the same name, rather than keeping them
+    #[structopt(long)]
+    pub(crate) force_response_header: bool,
+    /// Connect to every backend at startup, failing to start when any can't be reached
+    #[structopt(long)]
+    pub(crate) require_backends: bool,
+    /// How long --require-backends waits to connect to each backend. Defaults to 2s
+    #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub(crate) require_backends_timeout: Option<Duration>,
+    /// Maximum time, like 30s, to handle a request, including reading its body and calling backends.
+    /// Requests taking longer are answered with a 504
+    #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub(crate) request_timeout: Option<Duration>,
+    /// Maximum time, like 10s, a client may take to send a request's headers, including time a
+    /// kept alive connection sits idle. Clients taking longer are disconnected
+    #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub(crate) client_header_timeout: Option<Duration>,
+    /// Maximum time, like 10s, to wait for each next part of a request's body. Clients stalling
+    /// longer are disconnected
+    #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub(crate) client_body_timeout: Option<Duration>,
+    /// Status to respond to requests for backends which are not configured with. Defaults to 502
+    #[structopt(long)]
+    pub(crate) unknown_backend_status: Option<u16>,
+    /// Body to respond to requests for backends which are not configured with, where {name} is
+    /// replaced with the backend's name. Defaults to "Unknown backend {name}"
+    #[structopt(long)]
+    pub(crate) unknown_backend_body: Option<String>,
+    /// Add a Fastly-Backend-Unknown header naming the backend to responses for backends which are not configured
+    #[structopt(long)]
+    pub(crate) unknown_backend_header: bool,
+    /// TOML file of arguments and environment variables for the application to observe through WASI
+    #[structopt(long)]
+    pub(crate) wasi_config: Option<PathBuf>,
+    /// File to write the access log to, as one JSON object per request, rather than printing it
+    #[structopt(long)]
+    pub(crate) access_log: Option<PathBuf>,
+    /// Size, in bytes, past which the --access-log file is renamed with a .1 suffix and a new one started
+    #[structopt(long)]
+    pub(crate) access_log_max_size: Option<u64>,
+    /// HAR file of requests to replay against the application, reporting how its responses differ
+    /// from the recorded ones, rather than serving requests
+    #[structopt(long)]
+    pub(crate) replay: Option<PathBuf>,
+    /// Command to rebuild the .wasm file with when sources in --reload-dir change, reloading the application
+    /// when it succeeds. Implies --watch
+    #[structopt(long)]
+    pub(crate) reload_command: Option<String>,
+    /// Directory of sources to watch for changes with --reload-command
+    #[structopt(long, default_value = "src")]
+    pub(crate) reload_dir: PathBuf,
+    /// Name of a dictionary whose values are base64 encoded gzip, decompressed before the application reads them
+    #[structopt(long, number_of_values = 1)]
+    pub(crate) dictionary_gzip: Option<Vec<String>>,
+    /// Send concurrent identical backend requests, by backend, method, uri, and Idempotency-Key header, to
+    /// backends once, answering each with the same response
+    #[structopt(long)]
+    pub(crate) coalesce_backends: bool,
+    /// Maximum number of instances of the application to run at once, at least 1. Requests beyond it wait for an
+    /// instance to finish
+    #[structopt(long)]
+    pub(crate) max_instances: Option<NonZeroUsize>,
+    /// How long requests wait for an instance with --max-instances before being rejected with a 503,
+    /// 5s by default
+    #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub(crate) max_instances_timeout: Option<Duration>,
+    /// Print the Fastly hostcalls fasttime links applications with, marking those which are stubs that
+    /// always answer UNSUPPORTED, then exit
+    #[structopt(long)]
+    pub(crate) list_hostcalls: bool,
+    /// Pass downstream request uris to the application as received, usually path-only as on Fastly, rather
+    /// than making them absolute with the request's scheme and Host header
+    #[structopt(long)]
+    pub(crate) no_default_host_rewrite: bool,
     /// TOML file to load configuration from. Commandline parameters will override
-    /// the file, except for backends and dictionaries, which will be merged
+    /// the file, except for backends, dictionaries, log endpoints, and object and secret stores,
+    /// which will be merged
     #[structopt(long, short)]
     // Ignore config_file in TOML, because we don't support daisy chaining them
     #[serde(skip)]
     pub(crate) config_file: Option<PathBuf>,
     // For TOML, tables must go last
-    /// Backend to proxy in backend-name:host format (foo:foo.org)
+    /// Backend to proxy in backend-name:host format (foo:foo.org), optionally followed by the Host header
+    /// policy to send its requests with, like foo:foo.org,host_header=override
     #[structopt(name="backend", long, short, parse(try_from_str = parse_backend))]
     #[serde(rename = "backend")]
     pub(crate) backends: Option<Vec<Backend>>,
@@ -48,6 +271,51 @@ pub struct Opts {
     #[structopt(name="dictionary", long, short, parse(try_from_str = parse_dictionary))]
     #[serde(rename = "dictionary")]
     pub(crate) dictionaries: Option<Vec<Dictionary>>,
+    /// Header to add to backend requests in name:value format, or backend/name:value format
+    /// to add it to requests for a single backend. These are appended after headers set by the application
+    #[structopt(name="backend-header", long, parse(try_from_str = parse_backend_header))]
+    #[serde(rename = "backend_header")]
+    pub(crate) backend_headers: Option<Vec<BackendHeader>>,
+    /// Client certificate and private key to present to a backend requiring mutual tls in
+    /// backend-name:cert.pem:key.pem format
+    #[structopt(name="backend-cert", long, parse(try_from_str = parse_backend_cert))]
+    #[serde(rename = "backend_cert")]
+    pub(crate) backend_certs: Option<Vec<BackendCert>>,
+    /// Synthetic header to add to each downstream request, before the application runs, in name:value format.
+    /// These are counted among the request's original headers
+    #[structopt(name="extra-downstream-header", long, parse(try_from_str = parse_header))]
+    #[serde(rename = "extra_downstream_header")]
+    pub(crate) extra_downstream_headers: Option<Vec<Header>>,
+    /// Module to handle requests for a hostname in hostname:path.wasm format. Requests are routed by the
+    /// SNI hostname of their tls connection, then by their Host header, falling back on --wasm
+    #[structopt(name="host-module", long, parse(try_from_str = parse_host_module))]
+    #[serde(rename = "host_module")]
+    pub(crate) host_modules: Option<Vec<HostModule>>,
+    /// Header to add to each response, after the application runs, in name:value format, to simulate
+    /// headers an edge adds like Strict-Transport-Security. Headers the application set are kept
+    #[structopt(name="response-header", long, parse(try_from_str = parse_header))]
+    #[serde(rename = "response_header")]
+    pub(crate) response_headers: Option<Vec<Header>>,
+    /// Response to answer requests for a path with, without invoking the application, in
+    /// /path=status:body format. The body is optional. When several paths match, the first wins
+    #[structopt(name="route", long, parse(try_from_str = parse_route))]
+    #[serde(rename = "route")]
+    pub(crate) routes: Option<Vec<Stub>>,
+    /// Log endpoint to append the messages the application writes to it to a file with, rather than
+    /// printing them, in endpoint-name:path format
+    #[structopt(name="log-endpoint", long, parse(try_from_str = parse_log_endpoint))]
+    #[serde(rename = "log_endpoint")]
+    pub(crate) log_endpoints: Option<Vec<LogEndpoint>>,
+    /// Object store in store-name:key=value,key=value format. Object stores are not yet available
+    /// to applications
+    #[structopt(name="object-store", long, parse(try_from_str = parse_object_store))]
+    #[serde(rename = "object_store")]
+    pub(crate) object_stores: Option<Vec<ObjectStore>>,
+    /// Secret store in store-name:key=value,key=value format. Secret stores are not yet available
+    /// to applications
+    #[structopt(name="secret-store", long, parse(try_from_str = parse_secret_store))]
+    #[serde(rename = "secret_store")]
+    pub(crate) secret_stores: Option<Vec<SecretStore>>,
 }
 
 impl Opts {
@@ -72,38 +340,59 @@ impl Opts {
             // We can't load a whole Opts straight from TOML using Serde Derive, unfortunately,
             // because then certain things are no longer optional. StructOpt-TOML normally
             // takes care of that, but it uses some hefty magic to juggle defaults around.
-            // So instead, just load a struct that only has the two tables that we want to merge.
-            let mut toml_tables = toml::from_str::<TOMLTables>(&toml_string).unwrap();
-            // If backends is None for either, structopt-toml does the right thing, only
-            // if they're both Some(), do we need to get fancy. We'll let the conversion to
-            // HashMap later handle de-duplication, so we just need to make sure that the entries
-            // from the TOML are before the entries from the commandline.
-            if let (Some(args_backends), Some(toml_backends)) =
-                (&mut args.backends, &mut toml_tables.backends)
-            {
-                // when both are Some(), combined.backends should have the backends from args
-                let combined_backends = combined.backends.as_mut().unwrap();
-                assert_eq!(combined_backends, args_backends);
-                // since there is no prepend(), get them in the right order first
-                toml_backends.append(combined_backends);
-                // then move them where we need them
-                combined_backends.append(toml_backends);
-            }
-            // Exact same logic for dictionaries as for backends
-            if let (Some(args_dicts), Some(toml_dicts)) =
-                (&mut args.dictionaries, &mut toml_tables.dictionaries)
-            {
-                let combined_dicts = combined.dictionaries.as_mut().unwrap();
-                assert_eq!(combined_dicts, args_dicts);
-                toml_dicts.append(combined_dicts);
-                combined_dicts.append(toml_dicts);
-            }
+            // So instead, just load a struct that only has the tables that we want to merge.
+            let toml_tables = toml::from_str::<TOMLTables>(&toml_string).unwrap();
+            merge_tables(&args.backends, toml_tables.backends, &mut combined.backends);
+            merge_tables(
+                &args.dictionaries,
+                toml_tables.dictionaries,
+                &mut combined.dictionaries,
+            );
+            merge_tables(
+                &args.log_endpoints,
+                toml_tables.log_endpoints,
+                &mut combined.log_endpoints,
+            );
+            merge_tables(
+                &args.object_stores,
+                toml_tables.object_stores,
+                &mut combined.object_stores,
+            );
+            merge_tables(
+                &args.secret_stores,
+                toml_tables.secret_stores,
+                &mut combined.secret_stores,
+            );
             args = combined;
         }
         args
     }
 }
 
+/// Merges a table's entries from a TOML file with those from the commandline
+///
+/// If the table is None for either, structopt-toml does the right thing, only
+/// if they're both Some(), do we need to get fancy. We'll let the conversion to
+/// a map later handle de-duplication, so we just need to make sure that the entries
+/// from the TOML are before the entries from the commandline.
+fn merge_tables<T>(
+    args: &Option<Vec<T>>,
+    toml: Option<Vec<T>>,
+    combined: &mut Option<Vec<T>>,
+) where
+    T: Debug + PartialEq,
+{
+    if let (Some(args), Some(mut toml)) = (args, toml) {
+        // when both are Some(), combined should have the entries from args
+        let combined = combined.as_mut().unwrap();
+        assert_eq!(combined, args);
+        // since there is no prepend(), get them in the right order first
+        toml.append(combined);
+        // then move them where we need them
+        combined.append(&mut toml);
+    }
+}
+
 fn parse_key_value<T, U>(s: &str) -> Result<(T, U), Box<dyn StdError>>
 where
     T: FromStr,
@@ -118,22 +407,306 @@ where
 }
 
 fn parse_backend(s: &str) -> Result<Backend, Box<dyn StdError>> {
-    let (name, address) = parse_key_value(s)?;
-    Ok(Backend { name, address })
+    let (name, address) = parse_key_value::<String, String>(s)?;
+    let (address, host_header) = match address.find(',') {
+        Some(pos) => {
+            let policy = address[pos + 1..]
+                .strip_prefix("host_header=")
+                .ok_or_else(|| {
+                    format!(
+                        "invalid host_header=policy: found `{}`",
+                        &address[pos + 1..]
+                    )
+                })?;
+            (address[..pos].to_owned(), policy.parse()?)
+        }
+        _ => (address, HostHeader::default()),
+    };
+    Ok(Backend {
+        name,
+        address,
+        host_header,
+    })
 }
 
-fn parse_dictionary(s: &str) -> Result<Dictionary, Box<dyn StdError>> {
+fn parse_header(s: &str) -> Result<Header, Box<dyn StdError>> {
+    let (name, value) = parse_key_value(s)?;
+    Ok(Header { name, value })
+}
+
+fn parse_host_module(s: &str) -> Result<HostModule, Box<dyn StdError>> {
+    let (host, wasm) = parse_key_value(s)?;
+    Ok(HostModule { host, wasm })
+}
+
+fn parse_backend_header(s: &str) -> Result<BackendHeader, Box<dyn StdError>> {
+    let (scoped, value) = parse_key_value::<String, String>(s)?;
+    let (backend, name) = match scoped.find('/') {
+        Some(pos) => (Some(scoped[..pos].to_owned()), scoped[pos + 1..].to_owned()),
+        _ => (None, scoped),
+    };
+    Ok(BackendHeader {
+        backend,
+        name,
+        value,
+    })
+}
+
+fn parse_route(s: &str) -> Result<Stub, Box<dyn StdError>> {
+    let pos = s
+        .find('=')
+        .ok_or_else(|| format!("invalid /path=status:body: no `=` found in `{}`", s))?;
+    let (path, response) = (&s[..pos], &s[pos + 1..]);
+    let (status, body) = match response.find(':') {
+        Some(pos) => (&response[..pos], &response[pos + 1..]),
+        _ => (response, ""),
+    };
+    let status = status.parse::<http::StatusCode>()?.as_u16();
+    Ok(Stub {
+        path: path.into(),
+        status,
+        body: body.into(),
+    })
+}
+
+/// Deserializes TOML durations with the same format as the commandline, like "200ms"
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match <Option<String> as serde::Deserialize>::deserialize(deserializer)? {
+        Some(s) => humantime::parse_duration(&s)
+            .map(Some)
+            .map_err(de::Error::custom),
+        _ => Ok(None),
+    }
+}
+
+fn parse_backend_cert(s: &str) -> Result<BackendCert, Box<dyn StdError>> {
+    let (backend, paths) = parse_key_value::<String, String>(s)?;
+    let pos = paths
+        .find(':')
+        .ok_or_else(|| format!("invalid name:cert:key: no key `:` found in `{}`", s))?;
+    Ok(BackendCert {
+        backend,
+        cert: paths[..pos].into(),
+        key: paths[pos + 1..].into(),
+    })
+}
+
+/// Parses a name:key=value,key=value table
+fn parse_entries(s: &str) -> Result<(String, BTreeMap<String, String>), Box<dyn StdError>> {
     let (name, v) = parse_key_value::<String, String>(s)?;
-    let dict: Result<HashMap<String, String>, Box<dyn StdError>> =
-        v.split(',').try_fold(HashMap::default(), |mut res, el| {
+    let entries: Result<BTreeMap<String, String>, Box<dyn StdError>> =
+        v.split(',').try_fold(BTreeMap::default(), |mut res, el| {
             let pos = el
                 .find('=')
                 .ok_or_else(|| format!("invalid KEY=value: no `=` found in `{}`", el))?;
             res.insert(el[..pos].parse()?, el[pos + 1..].parse()?);
             Ok(res)
         });
-    Ok(Dictionary {
-        name,
-        entries: dict?,
-    })
+    Ok((name, entries?))
+}
+
+fn parse_dictionary(s: &str) -> Result<Dictionary, Box<dyn StdError>> {
+    let (name, entries) = parse_entries(s)?;
+    Ok(Dictionary { name, entries })
+}
+
+fn parse_log_endpoint(s: &str) -> Result<LogEndpoint, Box<dyn StdError>> {
+    let (name, path) = parse_key_value(s)?;
+    Ok(LogEndpoint { name, path })
+}
+
+fn parse_object_store(s: &str) -> Result<ObjectStore, Box<dyn StdError>> {
+    let (name, entries) = parse_entries(s)?;
+    Ok(ObjectStore { name, entries })
+}
+
+fn parse_secret_store(s: &str) -> Result<SecretStore, Box<dyn StdError>> {
+    let (name, secrets) = parse_entries(s)?;
+    Ok(SecretStore { name, secrets })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toml_tables_load() -> Result<(), Box<dyn StdError>> {
+        let tables = toml::from_str::<TOMLTables>(
+            r#"
+            [[backend]]
+            name = "backend_name"
+            address = "httpbin.org"
+
+            [[dictionary]]
+            name = "dictionary_name"
+            [dictionary.entries]
+            foo = "bar"
+            "#,
+        )?;
+        assert_eq!(
+            tables.backends,
+            Some(vec![Backend {
+                name: "backend_name".into(),
+                address: "httpbin.org".into(),
+                host_header: HostHeader::default(),
+            }])
+        );
+        assert_eq!(
+            tables.dictionaries.map(|dicts| dicts[0].entries.clone()),
+            Some(
+                vec![("foo".to_string(), "bar".to_string())]
+                    .into_iter()
+                    .collect()
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn store_and_log_endpoint_tables_load() -> Result<(), Box<dyn StdError>> {
+        let tables = toml::from_str::<TOMLTables>(
+            r#"
+            [[log_endpoint]]
+            name = "endpoint_name"
+            path = "logs/endpoint.log"
+
+            [[object_store]]
+            name = "object_store_name"
+            [object_store.entries]
+            foo = "bar"
+
+            [[secret_store]]
+            name = "secret_store_name"
+            [secret_store.secrets]
+            token = "hunter2"
+            "#,
+        )?;
+        assert_eq!(
+            tables.log_endpoints,
+            Some(vec![LogEndpoint {
+                name: "endpoint_name".into(),
+                path: "logs/endpoint.log".into(),
+            }])
+        );
+        assert_eq!(
+            tables.object_stores,
+            Some(vec![ObjectStore {
+                name: "object_store_name".into(),
+                entries: vec![("foo".to_string(), "bar".to_string())]
+                    .into_iter()
+                    .collect(),
+            }])
+        );
+        assert_eq!(
+            tables.secret_stores,
+            Some(vec![SecretStore {
+                name: "secret_store_name".into(),
+                secrets: vec![("token".to_string(), "hunter2".to_string())]
+                    .into_iter()
+                    .collect(),
+            }])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn stores_and_log_endpoints_parse() -> Result<(), Box<dyn StdError>> {
+        assert_eq!(
+            parse_log_endpoint("endpoint_name:logs/endpoint.log")?,
+            LogEndpoint {
+                name: "endpoint_name".into(),
+                path: "logs/endpoint.log".into(),
+            }
+        );
+        assert_eq!(parse_object_store("objects:a=1,b=2")?.entries.len(), 2);
+        assert_eq!(
+            parse_secret_store("secrets:token=hunter2")?.secrets["token"],
+            "hunter2"
+        );
+        assert!(parse_secret_store("secrets:token").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn backends_parse_with_host_header_policies() -> Result<(), Box<dyn StdError>> {
+        assert_eq!(
+            parse_backend("origin:localhost:3001")?,
+            Backend {
+                name: "origin".into(),
+                address: "localhost:3001".into(),
+                host_header: HostHeader::Preserve,
+            }
+        );
+        assert_eq!(
+            parse_backend("origin:localhost:3001,host_header=override")?.host_header,
+            HostHeader::Override
+        );
+        assert_eq!(
+            parse_backend("origin:localhost:3001,host_header=value:www.example.com")?,
+            Backend {
+                name: "origin".into(),
+                address: "localhost:3001".into(),
+                host_header: HostHeader::Value("www.example.com".into()),
+            }
+        );
+        assert!(parse_backend("origin:localhost:3001,host_header=overide").is_err());
+        assert!(parse_backend("origin:localhost:3001,override").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn max_instances_must_be_positive() {
+        assert!(Opts::from_iter_safe(&["fasttime", "--max-instances", "0"]).is_err());
+        assert!(Opts::from_iter_with_toml("max_instances = 0", &["fasttime"]).is_err());
+        assert_eq!(
+            Opts::from_iter_safe(&["fasttime", "--max-instances", "2"])
+                .ok()
+                .and_then(|opts| opts.max_instances)
+                .map(NonZeroUsize::get),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn routes_parse() -> Result<(), Box<dyn StdError>> {
+        assert_eq!(
+            parse_route("/health=200:OK")?,
+            Stub {
+                path: "/health".into(),
+                status: 200,
+                body: "OK".into(),
+            }
+        );
+        assert_eq!(
+            parse_route("/gone=410")?,
+            Stub {
+                path: "/gone".into(),
+                status: 410,
+                body: String::new(),
+            }
+        );
+        assert_eq!(parse_route("/time=200:12:00")?.body, "12:00");
+        assert!(parse_route("/health").is_err());
+        assert!(parse_route("/health=ok").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn merge_tables_places_toml_entries_first() {
+        let args = Some(vec![2, 3]);
+        let mut combined = args.clone();
+        merge_tables(&args, Some(vec![1]), &mut combined);
+        assert_eq!(combined, Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn merge_tables_keeps_args_without_toml() {
+        let args = Some(vec![2, 3]);
+        let mut combined = args.clone();
+        merge_tables(&args, None, &mut combined);
+        assert_eq!(combined, Some(vec![2, 3]));
+    }
 }
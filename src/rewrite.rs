@@ -0,0 +1,231 @@
+//! Rewrites downstream requests before the application receives them, for instance to simulate
+//! an upstream CDN in front of the application
+
+use crate::BoxError;
+use anyhow::anyhow;
+use http::{
+    header::{HeaderName, HeaderValue},
+    uri::{PathAndQuery, Uri},
+    Request,
+};
+use serde_derive::Deserialize;
+use std::{collections::BTreeMap, fs, path::Path};
+
+/// Which of the rules matching a request are applied
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Apply {
+    /// only the first matching rule
+    First,
+    /// every matching rule, in order
+    All,
+}
+
+impl Default for Apply {
+    fn default() -> Self {
+        Apply::First
+    }
+}
+
+/// A rule rewriting the path of, or adding headers to, requests whose path matches its pattern
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct Rule {
+    /// a path to match exactly, or a prefix to match when it ends with `*`
+    pub path: String,
+    /// a path to replace matching paths with. When both this and `path` end with `*`, the
+    /// remainder of the matched path is appended to it
+    pub rewrite: Option<String>,
+    /// headers to add to matching requests
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Rule {
+    /// Returns the rewritten path when `path` matches this rule's pattern
+    fn matches(
+        &self,
+        path: &str,
+    ) -> Option<String> {
+        let rest = match self.path.strip_suffix('*') {
+            Some(prefix) => path.strip_prefix(prefix)?,
+            None if self.path == path => "",
+            None => return None,
+        };
+        Some(match &self.rewrite {
+            Some(rewrite) => match rewrite.strip_suffix('*') {
+                Some(prefix) => format!("{}{}", prefix, rest),
+                None => rewrite.clone(),
+            },
+            None => path.to_owned(),
+        })
+    }
+}
+
+/// Rules loaded from a `--rewrite-rules` TOML file
+///
+/// ```toml
+/// apply = "all"
+///
+/// [[rule]]
+/// path = "/assets/*"
+/// rewrite = "/static/*"
+///
+/// [[rule]]
+/// path = "*"
+/// headers = { "Fastly-Client-IP" = "127.0.0.1" }
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct Rules {
+    #[serde(default)]
+    pub apply: Apply,
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>,
+}
+
+impl Rules {
+    /// Loads rules from a TOML file, validating their headers
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, BoxError> {
+        let path = path.as_ref();
+        let toml = fs::read_to_string(path)
+            .map_err(|e| anyhow!("unable to read rewrite rules {}: {}", path.display(), e))?;
+        let rules: Rules = toml::from_str(&toml)
+            .map_err(|e| anyhow!("invalid rewrite rules {}: {}", path.display(), e))?;
+        for rule in &rules.rules {
+            for (name, value) in &rule.headers {
+                HeaderName::from_bytes(name.as_bytes())?;
+                HeaderValue::from_str(value)?;
+            }
+        }
+        Ok(rules)
+    }
+
+    /// Rewrites a request with the rules matching its path, in order
+    pub fn apply<B>(
+        &self,
+        req: &mut Request<B>,
+    ) {
+        for rule in &self.rules {
+            let path = match rule.matches(req.uri().path()) {
+                Some(path) => path,
+                _ => continue,
+            };
+            if path != req.uri().path() {
+                let path_and_query = match req.uri().query() {
+                    Some(query) => format!("{}?{}", path, query),
+                    _ => path,
+                };
+                let mut parts = req.uri().clone().into_parts();
+                match path_and_query.parse::<PathAndQuery>() {
+                    Ok(path_and_query) => parts.path_and_query = Some(path_and_query),
+                    Err(e) => {
+                        log::warn!("skipping invalid rewritten path {}: {}", path_and_query, e);
+                        continue;
+                    }
+                }
+                match Uri::from_parts(parts) {
+                    Ok(uri) => *req.uri_mut() = uri,
+                    Err(e) => {
+                        log::warn!("skipping invalid rewritten uri: {}", e);
+                        continue;
+                    }
+                }
+            }
+            for (name, value) in &rule.headers {
+                if let (Ok(name), Ok(value)) = (
+                    HeaderName::from_bytes(name.as_bytes()),
+                    HeaderValue::from_str(value),
+                ) {
+                    req.headers_mut().append(name, value);
+                }
+            }
+            if self.apply == Apply::First {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(
+        apply: Apply,
+        rules: Vec<Rule>,
+    ) -> Rules {
+        Rules { apply, rules }
+    }
+
+    #[test]
+    fn rules_load_from_toml() -> Result<(), BoxError> {
+        let rules: Rules = toml::from_str(
+            r#"
+            apply = "all"
+
+            [[rule]]
+            path = "/assets/*"
+            rewrite = "/static/*"
+
+            [[rule]]
+            path = "*"
+            headers = { "x-cdn" = "fasttime" }
+            "#,
+        )?;
+        assert_eq!(rules.apply, Apply::All);
+        assert_eq!(rules.rules.len(), 2);
+        assert_eq!(rules.rules[1].headers["x-cdn"], "fasttime");
+        Ok(())
+    }
+
+    #[test]
+    fn apply_rewrites_paths() -> Result<(), BoxError> {
+        let rules = rules(
+            Apply::First,
+            vec![
+                Rule {
+                    path: "/assets/*".into(),
+                    rewrite: Some("/static/*".into()),
+                    ..Rule::default()
+                },
+                Rule {
+                    path: "/old".into(),
+                    rewrite: Some("/new".into()),
+                    ..Rule::default()
+                },
+            ],
+        );
+        let mut req = Request::get("/assets/app.js?v=1").body(())?;
+        rules.apply(&mut req);
+        assert_eq!(req.uri(), "/static/app.js?v=1");
+
+        let mut req = Request::get("/old").body(())?;
+        rules.apply(&mut req);
+        assert_eq!(req.uri(), "/new");
+
+        let mut req = Request::get("/older").body(())?;
+        rules.apply(&mut req);
+        assert_eq!(req.uri(), "/older");
+        Ok(())
+    }
+
+    #[test]
+    fn apply_adds_headers_from_first_or_all_matches() -> Result<(), BoxError> {
+        let header = |name: &str| Rule {
+            path: "*".into(),
+            headers: vec![(name.to_string(), "1".to_string())]
+                .into_iter()
+                .collect(),
+            ..Rule::default()
+        };
+        let mut req = Request::get("/").body(())?;
+        rules(Apply::First, vec![header("x-a"), header("x-b")]).apply(&mut req);
+        assert!(req.headers().contains_key("x-a"));
+        assert!(!req.headers().contains_key("x-b"));
+
+        let mut req = Request::get("/").body(())?;
+        rules(Apply::All, vec![header("x-a"), header("x-b")]).apply(&mut req);
+        assert!(req.headers().contains_key("x-a"));
+        assert!(req.headers().contains_key("x-b"));
+        Ok(())
+    }
+}
@@ -0,0 +1,265 @@
+//! Defines timeouts on how slowly clients may send requests, so stalled clients can't hold
+//! connections open indefinitely
+
+use futures_util::stream::{self, StreamExt};
+use hyper::{body::Bytes, Body, Request};
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep, Sleep},
+};
+
+/// Limits on how long a client may take to send a request
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClientTimeouts {
+    /// time to send a request's headers, including time a kept alive connection sits idle
+    pub header: Option<Duration>,
+    /// time to wait for each next part of a request's body
+    pub body: Option<Duration>,
+}
+
+/// Tracks whether a connection is awaiting a request's headers, reading its body, or serving it
+///
+/// Each request advances the generation twice, once when its headers are read and once when
+/// it is answered, so even generations await headers. While a request is served, its body is
+/// read until it ends, after which reads only wait on the response
+#[derive(Clone, Debug, Default)]
+pub struct Phase {
+    generation: Arc<AtomicUsize>,
+    reading_body: Arc<AtomicBool>,
+}
+
+impl Phase {
+    fn generation(&self) -> usize {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    fn reading_body(&self) -> bool {
+        self.reading_body.load(Ordering::SeqCst)
+    }
+
+    /// Marks a request as being served until the response `respond` answers it with resolves,
+    /// and its body as being read until it ends
+    pub fn serve<F, R>(
+        &self,
+        req: Request<Body>,
+        respond: F,
+    ) -> impl Future<Output = R::Output>
+    where
+        F: FnOnce(Request<Body>) -> R,
+        R: Future,
+    {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        let req = if req.body().is_end_stream() {
+            req
+        } else {
+            self.reading_body.store(true, Ordering::SeqCst);
+            let read = self.clone();
+            req.map(|body| {
+                Body::wrap_stream(body.chain(stream::poll_fn(move |_| {
+                    read.reading_body.store(false, Ordering::SeqCst);
+                    Poll::Ready(None::<Result<Bytes, hyper::Error>>)
+                })))
+            })
+        };
+        let response = respond(req);
+        let phase = self.clone();
+        async move {
+            let output = response.await;
+            phase.reading_body.store(false, Ordering::SeqCst);
+            phase.generation.fetch_add(1, Ordering::SeqCst);
+            output
+        }
+    }
+}
+
+/// A client connection whose reads fail with `TimedOut` when the client stalls
+pub struct TimeoutStream<S> {
+    inner: S,
+    timeouts: ClientTimeouts,
+    phase: Phase,
+    /// the generation a deadline was set in, and the deadline
+    deadline: Option<(usize, Pin<Box<Sleep>>)>,
+}
+
+impl<S> TimeoutStream<S> {
+    pub fn new(
+        inner: S,
+        timeouts: ClientTimeouts,
+    ) -> Self {
+        TimeoutStream {
+            inner,
+            timeouts,
+            phase: Phase::default(),
+            deadline: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns the phase services should mark the requests they serve with
+    pub fn phase(&self) -> Phase {
+        self.phase.clone()
+    }
+
+    fn arm(
+        &mut self,
+        generation: usize,
+        timeout: Duration,
+    ) {
+        if self.deadline.as_ref().map(|(armed, _)| *armed) != Some(generation) {
+            self.deadline = Some((generation, Box::pin(sleep(timeout))));
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TimeoutStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let generation = self.phase.generation();
+        let awaiting_headers = generation % 2 == 0;
+        // once a body is read, reads only wait on the application, however slowly it responds
+        let reading_body = !awaiting_headers && self.phase.reading_body();
+        if !awaiting_headers && !reading_body {
+            self.deadline = None;
+        }
+        // headers must arrive in full within their timeout, however quickly each part of them does
+        if let (true, Some(timeout)) = (awaiting_headers, self.timeouts.header) {
+            self.arm(generation, timeout);
+        }
+        if let Poll::Ready(result) = Pin::new(&mut self.inner).poll_read(cx, buf) {
+            if !awaiting_headers {
+                self.deadline = None;
+            }
+            return Poll::Ready(result);
+        }
+        if let (true, Some(timeout)) = (reading_body, self.timeouts.body) {
+            self.arm(generation, timeout);
+        }
+        match &mut self.deadline {
+            Some((armed, deadline)) if *armed == generation => match deadline.as_mut().poll(cx) {
+                Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    if awaiting_headers {
+                        "client took too long to send request headers"
+                    } else {
+                        "client took too long to send request body"
+                    },
+                ))),
+                Poll::Pending => Poll::Pending,
+            },
+            _ => Poll::Pending,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TimeoutStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt},
+        time::timeout,
+    };
+
+    #[tokio::test]
+    async fn stalled_bodies_time_out() -> Result<(), crate::BoxError> {
+        let (mut client, server) = duplex(64);
+        let mut stream = TimeoutStream::new(
+            server,
+            ClientTimeouts {
+                header: None,
+                body: Some(Duration::from_millis(100)),
+            },
+        );
+        let phase = stream.phase();
+        let (_sender, body) = Body::channel();
+        let serving = phase.serve(Request::new(body), |_| async {});
+        client.write_all(b"part").await?;
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await?;
+        let err = timeout(Duration::from_secs(5), stream.read(&mut buf))
+            .await?
+            .expect_err("expected the stalled body to time out");
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        serving.await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reads_after_the_body_ends_wait_on_the_response() -> Result<(), crate::BoxError> {
+        let (_client, server) = duplex(64);
+        let mut stream = TimeoutStream::new(
+            server,
+            ClientTimeouts {
+                header: None,
+                body: Some(Duration::from_millis(100)),
+            },
+        );
+        let phase = stream.phase();
+        // reads the whole body, then takes longer than the body timeout to respond
+        let serving = phase.serve(Request::new(Body::from("body")), |req| async move {
+            let body = hyper::body::to_bytes(req.into_body()).await?;
+            sleep(Duration::from_millis(300)).await;
+            Ok::<_, hyper::Error>(body)
+        });
+        let mut buf = [0; 4];
+        let (served, read) = tokio::join!(
+            serving,
+            timeout(Duration::from_millis(200), stream.read(&mut buf))
+        );
+        assert_eq!(served?, "body");
+        // still waiting, rather than failing, when the body timeout passed
+        assert!(read.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn idle_connections_without_timeouts_wait() -> Result<(), crate::BoxError> {
+        let (_client, server) = duplex(64);
+        let mut stream = TimeoutStream::new(server, ClientTimeouts::default());
+        let mut buf = [0; 4];
+        assert!(timeout(Duration::from_millis(200), stream.read(&mut buf))
+            .await
+            .is_err());
+        Ok(())
+    }
+}
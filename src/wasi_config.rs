@@ -0,0 +1,88 @@
+//! Loads the arguments and environment variables guests observe through WASI from a file
+
+use crate::BoxError;
+use anyhow::anyhow;
+use serde_derive::Deserialize;
+use std::{collections::BTreeMap, fs, path::Path};
+
+/// WASI arguments and environment variables loaded from a `--wasi-config` TOML file
+///
+/// ```toml
+/// args = ["app.wasm", "--verbose"]
+///
+/// [env]
+/// GREETING = "hello"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WasiConfig {
+    /// arguments, including the program name guests conventionally expect first
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// environment variables, by name
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+impl WasiConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, BoxError> {
+        let path = path.as_ref();
+        let toml = fs::read_to_string(path)
+            .map_err(|e| anyhow!("unable to read wasi config {}: {}", path.display(), e))?;
+        let config: WasiConfig = toml::from_str(&toml)
+            .map_err(|e| anyhow!("invalid wasi config {}: {}", path.display(), e))?;
+        config
+            .validate()
+            .map_err(|e| anyhow!("invalid wasi config {}: {}", path.display(), e))?;
+        Ok(config)
+    }
+
+    /// Rejects values WASI can't represent: strings with NUL bytes, and variable names which
+    /// are empty or contain `=`
+    fn validate(&self) -> Result<(), String> {
+        if let Some(arg) = self.args.iter().find(|arg| arg.contains('\0')) {
+            return Err(format!("argument {:?} contains a NUL byte", arg));
+        }
+        for (name, value) in &self.env {
+            if name.is_empty() || name.contains('=') || name.contains('\0') {
+                return Err(format!("invalid environment variable name {:?}", name));
+            }
+            if value.contains('\0') {
+                return Err(format!("environment variable {} contains a NUL byte", name));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configs_load_from_toml() -> Result<(), BoxError> {
+        let config: WasiConfig = toml::from_str(
+            r#"
+            args = ["app.wasm", "--verbose"]
+
+            [env]
+            GREETING = "hello"
+            "#,
+        )?;
+        assert_eq!(config.args, vec!["app.wasm", "--verbose"]);
+        assert_eq!(config.env["GREETING"], "hello");
+        assert_eq!(config.validate(), Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_configs_are_rejected() -> Result<(), BoxError> {
+        assert!(toml::from_str::<WasiConfig>("argv = []").is_err());
+        assert!(toml::from_str::<WasiConfig>("[env]\nPORT = 8080").is_err());
+        let config: WasiConfig = toml::from_str("[env]\n\"A=B\" = \"c\"")?;
+        assert!(config.validate().is_err());
+        let config: WasiConfig = toml::from_str("args = [\"a\\u0000b\"]")?;
+        assert!(config.validate().is_err());
+        Ok(())
+    }
+}